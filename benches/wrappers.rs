@@ -0,0 +1,101 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Measures the overhead of the wrappers on each call, for common kinds of schedules.
+//!
+//! Run with `cargo bench`. Each line reports the average time per call into the wrapper, so that
+//! changes to the hot path can be compared before and after.
+
+use partial_io::{PartialOp, PartialRead, PartialWrite};
+use std::{
+    hint::black_box,
+    io::{self, Read, Write},
+    iter,
+    time::Instant,
+};
+
+const CALLS: usize = 1_000_000;
+
+// Times `run` on a value built by `setup`, so that building the schedule isn't measured.
+fn bench<T>(name: &str, mut setup: impl FnMut() -> T, run: fn(&mut T)) {
+    // Warm up, then take the best of a few runs to reduce noise.
+    run(&mut setup());
+    let best = (0..5)
+        .map(|_| {
+            let mut value = setup();
+            let start = Instant::now();
+            run(&mut value);
+            start.elapsed()
+        })
+        .min()
+        .expect("at least one run");
+    println!(
+        "{:<24} {:>8.2} ns/call",
+        name,
+        best.as_nanos() as f64 / CALLS as f64
+    );
+}
+
+fn read_calls<R: Read>(reader: &mut R) {
+    let mut buf = [0; 16];
+    for _ in 0..CALLS {
+        black_box(reader.read(black_box(&mut buf)).ok());
+    }
+}
+
+fn write_calls<W: Write>(writer: &mut W) {
+    let buf = [0; 16];
+    for _ in 0..CALLS {
+        black_box(writer.write(black_box(&buf)).ok());
+    }
+}
+
+fn main() {
+    bench("read/unwrapped", || io::repeat(0), read_calls);
+    bench(
+        "read/passthrough",
+        || PartialRead::passthrough(io::repeat(0)),
+        read_calls,
+    );
+    bench(
+        "read/vec-unlimited",
+        || PartialRead::new(io::repeat(0), vec![PartialOp::Unlimited; CALLS]),
+        read_calls,
+    );
+    bench(
+        "read/vec-limited",
+        || PartialRead::new(io::repeat(0), vec![PartialOp::Limited(8); CALLS]),
+        read_calls,
+    );
+    bench(
+        "read/vec-exhausted",
+        || PartialRead::new(io::repeat(0), Vec::new()),
+        read_calls,
+    );
+    bench(
+        "read/iter-limited",
+        || PartialRead::new(io::repeat(0), iter::repeat(PartialOp::Limited(8))),
+        read_calls,
+    );
+    bench(
+        "read/byte-by-byte",
+        || PartialRead::byte_by_byte(io::repeat(0)),
+        read_calls,
+    );
+
+    bench("write/unwrapped", io::sink, write_calls);
+    bench(
+        "write/vec-unlimited",
+        || PartialWrite::new(io::sink(), vec![PartialOp::Unlimited; CALLS]),
+        write_calls,
+    );
+    bench(
+        "write/vec-limited",
+        || PartialWrite::new(io::sink(), vec![PartialOp::Limited(8); CALLS]),
+        write_calls,
+    );
+}
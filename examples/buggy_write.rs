@@ -85,6 +85,7 @@ impl<W: Write> Write for BuggyWrite<W> {
 
 fn main() {
     test::buggy_write();
+    test::verify_buggy_write();
     // To run this test and see it fail, uncomment the next line. To fix the
     // bug, see `examples/buggy_write.rs`.
     //test::quickcheck_buggy_write();
@@ -107,6 +108,7 @@ mod test {
 
     use partial_io::{
         quickcheck_types::{GenInterrupted, PartialWithErrors},
+        verify::{self, ViolationKind},
         PartialOp, PartialWrite,
     };
 
@@ -143,6 +145,21 @@ mod test {
         assert_eq!(inner, expected);
     }
 
+    #[test]
+    fn test_verify_buggy_write() {
+        verify_buggy_write();
+    }
+
+    /// Check `BuggyWrite` with the ready-made harness for buffered writers, which runs through a
+    /// fixed set of schedules instead of random ones.
+    pub fn verify_buggy_write() {
+        let violation = verify::buffered_writer(BuggyWrite::new).unwrap_err();
+        // The write is retried after an Interrupted error, by which point the data has already
+        // been copied into the buffer.
+        assert_eq!(violation.kind, ViolationKind::DuplicatedData);
+        println!("verify::buffered_writer found a bug: {}", violation);
+    }
+
     /// Test that quickcheck catches buggy writes.
     ///
     /// To run this test and see it fail, remove the #[ignore] annotation. To
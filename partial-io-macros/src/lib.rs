@@ -0,0 +1,160 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Procedural macros for `partial-io`.
+//!
+//! These are re-exported by `partial-io` with the `quickcheck1` feature, and
+//! shouldn't be depended on directly.
+
+#![deny(warnings)]
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input, Error, Expr, FnArg, Ident, ItemFn, Token, Type,
+};
+
+/// The arguments to `#[partial_test]`.
+struct Args {
+    fixture: Option<Expr>,
+}
+
+impl Parse for Args {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.is_empty() {
+            return Ok(Args { fixture: None });
+        }
+        let name: Ident = input.parse()?;
+        if name != "fixture" {
+            return Err(Error::new(name.span(), "expected `fixture = <expr>`"));
+        }
+        input.parse::<Token![=]>()?;
+        let fixture = input.parse()?;
+        Ok(Args {
+            fixture: Some(fixture),
+        })
+    }
+}
+
+/// Turns a function that takes a generated sequence of `PartialOp`s into a
+/// `quickcheck` test.
+///
+/// The first argument of the function is the sequence, of any type in
+/// `partial_io::quickcheck_types` that generates one, such as
+/// `PartialWithErrors<GenInterrupted>`. The function can return anything
+/// `quickcheck` can test, such as `bool`, `TestResult` to discard cases, or
+/// `()` to fail by panicking.
+///
+/// With `#[partial_test(fixture = <expr>)]`, the function takes a second
+/// argument: the fixture created by `<expr>` for each case, wrapped with the
+/// sequence through `PartialWrap::wrap`.
+///
+/// If the test fails, the operations of the minimal failing case are printed
+/// as a Rust expression, to paste into a regression test. If the
+/// `PARTIAL_IO_CORPUS_DIR` environment variable is set, they're also saved
+/// there, and saved cases are replayed before new ones are generated. See the
+/// `corpus` documentation in `partial-io` for more.
+///
+/// See the `quickcheck_types` documentation in `partial-io` for an example.
+#[proc_macro_attribute]
+pub fn partial_test(args: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as Args);
+    let item = parse_macro_input!(item as ItemFn);
+    match expand(args, item) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand(args: Args, item: ItemFn) -> syn::Result<proc_macro2::TokenStream> {
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = item;
+    if let Some(asyncness) = sig.asyncness {
+        return Err(Error::new_spanned(
+            asyncness,
+            "#[partial_test] functions can't be async",
+        ));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            sig.generics,
+            "#[partial_test] functions can't be generic",
+        ));
+    }
+
+    let types = sig
+        .inputs
+        .iter()
+        .map(|input| match input {
+            FnArg::Typed(pat_type) => Ok(&*pat_type.ty),
+            FnArg::Receiver(receiver) => Err(Error::new_spanned(
+                receiver,
+                "#[partial_test] functions can't take self",
+            )),
+        })
+        .collect::<syn::Result<Vec<&Type>>>()?;
+    let call = match (&types[..], args.fixture) {
+        ([_], None) => quote! { __partial_test_body(seq) },
+        ([_, fixture_ty], Some(fixture)) => quote! {
+            let fixture = <#fixture_ty as ::partial_io::PartialWrap>::wrap(
+                #fixture,
+                ::std::clone::Clone::clone(&seq),
+            );
+            __partial_test_body(seq, fixture)
+        },
+        ([_], Some(fixture)) => {
+            return Err(Error::new_spanned(
+                fixture,
+                "`fixture` requires a second argument to pass the wrapped fixture in",
+            ))
+        }
+        ([_, _], None) => {
+            return Err(Error::new_spanned(
+                &sig.inputs,
+                "a second argument requires `#[partial_test(fixture = <expr>)]`",
+            ))
+        }
+        _ => {
+            return Err(Error::new(
+                Span::call_site(),
+                "expected the operations, and optionally a wrapped fixture",
+            ))
+        }
+    };
+
+    let name = &sig.ident;
+    let inputs = &sig.inputs;
+    let output = &sig.output;
+    let ops_ty = types[0];
+    Ok(quote! {
+        #[test]
+        #(#attrs)*
+        #vis fn #name() {
+            fn __partial_test_body(#inputs) #output #block
+
+            fn __partial_test_case(
+                seq: #ops_ty,
+            ) -> ::partial_io::quickcheck_types::__private::TestResult {
+                let ops = seq.to_vec();
+                ::partial_io::quickcheck_types::__private::run_case(ops, move || { #call })
+            }
+
+            ::partial_io::quickcheck_types::__private::run(
+                ::std::concat!(::std::module_path!(), "::", ::std::stringify!(#name)),
+                __partial_test_case as fn(#ops_ty) -> _,
+            );
+        }
+    })
+}
@@ -11,8 +11,14 @@
 //! This is separate from `PartialWrite` because on `WouldBlock` errors, it
 //! causes `futures` to try writing or flushing again.
 
-use crate::{futures_util::FuturesOps, PartialOp};
-use futures::prelude::*;
+use crate::{
+    futures_util::FuturesOps,
+    read::{EofChecks, FillBufChecks},
+    seeded::SeededOps,
+    seek::SeekOps,
+    Alignment, AppliedOp, BufSize, LocalOps, OpEngine, PartialOp, PartialWrap, Profile, SeekOp,
+};
+use futures::{prelude::*, ready};
 use pin_project::pin_project;
 use std::{
     fmt, io,
@@ -23,6 +29,10 @@ use std::{
 /// A wrapper that breaks inner `AsyncRead` instances up according to the
 /// provided iterator.
 ///
+/// The reader may be unsized, such as `PartialAsyncRead<dyn AsyncRead>` behind a `Box`, or
+/// borrowed. To wrap a `&mut dyn AsyncRead` that isn't `Unpin`, pass in a
+/// `Pin<&mut dyn AsyncRead>` instead.
+///
 /// Available with the `futures03` feature for `futures` traits, and with the `tokio1` feature for
 /// `tokio` traits.
 ///
@@ -75,23 +85,75 @@ use std::{
 /// # }
 /// ```
 #[pin_project]
-pub struct PartialAsyncRead<R> {
+pub struct PartialAsyncRead<R: ?Sized> {
+    ops: FuturesOps,
+    checks: FillBufChecks,
+    eof: EofChecks,
+    overreport: bool,
+    seek_ops: SeekOps,
     #[pin]
     inner: R,
-    ops: FuturesOps,
 }
 
-impl<R> PartialAsyncRead<R> {
+impl<R: ?Sized> PartialAsyncRead<R> {
     /// Creates a new `PartialAsyncRead` wrapper over the reader with the specified `PartialOp`s.
     pub fn new<I>(inner: R, iter: I) -> Self
     where
+        R: Sized,
         I: IntoIterator<Item = PartialOp> + 'static,
         I::IntoIter: Send,
     {
-        PartialAsyncRead {
+        Self::from_engine(inner, OpEngine::new(iter))
+    }
+
+    /// Creates a new `PartialAsyncRead` wrapper over the reader with a reproducible randomized
+    /// schedule of `PartialOp`s, generated from `seed` and `profile`.
+    ///
+    /// Unless the profile sets error kinds, operations fail with `WouldBlock`, which turns into
+    /// `Poll::Pending`. If the thread panics while the wrapper is alive, the seed is printed when
+    /// it's dropped. See `Profile` for more.
+    pub fn with_seed(inner: R, seed: u64, profile: Profile) -> Self
+    where
+        R: Sized,
+    {
+        Self::new(
             inner,
-            ops: FuturesOps::new(iter),
-        }
+            SeededOps::for_wrapper("PartialAsyncRead", seed, profile, io::ErrorKind::WouldBlock),
+        )
+    }
+
+    /// Creates a new `PartialAsyncRead` wrapper over the reader with `PartialOp`s that aren't
+    /// `Send`, for single-threaded executors.
+    ///
+    /// The reader can only be used on the current thread. See `LocalOps` for more.
+    pub fn new_local<I>(inner: R, iter: I) -> Self
+    where
+        R: Sized,
+        I: IntoIterator<Item = PartialOp> + 'static,
+    {
+        Self::new(inner, LocalOps::new(iter))
+    }
+
+    /// Creates a new `PartialAsyncRead` wrapper over the reader in passthrough mode, which forwards
+    /// every call as-is.
+    ///
+    /// Setting `PartialOp`s turns passthrough mode off. See `OpEngine::passthrough` for more.
+    pub fn passthrough(inner: R) -> Self
+    where
+        R: Sized,
+    {
+        Self::from_engine(inner, OpEngine::passthrough())
+    }
+
+    /// Creates a new `PartialAsyncRead` wrapper over the reader that limits every operation to 1
+    /// byte, forever.
+    ///
+    /// This doesn't allocate. See `OpEngine::byte_by_byte` for more.
+    pub fn byte_by_byte(inner: R) -> Self
+    where
+        R: Sized,
+    {
+        Self::from_engine(inner, OpEngine::byte_by_byte())
     }
 
     /// Sets the `PartialOp`s for this reader.
@@ -115,6 +177,139 @@ impl<R> PartialAsyncRead<R> {
         this
     }
 
+    /// Caps every operation on this reader to at most `max_op_size` bytes, in addition to the
+    /// limits in `PartialOp::Limited`.
+    ///
+    /// See `OpEngine::set_max_op_size` for more.
+    pub fn set_max_op_size(&mut self, max_op_size: usize) -> &mut Self {
+        self.ops.engine_mut().set_max_op_size(max_op_size);
+        self
+    }
+
+    /// Caps the number of bytes read through this reader in total to `limit`. Once it's reached,
+    /// every further read returns EOF, whatever the operations are. `fill_buf` isn't capped.
+    ///
+    /// See `OpEngine::set_total_limit` for more.
+    pub fn set_total_limit(&mut self, limit: Option<u64>) -> &mut Self {
+        self.ops.engine_mut().set_total_limit(limit);
+        self
+    }
+
+    /// Starts recording the operations applied by this reader, and the methods they are applied to.
+    ///
+    /// Use `into_parts` and `OpEngine::into_report` to see which operations were never reached.
+    pub fn record_ops(&mut self) -> &mut Self {
+        self.ops.engine_mut().record_ops();
+        self
+    }
+
+    /// Returns the operations applied by this reader since `record_ops` was called.
+    pub fn applied_ops(&self) -> &[AppliedOp] {
+        self.ops.engine().applied_ops()
+    }
+
+    /// Starts recording the size of every buffer passed to this reader, along with the size
+    /// forwarded to the inner reader after limiting.
+    ///
+    /// See `OpEngine::record_sizes` for more.
+    pub fn record_sizes(&mut self) -> &mut Self {
+        self.ops.engine_mut().record_sizes();
+        self
+    }
+
+    /// Returns the buffer sizes recorded by this reader since `record_sizes` was called, in order.
+    pub fn buf_sizes(&self) -> &[BufSize] {
+        self.ops.engine().buf_sizes()
+    }
+
+    /// Starts recording the timeline of method calls on this reader.
+    ///
+    /// See the `timeline` module for more.
+    pub fn record_calls(&mut self) -> &mut Self {
+        self.ops.engine_mut().record_calls();
+        self
+    }
+
+    /// Returns the method calls on this reader since `record_calls` was called, in order.
+    pub fn calls(&self) -> &[&'static str] {
+        self.ops.engine().calls()
+    }
+
+    /// Snaps the sizes in `PartialOp::Limited` on this reader to boundaries.
+    ///
+    /// See `OpEngine::set_alignment` for more.
+    pub fn set_alignment(&mut self, alignment: Option<Alignment>) -> &mut Self {
+        self.ops.engine_mut().set_alignment(alignment);
+        self
+    }
+
+    /// Enables or disables checks for `AsyncBufRead` protocol invariants.
+    ///
+    /// When enabled, this reader panics if `consume` is called with more bytes than are left from
+    /// the last successful `poll_fill_buf`, or with any bytes after `poll_fill_buf` returned
+    /// `Poll::Pending` or an error. The latter usually means that the consumer held on to the
+    /// buffer from an earlier call across `Poll::Pending`.
+    pub fn set_invariant_checks(&mut self, enabled: bool) -> &mut Self {
+        self.checks.enabled = enabled;
+        self
+    }
+
+    /// Returns the number of reads into a non-empty buffer made after this reader returned EOF.
+    ///
+    /// See `PartialRead::reads_after_eof` for more.
+    pub fn reads_after_eof(&self) -> usize {
+        self.eof.reads_after_eof
+    }
+
+    /// Makes reads fail once more than `max` reads were made after this reader returned EOF, or
+    /// removes the limit if `max` is `None`.
+    ///
+    /// See `PartialRead::set_max_reads_after_eof` for more.
+    pub fn set_max_reads_after_eof(&mut self, max: Option<usize>) -> &mut Self {
+        self.eof.max = max;
+        self
+    }
+
+    /// Simulates a broken source that claims to have read more bytes than it produced.
+    ///
+    /// With overreporting on, every read that produces at least one byte reports the whole limit
+    /// as read: the limit from `PartialOp::Limited`, or the whole buffer otherwise. The extra
+    /// bytes are whatever the buffer held before. With `tokio`, the claim stays within the
+    /// initialized part of the `ReadBuf`. Reads at EOF still report 0 bytes.
+    ///
+    /// This is meant for checking that code downstream, such as a parser for length-prefixed
+    /// frames, validates the data it reads rather than trusting the count. No correct reader
+    /// behaves this way.
+    pub fn set_overreport(&mut self, overreport: bool) -> &mut Self {
+        self.overreport = overreport;
+        self
+    }
+
+    /// Sets the `SeekOp`s for seeks on this reader.
+    ///
+    /// See `SeekOp` for more.
+    pub fn set_seek_ops<I>(&mut self, iter: I) -> &mut Self
+    where
+        I: IntoIterator<Item = SeekOp> + 'static,
+        I::IntoIter: Send,
+    {
+        self.seek_ops.replace(iter);
+        self
+    }
+
+    /// Returns the operations of this reader, along with its wake and poll statistics.
+    pub fn ops(&self) -> &FuturesOps {
+        &self.ops
+    }
+
+    /// Returns the operations of this reader, to set its clock and how it delivers wakes, or to
+    /// enable checks on how it's polled.
+    ///
+    /// See `FuturesOps` for the settings.
+    pub fn ops_mut(&mut self) -> &mut FuturesOps {
+        &mut self.ops
+    }
+
     /// Returns a shared reference to the underlying reader.
     pub fn get_ref(&self) -> &R {
         &self.inner
@@ -130,10 +325,71 @@ impl<R> PartialAsyncRead<R> {
         self.project().inner
     }
 
+    /// Returns a pinned mutable reference to the underlying reader.
+    ///
+    /// This is the same as `pin_get_mut`, under the name used by `tokio`'s wrappers.
+    pub fn get_pin_mut(self: Pin<&mut Self>) -> Pin<&mut R> {
+        self.project().inner
+    }
+
     /// Consumes this wrapper, returning the underlying reader.
-    pub fn into_inner(self) -> R {
+    pub fn into_inner(self) -> R
+    where
+        R: Sized,
+    {
         self.inner
     }
+
+    /// Consumes this wrapper, returning the underlying reader and the engine with the `PartialOp`s
+    /// that haven't been consumed yet.
+    ///
+    /// Use `OpEngine::into_ops` to inspect the operations that are left.
+    pub fn into_parts(self) -> (R, OpEngine)
+    where
+        R: Sized,
+    {
+        (self.inner, self.ops.into_engine())
+    }
+
+    // Replaces the underlying reader, keeping the operations and settings.
+    #[cfg(feature = "tokio1")]
+    pub(crate) fn replace_inner<T>(self, inner: T) -> (R, PartialAsyncRead<T>)
+    where
+        R: Sized,
+    {
+        let replaced = PartialAsyncRead {
+            inner,
+            checks: self.checks,
+            eof: self.eof,
+            overreport: self.overreport,
+            seek_ops: self.seek_ops,
+            ops: self.ops,
+        };
+        (self.inner, replaced)
+    }
+}
+
+impl<R> PartialWrap for PartialAsyncRead<R> {
+    type Inner = R;
+
+    fn from_engine(inner: R, engine: OpEngine) -> Self {
+        PartialAsyncRead {
+            inner,
+            checks: FillBufChecks::default(),
+            eof: EofChecks::default(),
+            overreport: false,
+            seek_ops: SeekOps::new(),
+            ops: FuturesOps::from_engine(engine),
+        }
+    }
+
+    fn engine_mut(&mut self) -> &mut OpEngine {
+        self.ops.engine_mut()
+    }
+
+    fn into_parts(self) -> (R, OpEngine) {
+        PartialAsyncRead::into_parts(self)
+    }
 }
 
 // ---
@@ -142,7 +398,7 @@ impl<R> PartialAsyncRead<R> {
 
 impl<R> AsyncRead for PartialAsyncRead<R>
 where
-    R: AsyncRead,
+    R: AsyncRead + ?Sized,
 {
     #[inline]
     fn poll_read(
@@ -153,16 +409,25 @@ where
         let this = self.project();
         let inner = this.inner;
         let len = buf.len();
+        let overreport = *this.overreport;
 
-        this.ops.poll_impl(
+        this.ops.engine_mut().begin_call("poll_read");
+        if let Some(err) = this.eof.before_read("poll_read", len) {
+            return Poll::Ready(Err(err));
+        }
+        let res = ready!(this.ops.poll_impl(
             cx,
-            |cx, len| match len {
-                Some(len) => inner.poll_read(cx, &mut buf[..len]),
-                None => inner.poll_read(cx, buf),
+            |cx, limit| {
+                let limit = limit.unwrap_or(len);
+                let res = ready!(inner.poll_read(cx, &mut buf[..limit]));
+                Poll::Ready(res.map(|n| if overreport && n > 0 { limit } else { n }))
             },
             len,
             "error during poll_read, generated by partial-io",
-        )
+        ));
+        let res = this.ops.engine_mut().record(res);
+        this.eof.after_read(len, res.as_ref().ok().copied());
+        Poll::Ready(res)
     }
 
     // TODO: do we need to implement poll_read_vectored? It's a bit tricky to do.
@@ -170,29 +435,42 @@ where
 
 impl<R> AsyncBufRead for PartialAsyncRead<R>
 where
-    R: AsyncBufRead,
+    R: AsyncBufRead + ?Sized,
 {
     fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
         let this = self.project();
         let inner = this.inner;
 
-        this.ops.poll_impl_no_limit(
+        this.ops.engine_mut().begin_call("poll_fill_buf");
+        let res = this.ops.poll_impl_no_limit(
             cx,
             |cx| inner.poll_fill_buf(cx),
             "error during poll_read, generated by partial-io",
-        )
+        );
+        after_poll_fill_buf(this.checks, &res);
+        res
     }
 
     #[inline]
     fn consume(self: Pin<&mut Self>, amt: usize) {
-        self.project().inner.consume(amt)
+        let this = self.project();
+        this.checks
+            .before_consume("PartialAsyncRead", "poll_fill_buf", amt);
+        this.inner.consume(amt)
+    }
+}
+
+fn after_poll_fill_buf(checks: &mut FillBufChecks, res: &Poll<io::Result<&[u8]>>) {
+    match res {
+        Poll::Ready(res) => checks.after_fill_buf(Some(res)),
+        Poll::Pending => checks.after_fill_buf(None),
     }
 }
 
 /// This is a forwarding impl to support duplex structs.
 impl<R> AsyncWrite for PartialAsyncRead<R>
 where
-    R: AsyncWrite,
+    R: AsyncWrite + ?Sized,
 {
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
         self.project().inner.poll_write(cx, buf)
@@ -207,10 +485,10 @@ where
     }
 }
 
-/// This is a forwarding impl to support duplex structs.
+/// Seeks are forwarded to the inner reader, after applying `SeekOp`s.
 impl<R> AsyncSeek for PartialAsyncRead<R>
 where
-    R: AsyncSeek,
+    R: AsyncSeek + ?Sized,
 {
     #[inline]
     fn poll_seek(
@@ -218,7 +496,10 @@ where
         cx: &mut Context,
         pos: io::SeekFrom,
     ) -> Poll<io::Result<u64>> {
-        self.project().inner.poll_seek(cx, pos)
+        let this = self.project();
+        let pos = this.seek_ops.resume(pos);
+        let res = ready!(this.inner.poll_seek(cx, pos));
+        Poll::Ready(this.seek_ops.complete(res))
     }
 }
 
@@ -229,7 +510,9 @@ where
 #[cfg(feature = "tokio1")]
 pub(crate) mod tokio_impl {
     use super::PartialAsyncRead;
+    use futures::ready;
     use std::{
+        cmp,
         io::{self, SeekFrom},
         pin::Pin,
         task::{Context, Poll},
@@ -238,7 +521,7 @@ pub(crate) mod tokio_impl {
 
     impl<R> AsyncRead for PartialAsyncRead<R>
     where
-        R: AsyncRead,
+        R: AsyncRead + ?Sized,
     {
         fn poll_read(
             self: Pin<&mut Self>,
@@ -247,19 +530,42 @@ pub(crate) mod tokio_impl {
         ) -> Poll<io::Result<()>> {
             let this = self.project();
             let inner = this.inner;
-            let capacity = buf.capacity();
+            let remaining = buf.remaining();
+            let filled = buf.filled().len();
+            let overreport = *this.overreport;
 
-            this.ops.poll_impl(
+            this.ops.engine_mut().begin_call("poll_read");
+            if let Some(err) = this.eof.before_read("poll_read", remaining) {
+                return Poll::Ready(Err(err));
+            }
+            let res = ready!(this.ops.poll_impl(
                 cx,
-                |cx, len| match len {
-                    Some(len) => {
-                        buf.with_limited(len, |limited_buf| inner.poll_read(cx, limited_buf))
+                |cx, len| {
+                    let res = match len {
+                        Some(len) => {
+                            // The buffer may already be partly filled (e.g. by read_exact), so
+                            // the limit applies on top of the filled bytes.
+                            let limit = buf.filled().len() + len;
+                            buf.with_limited(limit, |limited_buf| inner.poll_read(cx, limited_buf))
+                        }
+                        None => inner.poll_read(cx, buf),
+                    };
+                    if overreport && buf.filled().len() > filled {
+                        let claimed = filled + len.unwrap_or(remaining);
+                        buf.set_filled(cmp::min(claimed, buf.initialized().len()));
                     }
-                    None => inner.poll_read(cx, buf),
+                    res
                 },
-                capacity,
+                remaining,
                 "error during poll_read, generated by partial-io",
-            )
+            ));
+            let n = buf.filled().len() - filled;
+            if res.is_ok() {
+                this.ops.engine_mut().advance(n);
+            }
+            this.eof
+                .after_read(remaining, res.as_ref().ok().map(|()| n));
+            Poll::Ready(res)
         }
     }
 
@@ -334,28 +640,34 @@ pub(crate) mod tokio_impl {
 
     impl<R> AsyncBufRead for PartialAsyncRead<R>
     where
-        R: AsyncBufRead,
+        R: AsyncBufRead + ?Sized,
     {
         fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
             let this = self.project();
             let inner = this.inner;
 
-            this.ops.poll_impl_no_limit(
+            this.ops.engine_mut().begin_call("poll_fill_buf");
+            let res = this.ops.poll_impl_no_limit(
                 cx,
                 |cx| inner.poll_fill_buf(cx),
                 "error during poll_fill_buf, generated by partial-io",
-            )
+            );
+            super::after_poll_fill_buf(this.checks, &res);
+            res
         }
 
         fn consume(self: Pin<&mut Self>, amt: usize) {
-            self.project().inner.consume(amt)
+            let this = self.project();
+            this.checks
+                .before_consume("PartialAsyncRead", "poll_fill_buf", amt);
+            this.inner.consume(amt)
         }
     }
 
     /// This is a forwarding impl to support duplex structs.
     impl<R> AsyncWrite for PartialAsyncRead<R>
     where
-        R: AsyncWrite,
+        R: AsyncWrite + ?Sized,
     {
         #[inline]
         fn poll_write(
@@ -377,19 +689,27 @@ pub(crate) mod tokio_impl {
         }
     }
 
-    /// This is a forwarding impl to support duplex structs.
+    /// Seeks are forwarded to the inner reader, after applying `SeekOp`s.
     impl<R> AsyncSeek for PartialAsyncRead<R>
     where
-        R: AsyncSeek,
+        R: AsyncSeek + ?Sized,
     {
         #[inline]
         fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
-            self.project().inner.start_seek(position)
+            let this = self.project();
+            let position = this.seek_ops.start(position);
+            let res = this.inner.start_seek(position);
+            if res.is_err() {
+                this.seek_ops.cancel();
+            }
+            res
         }
 
         #[inline]
         fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
-            self.project().inner.poll_complete(cx)
+            let this = self.project();
+            let res = ready!(this.inner.poll_complete(cx));
+            Poll::Ready(this.seek_ops.complete(res))
         }
     }
 
@@ -399,6 +719,38 @@ pub(crate) mod tokio_impl {
         use itertools::Itertools;
         use std::mem::MaybeUninit;
 
+        #[tokio::test]
+        async fn test_overreport() {
+            use crate::PartialOp;
+            use tokio::io::AsyncReadExt;
+
+            let inner = PartialAsyncRead::new(
+                std::io::Cursor::new(vec![1, 2, 3]),
+                vec![PartialOp::Limited(1)],
+            );
+            let mut partial_reader = PartialAsyncRead::new(inner, vec![PartialOp::Limited(3)]);
+            partial_reader.set_overreport(true);
+            let mut out = [9; 4];
+            assert_eq!(partial_reader.read(&mut out).await.unwrap(), 3);
+            assert_eq!(out, [1, 9, 9, 9], "2 stale bytes were claimed");
+            assert_eq!(partial_reader.read(&mut out).await.unwrap(), 4);
+            assert_eq!(partial_reader.read(&mut out).await.unwrap(), 0);
+        }
+
+        #[tokio::test]
+        async fn test_read_exact_limited() {
+            use crate::PartialOp;
+            use tokio::io::AsyncReadExt;
+
+            let reader = std::io::Cursor::new(vec![1, 2, 3, 4]);
+            let mut partial_reader =
+                PartialAsyncRead::new(reader, std::iter::repeat(PartialOp::Limited(1)));
+            let mut out = [0; 4];
+            // read_exact passes in a partly filled buffer after the first read.
+            partial_reader.read_exact(&mut out).await.unwrap();
+            assert_eq!(out, [1, 2, 3, 4]);
+        }
+
         // with_limited is pretty complex: test that it works properly.
         #[test]
         fn test_with_limited() {
@@ -584,11 +936,11 @@ pub(crate) mod tokio_impl {
 
 impl<R> fmt::Debug for PartialAsyncRead<R>
 where
-    R: fmt::Debug,
+    R: fmt::Debug + ?Sized,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("PartialAsyncRead")
-            .field("inner", &self.inner)
+            .field("inner", &&self.inner)
             .finish()
     }
 }
@@ -597,12 +949,275 @@ where
 mod tests {
     use super::*;
 
-    use std::fs::File;
+    use futures::{
+        executor::block_on,
+        io::Cursor,
+        task::{noop_waker, waker, ArcWake},
+    };
+    use std::{fs::File, sync::Arc};
 
-    use crate::tests::assert_send;
+    use crate::{
+        tests::{assert_send, assert_sync},
+        WakeDelivery,
+    };
 
     #[test]
     fn test_sendable() {
         assert_send::<PartialAsyncRead<File>>();
+        assert_sync::<PartialAsyncRead<File>>();
+    }
+
+    #[test]
+    fn test_pin_by_ref() {
+        // `Pin<&mut PartialAsyncRead<R>>` implements `AsyncRead` through the impl for `Pin<P>`.
+        let ops = vec![
+            PartialOp::Limited(1),
+            PartialOp::Err(io::ErrorKind::WouldBlock),
+        ];
+        let mut partial_read = PartialAsyncRead::new(Cursor::new(b"abc".to_vec()), ops);
+        let mut pinned = Pin::new(&mut partial_read);
+        let mut buf = [0; 3];
+        block_on(async {
+            assert_eq!(pinned.as_mut().read(&mut buf).await.unwrap(), 1);
+            assert_eq!(pinned.as_mut().read(&mut buf[1..]).await.unwrap(), 2);
+        });
+        assert_eq!(&buf, b"abc");
+    }
+
+    #[test]
+    fn test_overreport() {
+        let inner =
+            PartialAsyncRead::new(Cursor::new(b"abc".to_vec()), vec![PartialOp::Limited(1)]);
+        let mut partial_read = PartialAsyncRead::new(inner, vec![PartialOp::Limited(2)]);
+        partial_read.set_overreport(true);
+        let mut buf = [b'x'; 4];
+        block_on(async {
+            assert_eq!(partial_read.read(&mut buf).await.unwrap(), 2);
+            assert_eq!(&buf, b"axxx", "a stale byte was claimed");
+            assert_eq!(partial_read.read(&mut buf).await.unwrap(), 4);
+            assert_eq!(partial_read.read(&mut buf).await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn test_limited_fraction() {
+        let ops = vec![PartialOp::LimitedFraction(1, 2); 2];
+        let mut partial_read = PartialAsyncRead::new(Cursor::new(vec![0; 64]), ops);
+        let (mut small, mut large) = ([0; 4], [0; 32]);
+        block_on(async {
+            assert_eq!(partial_read.read(&mut small).await.unwrap(), 2);
+            assert_eq!(partial_read.read(&mut large).await.unwrap(), 16);
+        });
+    }
+
+    #[test]
+    fn test_seek_ops() {
+        let mut partial_read = PartialAsyncRead::new(Cursor::new(b"abcd".to_vec()), None);
+        partial_read.set_seek_ops(vec![SeekOp::SeekOffBy(1)]);
+        let mut buf = [0; 1];
+        block_on(async {
+            assert_eq!(partial_read.seek(io::SeekFrom::Start(1)).await.unwrap(), 1);
+            partial_read.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"c");
+            assert_eq!(partial_read.seek(io::SeekFrom::Start(1)).await.unwrap(), 1);
+        });
+    }
+
+    #[test]
+    fn test_wakes_issued() {
+        let ops = vec![
+            PartialOp::Err(io::ErrorKind::WouldBlock),
+            PartialOp::Limited(1),
+            PartialOp::Err(io::ErrorKind::WouldBlock),
+        ];
+        let mut partial_read = PartialAsyncRead::new(Cursor::new(b"ab".to_vec()), ops);
+        let mut buf = [0; 2];
+        block_on(partial_read.read_exact(&mut buf)).unwrap();
+        assert_eq!(partial_read.ops().wakes_issued(), 2);
+        partial_read
+            .ops()
+            .assert_woken_before_retries("PartialAsyncRead");
+    }
+
+    #[test]
+    #[should_panic(expected = "with a waker that wasn't woken up")]
+    fn test_stale_retry() {
+        struct Unused;
+        impl ArcWake for Unused {
+            fn wake_by_ref(_arc_self: &Arc<Self>) {}
+        }
+
+        let ops = vec![PartialOp::Err(io::ErrorKind::WouldBlock)];
+        let mut partial_read = PartialAsyncRead::new(Cursor::new(b"ab".to_vec()), ops);
+        let mut buf = [0; 2];
+
+        // Poll once with one waker, then retry with another.
+        let first = waker(Arc::new(Unused));
+        let res = Pin::new(&mut partial_read).poll_read(&mut Context::from_waker(&first), &mut buf);
+        assert!(res.is_pending());
+        let second = noop_waker();
+        let res =
+            Pin::new(&mut partial_read).poll_read(&mut Context::from_waker(&second), &mut buf);
+        assert!(res.is_ready());
+        partial_read
+            .ops()
+            .assert_woken_before_retries("PartialAsyncRead");
+    }
+
+    #[test]
+    fn test_thread_wake_delivery() {
+        use crate::sync::thread;
+        use std::{sync::Mutex, time::Duration};
+
+        crate::sync::model(|| {
+            #[derive(Default)]
+            struct RecordThread(Mutex<Vec<thread::ThreadId>>);
+            impl ArcWake for RecordThread {
+                fn wake_by_ref(arc_self: &Arc<Self>) {
+                    arc_self.0.lock().unwrap().push(thread::current().id());
+                }
+            }
+
+            let ops = vec![PartialOp::Err(io::ErrorKind::WouldBlock)];
+            let mut partial_read = PartialAsyncRead::new(Cursor::new(b"ab".to_vec()), ops);
+            partial_read
+                .ops_mut()
+                .set_wake_delivery(WakeDelivery::Thread(Duration::from_millis(1)));
+            let record = Arc::new(RecordThread::default());
+            let waker = waker(record.clone());
+
+            let res =
+                Pin::new(&mut partial_read).poll_read(&mut Context::from_waker(&waker), &mut []);
+            assert!(res.is_pending());
+            assert!(record.0.lock().unwrap().is_empty(), "not woken inline");
+            while record.0.lock().unwrap().is_empty() {
+                thread::yield_now();
+            }
+            assert_ne!(record.0.lock().unwrap()[0], thread::current().id());
+            assert_eq!(block_on(partial_read.read(&mut [0; 2])).unwrap(), 2);
+        });
+    }
+
+    #[cfg(feature = "tokio1")]
+    #[tokio::test]
+    async fn test_tokio_boundary() {
+        use tokio::io::AsyncReadExt;
+
+        let ops = std::iter::repeat(PartialOp::Limited(3));
+        let mut partial_read = PartialAsyncRead::new(std::io::Cursor::new(vec![0; 8]), ops);
+        partial_read.set_alignment(Some(Alignment::Boundary(4)));
+        let mut buf = [0; 8];
+        // Each read_exact call fills the buffer starting from what's already filled.
+        partial_read.read_exact(&mut buf[..5]).await.unwrap();
+        assert_eq!(partial_read.ops.engine_mut().offset(), 5);
+        let lens: Vec<_> = vec![
+            partial_read.read(&mut buf).await.unwrap(),
+            partial_read.read(&mut buf).await.unwrap(),
+        ];
+        assert_eq!(lens, [3, 0], "the read after offset 5 is cut at offset 8");
+    }
+
+    #[test]
+    fn test_reads_after_eof() {
+        // A retry after Poll::Pending is a single read.
+        let ops = vec![
+            PartialOp::Unlimited,
+            PartialOp::Err(io::ErrorKind::WouldBlock),
+        ];
+        let mut partial_read = PartialAsyncRead::new(&b"abc"[..], ops);
+        partial_read.set_max_reads_after_eof(Some(1));
+        let mut buf = [0; 4];
+        let mut reads = 0;
+        // A read loop that doesn't stop at EOF.
+        let err = block_on(async {
+            loop {
+                match partial_read.read(&mut buf).await {
+                    Ok(_) => reads += 1,
+                    Err(err) => break err,
+                }
+            }
+        });
+        assert_eq!(reads, 3, "data, EOF, and one more EOF");
+        assert!(err
+            .to_string()
+            .starts_with("poll_read called 2 times after EOF"));
+        assert_eq!(partial_read.reads_after_eof(), 2);
+    }
+
+    #[test]
+    fn test_fill_buf_checks() {
+        let ops = vec![PartialOp::Err(io::ErrorKind::WouldBlock)];
+        let mut partial_read = PartialAsyncRead::new(Cursor::new(b"abc".to_vec()), ops);
+        partial_read.set_invariant_checks(true);
+        block_on(async {
+            assert_eq!(partial_read.fill_buf().await.unwrap(), b"abc");
+            Pin::new(&mut partial_read).consume(1);
+            Pin::new(&mut partial_read).consume(2);
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "so the buffer from an earlier call is stale")]
+    fn test_fill_buf_stale() {
+        let ops = vec![
+            PartialOp::Unlimited,
+            PartialOp::Err(io::ErrorKind::WouldBlock),
+        ];
+        let mut partial_read = PartialAsyncRead::new(Cursor::new(b"abc".to_vec()), ops);
+        partial_read.set_invariant_checks(true);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let res = Pin::new(&mut partial_read).poll_fill_buf(&mut cx);
+        assert!(matches!(res, Poll::Ready(Ok(b"abc"))));
+
+        // The consumer polls again before consuming, and consumes what it saw the first time.
+        assert!(Pin::new(&mut partial_read)
+            .poll_fill_buf(&mut cx)
+            .is_pending());
+        Pin::new(&mut partial_read).consume(3);
+    }
+
+    #[test]
+    fn test_max_interrupted_retries() {
+        let ops = PartialOp::interrupted_storm(3).chain(PartialOp::interrupted_storm(4));
+        let mut partial_read = PartialAsyncRead::new(Cursor::new(b"ab".to_vec()), ops);
+        partial_read.ops_mut().set_max_interrupted_retries(Some(3));
+        let mut buf = [0; 1];
+        block_on(async {
+            // A storm within the limit is retried internally.
+            assert_eq!(partial_read.read(&mut buf).await.unwrap(), 1);
+            // A storm longer than the limit surfaces the fourth error.
+            let err = partial_read.read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+            assert_eq!(partial_read.read(&mut buf).await.unwrap(), 1);
+        });
+    }
+
+    #[cfg(feature = "tokio1")]
+    #[tokio::test(start_paused = true)]
+    async fn test_call_rate_limit() {
+        use std::time::Duration;
+        use tokio::{io::AsyncReadExt, time::Instant};
+
+        let ops = vec![
+            PartialOp::Limited(1),
+            PartialOp::Err(io::ErrorKind::WouldBlock),
+            PartialOp::Limited(1),
+        ];
+        let mut partial_read = PartialAsyncRead::new(std::io::Cursor::new(vec![0; 4]), ops);
+        partial_read
+            .ops_mut()
+            .set_call_rate_limit(2, Duration::from_secs(1));
+        let start = Instant::now();
+        let mut buf = [0; 8];
+        assert_eq!(partial_read.read(&mut buf).await.unwrap(), 1);
+        // The WouldBlock counts as the second call.
+        assert_eq!(partial_read.read(&mut buf).await.unwrap(), 1);
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        // The limit still applies once the operations have run out.
+        assert_eq!(partial_read.read(&mut buf).await.unwrap(), 2);
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert_eq!(partial_read.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
     }
 }
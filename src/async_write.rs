@@ -11,8 +11,11 @@
 //! This is separate from `PartialWrite` because on `WouldBlock` errors, it
 //! causes `futures` to try writing or flushing again.
 
-use crate::{futures_util::FuturesOps, PartialOp};
-use futures::{io, prelude::*};
+use crate::{
+    futures_util::FuturesOps, seeded::SeededOps, seek::SeekOps, write::LyingFlushes, Alignment,
+    AppliedOp, BufSize, FlushReport, LocalOps, OpEngine, PartialOp, PartialWrap, Profile, SeekOp,
+};
+use futures::{io, prelude::*, ready};
 use pin_project::pin_project;
 use std::{
     fmt,
@@ -23,6 +26,10 @@ use std::{
 /// A wrapper that breaks inner `AsyncWrite` instances up according to the
 /// provided iterator.
 ///
+/// The writer may be unsized, such as `PartialAsyncWrite<dyn AsyncWrite>` behind a `Box`, or
+/// borrowed. To wrap a `&mut dyn AsyncWrite` that isn't `Unpin`, pass in a
+/// `Pin<&mut dyn AsyncWrite>` instead.
+///
 /// Available with the `futures03` feature for `futures` traits, and with the `tokio1` feature for
 /// `tokio` traits.
 ///
@@ -79,23 +86,236 @@ use std::{
 /// # }
 /// ```
 #[pin_project]
-pub struct PartialAsyncWrite<W> {
+pub struct PartialAsyncWrite<W: ?Sized> {
+    ops: FuturesOps,
+    invariants: Invariants,
+    pending_before_flush: PendingBeforeFlush,
+    partial_flushes: bool,
+    shutdown: Shutdown,
+    lying_flushes: LyingFlushes,
+    seek_ops: SeekOps,
     #[pin]
     inner: W,
-    ops: FuturesOps,
 }
 
-impl<W> PartialAsyncWrite<W> {
+/// Tracks the state needed for `set_invariant_checks`, apart from writes after shutdown, which
+/// `Shutdown` tracks.
+#[derive(Debug, Default)]
+struct Invariants {
+    enabled: bool,
+    // Whether data was written since the last successful flush.
+    unflushed: bool,
+}
+
+/// Tracks the state needed for `set_pending_before_flush`.
+#[derive(Debug, Default)]
+struct PendingBeforeFlush {
+    enabled: bool,
+    // Whether the current call has already returned `Poll::Pending` once.
+    issued: bool,
+}
+
+/// A write issued after a successful `poll_close` or `poll_shutdown`, recorded by
+/// `PartialAsyncWrite::writes_after_shutdown`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct WriteAfterShutdown {
+    /// The index of the `poll_write` call among all the method calls on the writer, counting from
+    /// 0, including retries after `Poll::Pending`. This is its index in the timeline recorded by
+    /// `record_calls`.
+    pub call: usize,
+    /// The number of bytes the caller tried to write.
+    pub len: usize,
+}
+
+/// Tracks the state needed for `set_shutdown_checks`, `assert_shutdown_completed`,
+/// `writes_after_shutdown`, and the write-after-shutdown check of `set_invariant_checks`.
+#[derive(Debug, Default)]
+struct Shutdown {
+    enabled: bool,
+    state: ShutdownState,
+    // The number of times `poll_close` or `poll_shutdown` was polled.
+    polls: usize,
+    reject_writes: bool,
+    writes_after: Vec<WriteAfterShutdown>,
+}
+
+/// Whether `poll_close` or `poll_shutdown` has returned `Poll::Ready`, and how.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+enum ShutdownState {
+    #[default]
+    Open,
+    // Shutdown failed, and hasn't succeeded since.
+    Failed,
+    // Shutdown succeeded. This is final.
+    Closed,
+}
+
+impl Shutdown {
+    // Records a write after a successful shutdown. Panics if `invariant_checks` is set, and
+    // returns an error if the write is rejected.
+    fn before_write(
+        &mut self,
+        call_count: usize,
+        len: usize,
+        invariant_checks: bool,
+    ) -> Option<io::Error> {
+        if self.state != ShutdownState::Closed {
+            return None;
+        }
+        let write = WriteAfterShutdown {
+            call: call_count - 1,
+            len,
+        };
+        self.writes_after.push(write);
+        let message = format!(
+            "poll_write called after a successful shutdown (call #{}, {} bytes, write #{} after \
+             shutdown)",
+            write.call,
+            write.len,
+            self.writes_after.len()
+        );
+        if invariant_checks {
+            panic!("PartialAsyncWrite: {}", message);
+        }
+        if !self.reject_writes {
+            return None;
+        }
+        Some(io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            format!("{}, rejected by partial-io", message),
+        ))
+    }
+
+    fn before_shutdown(&mut self, method: &str) {
+        if self.enabled && self.state != ShutdownState::Open {
+            panic!(
+                "PartialAsyncWrite: {} polled again after shutdown returned Poll::Ready",
+                method
+            );
+        }
+        self.polls += 1;
+    }
+
+    fn after_shutdown(&mut self, res: &Poll<io::Result<()>>) {
+        self.state = match (self.state, res) {
+            (_, Poll::Ready(Ok(()))) | (ShutdownState::Closed, _) => ShutdownState::Closed,
+            (_, Poll::Ready(Err(_))) => ShutdownState::Failed,
+            (state, Poll::Pending) => state,
+        };
+    }
+
+    #[track_caller]
+    fn assert_completed(&self) {
+        assert!(
+            self.state != ShutdownState::Open,
+            "PartialAsyncWrite: shutdown was never driven to completion ({} polls, none ready)",
+            self.polls
+        );
+    }
+}
+
+impl PendingBeforeFlush {
+    // Returns true if `Poll::Pending` should be returned at the start of this call.
+    fn inject(&mut self) -> bool {
+        let inject = self.enabled && !self.issued;
+        self.issued |= inject;
+        inject
+    }
+
+    fn after_call(&mut self, res: &Poll<io::Result<()>>) {
+        if res.is_ready() {
+            self.issued = false;
+        }
+    }
+}
+
+impl Invariants {
+    fn after_write(&mut self, res: &io::Result<usize>) {
+        if let Ok(n) = res {
+            self.unflushed |= *n > 0;
+        }
+    }
+
+    fn after_flush(&mut self, res: &Poll<io::Result<()>>) {
+        if let Poll::Ready(Ok(())) = res {
+            self.unflushed = false;
+        }
+    }
+
+    fn before_shutdown(&self, method: &str) {
+        if self.enabled && self.unflushed {
+            panic!(
+                "PartialAsyncWrite: {} called with data written since the last successful flush",
+                method
+            );
+        }
+    }
+}
+
+impl<W: ?Sized> PartialAsyncWrite<W> {
     /// Creates a new `PartialAsyncWrite` wrapper over the writer with the specified `PartialOp`s.
     pub fn new<I>(inner: W, iter: I) -> Self
     where
+        W: Sized,
         I: IntoIterator<Item = PartialOp> + 'static,
         I::IntoIter: Send,
     {
-        PartialAsyncWrite {
+        Self::from_engine(inner, OpEngine::new(iter))
+    }
+
+    /// Creates a new `PartialAsyncWrite` wrapper over the writer with a reproducible randomized
+    /// schedule of `PartialOp`s, generated from `seed` and `profile`.
+    ///
+    /// Unless the profile sets error kinds, operations fail with `WouldBlock`, which turns into
+    /// `Poll::Pending`. If the thread panics while the wrapper is alive, the seed is printed when
+    /// it's dropped. See `Profile` for more.
+    pub fn with_seed(inner: W, seed: u64, profile: Profile) -> Self
+    where
+        W: Sized,
+    {
+        Self::new(
             inner,
-            ops: FuturesOps::new(iter),
-        }
+            SeededOps::for_wrapper(
+                "PartialAsyncWrite",
+                seed,
+                profile,
+                io::ErrorKind::WouldBlock,
+            ),
+        )
+    }
+
+    /// Creates a new `PartialAsyncWrite` wrapper over the writer with `PartialOp`s that aren't
+    /// `Send`, for single-threaded executors.
+    ///
+    /// The writer can only be used on the current thread. See `LocalOps` for more.
+    pub fn new_local<I>(inner: W, iter: I) -> Self
+    where
+        W: Sized,
+        I: IntoIterator<Item = PartialOp> + 'static,
+    {
+        Self::new(inner, LocalOps::new(iter))
+    }
+
+    /// Creates a new `PartialAsyncWrite` wrapper over the writer in passthrough mode, which
+    /// forwards every call as-is.
+    ///
+    /// Setting `PartialOp`s turns passthrough mode off. See `OpEngine::passthrough` for more.
+    pub fn passthrough(inner: W) -> Self
+    where
+        W: Sized,
+    {
+        Self::from_engine(inner, OpEngine::passthrough())
+    }
+
+    /// Creates a new `PartialAsyncWrite` wrapper over the writer that limits every operation to 1
+    /// byte, forever.
+    ///
+    /// This doesn't allocate. See `OpEngine::byte_by_byte` for more.
+    pub fn byte_by_byte(inner: W) -> Self
+    where
+        W: Sized,
+    {
+        Self::from_engine(inner, OpEngine::byte_by_byte())
     }
 
     /// Sets the `PartialOp`s for this writer.
@@ -119,6 +339,206 @@ impl<W> PartialAsyncWrite<W> {
         this
     }
 
+    /// Caps every operation on this writer to at most `max_op_size` bytes, in addition to the
+    /// limits in `PartialOp::Limited`.
+    ///
+    /// See `OpEngine::set_max_op_size` for more.
+    pub fn set_max_op_size(&mut self, max_op_size: usize) -> &mut Self {
+        self.ops.engine_mut().set_max_op_size(max_op_size);
+        self
+    }
+
+    /// Caps the number of bytes written through this writer in total to `limit`. Once it's
+    /// reached, every further write accepts 0 bytes, or fails with the error set with
+    /// `set_total_limit_error`.
+    ///
+    /// See `OpEngine::set_total_limit` for more.
+    pub fn set_total_limit(&mut self, limit: Option<u64>) -> &mut Self {
+        self.ops.engine_mut().set_total_limit(limit);
+        self
+    }
+
+    /// Makes writes on this writer fail with an error of kind `kind` once the limit set with
+    /// `set_total_limit` has been reached, like a full disk. Flushes still go through.
+    ///
+    /// See `OpEngine::set_total_limit_error` for more.
+    pub fn set_total_limit_error(&mut self, kind: Option<io::ErrorKind>) -> &mut Self {
+        self.ops.engine_mut().set_total_limit_error(kind);
+        self
+    }
+
+    /// Starts recording the operations applied by this writer, and the methods they are applied to.
+    ///
+    /// Use `into_parts` and `OpEngine::into_report` to see which operations were never reached.
+    pub fn record_ops(&mut self) -> &mut Self {
+        self.ops.engine_mut().record_ops();
+        self
+    }
+
+    /// Returns the operations applied by this writer since `record_ops` was called.
+    pub fn applied_ops(&self) -> &[AppliedOp] {
+        self.ops.engine().applied_ops()
+    }
+
+    /// Starts recording the size of every buffer passed to this writer, along with the size
+    /// forwarded to the inner writer after limiting.
+    ///
+    /// See `OpEngine::record_sizes` for more.
+    pub fn record_sizes(&mut self) -> &mut Self {
+        self.ops.engine_mut().record_sizes();
+        self
+    }
+
+    /// Returns the buffer sizes recorded by this writer since `record_sizes` was called, in order.
+    pub fn buf_sizes(&self) -> &[BufSize] {
+        self.ops.engine().buf_sizes()
+    }
+
+    /// Sets the `SeekOp`s for seeks on this writer.
+    ///
+    /// See `SeekOp` for more.
+    pub fn set_seek_ops<I>(&mut self, iter: I) -> &mut Self
+    where
+        I: IntoIterator<Item = SeekOp> + 'static,
+        I::IntoIter: Send,
+    {
+        self.seek_ops.replace(iter);
+        self
+    }
+
+    /// Enables or disables checks for `AsyncWrite` protocol invariants.
+    ///
+    /// When enabled, this writer panics if:
+    ///
+    /// * `poll_close` or `poll_shutdown` is called after data was written, without a successful
+    ///   `poll_flush` since. This check is strict: callers that rely on shutdown to flush the inner
+    ///   writer fail it.
+    /// * `poll_write` is called after a successful `poll_close` or `poll_shutdown`.
+    pub fn set_invariant_checks(&mut self, enabled: bool) -> &mut Self {
+        self.invariants.enabled = enabled;
+        self
+    }
+
+    /// Enables or disables checks that shutdown isn't polled again once it has completed.
+    ///
+    /// When enabled, this writer panics if `poll_close` or `poll_shutdown` is called after either
+    /// of them returned `Poll::Ready`, whether with success or an error. Re-entering a completed
+    /// shutdown usually means the code under test lost track of its own state.
+    ///
+    /// To check that shutdown was driven to completion at all, call `assert_shutdown_completed`
+    /// at the end of the test.
+    pub fn set_shutdown_checks(&mut self, enabled: bool) -> &mut Self {
+        self.shutdown.enabled = enabled;
+        self
+    }
+
+    /// Makes writes after a successful `poll_close` or `poll_shutdown` fail with `BrokenPipe`,
+    /// instead of forwarding them to the inner writer.
+    ///
+    /// Such writes are recorded in `writes_after_shutdown` either way. The error message includes
+    /// the index of the call, which matches the timeline recorded by `record_calls`. To panic
+    /// at the offending call instead, see `set_invariant_checks`, which takes precedence.
+    pub fn set_reject_writes_after_shutdown(&mut self, enabled: bool) -> &mut Self {
+        self.shutdown.reject_writes = enabled;
+        self
+    }
+
+    /// Returns the writes issued after a successful `poll_close` or `poll_shutdown` so far.
+    ///
+    /// Inner writers like `Vec<u8>` accept such writes, so without this, they go unnoticed.
+    pub fn writes_after_shutdown(&self) -> &[WriteAfterShutdown] {
+        &self.shutdown.writes_after
+    }
+
+    /// Panics if `poll_close` or `poll_shutdown` never returned `Poll::Ready` on this writer.
+    ///
+    /// This catches code that never shuts the writer down, or that starts a shutdown and drops it
+    /// after `Poll::Pending`. The panic message includes the number of times shutdown was polled.
+    #[track_caller]
+    pub fn assert_shutdown_completed(&self) {
+        self.shutdown.assert_completed();
+    }
+
+    /// Makes every flush and shutdown return `Poll::Pending` once before it proceeds.
+    ///
+    /// When enabled, the first poll of each `poll_flush`, `poll_close` or `poll_shutdown` call
+    /// behaves like `PartialOp::Err(WouldBlock)`, without consuming an operation. The call
+    /// proceeds as usual when it's polled again. This targets code that doesn't expect to be
+    /// suspended right after its last write, which call-indexed sequences rarely reach.
+    ///
+    /// This is ignored in passthrough mode.
+    pub fn set_pending_before_flush(&mut self, enabled: bool) -> &mut Self {
+        self.pending_before_flush.enabled = enabled;
+        self
+    }
+
+    /// Makes flushes that take a `PartialOp::Limited` or `PartialOp::Fragment` return
+    /// `Poll::Pending` after flushing the inner writer.
+    ///
+    /// By default, flushes treat these operations as `PartialOp::Unlimited`. When enabled, such a
+    /// flush wakes the task and returns `Poll::Pending` once the inner writer's flush completes, as
+    /// if it had made partial progress, and proceeds with the next operation when it's polled
+    /// again. See `PartialWrite::set_partial_flushes` for more.
+    ///
+    /// This is ignored in passthrough mode.
+    pub fn set_partial_flushes(&mut self, enabled: bool) -> &mut Self {
+        self.partial_flushes = enabled;
+        self
+    }
+
+    /// Makes the next `count` flushes on this writer return `Poll::Ready(Ok(()))` without flushing
+    /// the inner writer.
+    ///
+    /// Skipped flushes don't consume a `PartialOp`. A successful `poll_close` or `poll_shutdown`
+    /// counts as a flush in the report. See `PartialWrite::set_lying_flushes` for more.
+    ///
+    /// This is ignored in passthrough mode.
+    pub fn set_lying_flushes(&mut self, count: usize) -> &mut Self {
+        self.lying_flushes.set(count);
+        self
+    }
+
+    /// Returns a summary of the flushes on this writer so far.
+    ///
+    /// See `set_lying_flushes` for more.
+    pub fn flush_report(&self) -> FlushReport {
+        self.lying_flushes.report()
+    }
+
+    /// Starts recording the timeline of method calls on this writer.
+    ///
+    /// See the `timeline` module for more.
+    pub fn record_calls(&mut self) -> &mut Self {
+        self.ops.engine_mut().record_calls();
+        self
+    }
+
+    /// Returns the method calls on this writer since `record_calls` was called, in order.
+    pub fn calls(&self) -> &[&'static str] {
+        self.ops.engine().calls()
+    }
+
+    /// Snaps the sizes in `PartialOp::Limited` on this writer to boundaries.
+    ///
+    /// See `OpEngine::set_alignment` for more.
+    pub fn set_alignment(&mut self, alignment: Option<Alignment>) -> &mut Self {
+        self.ops.engine_mut().set_alignment(alignment);
+        self
+    }
+
+    /// Returns the operations of this writer, along with its wake and poll statistics.
+    pub fn ops(&self) -> &FuturesOps {
+        &self.ops
+    }
+
+    /// Returns the operations of this writer, to set its clock and how it delivers wakes, or to
+    /// enable checks on how it's polled.
+    ///
+    /// See `FuturesOps` for the settings.
+    pub fn ops_mut(&mut self) -> &mut FuturesOps {
+        &mut self.ops
+    }
+
     /// Returns a shared reference to the underlying writer.
     pub fn get_ref(&self) -> &W {
         &self.inner
@@ -134,10 +554,75 @@ impl<W> PartialAsyncWrite<W> {
         self.project().inner
     }
 
+    /// Returns a pinned mutable reference to the underlying writer.
+    ///
+    /// This is the same as `pin_get_mut`, under the name used by `tokio`'s wrappers.
+    pub fn get_pin_mut(self: Pin<&mut Self>) -> Pin<&mut W> {
+        self.project().inner
+    }
+
     /// Consumes this wrapper, returning the underlying writer.
-    pub fn into_inner(self) -> W {
+    pub fn into_inner(self) -> W
+    where
+        W: Sized,
+    {
         self.inner
     }
+
+    /// Consumes this wrapper, returning the underlying writer and the engine with the `PartialOp`s
+    /// that haven't been consumed yet.
+    ///
+    /// Use `OpEngine::into_ops` to inspect the operations that are left.
+    pub fn into_parts(self) -> (W, OpEngine)
+    where
+        W: Sized,
+    {
+        (self.inner, self.ops.into_engine())
+    }
+
+    // Replaces the underlying writer, keeping the operations and settings.
+    #[cfg(feature = "tokio1")]
+    pub(crate) fn replace_inner<T>(self, inner: T) -> (W, PartialAsyncWrite<T>)
+    where
+        W: Sized,
+    {
+        let replaced = PartialAsyncWrite {
+            inner,
+            ops: self.ops,
+            invariants: self.invariants,
+            pending_before_flush: self.pending_before_flush,
+            partial_flushes: self.partial_flushes,
+            shutdown: self.shutdown,
+            lying_flushes: self.lying_flushes,
+            seek_ops: self.seek_ops,
+        };
+        (self.inner, replaced)
+    }
+}
+
+impl<W> PartialWrap for PartialAsyncWrite<W> {
+    type Inner = W;
+
+    fn from_engine(inner: W, engine: OpEngine) -> Self {
+        PartialAsyncWrite {
+            inner,
+            ops: FuturesOps::from_engine(engine),
+            invariants: Invariants::default(),
+            pending_before_flush: PendingBeforeFlush::default(),
+            partial_flushes: false,
+            shutdown: Shutdown::default(),
+            lying_flushes: LyingFlushes::default(),
+            seek_ops: SeekOps::new(),
+        }
+    }
+
+    fn engine_mut(&mut self) -> &mut OpEngine {
+        self.ops.engine_mut()
+    }
+
+    fn into_parts(self) -> (W, OpEngine) {
+        PartialAsyncWrite::into_parts(self)
+    }
 }
 
 // ---
@@ -146,13 +631,21 @@ impl<W> PartialAsyncWrite<W> {
 
 impl<W> AsyncWrite for PartialAsyncWrite<W>
 where
-    W: AsyncWrite,
+    W: AsyncWrite + ?Sized,
 {
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
         let this = self.project();
         let inner = this.inner;
 
-        this.ops.poll_impl(
+        this.ops.engine_mut().begin_call("poll_write");
+        let call_count = this.ops.engine().call_count();
+        if let Some(err) =
+            this.shutdown
+                .before_write(call_count, buf.len(), this.invariants.enabled)
+        {
+            return Poll::Ready(Err(err));
+        }
+        let res = ready!(this.ops.poll_impl(
             cx,
             |cx, len| match len {
                 Some(len) => inner.poll_write(cx, &buf[..len]),
@@ -160,36 +653,84 @@ where
             },
             buf.len(),
             "error during poll_write, generated by partial-io",
-        )
+        ));
+        let res = this.ops.engine_mut().record(res);
+        this.invariants.after_write(&res);
+        this.lying_flushes.after_write(&res);
+        Poll::Ready(res)
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
         let this = self.project();
         let inner = this.inner;
 
-        this.ops.poll_impl_no_limit(
+        this.ops.engine_mut().begin_call("poll_flush");
+
+        if !this.ops.engine().is_passthrough() && this.lying_flushes.lie() {
+            let res = Poll::Ready(Ok(()));
+            this.invariants.after_flush(&res);
+            return res;
+        }
+        if !this.ops.engine().is_passthrough() && this.pending_before_flush.inject() {
+            return this
+                .ops
+                .would_block(cx, "error during poll_flush, generated by partial-io");
+        }
+        let lying_flushes = &mut *this.lying_flushes;
+        let res = this.ops.poll_impl_no_limit(
             cx,
-            |cx| inner.poll_flush(cx),
+            |cx| {
+                let res = ready!(inner.poll_flush(cx));
+                lying_flushes.after_flush(&res);
+                Poll::Ready(res)
+            },
             "error during poll_flush, generated by partial-io",
-        )
+        );
+        let res = match res {
+            Poll::Ready(Ok(())) if *this.partial_flushes && this.ops.engine().took_limited() => {
+                this.ops
+                    .would_block(cx, "partial poll_flush, generated by partial-io")
+            }
+            res => res,
+        };
+        this.pending_before_flush.after_call(&res);
+        this.invariants.after_flush(&res);
+        res
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
         let this = self.project();
         let inner = this.inner;
 
-        this.ops.poll_impl_no_limit(
+        this.ops.engine_mut().begin_call("poll_close");
+        this.invariants.before_shutdown("poll_close");
+        this.shutdown.before_shutdown("poll_close");
+
+        if !this.ops.engine().is_passthrough() && this.pending_before_flush.inject() {
+            return this
+                .ops
+                .would_block(cx, "error during poll_close, generated by partial-io");
+        }
+        let lying_flushes = &mut *this.lying_flushes;
+        let res = this.ops.poll_impl_no_limit(
             cx,
-            |cx| inner.poll_close(cx),
+            |cx| {
+                let res = ready!(inner.poll_close(cx));
+                lying_flushes.after_flush(&res);
+                Poll::Ready(res)
+            },
             "error during poll_close, generated by partial-io",
-        )
+        );
+        this.pending_before_flush.after_call(&res);
+        this.shutdown.after_shutdown(&res);
+        res
     }
 }
 
 /// This is a forwarding impl to support duplex structs.
 impl<W> AsyncRead for PartialAsyncWrite<W>
 where
-    W: AsyncRead,
+    W: AsyncRead + ?Sized,
 {
     #[inline]
     fn poll_read(
@@ -213,7 +754,7 @@ where
 /// This is a forwarding impl to support duplex structs.
 impl<W> AsyncBufRead for PartialAsyncWrite<W>
 where
-    W: AsyncBufRead,
+    W: AsyncBufRead + ?Sized,
 {
     #[inline]
     fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
@@ -226,10 +767,10 @@ where
     }
 }
 
-/// This is a forwarding impl to support duplex structs.
+/// Seeks are forwarded to the inner writer, after applying `SeekOp`s.
 impl<W> AsyncSeek for PartialAsyncWrite<W>
 where
-    W: AsyncSeek,
+    W: AsyncSeek + ?Sized,
 {
     #[inline]
     fn poll_seek(
@@ -237,7 +778,10 @@ where
         cx: &mut Context,
         pos: io::SeekFrom,
     ) -> Poll<io::Result<u64>> {
-        self.project().inner.poll_seek(cx, pos)
+        let this = self.project();
+        let pos = this.seek_ops.resume(pos);
+        let res = ready!(this.inner.poll_seek(cx, pos));
+        Poll::Ready(this.seek_ops.complete(res))
     }
 }
 
@@ -248,6 +792,7 @@ where
 #[cfg(feature = "tokio1")]
 mod tokio_impl {
     use super::PartialAsyncWrite;
+    use futures::ready;
     use std::{
         io::{self, SeekFrom},
         pin::Pin,
@@ -257,7 +802,7 @@ mod tokio_impl {
 
     impl<W> AsyncWrite for PartialAsyncWrite<W>
     where
-        W: AsyncWrite,
+        W: AsyncWrite + ?Sized,
     {
         fn poll_write(
             self: Pin<&mut Self>,
@@ -267,7 +812,15 @@ mod tokio_impl {
             let this = self.project();
             let inner = this.inner;
 
-            this.ops.poll_impl(
+            this.ops.engine_mut().begin_call("poll_write");
+            let call_count = this.ops.engine().call_count();
+            if let Some(err) =
+                this.shutdown
+                    .before_write(call_count, buf.len(), this.invariants.enabled)
+            {
+                return Poll::Ready(Err(err));
+            }
+            let res = ready!(this.ops.poll_impl(
                 cx,
                 |cx, len| match len {
                     Some(len) => inner.poll_write(cx, &buf[..len]),
@@ -275,36 +828,86 @@ mod tokio_impl {
                 },
                 buf.len(),
                 "error during poll_write, generated by partial-io",
-            )
+            ));
+            let res = this.ops.engine_mut().record(res);
+            this.invariants.after_write(&res);
+            this.lying_flushes.after_write(&res);
+            Poll::Ready(res)
         }
 
         fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
             let this = self.project();
             let inner = this.inner;
 
-            this.ops.poll_impl_no_limit(
+            this.ops.engine_mut().begin_call("poll_flush");
+
+            if !this.ops.engine().is_passthrough() && this.lying_flushes.lie() {
+                let res = Poll::Ready(Ok(()));
+                this.invariants.after_flush(&res);
+                return res;
+            }
+            if !this.ops.engine().is_passthrough() && this.pending_before_flush.inject() {
+                return this
+                    .ops
+                    .would_block(cx, "error during poll_flush, generated by partial-io");
+            }
+            let lying_flushes = &mut *this.lying_flushes;
+            let res = this.ops.poll_impl_no_limit(
                 cx,
-                |cx| inner.poll_flush(cx),
+                |cx| {
+                    let res = ready!(inner.poll_flush(cx));
+                    lying_flushes.after_flush(&res);
+                    Poll::Ready(res)
+                },
                 "error during poll_flush, generated by partial-io",
-            )
+            );
+            let res = match res {
+                Poll::Ready(Ok(()))
+                    if *this.partial_flushes && this.ops.engine().took_limited() =>
+                {
+                    this.ops
+                        .would_block(cx, "partial poll_flush, generated by partial-io")
+                }
+                res => res,
+            };
+            this.pending_before_flush.after_call(&res);
+            this.invariants.after_flush(&res);
+            res
         }
 
         fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
             let this = self.project();
             let inner = this.inner;
 
-            this.ops.poll_impl_no_limit(
+            this.ops.engine_mut().begin_call("poll_shutdown");
+            this.invariants.before_shutdown("poll_shutdown");
+            this.shutdown.before_shutdown("poll_shutdown");
+
+            if !this.ops.engine().is_passthrough() && this.pending_before_flush.inject() {
+                return this
+                    .ops
+                    .would_block(cx, "error during poll_shutdown, generated by partial-io");
+            }
+            let lying_flushes = &mut *this.lying_flushes;
+            let res = this.ops.poll_impl_no_limit(
                 cx,
-                |cx| inner.poll_shutdown(cx),
+                |cx| {
+                    let res = ready!(inner.poll_shutdown(cx));
+                    lying_flushes.after_flush(&res);
+                    Poll::Ready(res)
+                },
                 "error during poll_shutdown, generated by partial-io",
-            )
+            );
+            this.pending_before_flush.after_call(&res);
+            this.shutdown.after_shutdown(&res);
+            res
         }
     }
 
     /// This is a forwarding impl to support duplex structs.
     impl<W> AsyncRead for PartialAsyncWrite<W>
     where
-        W: AsyncRead,
+        W: AsyncRead + ?Sized,
     {
         #[inline]
         fn poll_read(
@@ -319,7 +922,7 @@ mod tokio_impl {
     /// This is a forwarding impl to support duplex structs.
     impl<W> AsyncBufRead for PartialAsyncWrite<W>
     where
-        W: AsyncBufRead,
+        W: AsyncBufRead + ?Sized,
     {
         #[inline]
         fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
@@ -332,30 +935,38 @@ mod tokio_impl {
         }
     }
 
-    /// This is a forwarding impl to support duplex structs.
+    /// Seeks are forwarded to the inner writer, after applying `SeekOp`s.
     impl<W> AsyncSeek for PartialAsyncWrite<W>
     where
-        W: AsyncSeek,
+        W: AsyncSeek + ?Sized,
     {
         #[inline]
         fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
-            self.project().inner.start_seek(position)
+            let this = self.project();
+            let position = this.seek_ops.start(position);
+            let res = this.inner.start_seek(position);
+            if res.is_err() {
+                this.seek_ops.cancel();
+            }
+            res
         }
 
         #[inline]
         fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
-            self.project().inner.poll_complete(cx)
+            let this = self.project();
+            let res = ready!(this.inner.poll_complete(cx));
+            Poll::Ready(this.seek_ops.complete(res))
         }
     }
 }
 
 impl<W> fmt::Debug for PartialAsyncWrite<W>
 where
-    W: fmt::Debug,
+    W: fmt::Debug + ?Sized,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("PartialAsyncWrite")
-            .field("inner", &self.inner)
+            .field("inner", &&self.inner)
             .finish()
     }
 }
@@ -364,12 +975,420 @@ where
 mod tests {
     use super::*;
 
-    use std::fs::File;
+    use std::{fs::File, task::Waker};
 
-    use crate::tests::assert_send;
+    use crate::{
+        tests::{assert_send, assert_sync},
+        WakeDelivery,
+    };
 
     #[test]
     fn test_sendable() {
         assert_send::<PartialAsyncWrite<File>>();
+        assert_sync::<PartialAsyncWrite<File>>();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_os_err() {
+        use futures::executor::block_on;
+
+        // EAGAIN maps to WouldBlock, so it's translated to Poll::Pending. EPIPE is returned.
+        let ops = vec![PartialOp::OsErr(11), PartialOp::OsErr(32)];
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+        let err = block_on(partial_write.write(b"abc")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(32));
+        assert_eq!(partial_write.ops().wakes_issued(), 1);
+    }
+
+    #[test]
+    fn test_busy_polls() {
+        use futures::{executor::block_on, task::noop_waker};
+
+        // Returns Poll::Pending on every other poll, without ever waking the task.
+        struct Flaky(bool);
+
+        impl AsyncWrite for Flaky {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                _cx: &mut Context,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                self.0 = !self.0;
+                if self.0 {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(Ok(buf.len()))
+                }
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        // Injected WouldBlocks wake the task before returning Poll::Pending.
+        let ops = vec![PartialOp::Err(io::ErrorKind::WouldBlock); 2];
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+        partial_write.ops_mut().set_busy_poll_detection(true);
+        block_on(partial_write.write_all(b"abc")).unwrap();
+        partial_write
+            .ops()
+            .assert_no_busy_polls("PartialAsyncWrite");
+
+        let mut partial_write = PartialAsyncWrite::new(Flaky(false), None);
+        partial_write.ops_mut().set_busy_poll_detection(true);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        // A caller that spins on Poll::Pending.
+        while Pin::new(&mut partial_write)
+            .poll_write(&mut cx, b"abc")
+            .is_pending()
+        {}
+        assert_eq!(partial_write.ops().busy_polls(), 1);
+    }
+
+    #[test]
+    fn test_waker_registered() {
+        use futures::task::{waker, ArcWake};
+        use std::sync::Arc;
+
+        struct Task;
+
+        impl ArcWake for Task {
+            fn wake_by_ref(_arc_self: &Arc<Self>) {}
+        }
+
+        let (first, second) = (waker(Arc::new(Task)), waker(Arc::new(Task)));
+        let ops = vec![PartialOp::Err(io::ErrorKind::WouldBlock); 2];
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+        let mut poll = |waker| {
+            Pin::new(&mut partial_write).poll_write(&mut Context::from_waker(waker), b"abc")
+        };
+        assert!(poll(&first).is_pending());
+        // A caller that keeps polling with the waker from its first poll.
+        assert!(poll(&first).is_pending());
+        partial_write
+            .ops()
+            .assert_waker_registered("PartialAsyncWrite", &first);
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            partial_write
+                .ops()
+                .assert_waker_registered("PartialAsyncWrite", &second)
+        }));
+        assert!(res.is_err(), "the stale waker was registered");
+
+        assert!(Pin::new(&mut partial_write)
+            .poll_write(&mut Context::from_waker(&second), b"abc")
+            .is_ready());
+        assert!(partial_write.ops().registered_waker().is_none());
+    }
+
+    #[test]
+    fn test_leaked_wakers() {
+        use futures::executor::block_on;
+
+        // Registers the waker on every write, and never cleans the registrations up.
+        #[derive(Default)]
+        struct Leaky(Vec<Waker>);
+
+        impl AsyncWrite for Leaky {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                self.0.push(cx.waker().clone());
+                Poll::Ready(Ok(buf.len()))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let ops = vec![PartialOp::Err(io::ErrorKind::WouldBlock)];
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+        partial_write.ops_mut().set_waker_leak_detection(true);
+        block_on(partial_write.write_all(b"abc")).unwrap();
+        partial_write
+            .ops()
+            .assert_no_leaked_wakers("PartialAsyncWrite");
+
+        let mut partial_write = PartialAsyncWrite::new(Leaky::default(), None);
+        partial_write.ops_mut().set_waker_leak_detection(true);
+        block_on(async {
+            partial_write.write_all(b"abc").await.unwrap();
+            partial_write.write_all(b"def").await.unwrap();
+        });
+        assert_eq!(partial_write.ops().outstanding_wakers(), 2);
+        partial_write.get_mut().0.clear();
+        partial_write
+            .ops()
+            .assert_no_leaked_wakers("PartialAsyncWrite");
+    }
+
+    #[test]
+    fn test_return_would_block() {
+        use futures::executor::block_on;
+
+        let ops = vec![PartialOp::Err(io::ErrorKind::WouldBlock)];
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+        partial_write
+            .ops_mut()
+            .set_wake_delivery(WakeDelivery::ReturnError);
+        let err = block_on(partial_write.write(b"abc")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(partial_write.ops().wakes_issued(), 0);
+        assert_eq!(block_on(partial_write.write(b"abc")).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_invariant_checks() {
+        use futures::executor::block_on;
+
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), None);
+        partial_write.set_invariant_checks(true);
+        block_on(async {
+            partial_write.write_all(b"abc").await.unwrap();
+            partial_write.flush().await.unwrap();
+            partial_write.close().await.unwrap();
+        });
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "poll_close called with data written since the last successful flush"
+    )]
+    fn test_invariant_shutdown_unflushed() {
+        use futures::executor::block_on;
+
+        let ops = vec![PartialOp::Unlimited, PartialOp::Err(io::ErrorKind::Other)];
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+        partial_write.set_invariant_checks(true);
+        block_on(async {
+            partial_write.write_all(b"abc").await.unwrap();
+            // The flush fails, so the data is still unflushed.
+            partial_write.flush().await.unwrap_err();
+            let _ = partial_write.close().await;
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "poll_write called after a successful shutdown (call #1, 3 bytes")]
+    fn test_invariant_write_after_shutdown() {
+        use futures::executor::block_on;
+
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), None);
+        partial_write.set_invariant_checks(true);
+        block_on(async {
+            partial_write.close().await.unwrap();
+            let _ = partial_write.write(b"abc").await;
+        });
+    }
+
+    #[test]
+    fn test_write_after_failed_shutdown() {
+        use futures::executor::block_on;
+
+        let ops = vec![PartialOp::Err(io::ErrorKind::BrokenPipe)];
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+        partial_write.set_invariant_checks(true);
+        block_on(async {
+            // A failed shutdown leaves the writer open.
+            partial_write.close().await.unwrap_err();
+            partial_write.write_all(b"abc").await.unwrap();
+            partial_write.flush().await.unwrap();
+            partial_write.close().await.unwrap();
+        });
+        assert!(partial_write.writes_after_shutdown().is_empty());
+        assert_eq!(partial_write.get_ref(), b"abc");
+    }
+
+    #[test]
+    #[should_panic(expected = "poll_close polled again after shutdown returned Poll::Ready")]
+    fn test_shutdown_repolled() {
+        use futures::executor::block_on;
+
+        let ops = vec![PartialOp::Err(io::ErrorKind::BrokenPipe)];
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+        partial_write.set_shutdown_checks(true);
+        block_on(async {
+            // A failed shutdown has completed too.
+            partial_write.close().await.unwrap_err();
+            let _ = partial_write.close().await;
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "shutdown was never driven to completion (1 polls, none ready)")]
+    fn test_shutdown_not_completed() {
+        use futures::{executor::block_on, poll};
+
+        let ops = vec![PartialOp::Err(io::ErrorKind::WouldBlock)];
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+        block_on(async {
+            assert!(poll!(partial_write.close()).is_pending());
+        });
+        partial_write.assert_shutdown_completed();
+    }
+
+    #[test]
+    fn test_writes_after_shutdown() {
+        use futures::executor::block_on;
+
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), None);
+        partial_write.record_calls();
+        block_on(async {
+            partial_write.write_all(b"abc").await.unwrap();
+            partial_write.close().await.unwrap();
+            partial_write.write_all(b"de").await.unwrap();
+            assert_eq!(partial_write.get_ref(), b"abcde", "accepted by default");
+
+            partial_write.set_reject_writes_after_shutdown(true);
+            let err = partial_write.write(b"f").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+            assert!(
+                err.to_string()
+                    .contains("(call #3, 1 bytes, write #2 after shutdown)"),
+                "{}",
+                err
+            );
+        });
+        assert_eq!(
+            partial_write.writes_after_shutdown(),
+            [
+                WriteAfterShutdown { call: 2, len: 2 },
+                WriteAfterShutdown { call: 3, len: 1 },
+            ]
+        );
+        assert_eq!(partial_write.calls()[3], "poll_write");
+        assert_eq!(partial_write.get_ref(), b"abcde");
+    }
+
+    #[test]
+    fn test_watchdog_reset() {
+        use futures::executor::block_on;
+
+        let wouldblock = PartialOp::Err(io::ErrorKind::WouldBlock);
+        let mut ops = vec![wouldblock.clone(); 2];
+        ops.push(PartialOp::Limited(1));
+        ops.extend(vec![wouldblock; 2]);
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+        partial_write.ops_mut().set_watchdog(Some(3));
+        block_on(partial_write.write_all(b"ab")).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "3 consecutive polls returned Poll::Pending because of \
+                               PartialOp::Err(WouldBlock), with no progress and no wake from \
+                               outside partial-io\n\
+                               method: poll_write, ops taken: 4, bytes processed: 1\n\
+                               remaining ops: 2 left: [Err(WouldBlock), Err(WouldBlock)]\n\
+                               last wake: Inline by partial-io, 3 wakes issued")]
+    fn test_watchdog() {
+        use futures::executor::block_on;
+
+        let mut ops = vec![PartialOp::Limited(1)];
+        ops.extend(vec![PartialOp::Err(io::ErrorKind::WouldBlock); 5]);
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+        partial_write.ops_mut().set_watchdog(Some(3));
+        let _ = block_on(partial_write.write_all(b"ab"));
+    }
+
+    #[test]
+    fn test_pending_before_flush() {
+        use futures::executor::block_on;
+
+        let ops = vec![PartialOp::Unlimited, PartialOp::Err(io::ErrorKind::Other)];
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+        partial_write.set_pending_before_flush(true);
+        block_on(async {
+            partial_write.write_all(b"abc").await.unwrap();
+            assert_eq!(partial_write.ops().wakes_issued(), 0);
+            // The Pending doesn't consume an operation, so the flush still fails afterwards.
+            let err = partial_write.flush().await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::Other);
+            assert_eq!(partial_write.ops().wakes_issued(), 1);
+            partial_write.close().await.unwrap();
+            assert_eq!(partial_write.ops().wakes_issued(), 2);
+        });
+    }
+
+    #[test]
+    fn test_partial_flushes() {
+        use futures::executor::block_on;
+
+        let ops = vec![PartialOp::Limited(1), PartialOp::Unlimited];
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+        partial_write.set_partial_flushes(true);
+        block_on(partial_write.flush()).unwrap();
+        assert_eq!(partial_write.ops().wakes_issued(), 1);
+        assert_eq!(partial_write.flush_report().forwarded, 2);
+    }
+
+    #[test]
+    fn test_lying_flushes() {
+        use futures::executor::block_on;
+
+        let ops = vec![PartialOp::Err(io::ErrorKind::Other)];
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+        partial_write.set_lying_flushes(1);
+        block_on(async {
+            partial_write.write_all(b"abc").await.unwrap_err();
+            partial_write.write_all(b"abc").await.unwrap();
+            partial_write.flush().await.unwrap();
+            assert!(!partial_write.flush_report().durable);
+            partial_write.close().await.unwrap();
+        });
+        let report = partial_write.flush_report();
+        assert_eq!(
+            report,
+            FlushReport {
+                skipped: 1,
+                forwarded: 1,
+                durable: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_inner_access() {
+        use futures::executor::block_on;
+
+        let ops = vec![PartialOp::Limited(2)];
+        let mut partial_write = Box::pin(PartialAsyncWrite::new(Vec::new(), ops));
+        block_on(async {
+            partial_write.write_all(b"abc").await.unwrap();
+            // The inner writer can be inspected and changed without consuming the wrapper.
+            assert_eq!(partial_write.get_ref(), b"abc");
+            partial_write.as_mut().get_pin_mut().clear();
+            partial_write.write_all(b"de").await.unwrap();
+        });
+        assert_eq!(partial_write.get_mut(), b"de");
+    }
+
+    #[test]
+    fn test_total_limit_error() {
+        use futures::executor::block_on;
+
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), vec![]);
+        partial_write
+            .set_total_limit(Some(2))
+            .set_total_limit_error(Some(io::ErrorKind::BrokenPipe));
+        block_on(async {
+            let err = partial_write.write_all(b"abc").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+            partial_write.flush().await.unwrap();
+        });
+        assert_eq!(partial_write.get_ref(), b"ab");
     }
 }
@@ -0,0 +1,308 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! This module contains an `http_body::Body` wrapper that breaks data frames
+//! up according to a provided iterator.
+
+use crate::{futures_util::FuturesOps, PartialOp};
+use bytes::{Buf, Bytes};
+use http_body::{Body, Frame, SizeHint};
+use pin_project::pin_project;
+use std::{
+    error::Error,
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The error type returned by `PartialBody`.
+///
+/// Errors from the inner body and errors injected through `PartialOp::Err` are both boxed into
+/// this type. Injected errors can be recovered by downcasting to `io::Error`.
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+/// A wrapper that breaks inner `http_body::Body` instances up according to the provided iterator.
+///
+/// Each call to `poll_frame` consumes an operation:
+///
+/// * `PartialOp::Limited(n)` splits data frames so that at most `n` bytes are returned in the
+///   next frame. The rest of the frame is held back and returned by subsequent calls.
+/// * `PartialOp::Err(io::ErrorKind::WouldBlock)` returns `Poll::Pending` and reschedules the
+///   task, `PartialOp::Err(io::ErrorKind::Interrupted)` causes a retry, and any other error is
+///   returned as a frame error.
+/// * `PartialOp::Unlimited` returns the next frame (or the rest of a split frame) as-is.
+///
+/// Trailers frames are never split.
+///
+/// Available with the `http-body1` feature.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(feature = "http-body1")]
+/// use bytes::Bytes;
+/// # #[cfg(feature = "http-body1")]
+/// use http_body_util::{BodyExt, Full};
+/// # #[cfg(feature = "http-body1")]
+/// use partial_io::{PartialBody, PartialOp};
+/// # #[cfg(feature = "http-body1")]
+/// use std::io;
+///
+/// # #[cfg(feature = "http-body1")]
+/// # fn main() {
+/// futures::executor::block_on(async {
+///     let body = Full::new(Bytes::from_static(b"Hello, world!"));
+///     let iter = vec![
+///         PartialOp::Limited(5),                      // Only return 5 bytes in the first frame.
+///         PartialOp::Err(io::ErrorKind::WouldBlock),  // A not-ready state.
+///         PartialOp::Unlimited,                       // The rest of the frame.
+///     ];
+///     let mut partial_body = PartialBody::new(body, iter);
+///
+///     let frame = partial_body.frame().await.unwrap().unwrap();
+///     assert_eq!(frame.into_data().unwrap(), "Hello");
+///     // This yields once (WouldBlock) before returning the rest of the data.
+///     let frame = partial_body.frame().await.unwrap().unwrap();
+///     assert_eq!(frame.into_data().unwrap(), ", world!");
+///     assert!(partial_body.frame().await.is_none());
+/// });
+/// # }
+///
+/// # #[cfg(not(feature = "http-body1"))]
+/// # fn main() {
+/// #     assert!(true, "dummy test");
+/// # }
+/// ```
+#[pin_project]
+pub struct PartialBody<B> {
+    #[pin]
+    inner: B,
+    ops: FuturesOps,
+    // The unreturned part of a data frame that was split up.
+    remaining: Option<Bytes>,
+}
+
+impl<B> PartialBody<B> {
+    /// Creates a new `PartialBody` wrapper over the body with the specified `PartialOp`s.
+    pub fn new<I>(inner: B, iter: I) -> Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        PartialBody {
+            inner,
+            ops: FuturesOps::new(iter),
+            remaining: None,
+        }
+    }
+
+    /// Sets the `PartialOp`s for this body.
+    pub fn set_ops<I>(&mut self, iter: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        self.ops.replace(iter);
+        self
+    }
+
+    /// Sets the `PartialOp`s for this body in a pinned context.
+    pub fn pin_set_ops<I>(self: Pin<&mut Self>, iter: I) -> Pin<&mut Self>
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        let mut this = self;
+        this.as_mut().project().ops.replace(iter);
+        this
+    }
+
+    /// Returns the operations of this body, along with its wake and poll statistics.
+    pub fn ops(&self) -> &FuturesOps {
+        &self.ops
+    }
+
+    /// Returns the operations of this body, to set its clock and how it delivers wakes, or to
+    /// enable checks on how it's polled.
+    ///
+    /// See `FuturesOps` for the settings.
+    pub fn ops_mut(&mut self) -> &mut FuturesOps {
+        &mut self.ops
+    }
+
+    /// Returns a shared reference to the underlying body.
+    pub fn get_ref(&self) -> &B {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying body.
+    pub fn get_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+
+    /// Returns a pinned mutable reference to the underlying body.
+    pub fn pin_get_mut(self: Pin<&mut Self>) -> Pin<&mut B> {
+        self.project().inner
+    }
+
+    /// Consumes this wrapper, returning the underlying body.
+    ///
+    /// Any data held back from a split frame is lost.
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+
+impl<B> Body for PartialBody<B>
+where
+    B: Body,
+    B::Error: Into<BoxError>,
+{
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let inner = this.inner;
+        let remaining = this.remaining;
+        let len = remaining.as_ref().map_or(usize::MAX, |data| data.len());
+
+        let res = this.ops.poll_impl(
+            cx,
+            |cx, len| {
+                let data = match remaining.take() {
+                    Some(data) => data,
+                    None => match inner.poll_frame(cx) {
+                        Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                            Ok(mut data) => data.copy_to_bytes(data.remaining()),
+                            Err(frame) => {
+                                // Trailers are returned as-is.
+                                let frame =
+                                    frame.map_data(|mut data| data.copy_to_bytes(data.remaining()));
+                                return Poll::Ready(Ok(Some(Ok(frame))));
+                            }
+                        },
+                        Poll::Ready(Some(Err(err))) => {
+                            return Poll::Ready(Ok(Some(Err(err.into()))));
+                        }
+                        Poll::Ready(None) => return Poll::Ready(Ok(None)),
+                        Poll::Pending => return Poll::Pending,
+                    },
+                };
+
+                let data = match len {
+                    Some(len) if len < data.len() => {
+                        let mut data = data;
+                        let head = data.split_to(len);
+                        *remaining = Some(data);
+                        head
+                    }
+                    _ => data,
+                };
+                Poll::Ready(Ok(Some(Ok(Frame::data(data)))))
+            },
+            len,
+            "error during poll_frame, generated by partial-io",
+        );
+
+        match res {
+            Poll::Ready(Ok(frame)) => Poll::Ready(frame),
+            Poll::Ready(Err(err)) => Poll::Ready(Some(Err(err.into()))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.remaining.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let remaining = self.remaining.as_ref().map_or(0, |data| data.len() as u64);
+        let hint = self.inner.size_hint();
+        let mut new_hint = SizeHint::new();
+        new_hint.set_lower(hint.lower() + remaining);
+        if let Some(upper) = hint.upper() {
+            new_hint.set_upper(upper + remaining);
+        }
+        new_hint
+    }
+}
+
+impl<B> fmt::Debug for PartialBody<B>
+where
+    B: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PartialBody")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http_body_util::{BodyExt, Full, StreamBody};
+    use std::io;
+
+    use crate::tests::{assert_send, assert_sync};
+
+    #[test]
+    fn test_sendable() {
+        assert_send::<PartialBody<Full<Bytes>>>();
+        assert_sync::<PartialBody<Full<Bytes>>>();
+    }
+
+    #[test]
+    fn test_split_frames() {
+        let frames: Vec<Result<_, io::Error>> = vec![
+            Ok(Frame::data(Bytes::from_static(b"abcdef"))),
+            Ok(Frame::data(Bytes::from_static(b"ghi"))),
+            Ok(Frame::trailers(Default::default())),
+        ];
+        let body = StreamBody::new(futures::stream::iter(frames));
+        let ops = vec![
+            PartialOp::Limited(2),
+            PartialOp::Err(io::ErrorKind::WouldBlock),
+            PartialOp::Limited(3),
+            PartialOp::Err(io::ErrorKind::InvalidData),
+            PartialOp::Limited(4),
+            PartialOp::Unlimited,
+            PartialOp::Limited(1),
+        ];
+        let mut partial_body = PartialBody::new(body, ops);
+
+        futures::executor::block_on(async {
+            let mut data = vec![];
+            let mut errors = vec![];
+            let mut trailers = 0;
+            while let Some(frame) = partial_body.frame().await {
+                match frame {
+                    Ok(frame) => match frame.into_data() {
+                        Ok(chunk) => data.push(chunk),
+                        Err(frame) => {
+                            assert!(frame.is_trailers(), "non-data frames are trailers");
+                            trailers += 1;
+                        }
+                    },
+                    Err(err) => {
+                        let err = err.downcast::<io::Error>().expect("injected io::Error");
+                        errors.push(err.kind());
+                    }
+                }
+            }
+
+            assert_eq!(data, vec!["ab", "cde", "f", "ghi"]);
+            assert_eq!(errors, vec![io::ErrorKind::InvalidData]);
+            assert_eq!(trailers, 1, "trailers are passed through");
+        });
+    }
+}
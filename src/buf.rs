@@ -0,0 +1,457 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! This module contains `bytes` buffer wrappers that break chunks up according to a provided
+//! iterator, along with other helpers for `bytes` buffers.
+
+use crate::{OpEngine, PartialOp};
+use bytes::{
+    buf::{Limit, UninitSlice},
+    Buf, BufMut,
+};
+use std::{cmp, fmt};
+
+/// A wrapper that breaks up the chunks of inner `Buf` instances according to the provided
+/// iterator.
+///
+/// This is useful for testing code that consumes a `Buf` directly, for example through
+/// vectored writes from a chain of `Bytes`, since such code bypasses `Read` and `Write`.
+///
+/// An operation is consumed when the wrapper is created and after each call to `advance`. The
+/// operation determines the length of the slices returned by `chunk`, until the next `advance`:
+///
+/// * `PartialOp::Limited(n)` limits the chunk to `n` bytes. Since `chunk` may only return an empty
+///   slice at the end of the buffer, `Limited(0)` behaves the same as `Limited(1)`.
+/// * `PartialOp::Unlimited` returns the inner chunk as-is.
+/// * A buffer has no way to signal errors or wait, so error operations (such as `PartialOp::Err`)
+///   and `PartialOp::Delay` behave the same as `Unlimited`.
+///
+/// `chunks_vectored` returns at most one chunk, so vectored consumers see the same limits.
+///
+/// Requires the `bytes1` feature to be enabled.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(feature = "bytes1")]
+/// use bytes::{Buf, Bytes};
+/// # #[cfg(feature = "bytes1")]
+/// use partial_io::{PartialBuf, PartialOp};
+///
+/// # #[cfg(feature = "bytes1")]
+/// # fn main() {
+/// let buf = Bytes::from_static(b"Hello, world!");
+/// let iter = vec![PartialOp::Limited(7), PartialOp::Limited(2)];
+/// let mut partial_buf = PartialBuf::new(buf, iter);
+///
+/// assert_eq!(partial_buf.chunk(), b"Hello, ");
+/// partial_buf.advance(7);
+/// assert_eq!(partial_buf.chunk(), b"wo");
+/// partial_buf.advance(2);
+/// // The iterator has run out of operations, so it no longer limits chunks.
+/// assert_eq!(partial_buf.chunk(), b"rld!");
+/// # }
+///
+/// # #[cfg(not(feature = "bytes1"))]
+/// # fn main() {
+/// #     assert!(true, "dummy test");
+/// # }
+/// ```
+pub struct PartialBuf<B> {
+    inner: B,
+    ops: OpEngine,
+    limit: Option<usize>,
+}
+
+impl<B> PartialBuf<B>
+where
+    B: Buf,
+{
+    /// Creates a new `PartialBuf` wrapper over the buffer with the specified `PartialOp`s.
+    pub fn new<I>(inner: B, iter: I) -> Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        let mut ops = OpEngine::new(iter);
+        let limit = next_limit(&mut ops);
+        PartialBuf { inner, ops, limit }
+    }
+
+    /// Sets the `PartialOp`s for this buffer.
+    ///
+    /// The first operation is consumed immediately, and applies to the current chunk.
+    pub fn set_ops<I>(&mut self, iter: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        self.ops.replace(iter);
+        self.limit = next_limit(&mut self.ops);
+        self
+    }
+
+    /// Acquires a reference to the underlying buffer.
+    pub fn get_ref(&self) -> &B {
+        &self.inner
+    }
+
+    /// Acquires a mutable reference to the underlying buffer.
+    pub fn get_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+
+    /// Consumes this wrapper, returning the underlying buffer.
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+
+impl<B> Buf for PartialBuf<B>
+where
+    B: Buf,
+{
+    #[inline]
+    fn remaining(&self) -> usize {
+        self.inner.remaining()
+    }
+
+    fn chunk(&self) -> &[u8] {
+        let chunk = self.inner.chunk();
+        match self.limit {
+            Some(n) => &chunk[..cmp::min(n, chunk.len())],
+            None => chunk,
+        }
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        self.inner.advance(cnt);
+        self.limit = next_limit(&mut self.ops);
+    }
+}
+
+impl<B> fmt::Debug for PartialBuf<B>
+where
+    B: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PartialBuf")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+/// A wrapper that breaks up the chunks of inner `BufMut` instances according to the provided
+/// iterator.
+///
+/// An operation is consumed when the wrapper is created and after each call to `advance_mut`.
+/// The operation determines the length of the slices returned by `chunk_mut`, until the next
+/// `advance_mut`. Operations are interpreted the same way as for [`PartialBuf`].
+///
+/// Requires the `bytes1` feature to be enabled.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(feature = "bytes1")]
+/// use bytes::BufMut;
+/// # #[cfg(feature = "bytes1")]
+/// use partial_io::{PartialBufMut, PartialOp};
+///
+/// # #[cfg(feature = "bytes1")]
+/// # fn main() {
+/// let iter = ::std::iter::repeat(PartialOp::Limited(2));
+/// let mut partial_buf = PartialBufMut::new(Vec::new(), iter);
+///
+/// assert!(partial_buf.chunk_mut().len() <= 2);
+/// // put_slice writes the data across several limited chunks.
+/// partial_buf.put_slice(b"Hello");
+/// assert_eq!(&partial_buf.get_ref()[..], b"Hello");
+/// # }
+///
+/// # #[cfg(not(feature = "bytes1"))]
+/// # fn main() {
+/// #     assert!(true, "dummy test");
+/// # }
+/// ```
+///
+/// [`PartialBuf`]: struct.PartialBuf.html
+pub struct PartialBufMut<B> {
+    inner: B,
+    ops: OpEngine,
+    limit: Option<usize>,
+}
+
+impl<B> PartialBufMut<B>
+where
+    B: BufMut,
+{
+    /// Creates a new `PartialBufMut` wrapper over the buffer with the specified `PartialOp`s.
+    pub fn new<I>(inner: B, iter: I) -> Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        let mut ops = OpEngine::new(iter);
+        let limit = next_limit(&mut ops);
+        PartialBufMut { inner, ops, limit }
+    }
+
+    /// Sets the `PartialOp`s for this buffer.
+    ///
+    /// The first operation is consumed immediately, and applies to the current chunk.
+    pub fn set_ops<I>(&mut self, iter: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        self.ops.replace(iter);
+        self.limit = next_limit(&mut self.ops);
+        self
+    }
+
+    /// Acquires a reference to the underlying buffer.
+    pub fn get_ref(&self) -> &B {
+        &self.inner
+    }
+
+    /// Acquires a mutable reference to the underlying buffer.
+    pub fn get_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+
+    /// Consumes this wrapper, returning the underlying buffer.
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+
+unsafe impl<B> BufMut for PartialBufMut<B>
+where
+    B: BufMut,
+{
+    #[inline]
+    fn remaining_mut(&self) -> usize {
+        self.inner.remaining_mut()
+    }
+
+    unsafe fn advance_mut(&mut self, cnt: usize) {
+        self.inner.advance_mut(cnt);
+        self.limit = next_limit(&mut self.ops);
+    }
+
+    fn chunk_mut(&mut self) -> &mut UninitSlice {
+        let limit = self.limit;
+        let chunk = self.inner.chunk_mut();
+        match limit {
+            Some(n) => {
+                let len = cmp::min(n, chunk.len());
+                &mut chunk[..len]
+            }
+            None => chunk,
+        }
+    }
+}
+
+impl<B> fmt::Debug for PartialBufMut<B>
+where
+    B: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PartialBufMut")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+#[inline]
+fn next_limit(ops: &mut OpEngine) -> Option<usize> {
+    match ops.next_op() {
+        // Chunks may only be empty at the end of the buffer.
+        Some(PartialOp::Limited(n)) | Some(PartialOp::Fragment(n)) => Some(cmp::max(n, 1)),
+        Some(PartialOp::Panic(message)) => ops.injected_panic(message),
+        Some(PartialOp::Labeled(..)) => unreachable!("labels are removed by next_op"),
+        Some(PartialOp::Unlimited)
+        | Some(PartialOp::LimitedFraction(..))
+        | Some(PartialOp::Err(_))
+        | Some(PartialOp::ErrTimes(..))
+        | Some(PartialOp::OsErr(_))
+        | Some(PartialOp::ErrWith(_))
+        | Some(PartialOp::Delay(_))
+        | None => None,
+    }
+}
+
+/// Extensions to `bytes`'s `BufMut`.
+///
+/// This is the `BufMut` counterpart to `ReadBufExt`. It is useful for code that reads into a
+/// `BytesMut` or another `BufMut`, for example through `tokio_util::io::poll_read_buf`: unlike
+/// slices and `ReadBuf`s, a `BufMut` may grow and may expose an uninitialized tail through
+/// `chunk_mut`.
+///
+/// Requires the `bytes1` feature to be enabled.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(feature = "bytes1")]
+/// use bytes::{BufMut, BytesMut};
+/// # #[cfg(feature = "bytes1")]
+/// use partial_io::BufMutExt;
+///
+/// # #[cfg(feature = "bytes1")]
+/// # fn main() {
+/// let mut buf = BytesMut::new();
+/// buf.with_limited(5, |limited_buf| {
+///     assert_eq!(limited_buf.remaining_mut(), 5);
+///     limited_buf.put_slice(b"Hello");
+/// });
+/// assert_eq!(&buf[..], b"Hello");
+/// # }
+///
+/// # #[cfg(not(feature = "bytes1"))]
+/// # fn main() {
+/// #     assert!(true, "dummy test");
+/// # }
+/// ```
+pub trait BufMutExt {
+    /// Wrap this `BufMut` in a limited one backed by the same storage, then call the callback
+    /// with this limited instance.
+    ///
+    /// Both `remaining_mut` and the length of `chunk_mut` (including any uninitialized tail) are
+    /// capped at `limit`. Any bytes written to the limited `BufMut` by the callback are reflected
+    /// in the original `BufMut`.
+    fn with_limited<F, T>(&mut self, limit: usize, callback: F) -> T
+    where
+        F: FnOnce(&mut Limit<&mut Self>) -> T;
+}
+
+impl<B> BufMutExt for B
+where
+    B: BufMut + ?Sized,
+{
+    fn with_limited<F, T>(&mut self, limit: usize, callback: F) -> T
+    where
+        F: FnOnce(&mut Limit<&mut Self>) -> T,
+    {
+        callback(&mut self.limit(limit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::{Bytes, BytesMut};
+    use itertools::Itertools;
+
+    use crate::tests::{assert_send, assert_sync};
+
+    #[test]
+    fn test_sendable() {
+        assert_send::<PartialBuf<Bytes>>();
+        assert_send::<PartialBufMut<BytesMut>>();
+        assert_sync::<PartialBuf<Bytes>>();
+        assert_sync::<PartialBufMut<BytesMut>>();
+    }
+
+    #[test]
+    fn test_partial_buf_chain() {
+        let buf = Bytes::from_static(b"Hello, ").chain(Bytes::from_static(b"world!"));
+        let ops = vec![
+            PartialOp::Limited(3),
+            PartialOp::Limited(0),
+            PartialOp::Err(std::io::ErrorKind::WouldBlock),
+        ];
+        let mut partial_buf = PartialBuf::new(buf, ops.into_iter().cycle());
+
+        let mut chunks = vec![];
+        while partial_buf.has_remaining() {
+            let mut iovs = [std::io::IoSlice::new(&[]); 4];
+            assert_eq!(
+                partial_buf.chunks_vectored(&mut iovs),
+                1,
+                "at most one chunk"
+            );
+            let chunk = partial_buf.chunk().to_vec();
+            partial_buf.advance(chunk.len());
+            chunks.push(chunk);
+        }
+        assert_eq!(chunks, vec![&b"Hel"[..], b"l", b"o, ", b"wor", b"l", b"d!"]);
+    }
+
+    #[test]
+    fn test_partial_buf_mut() {
+        let ops = vec![
+            PartialOp::Limited(2),
+            PartialOp::Unlimited,
+            PartialOp::Limited(1),
+        ];
+        let mut partial_buf = PartialBufMut::new(BytesMut::with_capacity(64), ops);
+
+        assert_eq!(partial_buf.chunk_mut().len(), 2);
+        partial_buf.put_u8(b'a');
+        assert!(partial_buf.chunk_mut().len() > 2, "unlimited");
+        partial_buf.put_u8(b'b');
+        assert_eq!(partial_buf.chunk_mut().len(), 1);
+        partial_buf.put_slice(b"cdefgh");
+        assert_eq!(&partial_buf.into_inner()[..], b"abcdefgh");
+    }
+
+    #[test]
+    fn test_with_limited() {
+        let limits = vec![0, 1, 7, 64, 128];
+        let capacities = vec![0, 16, 64];
+
+        for (limit, capacity) in limits.into_iter().cartesian_product(capacities) {
+            let mut buf = BytesMut::with_capacity(capacity);
+            buf.put_slice(b"abc");
+            let written = buf.with_limited(limit, |limited_buf| {
+                assert!(limited_buf.remaining_mut() <= limit, "limit is applied");
+                // BytesMut may grow here to expose more uninitialized capacity.
+                let chunk_len = limited_buf.chunk_mut().len();
+                assert!(
+                    chunk_len <= limit,
+                    "limit is applied to the uninitialized tail"
+                );
+                let to_write = chunk_len.min(5);
+                limited_buf.put_slice(&b"defgh"[..to_write]);
+                to_write
+            });
+
+            assert_eq!(
+                buf.len(),
+                3 + written,
+                "written bytes are reflected in the original"
+            );
+            assert_eq!(&buf[..], &b"abcdefgh"[..3 + written]);
+        }
+    }
+
+    #[cfg(feature = "tokio-util07")]
+    #[test]
+    fn test_poll_read_buf() {
+        use futures::{executor::block_on, future::poll_fn};
+        use std::{io::Cursor, pin::Pin};
+
+        let mut reader = Cursor::new(b"Hello, world!".to_vec());
+        let mut buf = BytesMut::new();
+        loop {
+            let n = block_on(poll_fn(|cx| {
+                buf.with_limited(4, |limited_buf| {
+                    tokio_util::io::poll_read_buf(Pin::new(&mut reader), cx, limited_buf)
+                })
+            }))
+            .unwrap();
+            assert!(n <= 4, "limit is applied to poll_read_buf");
+            if n == 0 {
+                break;
+            }
+        }
+        assert_eq!(&buf[..], b"Hello, world!");
+    }
+}
@@ -0,0 +1,325 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! A TCP proxy that applies `PartialOp`s, latency and corruption to the connections through it.
+//!
+//! The other helpers in this crate wrap one side of a connection. [`ChaosProxy`] goes between an
+//! unmodified client and a real server instead, for end-to-end tests: it listens on the loopback
+//! interface, and forwards each connection it accepts to an upstream address. The bytes it
+//! forwards in each [`Direction`] are shaped by a schedule of `PartialOp`s, which can be changed
+//! while connections are open.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use partial_io::{chaos::{ChaosProxy, Direction}, server::Builder, PartialOp};
+//! use std::{
+//!     io::{self, Read, Write},
+//!     net::TcpStream,
+//! };
+//!
+//! let server = Builder::new().expect(b"ping").send(b"pong").spawn().unwrap();
+//! let proxy = ChaosProxy::start(*server.local_addr()).unwrap();
+//! // Forward the request to the server one byte at a time.
+//! proxy.set_ops(Direction::ToServer, vec![PartialOp::Limited(1); 4]);
+//!
+//! let mut stream = TcpStream::connect(proxy.local_addr()).unwrap();
+//! stream.write_all(b"ping").unwrap();
+//! let mut buf = Vec::new();
+//! stream.read_to_end(&mut buf).unwrap();
+//! assert_eq!(buf, b"pong");
+//! server.join().unwrap();
+//! ```
+//!
+//! [`ChaosProxy`]: struct.ChaosProxy.html
+//! [`Direction`]: enum.Direction.html
+
+use crate::{PartialOp, PartialWrite};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+/// The size of the buffer each direction of a connection is forwarded through.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// A direction in which a [`ChaosProxy`] forwards bytes.
+///
+/// [`ChaosProxy`]: struct.ChaosProxy.html
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Direction {
+    /// From the client to the upstream server.
+    ToServer,
+    /// From the upstream server back to the client.
+    ToClient,
+}
+
+/// A TCP proxy between clients and an upstream server, which applies `PartialOp`s, latency and
+/// corruption to the bytes it forwards.
+///
+/// Each direction of each connection is forwarded on its own thread. Bytes are read from one
+/// side as they arrive, then written to the other side through a `PartialWrite` with the
+/// schedule for that direction:
+///
+/// * `PartialOp::Limited(n)` forwards the next `n` bytes as a separate write, and
+///   `PartialOp::Delay` stalls before a write.
+/// * Any error in the schedule, other than `Interrupted`, closes both sides of the connection, as
+///   if it had been reset mid-stream.
+/// * Once the schedule runs out, bytes are forwarded as they arrive.
+///
+/// The settings for a direction are changed with [`set_ops`], [`set_latency`] and
+/// [`set_corruption`], and apply to open connections from the next bytes they forward. Every
+/// connection starts the current schedule from its beginning, whenever it was set.
+///
+/// The proxy stops accepting connections once it's dropped. Connections that are already open
+/// stay open until either side closes them.
+///
+/// See [the module level documentation](index.html) for more.
+///
+/// [`set_ops`]: struct.ChaosProxy.html#method.set_ops
+/// [`set_latency`]: struct.ChaosProxy.html#method.set_latency
+/// [`set_corruption`]: struct.ChaosProxy.html#method.set_corruption
+pub struct ChaosProxy {
+    addr: SocketAddr,
+    upstream: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    to_server: Mutex<Settings>,
+    to_client: Mutex<Settings>,
+    connections: AtomicUsize,
+    stopped: AtomicBool,
+}
+
+impl Shared {
+    fn settings(&self, direction: Direction) -> &Mutex<Settings> {
+        match direction {
+            Direction::ToServer => &self.to_server,
+            Direction::ToClient => &self.to_client,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Settings {
+    ops: Vec<PartialOp>,
+    // Incremented every time the ops are replaced, so forwarders know to pick them up.
+    generation: u64,
+    latency: Duration,
+    corrupt_every: Option<u64>,
+}
+
+impl ChaosProxy {
+    /// Starts a proxy on the loopback interface that forwards every connection to `upstream`.
+    ///
+    /// Bytes are forwarded unchanged until the settings are changed.
+    pub fn start(upstream: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            to_server: Mutex::default(),
+            to_client: Mutex::default(),
+            connections: AtomicUsize::new(0),
+            stopped: AtomicBool::new(false),
+        });
+        let accept_shared = shared.clone();
+        thread::spawn(move || accept(listener, upstream, accept_shared));
+        Ok(ChaosProxy {
+            addr,
+            upstream,
+            shared,
+        })
+    }
+
+    /// Returns the address the proxy is listening on, for clients to connect to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the address connections are forwarded to.
+    pub fn upstream_addr(&self) -> SocketAddr {
+        self.upstream
+    }
+
+    /// Returns the number of connections accepted so far.
+    pub fn connections(&self) -> usize {
+        self.shared.connections.load(Ordering::SeqCst)
+    }
+
+    /// Replaces the schedule of `PartialOp`s for writes in `direction`.
+    ///
+    /// Every open connection starts the new schedule from its beginning, from the next bytes it
+    /// forwards in that direction, and so does every connection accepted later.
+    pub fn set_ops(&self, direction: Direction, ops: Vec<PartialOp>) -> &Self {
+        let mut settings = self.shared.settings(direction).lock().unwrap();
+        settings.ops = ops;
+        settings.generation += 1;
+        self
+    }
+
+    /// Delays every chunk of bytes forwarded in `direction` by `latency`, on top of any
+    /// `PartialOp::Delay` in the schedule.
+    pub fn set_latency(&self, direction: Direction, latency: Duration) -> &Self {
+        self.shared.settings(direction).lock().unwrap().latency = latency;
+        self
+    }
+
+    /// Corrupts every `n`th byte forwarded in `direction` by inverting its bits, or stops
+    /// corrupting bytes if `n` is `None`.
+    ///
+    /// Bytes are counted from the start of each connection, so the same bytes are corrupted
+    /// every time a test runs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is `Some(0)`.
+    pub fn set_corruption(&self, direction: Direction, n: Option<u64>) -> &Self {
+        assert!(n != Some(0), "corruption interval must be greater than 0");
+        self.shared
+            .settings(direction)
+            .lock()
+            .unwrap()
+            .corrupt_every = n;
+        self
+    }
+}
+
+impl Drop for ChaosProxy {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        // Wake up the accepting thread, so that it sees the flag and exits.
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+impl fmt::Debug for ChaosProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChaosProxy")
+            .field("addr", &self.addr)
+            .field("upstream", &self.upstream)
+            .field("connections", &self.connections())
+            .finish()
+    }
+}
+
+fn accept(listener: TcpListener, upstream: SocketAddr, shared: Arc<Shared>) {
+    for client in listener.incoming() {
+        if shared.stopped.load(Ordering::SeqCst) {
+            return;
+        }
+        let client = match client {
+            Ok(client) => client,
+            Err(_) => continue,
+        };
+        shared.connections.fetch_add(1, Ordering::SeqCst);
+        // If the upstream server can't be reached, the client is disconnected right away.
+        if let Ok(server) = TcpStream::connect(upstream) {
+            let _ = connect(client, server, &shared);
+        }
+    }
+}
+
+// Starts forwarding in both directions between `client` and `server`.
+fn connect(client: TcpStream, server: TcpStream, shared: &Arc<Shared>) -> io::Result<()> {
+    client.set_nodelay(true)?;
+    server.set_nodelay(true)?;
+    let (client_src, server_dst) = (client.try_clone()?, server.try_clone()?);
+    let to_server = shared.clone();
+    thread::spawn(move || forward(client_src, server_dst, &to_server, Direction::ToServer));
+    let to_client = shared.clone();
+    thread::spawn(move || forward(server, client, &to_client, Direction::ToClient));
+    Ok(())
+}
+
+// Forwards bytes from `src` to `dst` until either side is closed.
+fn forward(mut src: TcpStream, dst: TcpStream, shared: &Shared, direction: Direction) {
+    let mut generation = 0;
+    let mut dst = PartialWrite::new(dst, vec![]);
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut offset = 0;
+    loop {
+        let n = match src.read(&mut buf) {
+            Ok(0) | Err(_) => {
+                // Pass the end of the stream on.
+                let _ = dst.get_ref().shutdown(Shutdown::Write);
+                return;
+            }
+            Ok(n) => n,
+        };
+        let settings = shared.settings(direction).lock().unwrap().clone();
+        if settings.generation != generation {
+            generation = settings.generation;
+            dst.set_ops(settings.ops);
+        }
+        if let Some(every) = settings.corrupt_every {
+            for (i, byte) in buf[..n].iter_mut().enumerate() {
+                if (offset + i as u64 + 1).is_multiple_of(every) {
+                    *byte = !*byte;
+                }
+            }
+        }
+        offset += n as u64;
+        if !settings.latency.is_zero() {
+            thread::sleep(settings.latency);
+        }
+        if dst.write_all(&buf[..n]).is_err() {
+            // Reset both sides.
+            let _ = src.shutdown(Shutdown::Both);
+            let _ = dst.get_ref().shutdown(Shutdown::Both);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Builder;
+
+    #[test]
+    fn test_live_changes() {
+        let server = Builder::new()
+            .expect(b"abcd")
+            .send(b"1234")
+            .expect(b"efgh")
+            .send(b"5678")
+            .spawn()
+            .unwrap();
+        let proxy = ChaosProxy::start(*server.local_addr()).unwrap();
+        proxy.set_corruption(Direction::ToClient, Some(2));
+
+        let mut stream = TcpStream::connect(proxy.local_addr()).unwrap();
+        stream.write_all(b"abcd").unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [b'1', !b'2', b'3', !b'4']);
+
+        // The next request is cut off after 2 bytes, which resets the connection.
+        proxy.set_corruption(Direction::ToClient, None).set_ops(
+            Direction::ToServer,
+            vec![
+                PartialOp::Limited(2),
+                PartialOp::Err(io::ErrorKind::ConnectionReset),
+            ],
+        );
+        stream.write_all(b"efgh").unwrap();
+        let mut rest = Vec::new();
+        let _ = stream.read_to_end(&mut rest);
+        assert_eq!(rest, b"");
+        let err = server.join().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(proxy.connections(), 1);
+    }
+}
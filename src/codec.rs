@@ -0,0 +1,208 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Helpers for testing `tokio_util::codec` decoders and encoders with partial IO.
+//!
+//! Decoders driven by `Framed` and `FramedRead` are state machines that have to cope with frames
+//! arriving in arbitrary pieces. The functions in this module wrap a transport in
+//! [`PartialAsyncRead`] and [`PartialAsyncWrite`] and hand back a ready-to-use framed instance,
+//! and provide canned schedules for common problem cases.
+//!
+//! Available with the `tokio-util07` feature.
+//!
+//! # Examples
+//!
+//! ```rust
+//! # #[cfg(feature = "tokio-util07")]
+//! use futures::prelude::*;
+//! # #[cfg(feature = "tokio-util07")]
+//! use partial_io::codec;
+//! # #[cfg(feature = "tokio-util07")]
+//! use std::io::Cursor;
+//! # #[cfg(feature = "tokio-util07")]
+//! use tokio_util::codec::LengthDelimitedCodec;
+//!
+//! # #[cfg(feature = "tokio-util07")]
+//! #[tokio::main]
+//! async fn main() {
+//!     // A 4-byte length prefix followed by a 5-byte payload.
+//!     let data = b"\x00\x00\x00\x05hello".to_vec();
+//!     // Return Poll::Pending right after the length prefix has been read.
+//!     let ops = codec::pending_after_prefix(4);
+//!     let mut framed = codec::framed_read(Cursor::new(data), LengthDelimitedCodec::new(), ops);
+//!
+//!     let frame = framed.next().await.unwrap().unwrap();
+//!     assert_eq!(&frame[..], b"hello");
+//!     assert!(framed.next().await.is_none());
+//! }
+//!
+//! # #[cfg(not(feature = "tokio-util07"))]
+//! # fn main() {
+//! #     assert!(true, "dummy test");
+//! # }
+//! ```
+//!
+//! [`PartialAsyncRead`]: ../struct.PartialAsyncRead.html
+//! [`PartialAsyncWrite`]: ../struct.PartialAsyncWrite.html
+
+use crate::{PartialAsyncRead, PartialAsyncWrite, PartialOp};
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedRead, FramedWrite};
+
+/// A transport with independent `PartialOp`s for reads and writes.
+pub type PartialTransport<T> = PartialAsyncRead<PartialAsyncWrite<T>>;
+
+/// Wraps `inner` with the given read and write `PartialOp`s, then creates a `Framed` over it.
+///
+/// The read ops apply to `poll_read`, and the write ops apply to `poll_write`, `poll_flush` and
+/// `poll_shutdown`.
+pub fn framed<T, C, IR, IW>(
+    inner: T,
+    codec: C,
+    read_ops: IR,
+    write_ops: IW,
+) -> Framed<PartialTransport<T>, C>
+where
+    T: AsyncRead + AsyncWrite,
+    IR: IntoIterator<Item = PartialOp> + 'static,
+    IR::IntoIter: Send,
+    IW: IntoIterator<Item = PartialOp> + 'static,
+    IW::IntoIter: Send,
+{
+    let transport = PartialAsyncRead::new(PartialAsyncWrite::new(inner, write_ops), read_ops);
+    Framed::new(transport, codec)
+}
+
+/// Wraps `inner` with the given `PartialOp`s, then creates a `FramedRead` over it.
+pub fn framed_read<R, D, I>(inner: R, decoder: D, ops: I) -> FramedRead<PartialAsyncRead<R>, D>
+where
+    R: AsyncRead,
+    D: Decoder,
+    I: IntoIterator<Item = PartialOp> + 'static,
+    I::IntoIter: Send,
+{
+    FramedRead::new(PartialAsyncRead::new(inner, ops), decoder)
+}
+
+/// Wraps `inner` with the given `PartialOp`s, then creates a `FramedWrite` over it.
+pub fn framed_write<W, E, Item, I>(
+    inner: W,
+    encoder: E,
+    ops: I,
+) -> FramedWrite<PartialAsyncWrite<W>, E>
+where
+    W: AsyncWrite,
+    E: Encoder<Item>,
+    I: IntoIterator<Item = PartialOp> + 'static,
+    I::IntoIter: Send,
+{
+    FramedWrite::new(PartialAsyncWrite::new(inner, ops), encoder)
+}
+
+/// Returns ops that deliver a frame of `frame_len` bytes across `n` reads of roughly equal size.
+///
+/// Subsequent reads are unlimited.
+///
+/// # Panics
+///
+/// Panics if `n` is 0.
+pub fn split_frame(frame_len: usize, n: usize) -> Vec<PartialOp> {
+    assert!(n > 0, "a frame must be split into at least one read");
+    let base = frame_len / n;
+    let extra = frame_len % n;
+    (0..n)
+        .map(|i| PartialOp::Limited(base + if i < extra { 1 } else { 0 }))
+        .filter(|op| !matches!(op, PartialOp::Limited(0)))
+        .collect()
+}
+
+/// Returns ops that read the first `offset` bytes of a frame, then fail with an error of the
+/// given kind.
+///
+/// Subsequent reads are unlimited.
+pub fn error_mid_frame(offset: usize, kind: io::ErrorKind) -> Vec<PartialOp> {
+    vec![PartialOp::Limited(offset), PartialOp::Err(kind)]
+}
+
+/// Returns ops that read a length prefix of `prefix_len` bytes, then return `Poll::Pending` once
+/// before the payload is read.
+///
+/// Subsequent reads are unlimited.
+pub fn pending_after_prefix(prefix_len: usize) -> Vec<PartialOp> {
+    vec![
+        PartialOp::Limited(prefix_len),
+        PartialOp::Err(io::ErrorKind::WouldBlock),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+    use futures::prelude::*;
+    use std::io::Cursor;
+    use tokio_util::codec::LengthDelimitedCodec;
+
+    fn encoded_frames() -> Vec<u8> {
+        let mut data = vec![];
+        for payload in [&b"hello"[..], &b"partial"[..], &b""[..]] {
+            data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            data.extend_from_slice(payload);
+        }
+        data
+    }
+
+    #[test]
+    fn test_split_frame() {
+        assert_eq!(
+            format!("{:?}", split_frame(10, 3)),
+            "[Limited(4), Limited(3), Limited(3)]"
+        );
+        assert_eq!(split_frame(2, 4).len(), 2, "empty reads are skipped");
+    }
+
+    #[tokio::test]
+    async fn test_framed_read_scenarios() {
+        let scenarios = vec![
+            split_frame(9, 9),
+            pending_after_prefix(4),
+            error_mid_frame(6, io::ErrorKind::Interrupted),
+        ];
+        for ops in scenarios {
+            let mut framed = framed_read(
+                Cursor::new(encoded_frames()),
+                LengthDelimitedCodec::new(),
+                ops,
+            );
+            let mut frames = vec![];
+            while let Some(frame) = framed.next().await {
+                frames.push(frame.unwrap());
+            }
+            assert_eq!(frames, vec!["hello", "partial", ""]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_framed_error_mid_frame() {
+        let ops = error_mid_frame(6, io::ErrorKind::ConnectionReset);
+        let write_ops = vec![PartialOp::Limited(1)];
+        let mut framed = framed(
+            Cursor::new(encoded_frames()),
+            LengthDelimitedCodec::new(),
+            ops,
+            write_ops,
+        );
+        assert_eq!(
+            framed.next().await.unwrap().unwrap_err().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+
+        framed.send(Bytes::from_static(b"abc")).await.unwrap();
+    }
+}
@@ -0,0 +1,405 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Fault injection for the connections made by a client's connector.
+//!
+//! HTTP and RPC clients such as `hyper` and `tonic` open connections through a connector: a
+//! `tower::Service` that takes a destination and returns an IO object. Wrapping that IO object
+//! injects faults into the client without touching its internals. [`PartialConnector`] holds a
+//! connector along with a policy that picks the read and write schedules for each connection,
+//! and [`PartialConnector::connect`] wraps the future returned by the connector so that it yields
+//! a [`PartialDuplex`].
+//!
+//! With the `tower03` feature, `PartialConnector` implements `tower::Service` whenever the
+//! connector does, and [`PartialConnectorLayer`] adds it to a stack of `tower` layers.
+//!
+//! Available with the `futures03` feature.
+//!
+//! # Examples
+//!
+//! ```rust
+//! # #[cfg(feature = "tower03")]
+//! use partial_io::{
+//!     connector::{ConnectionOps, PartialConnector},
+//!     PartialOp,
+//! };
+//! # #[cfg(feature = "tower03")]
+//! use std::io;
+//!
+//! # #[cfg(feature = "tower03")]
+//! # fn main() {
+//! # #[derive(Clone)]
+//! # struct HttpConnector;
+//! # let http_connector = HttpConnector;
+//! // Every third connection is reset after its first 100 bytes of response. With the `tower03`
+//! // feature, the connector can be passed to clients as is, such as `hyper`'s.
+//! let connector = PartialConnector::new(http_connector, |index| {
+//!     if index % 3 == 2 {
+//!         ConnectionOps::new(
+//!             vec![
+//!                 PartialOp::Limited(100),
+//!                 PartialOp::Err(io::ErrorKind::ConnectionReset),
+//!             ],
+//!             vec![],
+//!         )
+//!     } else {
+//!         ConnectionOps::unlimited()
+//!     }
+//! });
+//! # let _ = connector;
+//! # }
+//! #
+//! # #[cfg(not(feature = "tower03"))]
+//! # fn main() {
+//! #     assert!(true, "dummy test");
+//! # }
+//! ```
+//!
+//! [`PartialConnector`]: struct.PartialConnector.html
+//! [`PartialConnector::connect`]: struct.PartialConnector.html#method.connect
+//! [`PartialConnectorLayer`]: struct.PartialConnectorLayer.html
+//! [`PartialDuplex`]: ../struct.PartialDuplex.html
+
+use crate::{
+    make_ops,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    PartialDuplex, PartialOp,
+};
+use futures::ready;
+use pin_project::pin_project;
+use std::{
+    fmt,
+    future::Future,
+    iter,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The schedules of `PartialOp`s for the reads and writes on one connection.
+pub struct ConnectionOps {
+    read: Box<dyn Iterator<Item = PartialOp> + Send>,
+    write: Box<dyn Iterator<Item = PartialOp> + Send>,
+}
+
+impl ConnectionOps {
+    /// Creates a new `ConnectionOps` with the specified `PartialOp`s for reads and writes.
+    pub fn new<IR, IW>(read_ops: IR, write_ops: IW) -> Self
+    where
+        IR: IntoIterator<Item = PartialOp> + 'static,
+        IR::IntoIter: Send,
+        IW: IntoIterator<Item = PartialOp> + 'static,
+        IW::IntoIter: Send,
+    {
+        ConnectionOps {
+            read: make_ops(read_ops),
+            write: make_ops(write_ops),
+        }
+    }
+
+    /// Creates a new `ConnectionOps` that leaves reads and writes unchanged.
+    pub fn unlimited() -> Self {
+        Self::new(iter::empty(), iter::empty())
+    }
+}
+
+impl fmt::Debug for ConnectionOps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionOps").finish()
+    }
+}
+
+/// A connector that wraps every connection it makes in a [`PartialDuplex`], with schedules picked
+/// by a policy.
+///
+/// The policy is called with the index of each connection, counting from 0 in the order they
+/// were established, and returns the schedules for that connection. Clones of a
+/// `PartialConnector` share the policy and the count, as clients often clone their connector.
+///
+/// See [the module level documentation](index.html) for more.
+///
+/// [`PartialDuplex`]: ../struct.PartialDuplex.html
+pub struct PartialConnector<C> {
+    inner: C,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    policy: Box<dyn Fn(usize) -> ConnectionOps + Send + Sync>,
+    connections: AtomicUsize,
+}
+
+impl Shared {
+    fn new(policy: Box<dyn Fn(usize) -> ConnectionOps + Send + Sync>) -> Self {
+        Shared {
+            policy,
+            connections: AtomicUsize::new(0),
+        }
+    }
+
+    fn wrap<S>(&self, io: S) -> PartialDuplex<S> {
+        let index = self.connections.fetch_add(1, Ordering::SeqCst);
+        let ops = (self.policy)(index);
+        PartialDuplex::new(io, ops.read, ops.write)
+    }
+}
+
+impl<C> PartialConnector<C> {
+    /// Creates a new `PartialConnector` around `inner`, picking the schedules for each connection
+    /// with `policy`.
+    pub fn new<P>(inner: C, policy: P) -> Self
+    where
+        P: Fn(usize) -> ConnectionOps + Send + Sync + 'static,
+    {
+        PartialConnector {
+            inner,
+            shared: Arc::new(Shared::new(Box::new(policy))),
+        }
+    }
+
+    /// Acquires a reference to the underlying connector.
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    /// Acquires a mutable reference to the underlying connector.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    /// Consumes this wrapper, returning the underlying connector.
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    /// Returns the number of connections wrapped so far, by this connector and its clones.
+    pub fn connections(&self) -> usize {
+        self.shared.connections.load(Ordering::SeqCst)
+    }
+
+    /// Wraps a connection with the schedules picked by the policy for it.
+    pub fn wrap<S>(&self, io: S) -> PartialDuplex<S> {
+        self.shared.wrap(io)
+    }
+
+    /// Wraps a future returned by the underlying connector, so that the connection it yields is
+    /// wrapped with the schedules picked by the policy for it.
+    pub fn connect<F>(&self, future: F) -> Connecting<F> {
+        Connecting {
+            future,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<C: Clone> Clone for PartialConnector<C> {
+    fn clone(&self) -> Self {
+        PartialConnector {
+            inner: self.inner.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<C: fmt::Debug> fmt::Debug for PartialConnector<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PartialConnector")
+            .field("inner", &self.inner)
+            .field("connections", &self.connections())
+            .finish()
+    }
+}
+
+/// A future returned by [`PartialConnector::connect`], which yields a wrapped connection.
+///
+/// [`PartialConnector::connect`]: struct.PartialConnector.html#method.connect
+#[pin_project]
+pub struct Connecting<F> {
+    #[pin]
+    future: F,
+    shared: Arc<Shared>,
+}
+
+impl<F, S, E> Future for Connecting<F>
+where
+    F: Future<Output = Result<S, E>>,
+{
+    type Output = Result<PartialDuplex<S>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let io = ready!(this.future.poll(cx))?;
+        Poll::Ready(Ok(this.shared.wrap(io)))
+    }
+}
+
+impl<F> fmt::Debug for Connecting<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connecting").finish()
+    }
+}
+
+/// A `tower::Layer` that wraps connectors in a [`PartialConnector`].
+///
+/// Every connector wrapped by the same layer, or by its clones, shares the policy and the count
+/// of connections. Available with the `tower03` feature.
+///
+/// [`PartialConnector`]: struct.PartialConnector.html
+#[cfg(feature = "tower03")]
+#[derive(Clone)]
+pub struct PartialConnectorLayer {
+    shared: Arc<Shared>,
+}
+
+#[cfg(feature = "tower03")]
+impl PartialConnectorLayer {
+    /// Creates a new `PartialConnectorLayer`, picking the schedules for each connection with
+    /// `policy`.
+    pub fn new<P>(policy: P) -> Self
+    where
+        P: Fn(usize) -> ConnectionOps + Send + Sync + 'static,
+    {
+        PartialConnectorLayer {
+            shared: Arc::new(Shared::new(Box::new(policy))),
+        }
+    }
+
+    /// Returns the number of connections wrapped so far, by every connector this layer wrapped.
+    pub fn connections(&self) -> usize {
+        self.shared.connections.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(feature = "tower03")]
+impl<C> tower_layer::Layer<C> for PartialConnectorLayer {
+    type Service = PartialConnector<C>;
+
+    fn layer(&self, inner: C) -> PartialConnector<C> {
+        PartialConnector {
+            inner,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "tower03")]
+impl fmt::Debug for PartialConnectorLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PartialConnectorLayer")
+            .field("connections", &self.connections())
+            .finish()
+    }
+}
+
+#[cfg(feature = "tower03")]
+impl<C, Req> tower_service::Service<Req> for PartialConnector<C>
+where
+    C: tower_service::Service<Req>,
+{
+    type Response = PartialDuplex<C::Response>;
+    type Error = C::Error;
+    type Future = Connecting<C::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        let future = self.inner.call(req);
+        self.connect(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{executor::block_on, future, io::Cursor, AsyncReadExt};
+    use std::io;
+
+    use crate::tests::{assert_send, assert_sync};
+
+    #[test]
+    fn test_sendable() {
+        assert_send::<PartialConnector<()>>();
+        assert_sync::<PartialConnector<()>>();
+    }
+
+    #[test]
+    fn test_policy() {
+        crate::sync::model(|| {
+            let connector = PartialConnector::new((), |index| {
+                ConnectionOps::new(vec![PartialOp::Limited(index + 1)], vec![])
+            });
+            let clone = connector.clone();
+            block_on(async {
+                for (connector, expected) in [(&connector, 1), (&clone, 2)] {
+                    let connecting = future::ok::<_, io::Error>(Cursor::new(b"abc".to_vec()));
+                    let mut stream = connector.connect(connecting).await.unwrap();
+                    let mut buf = [0; 3];
+                    assert_eq!(stream.read(&mut buf).await.unwrap(), expected);
+                }
+            });
+            assert_eq!(connector.connections(), 2);
+
+            let connecting = future::err::<Cursor<Vec<u8>>, _>(io::ErrorKind::ConnectionRefused);
+            assert!(block_on(connector.connect(connecting)).is_err());
+            assert_eq!(
+                connector.connections(),
+                2,
+                "failed connections aren't counted"
+            );
+        });
+    }
+
+    #[cfg(feature = "tower03")]
+    #[test]
+    fn test_tower() {
+        use futures::future::Ready;
+        use tower_layer::Layer;
+        use tower_service::Service;
+
+        struct CursorConnector;
+
+        impl Service<&'static [u8]> for CursorConnector {
+            type Response = Cursor<Vec<u8>>;
+            type Error = io::Error;
+            type Future = Ready<io::Result<Cursor<Vec<u8>>>>;
+
+            fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, data: &'static [u8]) -> Self::Future {
+                future::ok(Cursor::new(data.to_vec()))
+            }
+        }
+
+        let layer = PartialConnectorLayer::new(|index| {
+            ConnectionOps::new(vec![PartialOp::Limited(index + 1)], vec![])
+        });
+        let mut first = layer.layer(CursorConnector);
+        let mut second = layer.layer(CursorConnector);
+        block_on(async {
+            for (connector, expected) in [(&mut first, 1), (&mut second, 2)] {
+                future::poll_fn(|cx| connector.poll_ready(cx))
+                    .await
+                    .unwrap();
+                let mut stream = connector.call(b"abc").await.unwrap();
+                let mut buf = [0; 3];
+                assert_eq!(stream.read(&mut buf).await.unwrap(), expected);
+            }
+        });
+        assert_eq!(
+            layer.connections(),
+            2,
+            "the layer's connectors share the count"
+        );
+    }
+}
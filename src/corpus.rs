@@ -0,0 +1,402 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Saving sequences of `PartialOp`s to disk, and replaying them.
+//!
+//! Sequences are stored in a line-based text format, with one operation per line:
+//!
+//! ```text
+//! # Blank lines and lines starting with '#' are ignored.
+//! limited 3
+//! limited_fraction 1/4
+//! unlimited
+//! err Interrupted
+//! os_err 32
+//! err_times WouldBlock 2
+//! delay_ns 1500000
+//! fragment 4
+//! panic injected by partial-io
+//! [header-retry] err BrokenPipe
+//! ```
+//!
+//! Error kinds are written with their `Debug` names. Names that aren't stable error kinds, such as
+//! ones added by newer versions of Rust or `Uncategorized`, are loaded back as `Other`.
+//! `PartialOp::ErrWith` is saved as `err_with <kind>` and loaded back as `PartialOp::Err(<kind>)`,
+//! since the factory can't be saved. The message of a `PartialOp::Panic` takes up the rest of its
+//! line, with line breaks replaced by spaces. A `PartialOp::Labeled` is saved as its operation,
+//! prefixed with the label in brackets; line breaks and closing brackets in the label are replaced
+//! by spaces. Loading a message or a label leaks it, since the operation holds a `&'static str`.
+//! Leading whitespace on a line is ignored, but trailing whitespace is part of a panic message. A
+//! bare `panic` line is loaded as a panic with an empty message.
+//!
+//! With the `quickcheck1` feature, `#[partial_test]` saves the minimal failing sequence of each
+//! failed test to a corpus directory, if the `PARTIAL_IO_CORPUS_DIR` environment variable is set.
+//! Each test gets its own subdirectory, which is replayed before any new cases are generated.
+//! [`replay_corpus`] replays a directory of saved sequences against any test function: add the
+//! corpus directory to version control or to CI artifacts, and shrunk failures aren't lost once
+//! logs rotate. [`run_with_corpus`] does both for a plain `quickcheck` test and a corpus
+//! directory of its choosing: it replays the saved sequences first, then generates new ones.
+//! Saved files are named after the [`fingerprint`] of their sequence, which failure messages
+//! also print, to tell the same schedule failing again apart from a new failure.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use partial_io::{corpus, PartialOp};
+//! use std::io;
+//!
+//! let ops = vec![PartialOp::Limited(3), PartialOp::Err(io::ErrorKind::Interrupted)];
+//! let text = corpus::encode(&ops);
+//! assert_eq!(text, "limited 3\nerr Interrupted\n");
+//!
+//! let decoded = corpus::decode(&text).unwrap();
+//! assert!(matches!(
+//!     decoded[..],
+//!     [PartialOp::Limited(3), PartialOp::Err(io::ErrorKind::Interrupted)]
+//! ));
+//! ```
+//!
+//! [`fingerprint`]: fn.fingerprint.html
+//! [`replay_corpus`]: fn.replay_corpus.html
+//! [`run_with_corpus`]: ../quickcheck_types/fn.run_with_corpus.html
+
+use crate::{fixtures::HashingWriter, PartialOp};
+use std::{
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// The environment variable that sets the corpus directory for `#[partial_test]`.
+pub const CORPUS_DIR_VAR: &str = "PARTIAL_IO_CORPUS_DIR";
+
+/// The extension of saved sequences.
+const EXTENSION: &str = "ops";
+
+/// The error kinds that can be decoded: every stable kind.
+const ERROR_KINDS: &[io::ErrorKind] = &[
+    io::ErrorKind::NotFound,
+    io::ErrorKind::PermissionDenied,
+    io::ErrorKind::ConnectionRefused,
+    io::ErrorKind::ConnectionReset,
+    io::ErrorKind::HostUnreachable,
+    io::ErrorKind::NetworkUnreachable,
+    io::ErrorKind::ConnectionAborted,
+    io::ErrorKind::NotConnected,
+    io::ErrorKind::AddrInUse,
+    io::ErrorKind::AddrNotAvailable,
+    io::ErrorKind::NetworkDown,
+    io::ErrorKind::BrokenPipe,
+    io::ErrorKind::AlreadyExists,
+    io::ErrorKind::WouldBlock,
+    io::ErrorKind::NotADirectory,
+    io::ErrorKind::IsADirectory,
+    io::ErrorKind::DirectoryNotEmpty,
+    io::ErrorKind::ReadOnlyFilesystem,
+    io::ErrorKind::StaleNetworkFileHandle,
+    io::ErrorKind::InvalidInput,
+    io::ErrorKind::InvalidData,
+    io::ErrorKind::TimedOut,
+    io::ErrorKind::WriteZero,
+    io::ErrorKind::StorageFull,
+    io::ErrorKind::NotSeekable,
+    io::ErrorKind::QuotaExceeded,
+    io::ErrorKind::FileTooLarge,
+    io::ErrorKind::ResourceBusy,
+    io::ErrorKind::ExecutableFileBusy,
+    io::ErrorKind::Deadlock,
+    io::ErrorKind::CrossesDevices,
+    io::ErrorKind::TooManyLinks,
+    io::ErrorKind::InvalidFilename,
+    io::ErrorKind::ArgumentListTooLong,
+    io::ErrorKind::Interrupted,
+    io::ErrorKind::Unsupported,
+    io::ErrorKind::UnexpectedEof,
+    io::ErrorKind::OutOfMemory,
+    io::ErrorKind::Other,
+];
+
+/// Encodes a sequence of operations in the corpus format.
+///
+/// The factory of each `PartialOp::ErrWith` is called once, to find the kind of its error.
+pub fn encode(ops: &[PartialOp]) -> String {
+    let mut out = String::new();
+    for op in ops {
+        encode_op(&mut out, op);
+    }
+    out
+}
+
+fn encode_op(out: &mut String, op: &PartialOp) {
+    // Writing to a String can't fail.
+    let _ = writeln!(out, "{}", op);
+}
+
+/// Decodes a sequence of operations in the corpus format.
+///
+/// Returns an error of kind `InvalidData` if a line can't be decoded.
+pub fn decode(text: &str) -> io::Result<Vec<PartialOp>> {
+    text.lines()
+        .enumerate()
+        // Only leading whitespace is trimmed, since the message of a panic extends to the end
+        // of its line.
+        .map(|(index, line)| (index, line.trim_start()))
+        .filter(|(_, line)| !line.trim_end().is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            decode_op(line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: invalid operation: {:?}", index + 1, line),
+                )
+            })
+        })
+        .collect()
+}
+
+fn decode_op(line: &str) -> Option<PartialOp> {
+    if let Some(message) = line.strip_prefix("panic ") {
+        return Some(PartialOp::Panic(Box::leak(message.into())));
+    }
+    // An empty message is saved as `panic `, which loses its space if trailing whitespace is
+    // stripped.
+    if line.trim_end() == "panic" {
+        return Some(PartialOp::Panic(""));
+    }
+    if let Some(rest) = line.strip_prefix('[') {
+        let (label, op) = rest.split_once("] ")?;
+        return Some(decode_op(op)?.labeled(Box::leak(label.into())));
+    }
+    let words: Vec<_> = line.split_whitespace().collect();
+    let op = match words[..] {
+        ["limited", n] => PartialOp::Limited(n.parse().ok()?),
+        ["limited_fraction", fraction] => {
+            let (num, den) = fraction.split_once('/')?;
+            PartialOp::LimitedFraction(num.parse().ok()?, den.parse().ok()?)
+        }
+        ["unlimited"] => PartialOp::Unlimited,
+        ["err", kind] | ["err_with", kind] => PartialOp::Err(decode_kind(kind)),
+        ["os_err", code] => PartialOp::OsErr(code.parse().ok()?),
+        ["err_times", kind, n] => PartialOp::ErrTimes(decode_kind(kind), n.parse().ok()?),
+        ["delay_ns", nanos] => PartialOp::Delay(Duration::from_nanos(nanos.parse().ok()?)),
+        ["fragment", n] => PartialOp::Fragment(n.parse().ok()?),
+        _ => return None,
+    };
+    Some(op)
+}
+
+// Unknown names decode to `Other`, so that a corpus saved by a newer toolchain still loads.
+fn decode_kind(name: &str) -> io::ErrorKind {
+    ERROR_KINDS
+        .iter()
+        .copied()
+        .find(|kind| format!("{:?}", kind) == name)
+        .unwrap_or(io::ErrorKind::Other)
+}
+
+/// Returns a short hash of a sequence of operations, which stays the same across runs and
+/// builds.
+///
+/// This tells a schedule that fails again apart from a new failure, for example when triaging
+/// flaky tests across many CI runs. The fingerprint is the checksum of the corpus encoding of the
+/// sequence, so sequences that are saved the same way have the same fingerprint, and `save`
+/// names files after it. Fingerprints are printed as 16 hexadecimal digits, with `{:016x}`.
+///
+/// As with `encode`, the factory of a `PartialOp::ErrWith` is called to find the kind of its
+/// error, so failure messages that include a fingerprint call it too.
+///
+/// # Examples
+///
+/// ```rust
+/// use partial_io::{corpus, PartialOp};
+/// use std::io;
+///
+/// let ops = vec![PartialOp::Limited(3), PartialOp::Err(io::ErrorKind::Interrupted)];
+/// assert_eq!(corpus::fingerprint(&ops), corpus::fingerprint(&ops.clone()));
+/// assert_ne!(corpus::fingerprint(&ops), corpus::fingerprint(&ops[..1]));
+/// ```
+pub fn fingerprint(ops: &[PartialOp]) -> u64 {
+    HashingWriter::checksum_of(encode(ops).as_bytes())
+}
+
+/// Saves a sequence of operations to `dir`, creating the directory if necessary.
+///
+/// The file is named after the fingerprint of the sequence, so saving the same sequence twice
+/// leaves a single file. Returns the path of the file.
+pub fn save(dir: impl AsRef<Path>, ops: &[PartialOp]) -> io::Result<PathBuf> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{:016x}.{}", fingerprint(ops), EXTENSION));
+    fs::write(&path, encode(ops))?;
+    Ok(path)
+}
+
+/// Loads every sequence saved in `dir`, sorted by path.
+///
+/// A directory that doesn't exist is treated as empty.
+pub fn load(dir: impl AsRef<Path>) -> io::Result<Vec<(PathBuf, Vec<PartialOp>)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    let mut paths = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == EXTENSION) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let ops = decode(&fs::read_to_string(&path)?).map_err(|err| {
+                io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+            })?;
+            Ok((path, ops))
+        })
+        .collect()
+}
+
+/// Replays every sequence saved in `dir` against `test_fn`, returning the number of sequences.
+///
+/// `test_fn` takes any generated input that can be created from a sequence, such as
+/// `PartialWithErrors<GE>`, and returns anything `quickcheck` can test.
+///
+/// Available with the `quickcheck1` feature.
+///
+/// # Panics
+///
+/// Panics if the directory can't be read, or with the path of the first sequence that fails.
+#[cfg(feature = "quickcheck1")]
+pub fn replay_corpus<A, T>(dir: impl AsRef<Path>, test_fn: fn(A) -> T) -> usize
+where
+    A: From<Vec<PartialOp>>,
+    T: quickcheck::Testable,
+{
+    use quickcheck::Gen;
+    use std::panic::{self, AssertUnwindSafe};
+
+    let dir = dir.as_ref();
+    let cases =
+        load(dir).unwrap_or_else(|err| panic!("failed to load corpus {}: {}", dir.display(), err));
+    for (path, ops) in &cases {
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            test_fn(A::from(ops.clone())).result(&mut Gen::new(0))
+        }));
+        if res.map_or(true, |res| res.is_failure()) {
+            panic!("replaying {} failed: {:?}", path.display(), ops);
+        }
+    }
+    cases.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let ops = vec![
+            PartialOp::Limited(3),
+            PartialOp::LimitedFraction(1, 4),
+            PartialOp::Unlimited,
+            PartialOp::Err(io::ErrorKind::Interrupted),
+            PartialOp::OsErr(32),
+            PartialOp::ErrTimes(io::ErrorKind::WouldBlock, 2),
+            PartialOp::Delay(Duration::from_micros(1500)),
+            PartialOp::Fragment(4),
+            PartialOp::Panic("a panic"),
+            PartialOp::Limited(2).labeled("a label"),
+            PartialOp::Panic("two\nlines"),
+            PartialOp::Unlimited.labeled("a]b"),
+            PartialOp::err_with(|| io::Error::new(io::ErrorKind::TimedOut, "custom")),
+        ];
+        for op in &ops {
+            assert_eq!(encode(std::slice::from_ref(op)), format!("{}\n", op));
+        }
+        let text = encode(&ops);
+        let decoded = decode(&format!("# A comment.\n\n{}", text)).unwrap();
+        assert_eq!(encode(&decoded), text.replace("err_with", "err"));
+
+        let err = decode("limited 3\nlimited x\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("line 2:"), "{}", err);
+    }
+
+    #[test]
+    fn test_panic_messages() {
+        for message in ["", " padded ", "two  spaces", "trailing\t"] {
+            let decoded = decode(&encode(&[PartialOp::Panic(message)])).unwrap();
+            assert!(
+                matches!(decoded[..], [PartialOp::Panic(m)] if m == message),
+                "{:?} decoded as {:?}",
+                message,
+                decoded
+            );
+        }
+        let decoded = decode("panic\n[label] panic").unwrap();
+        assert!(matches!(
+            &decoded[..],
+            [PartialOp::Panic(""), PartialOp::Labeled(op, "label")]
+                if matches!(**op, PartialOp::Panic(""))
+        ));
+        let decoded = decode("  [label] panic  x \n").unwrap();
+        assert!(matches!(
+            &decoded[..],
+            [PartialOp::Labeled(op, "label")] if matches!(**op, PartialOp::Panic(" x "))
+        ));
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        for &kind in ERROR_KINDS {
+            let ops = [
+                PartialOp::Err(kind),
+                PartialOp::ErrTimes(kind, 2),
+                PartialOp::err_with(move || kind.into()),
+            ];
+            let decoded = decode(&encode(&ops)).unwrap();
+            assert!(
+                matches!(
+                    decoded[..],
+                    [PartialOp::Err(a), PartialOp::ErrTimes(b, 2), PartialOp::Err(c)]
+                        if a == kind && b == kind && c == kind
+                ),
+                "{:?} didn't round-trip: {:?}",
+                kind,
+                decoded
+            );
+        }
+
+        let decoded = decode("err Uncategorized\nerr_times SomethingNew 3\n").unwrap();
+        assert!(matches!(
+            decoded[..],
+            [
+                PartialOp::Err(io::ErrorKind::Other),
+                PartialOp::ErrTimes(io::ErrorKind::Other, 3)
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = std::env::temp_dir().join(format!("partial-io-corpus-{}", std::process::id()));
+        let ops = vec![PartialOp::Limited(1), PartialOp::Err(io::ErrorKind::Other)];
+        let path = save(&dir, &ops).unwrap();
+        assert_eq!(save(&dir, &ops).unwrap(), path, "saving is idempotent");
+        assert!(path.ends_with(format!("{:016x}.ops", fingerprint(&ops))));
+
+        let loaded = load(&dir).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].0, path);
+        assert_eq!(encode(&loaded[0].1), encode(&ops));
+        assert!(load(dir.join("missing")).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,748 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! This module contains a duplex wrapper that breaks reads and writes up according to two
+//! separate iterators.
+
+use crate::{
+    AppliedOp, FuturesOps, PartialAsyncRead, PartialAsyncWrite, PartialOp, WriteAfterShutdown,
+};
+use futures::prelude::*;
+use pin_project::pin_project;
+use std::{
+    fmt, io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A wrapper that breaks inner duplex streams up according to two provided iterators, one for
+/// reads and one for writes.
+///
+/// Read ops are consumed by `poll_read` and `poll_fill_buf`. Write ops are consumed by
+/// `poll_write`, `poll_flush`, and `poll_close` or `poll_shutdown`. This is equivalent to nesting a
+/// `PartialAsyncWrite` inside a `PartialAsyncRead`, but a single wrapper is easier to pass around.
+///
+/// Available with the `futures03` feature for `futures` traits, and with the `tokio1` feature for
+/// `tokio` traits.
+///
+/// # Examples
+///
+/// This example uses `tokio`.
+///
+/// ```rust
+/// # #[cfg(feature = "tokio1")]
+/// use partial_io::{PartialDuplex, PartialOp};
+/// # #[cfg(feature = "tokio1")]
+/// use std::io;
+/// # #[cfg(feature = "tokio1")]
+/// use tokio::io::{AsyncReadExt, AsyncWriteExt};
+///
+/// # #[cfg(feature = "tokio1")]
+/// #[tokio::main]
+/// async fn main() -> io::Result<()> {
+///     let (client, mut server) = tokio::io::duplex(64);
+///     let read_ops = vec![PartialOp::Limited(2)];
+///     let write_ops = vec![PartialOp::Err(io::ErrorKind::WouldBlock), PartialOp::Limited(1)];
+///     let mut partial_client = PartialDuplex::new(client, read_ops, write_ops);
+///
+///     // This causes poll_write to be called twice, yielding after the first call (WouldBlock).
+///     assert_eq!(partial_client.write(b"ping").await?, 1);
+///
+///     server.write_all(b"pong").await?;
+///     let mut buf = [0; 4];
+///     assert_eq!(partial_client.read(&mut buf).await?, 2);
+///     assert_eq!(&buf[..2], b"po");
+///
+///     Ok(())
+/// }
+///
+/// # #[cfg(not(feature = "tokio1"))]
+/// # fn main() {
+/// #     assert!(true, "dummy test");
+/// # }
+/// ```
+///
+/// # Deterministic simulation
+///
+/// Streams from simulated networks, such as `turmoil::net::TcpStream`, implement `tokio`'s traits
+/// and can be wrapped like any other. `turmoil` models latency and partitions between hosts, but
+/// delivers every write in one piece; a `PartialDuplex` adds the byte-level partiality. The
+/// simulation stays deterministic as long as the schedules do:
+///
+/// * `PartialOp::Delay` uses `tokio::time` through [`DefaultClock`], so it runs on the
+///   simulation's clock rather than the real one.
+/// * `PartialOp::Err(io::ErrorKind::WouldBlock)` returns `Poll::Pending` and wakes the task, which
+///   lets the simulation schedule other hosts in between.
+/// * Seeded schedules, such as [`scenarios::tcp_segments`], are reproducible from the
+///   simulation's own seed.
+///
+/// With the `turmoil06` feature, the [`simulation`] module wraps `turmoil`'s streams this way.
+///
+/// [`DefaultClock`]: time/struct.DefaultClock.html
+/// [`scenarios::tcp_segments`]: scenarios/fn.tcp_segments.html
+/// [`simulation`]: simulation/index.html
+#[pin_project]
+pub struct PartialDuplex<S> {
+    #[pin]
+    inner: PartialAsyncRead<PartialAsyncWrite<S>>,
+    trace: Option<Vec<TracedPoll>>,
+}
+
+/// A direction of a [`PartialDuplex`]: reads or writes.
+///
+/// [`PartialDuplex`]: struct.PartialDuplex.html
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DuplexSide {
+    /// `poll_read` and `poll_fill_buf`.
+    Read,
+    /// `poll_write`, `poll_flush`, and `poll_close` or `poll_shutdown`.
+    Write,
+}
+
+/// Statistics for one direction of a [`PartialDuplex`].
+///
+/// Returned by `PartialDuplex::read_stats` and `PartialDuplex::write_stats`.
+///
+/// [`PartialDuplex`]: struct.PartialDuplex.html
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DuplexStats {
+    /// The number of calls made in this direction, including retries after `Poll::Pending`.
+    pub calls: usize,
+    /// The number of calls that returned `Poll::Pending`, whether from the operations or from
+    /// the inner stream.
+    pub pendings: usize,
+    /// The number of `Poll::Pending`s passed a waker that won't wake the same task as the one
+    /// before. See `FuturesOps::waker_stats` for more.
+    pub waker_changes: usize,
+    /// The number of times the task was woken because of `PartialOp::Err(WouldBlock)`.
+    pub wakes_issued: usize,
+    /// The number of calls made without a wake since this direction last returned
+    /// `Poll::Pending`, while busy-poll detection was enabled.
+    pub busy_polls: usize,
+    /// The number of clones of wakers passed to the inner stream in this direction that were
+    /// neither woken nor dropped, while waker leak detection was enabled.
+    pub outstanding_wakers: usize,
+}
+
+/// A poll of a [`PartialDuplex`], recorded by `PartialDuplex::record_trace`.
+///
+/// [`PartialDuplex`]: struct.PartialDuplex.html
+#[derive(Clone, Debug)]
+pub struct DuplexPoll {
+    /// The direction of the poll.
+    pub side: DuplexSide,
+    /// The name of the method, such as `"poll_read"` or `"poll_flush"`.
+    pub method: &'static str,
+    /// The operations applied during the poll, including those retried after `Interrupted`.
+    pub ops: Vec<AppliedOp>,
+    /// Whether the poll returned `Poll::Pending`.
+    pub pending: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct TracedPoll {
+    side: DuplexSide,
+    method: &'static str,
+    // The number of operations applied in this direction before the poll.
+    start: usize,
+    pending: bool,
+}
+
+impl<S> PartialAsyncRead<PartialAsyncWrite<S>> {
+    fn applied_len(&self, side: DuplexSide) -> usize {
+        match side {
+            DuplexSide::Read => self.applied_ops().len(),
+            DuplexSide::Write => self.get_ref().applied_ops().len(),
+        }
+    }
+}
+
+fn trace_poll<T>(
+    trace: &mut Option<Vec<TracedPoll>>,
+    side: DuplexSide,
+    method: &'static str,
+    start: usize,
+    res: &Poll<T>,
+) {
+    if let Some(trace) = trace {
+        trace.push(TracedPoll {
+            side,
+            method,
+            start,
+            pending: res.is_pending(),
+        });
+    }
+}
+
+impl<S> PartialDuplex<S> {
+    /// Creates a new `PartialDuplex` wrapper over the stream with the specified `PartialOp`s for
+    /// reads and writes.
+    pub fn new<IR, IW>(inner: S, read_ops: IR, write_ops: IW) -> Self
+    where
+        IR: IntoIterator<Item = PartialOp> + 'static,
+        IR::IntoIter: Send,
+        IW: IntoIterator<Item = PartialOp> + 'static,
+        IW::IntoIter: Send,
+    {
+        PartialDuplex {
+            inner: PartialAsyncRead::new(PartialAsyncWrite::new(inner, write_ops), read_ops),
+            trace: None,
+        }
+    }
+
+    /// Sets the `PartialOp`s for reads from this stream.
+    pub fn set_read_ops<I>(&mut self, iter: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        self.inner.set_ops(iter);
+        self
+    }
+
+    /// Sets the `PartialOp`s for writes to this stream.
+    pub fn set_write_ops<I>(&mut self, iter: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        self.inner.get_mut().set_ops(iter);
+        self
+    }
+
+    /// Sets the `PartialOp`s for reads from this stream in a pinned context.
+    pub fn pin_set_read_ops<I>(self: Pin<&mut Self>, iter: I) -> Pin<&mut Self>
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        let mut this = self;
+        this.as_mut().project().inner.pin_set_ops(iter);
+        this
+    }
+
+    /// Sets the `PartialOp`s for writes to this stream in a pinned context.
+    pub fn pin_set_write_ops<I>(self: Pin<&mut Self>, iter: I) -> Pin<&mut Self>
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        let mut this = self;
+        this.as_mut()
+            .project()
+            .inner
+            .pin_get_mut()
+            .pin_set_ops(iter);
+        this
+    }
+
+    /// Makes every flush and shutdown return `Poll::Pending` once before it proceeds.
+    ///
+    /// See `PartialAsyncWrite::set_pending_before_flush` for more.
+    pub fn set_pending_before_flush(&mut self, enabled: bool) -> &mut Self {
+        self.inner.get_mut().set_pending_before_flush(enabled);
+        self
+    }
+
+    /// Makes flushes of the write side that take a `PartialOp::Limited` or `PartialOp::Fragment`
+    /// return `Poll::Pending` after flushing the inner stream.
+    ///
+    /// See `PartialAsyncWrite::set_partial_flushes` for more.
+    pub fn set_partial_flushes(&mut self, enabled: bool) -> &mut Self {
+        self.inner.get_mut().set_partial_flushes(enabled);
+        self
+    }
+
+    /// Enables or disables checks that shutdown isn't polled again once it has completed.
+    ///
+    /// See `PartialAsyncWrite::set_shutdown_checks` for more.
+    pub fn set_shutdown_checks(&mut self, enabled: bool) -> &mut Self {
+        self.inner.get_mut().set_shutdown_checks(enabled);
+        self
+    }
+
+    /// Returns the operations of reads on this stream, along with their wake and poll statistics.
+    pub fn read_ops(&self) -> &FuturesOps {
+        self.inner.ops()
+    }
+
+    /// Returns the operations of reads on this stream, to set their clock and how they deliver
+    /// wakes, or to enable checks on how they're polled.
+    ///
+    /// Reads and writes are configured separately, so a setting that should apply in both
+    /// directions needs to be made through `write_ops_mut` as well.
+    pub fn read_ops_mut(&mut self) -> &mut FuturesOps {
+        self.inner.ops_mut()
+    }
+
+    /// Returns the operations of writes on this stream, along with their wake and poll
+    /// statistics.
+    pub fn write_ops(&self) -> &FuturesOps {
+        self.inner.get_ref().ops()
+    }
+
+    /// Returns the operations of writes on this stream, to set their clock and how they deliver
+    /// wakes, or to enable checks on how they're polled.
+    ///
+    /// See `read_ops_mut` for more.
+    pub fn write_ops_mut(&mut self) -> &mut FuturesOps {
+        self.inner.get_mut().ops_mut()
+    }
+
+    /// Returns the statistics of reads on this stream.
+    pub fn read_stats(&self) -> DuplexStats {
+        side_stats(self.inner.ops())
+    }
+
+    /// Returns the statistics of writes on this stream.
+    ///
+    /// Together with `read_stats`, this tells which direction stalled, for example in an echo
+    /// test where both directions are polled by the same task.
+    pub fn write_stats(&self) -> DuplexStats {
+        side_stats(self.inner.get_ref().ops())
+    }
+
+    /// Starts recording the polls of this stream in both directions, along with the operations
+    /// applied during each of them.
+    ///
+    /// This also starts recording the operations of each direction, as with
+    /// `PartialAsyncRead::record_ops`. Any polls and operations recorded earlier are cleared.
+    pub fn record_trace(&mut self) -> &mut Self {
+        self.inner.record_ops();
+        self.inner.get_mut().record_ops();
+        self.trace = Some(Vec::new());
+        self
+    }
+
+    /// Returns the operations applied by reads since `record_trace` was called.
+    pub fn read_applied_ops(&self) -> &[AppliedOp] {
+        self.inner.applied_ops()
+    }
+
+    /// Returns the operations applied by writes since `record_trace` was called.
+    pub fn write_applied_ops(&self) -> &[AppliedOp] {
+        self.inner.get_ref().applied_ops()
+    }
+
+    /// Returns the polls of this stream in both directions since `record_trace` was called, in
+    /// the order they were made.
+    pub fn trace(&self) -> Vec<DuplexPoll> {
+        let trace = self.trace.as_deref().unwrap_or(&[]);
+        trace
+            .iter()
+            .enumerate()
+            .map(|(i, poll)| {
+                let applied = match poll.side {
+                    DuplexSide::Read => self.read_applied_ops(),
+                    DuplexSide::Write => self.write_applied_ops(),
+                };
+                // The operations of a poll end where the next poll in the same direction starts.
+                let end = trace[i + 1..]
+                    .iter()
+                    .find(|next| next.side == poll.side)
+                    .map_or(applied.len(), |next| next.start);
+                DuplexPoll {
+                    side: poll.side,
+                    method: poll.method,
+                    ops: applied.get(poll.start..end).unwrap_or(&[]).to_vec(),
+                    pending: poll.pending,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the number of reads into a non-empty buffer made after this stream returned EOF.
+    ///
+    /// See `PartialRead::reads_after_eof` for more.
+    pub fn reads_after_eof(&self) -> usize {
+        self.inner.reads_after_eof()
+    }
+
+    /// Makes reads fail once more than `max` reads were made after this stream returned EOF, or
+    /// removes the limit if `max` is `None`.
+    ///
+    /// See `PartialRead::set_max_reads_after_eof` for more.
+    pub fn set_max_reads_after_eof(&mut self, max: Option<usize>) -> &mut Self {
+        self.inner.set_max_reads_after_eof(max);
+        self
+    }
+
+    /// Makes writes after a successful shutdown fail with `BrokenPipe`.
+    ///
+    /// See `PartialAsyncWrite::set_reject_writes_after_shutdown` for more.
+    pub fn set_reject_writes_after_shutdown(&mut self, enabled: bool) -> &mut Self {
+        self.inner
+            .get_mut()
+            .set_reject_writes_after_shutdown(enabled);
+        self
+    }
+
+    /// Returns the writes issued after a successful shutdown so far.
+    ///
+    /// See `PartialAsyncWrite::writes_after_shutdown` for more.
+    pub fn writes_after_shutdown(&self) -> &[WriteAfterShutdown] {
+        self.inner.get_ref().writes_after_shutdown()
+    }
+
+    /// Panics if shutdown was never driven to completion on this stream.
+    ///
+    /// See `PartialAsyncWrite::assert_shutdown_completed` for more.
+    #[track_caller]
+    pub fn assert_shutdown_completed(&self) {
+        self.inner.get_ref().assert_shutdown_completed();
+    }
+
+    /// Returns a shared reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        self.inner.get_ref().get_ref()
+    }
+
+    /// Returns a mutable reference to the underlying stream.
+    pub fn get_mut(&mut self) -> &mut S {
+        self.inner.get_mut().get_mut()
+    }
+
+    /// Returns a pinned mutable reference to the underlying stream.
+    pub fn pin_get_mut(self: Pin<&mut Self>) -> Pin<&mut S> {
+        self.project().inner.pin_get_mut().pin_get_mut()
+    }
+
+    /// Returns a pinned mutable reference to the underlying stream.
+    ///
+    /// This is the same as `pin_get_mut`, under the name used by `tokio`'s wrappers.
+    pub fn get_pin_mut(self: Pin<&mut Self>) -> Pin<&mut S> {
+        self.pin_get_mut()
+    }
+
+    /// Consumes this wrapper, returning the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.inner.into_inner().into_inner()
+    }
+
+    /// Splits this wrapper into a read half and a write half, with `tokio::io::split`.
+    ///
+    /// The read half keeps consuming the read ops and the write half the write ops, along with
+    /// the clock, wake delivery and any other settings of each direction. Unlike the halves
+    /// returned by calling `tokio::io::split` on this wrapper, each half can still be
+    /// controlled and inspected on its own: for example, `set_ops` and `ops_mut` only affect one
+    /// direction.
+    ///
+    /// Available with the `tokio1` feature.
+    #[cfg(feature = "tokio1")]
+    pub fn into_split(
+        self,
+    ) -> (
+        PartialAsyncRead<tokio::io::ReadHalf<S>>,
+        PartialAsyncWrite<tokio::io::WriteHalf<S>>,
+    )
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+    {
+        let (write, read) = self.inner.replace_inner(());
+        let (stream, write) = write.replace_inner(());
+        let (read_half, write_half) = tokio::io::split(stream);
+        (
+            read.replace_inner(read_half).1,
+            write.replace_inner(write_half).1,
+        )
+    }
+}
+
+fn side_stats(ops: &crate::FuturesOps) -> DuplexStats {
+    let (pendings, waker_changes) = ops.waker_stats();
+    DuplexStats {
+        calls: ops.engine().call_count(),
+        pendings,
+        waker_changes,
+        wakes_issued: ops.wakes_issued(),
+        busy_polls: ops.busy_polls(),
+        outstanding_wakers: ops.outstanding_wakers(),
+    }
+}
+
+// ---
+// Futures impls
+// ---
+
+impl<S> AsyncRead for PartialDuplex<S>
+where
+    S: AsyncRead,
+{
+    #[inline]
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let start = this.inner.applied_len(DuplexSide::Read);
+        let res = this.inner.poll_read(cx, buf);
+        trace_poll(this.trace, DuplexSide::Read, "poll_read", start, &res);
+        res
+    }
+}
+
+impl<S> AsyncBufRead for PartialDuplex<S>
+where
+    S: AsyncBufRead,
+{
+    #[inline]
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+        let this = self.project();
+        let start = this.inner.applied_len(DuplexSide::Read);
+        let res = this.inner.poll_fill_buf(cx);
+        trace_poll(this.trace, DuplexSide::Read, "poll_fill_buf", start, &res);
+        res
+    }
+
+    #[inline]
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.project().inner.consume(amt)
+    }
+}
+
+impl<S> AsyncWrite for PartialDuplex<S>
+where
+    S: AsyncWrite,
+{
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let start = this.inner.applied_len(DuplexSide::Write);
+        let res = this.inner.poll_write(cx, buf);
+        trace_poll(this.trace, DuplexSide::Write, "poll_write", start, &res);
+        res
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let start = this.inner.applied_len(DuplexSide::Write);
+        let res = this.inner.poll_flush(cx);
+        trace_poll(this.trace, DuplexSide::Write, "poll_flush", start, &res);
+        res
+    }
+
+    #[inline]
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let start = this.inner.applied_len(DuplexSide::Write);
+        let res = this.inner.poll_close(cx);
+        trace_poll(this.trace, DuplexSide::Write, "poll_close", start, &res);
+        res
+    }
+}
+
+// ---
+// Tokio impls
+// ---
+
+#[cfg(feature = "tokio1")]
+mod tokio_impl {
+    use super::{trace_poll, DuplexSide, PartialDuplex};
+    use std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+
+    impl<S> AsyncRead for PartialDuplex<S>
+    where
+        S: AsyncRead,
+    {
+        #[inline]
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let this = self.project();
+            let start = this.inner.applied_len(DuplexSide::Read);
+            let res = this.inner.poll_read(cx, buf);
+            trace_poll(this.trace, DuplexSide::Read, "poll_read", start, &res);
+            res
+        }
+    }
+
+    impl<S> AsyncBufRead for PartialDuplex<S>
+    where
+        S: AsyncBufRead,
+    {
+        #[inline]
+        fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+            let this = self.project();
+            let start = this.inner.applied_len(DuplexSide::Read);
+            let res = this.inner.poll_fill_buf(cx);
+            trace_poll(this.trace, DuplexSide::Read, "poll_fill_buf", start, &res);
+            res
+        }
+
+        #[inline]
+        fn consume(self: Pin<&mut Self>, amt: usize) {
+            self.project().inner.consume(amt)
+        }
+    }
+
+    impl<S> AsyncWrite for PartialDuplex<S>
+    where
+        S: AsyncWrite,
+    {
+        #[inline]
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.project();
+            let start = this.inner.applied_len(DuplexSide::Write);
+            let res = this.inner.poll_write(cx, buf);
+            trace_poll(this.trace, DuplexSide::Write, "poll_write", start, &res);
+            res
+        }
+
+        #[inline]
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            let this = self.project();
+            let start = this.inner.applied_len(DuplexSide::Write);
+            let res = this.inner.poll_flush(cx);
+            trace_poll(this.trace, DuplexSide::Write, "poll_flush", start, &res);
+            res
+        }
+
+        #[inline]
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            let this = self.project();
+            let start = this.inner.applied_len(DuplexSide::Write);
+            let res = this.inner.poll_shutdown(cx);
+            trace_poll(this.trace, DuplexSide::Write, "poll_shutdown", start, &res);
+            res
+        }
+    }
+}
+
+impl<S> fmt::Debug for PartialDuplex<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PartialDuplex")
+            .field("inner", self.get_ref())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+
+    use crate::tests::{assert_send, assert_sync};
+
+    #[test]
+    fn test_sendable() {
+        assert_send::<PartialDuplex<File>>();
+        assert_sync::<PartialDuplex<File>>();
+    }
+
+    #[test]
+    fn test_trace() {
+        use futures::{executor::block_on, io::Cursor};
+
+        let read_ops = vec![
+            PartialOp::Err(io::ErrorKind::WouldBlock),
+            PartialOp::Limited(2),
+        ];
+        let write_ops = vec![PartialOp::Limited(1)];
+        let mut stream = PartialDuplex::new(Cursor::new(b"pong".to_vec()), read_ops, write_ops);
+        stream.record_trace();
+        block_on(async {
+            let mut buf = [0; 4];
+            assert_eq!(stream.read(&mut buf).await.unwrap(), 2);
+            assert_eq!(stream.write(b"ping").await.unwrap(), 1);
+        });
+
+        let read_stats = stream.read_stats();
+        assert_eq!(
+            (
+                read_stats.calls,
+                read_stats.pendings,
+                read_stats.wakes_issued
+            ),
+            (2, 1, 1)
+        );
+        let write_stats = stream.write_stats();
+        assert_eq!((write_stats.calls, write_stats.pendings), (1, 0));
+        assert_eq!(stream.read_applied_ops().len(), 2);
+        assert_eq!(stream.write_applied_ops().len(), 1);
+
+        let trace: Vec<_> = stream
+            .trace()
+            .into_iter()
+            .map(|poll| {
+                let ops: Vec<_> = poll.ops.into_iter().map(|applied| applied.op).collect();
+                (poll.side, poll.method, ops, poll.pending)
+            })
+            .collect();
+        assert_eq!(
+            trace,
+            [
+                (
+                    DuplexSide::Read,
+                    "poll_read",
+                    vec![PartialOp::Err(io::ErrorKind::WouldBlock)],
+                    true
+                ),
+                (
+                    DuplexSide::Read,
+                    "poll_read",
+                    vec![PartialOp::Limited(2)],
+                    false
+                ),
+                (
+                    DuplexSide::Write,
+                    "poll_write",
+                    vec![PartialOp::Limited(1)],
+                    false
+                ),
+            ]
+        );
+    }
+
+    #[cfg(feature = "tokio1")]
+    #[tokio::test]
+    async fn test_into_split() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client, mut server) = tokio::io::duplex(64);
+        let read_ops = vec![PartialOp::Limited(2)];
+        let write_ops = vec![
+            PartialOp::Err(io::ErrorKind::WouldBlock),
+            PartialOp::Limited(1),
+        ];
+        let partial_client = PartialDuplex::new(client, read_ops, write_ops);
+        let (mut read_half, mut write_half) = partial_client.into_split();
+
+        assert_eq!(write_half.write(b"ping").await.unwrap(), 1);
+        assert_eq!(write_half.ops().wakes_issued(), 1);
+        assert_eq!(read_half.ops().wakes_issued(), 0);
+
+        server.write_all(b"pong").await.unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(read_half.read(&mut buf).await.unwrap(), 2);
+        // Replacing the read ops doesn't affect the write half.
+        read_half.set_ops(vec![PartialOp::Limited(1)]);
+        assert_eq!(read_half.read(&mut buf[2..]).await.unwrap(), 1);
+        assert_eq!(write_half.write(b"ing").await.unwrap(), 3);
+
+        let mut received = [0; 4];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+        assert_eq!(&buf[..3], b"pon");
+    }
+}
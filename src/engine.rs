@@ -0,0 +1,1501 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! This module contains the building blocks for partial wrappers: `OpEngine`, which applies
+//! `PartialOp`s to IO calls, and `PartialWrap`, which is implemented by wrappers built on it.
+
+use crate::{corpus, exclusive::Exclusive, make_ops, PartialOp};
+use std::{any::Any, cmp, fmt, io, iter, panic, thread, vec};
+
+/// The engine that applies `PartialOp`s to blocking IO calls, for use in custom wrappers.
+///
+/// This is what `PartialRead` and `PartialWrite` use internally, and what `FuturesOps` builds on
+/// for async wrappers. Each call to [`run`] or [`run_no_limit`] consumes the next operation:
+///
+/// * `PartialOp::Limited(n)` calls the callback with `Some(n)`, capped to the number of bytes
+///   remaining.
+/// * `PartialOp::Unlimited` (and the end of the iterator) calls the callback with `None`.
+/// * `PartialOp::Err(kind)` returns an `io::Error` of that kind, without calling the callback.
+///   `PartialOp::ErrTimes(kind, n)` does the same for the next `n` calls.
+/// * `PartialOp::OsErr(code)` returns `io::Error::from_raw_os_error(code)`, without calling the
+///   callback.
+/// * `PartialOp::ErrWith(factory)` returns the error created by `factory`, without calling the
+///   callback.
+/// * `PartialOp::Delay(duration)` blocks the current thread, then calls the callback with `None`.
+/// * `PartialOp::Fragment(n)` behaves the same as `Limited(n)`, unless the call goes through
+///   [`run_fragmented`], which calls the callback several times.
+/// * `PartialOp::Panic(message)` panics with `message`, without calling the callback.
+///
+/// If [`set_max_op_size`] was called, `None` becomes `Some(max_op_size)` and `Some(n)` is capped
+/// to `max_op_size` when they would exceed it.
+///
+/// `OpEngine` is `Send` and `Sync`, so wrappers built on it can be shared across threads.
+///
+/// Since wrappers built on `OpEngine` take the same iterators of `PartialOp`s as the wrappers in
+/// this crate, the generators in `quickcheck_types` can be used with them as well.
+///
+/// # Examples
+///
+/// A wrapper for a custom object store trait:
+///
+/// ```rust
+/// use partial_io::{OpEngine, PartialOp, PartialWrap};
+/// use std::io;
+///
+/// trait ObjectStore {
+///     fn get(&mut self, key: &str, buf: &mut [u8]) -> io::Result<usize>;
+/// }
+///
+/// struct PartialStore<S> {
+///     inner: S,
+///     engine: OpEngine,
+/// }
+///
+/// impl<S> PartialWrap for PartialStore<S> {
+///     type Inner = S;
+///
+///     fn from_engine(inner: S, engine: OpEngine) -> Self {
+///         PartialStore { inner, engine }
+///     }
+///
+///     fn engine_mut(&mut self) -> &mut OpEngine {
+///         &mut self.engine
+///     }
+///
+///     fn into_parts(self) -> (S, OpEngine) {
+///         (self.inner, self.engine)
+///     }
+/// }
+///
+/// impl<S: ObjectStore> ObjectStore for PartialStore<S> {
+///     fn get(&mut self, key: &str, buf: &mut [u8]) -> io::Result<usize> {
+///         let inner = &mut self.inner;
+///         let len = buf.len();
+///         self.engine.run(
+///             |limit| match limit {
+///                 Some(limit) => inner.get(key, &mut buf[..limit]),
+///                 None => inner.get(key, buf),
+///             },
+///             len,
+///             "error during get, generated by partial-io",
+///         )
+///     }
+/// }
+///
+/// struct Store;
+///
+/// impl ObjectStore for Store {
+///     fn get(&mut self, _key: &str, buf: &mut [u8]) -> io::Result<usize> {
+///         buf.fill(b'x');
+///         Ok(buf.len())
+///     }
+/// }
+///
+/// let ops = vec![PartialOp::Limited(2), PartialOp::Err(io::ErrorKind::TimedOut)];
+/// let mut store = PartialStore::wrap(Store, ops);
+/// let mut buf = [0; 4];
+/// assert_eq!(store.get("key", &mut buf).unwrap(), 2);
+/// assert_eq!(store.get("key", &mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+/// assert_eq!(store.get("key", &mut buf).unwrap(), 4);
+/// ```
+///
+/// [`run`]: struct.OpEngine.html#method.run
+/// [`run_no_limit`]: struct.OpEngine.html#method.run_no_limit
+/// [`set_max_op_size`]: struct.OpEngine.html#method.set_max_op_size
+/// [`run_fragmented`]: struct.OpEngine.html#method.run_fragmented
+pub struct OpEngine {
+    // `None` in passthrough mode.
+    ops: Option<Exclusive<Ops>>,
+    // The remainder of a `PartialOp::ErrTimes` that is being performed.
+    repeat: Option<(io::ErrorKind, usize)>,
+    // The number of operations taken from the iterator, and the index of the current one.
+    taken: usize,
+    op_index: usize,
+    // The label of the current operation, from `PartialOp::Labeled`.
+    label: Option<&'static str>,
+    max_op_size: usize,
+    alignment: Option<Alignment>,
+    // The number of bytes processed so far, as reported through `advance`.
+    offset: u64,
+    // The method being performed, as reported through `begin_call`.
+    method: &'static str,
+    // The number of calls to `begin_call` so far.
+    call_count: usize,
+    // Whether the last operation taken by the current call was `PartialOp::Limited` or
+    // `PartialOp::Fragment`.
+    limited: bool,
+    // `Some` if operations are being recorded.
+    applied: Option<Vec<AppliedOp>>,
+    // `Some` if method calls are being recorded.
+    calls: Option<Vec<&'static str>>,
+    // `Some` if buffer sizes are being recorded.
+    sizes: Option<Vec<BufSize>>,
+    // The number of bytes that can be processed in total, and the error returned after that.
+    total_limit: Option<u64>,
+    total_limit_error: Option<io::ErrorKind>,
+}
+
+/// An operation that was applied to a method call, recorded by `OpEngine::record_ops`.
+#[derive(Clone, Debug)]
+pub struct AppliedOp {
+    /// The name of the method the operation was applied to, such as `"read"` or `"poll_write"`.
+    ///
+    /// This is `"unknown"` for custom wrappers that don't call `OpEngine::begin_call`.
+    pub method: &'static str,
+    /// The operation, without its label.
+    pub op: PartialOp,
+    /// The label of the operation, from `PartialOp::Labeled`.
+    pub label: Option<&'static str>,
+}
+
+/// The size of a buffer passed to a method call, and the size forwarded to the inner type,
+/// recorded by `OpEngine::record_sizes`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BufSize {
+    /// The name of the method, such as `"write"` or `"poll_read"`.
+    ///
+    /// This is `"unknown"` for custom wrappers that don't call `OpEngine::begin_call`.
+    pub method: &'static str,
+    /// The number of bytes the caller passed in.
+    pub requested: usize,
+    /// The number of bytes forwarded to the inner type, after limiting.
+    pub forwarded: usize,
+}
+
+/// A report of which operations were applied, and which were never reached.
+///
+/// Returned by `OpEngine::into_report`.
+#[derive(Clone, Debug)]
+pub struct OpReport {
+    /// The operations that were applied since `OpEngine::record_ops` was called, in order.
+    pub applied: Vec<AppliedOp>,
+    /// The operations that were never reached.
+    pub unreached: Vec<PartialOp>,
+}
+
+impl OpReport {
+    /// Returns the error operations that were never reached.
+    ///
+    /// If this isn't empty, some injected errors landed after the code under test had finished.
+    pub fn unreached_errors(&self) -> impl Iterator<Item = &PartialOp> {
+        self.unreached.iter().filter(|op| op.is_error())
+    }
+
+    /// Returns the fingerprint of the schedule: the applied operations with their labels,
+    /// followed by the unreached ones.
+    ///
+    /// If operations were recorded from the first one, this is the fingerprint of the whole
+    /// schedule. See `corpus::fingerprint` for more.
+    pub fn fingerprint(&self) -> u64 {
+        let ops: Vec<_> = self
+            .applied
+            .iter()
+            .map(|applied| match applied.label {
+                Some(label) => applied.op.clone().labeled(label),
+                None => applied.op.clone(),
+            })
+            .chain(self.unreached.iter().cloned())
+            .collect();
+        corpus::fingerprint(&ops)
+    }
+}
+
+/// How `PartialOp::Limited` sizes are snapped to boundaries, to simulate how block devices and
+/// sockets fragment transfers.
+///
+/// Set with `OpEngine::set_alignment`, or with the `set_alignment` methods on wrappers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Alignment {
+    /// Round limits down to a multiple of this size. Limits smaller than this size are rounded up
+    /// to it, except for `PartialOp::Limited(0)`, which stays 0.
+    Multiple(usize),
+    /// Cut limits off at the next multiple of this size from the current offset, like a
+    /// transfer that doesn't cross a page boundary.
+    ///
+    /// The offset is the number of bytes processed so far, as reported through
+    /// `OpEngine::advance`. The wrappers in this crate report it automatically.
+    Boundary(usize),
+}
+
+impl OpEngine {
+    /// Creates a new `OpEngine` with the specified `PartialOp`s.
+    pub fn new<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        OpEngine {
+            ops: Some(Exclusive::new(Ops::new(iter))),
+            repeat: None,
+            taken: 0,
+            op_index: 0,
+            label: None,
+            max_op_size: usize::MAX,
+            alignment: None,
+            offset: 0,
+            method: "unknown",
+            call_count: 0,
+            limited: false,
+            applied: None,
+            calls: None,
+            sizes: None,
+            total_limit: None,
+            total_limit_error: None,
+        }
+    }
+
+    /// Creates a new `OpEngine` in passthrough mode, which forwards every call as-is.
+    ///
+    /// In passthrough mode there is no iterator to box or advance: `run` makes a single check and
+    /// calls the callback with `None`. `max_op_size` and the alignment are ignored. This allows
+    /// the same test binary to run with faults disabled, for baseline benchmarks.
+    ///
+    /// Calling [`replace`] turns passthrough mode off.
+    ///
+    /// To put every engine into passthrough mode at compile time, build with
+    /// `RUSTFLAGS="--cfg partial_io_passthrough"`. This is a `cfg` rather than a Cargo feature
+    /// because it changes behavior for every user of this crate in the build.
+    ///
+    /// [`replace`]: struct.OpEngine.html#method.replace
+    pub fn passthrough() -> Self {
+        OpEngine {
+            ops: None,
+            repeat: None,
+            taken: 0,
+            op_index: 0,
+            label: None,
+            max_op_size: usize::MAX,
+            alignment: None,
+            offset: 0,
+            method: "unknown",
+            call_count: 0,
+            limited: false,
+            applied: None,
+            calls: None,
+            sizes: None,
+            total_limit: None,
+            total_limit_error: None,
+        }
+    }
+
+    /// Creates a new `OpEngine` that limits every operation to 1 byte, forever.
+    ///
+    /// This is the same as `OpEngine::new(iter::repeat(PartialOp::Limited(1)))`, but doesn't
+    /// allocate. Reading or writing one byte at a time is the most effective smoke test for
+    /// framing and decoder code.
+    pub fn byte_by_byte() -> Self {
+        let mut engine = Self::passthrough();
+        engine.ops = Some(Exclusive::new(Ops::ByteByByte));
+        engine
+    }
+
+    /// Returns true if this engine is in passthrough mode.
+    #[inline]
+    pub fn is_passthrough(&self) -> bool {
+        cfg!(partial_io_passthrough) || self.ops.is_none()
+    }
+
+    /// Replaces ops with a new iterator.
+    pub fn replace<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        self.ops = Some(Exclusive::new(Ops::new(iter)));
+        self.repeat = None;
+        self.taken = 0;
+        self.label = None;
+    }
+
+    /// Caps every operation to at most `max_op_size` bytes, in addition to the limits in
+    /// `PartialOp::Limited`.
+    ///
+    /// This applies to unlimited operations as well, and is kept when the operations are replaced.
+    /// It can be used to simulate a transport MTU without encoding it into every operation.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_op_size` is 0.
+    pub fn set_max_op_size(&mut self, max_op_size: usize) {
+        assert!(max_op_size > 0, "max_op_size must be greater than 0");
+        self.max_op_size = max_op_size;
+    }
+
+    /// Returns the maximum number of bytes processed by each operation.
+    ///
+    /// This is `usize::MAX` unless it was changed with [`set_max_op_size`].
+    ///
+    /// [`set_max_op_size`]: struct.OpEngine.html#method.set_max_op_size
+    pub fn max_op_size(&self) -> usize {
+        self.max_op_size
+    }
+
+    /// Snaps the sizes in `PartialOp::Limited` to boundaries, or stops snapping them if
+    /// `alignment` is `None`.
+    ///
+    /// This is kept when the operations are replaced. Sizes are snapped before they're capped to
+    /// `max_op_size` and to the number of bytes remaining.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the size in `alignment` is 0.
+    pub fn set_alignment(&mut self, alignment: Option<Alignment>) {
+        if let Some(Alignment::Multiple(size) | Alignment::Boundary(size)) = alignment {
+            assert!(size > 0, "alignment size must be greater than 0");
+        }
+        self.alignment = alignment;
+    }
+
+    /// Caps the number of bytes processed in total, across all operations, to `limit`, or removes
+    /// the cap if `limit` is `None`.
+    ///
+    /// Bytes are counted through [`advance`]. Once `limit` bytes have been processed, every
+    /// further operation is limited to 0 bytes: reads return EOF, and writes return `Ok(0)`, which
+    /// `write_all` turns into a `WriteZero` error. Use [`set_total_limit_error`] to return another
+    /// error instead. Operations are still taken from the schedule, so errors in it keep coming
+    /// up.
+    ///
+    /// This is kept when the operations are replaced, and ignored in passthrough mode.
+    ///
+    /// [`advance`]: struct.OpEngine.html#method.advance
+    /// [`set_total_limit_error`]: struct.OpEngine.html#method.set_total_limit_error
+    pub fn set_total_limit(&mut self, limit: Option<u64>) {
+        self.total_limit = limit;
+    }
+
+    /// Returns an error of kind `kind` from operations that would process bytes once the limit
+    /// set with [`set_total_limit`] has been reached, instead of limiting them to 0 bytes.
+    ///
+    /// Operations that don't process bytes, such as flushes, aren't affected. If `kind` is `None`,
+    /// operations are limited to 0 bytes again.
+    ///
+    /// [`set_total_limit`]: struct.OpEngine.html#method.set_total_limit
+    pub fn set_total_limit_error(&mut self, kind: Option<io::ErrorKind>) {
+        self.total_limit_error = kind;
+    }
+
+    /// Returns the error for an operation that would process bytes past the total limit, if any.
+    #[inline]
+    pub(crate) fn total_limit_reached(&self, err_str: &str) -> Option<io::Error> {
+        match (self.total_limit, self.total_limit_error) {
+            (Some(limit), Some(kind)) if self.offset >= limit && !self.is_passthrough() => {
+                Some(io::Error::new(
+                    kind,
+                    format!(
+                        "{}: total limit of {} bytes reached during {}",
+                        err_str, limit, self.method
+                    ),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the number of bytes left before the limit set with [`set_total_limit`] is reached,
+    /// if there is one.
+    ///
+    /// [`set_total_limit`]: struct.OpEngine.html#method.set_total_limit
+    #[inline]
+    pub(crate) fn total_left(&self) -> Option<u64> {
+        match self.total_limit {
+            Some(limit) if !self.is_passthrough() => Some(limit.saturating_sub(self.offset)),
+            _ => None,
+        }
+    }
+
+    /// Records that `n` bytes were processed, for `Alignment::Boundary`.
+    ///
+    /// The wrappers in this crate call this after every successful read or write. Custom wrappers
+    /// only need to call it if they support `Alignment::Boundary`.
+    #[inline]
+    pub fn advance(&mut self, n: usize) {
+        self.offset += n as u64;
+    }
+
+    /// Returns the number of bytes processed so far, as reported through [`advance`].
+    ///
+    /// [`advance`]: struct.OpEngine.html#method.advance
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Records the number of bytes processed by a successful operation, then returns its result.
+    #[inline]
+    pub(crate) fn record(&mut self, res: io::Result<usize>) -> io::Result<usize> {
+        if let Ok(n) = res {
+            self.advance(n);
+        }
+        res
+    }
+
+    /// Snaps a limit from `PartialOp::Limited` according to the alignment.
+    fn align(&self, n: usize) -> usize {
+        match self.alignment {
+            // A limit of 0 still means EOF, or a writer that accepts nothing.
+            Some(Alignment::Multiple(_)) if n == 0 => 0,
+            Some(Alignment::Multiple(size)) => cmp::max(n - n % size, size),
+            Some(Alignment::Boundary(size)) => {
+                let to_boundary = size - (self.offset % size as u64) as usize;
+                cmp::min(n, to_boundary)
+            }
+            None => n,
+        }
+    }
+
+    /// Combines the limit from an operation with the alignment and `max_op_size`, and records the
+    /// size forwarded to the inner type if sizes are being recorded.
+    #[inline]
+    pub(crate) fn forward(&mut self, limit: Option<usize>, remaining: usize) -> Option<usize> {
+        let limit = self.cap(limit, remaining);
+        if let Some(sizes) = &mut self.sizes {
+            sizes.push(BufSize {
+                method: self.method,
+                requested: remaining,
+                forwarded: limit.unwrap_or(remaining),
+            });
+        }
+        limit
+    }
+
+    /// Combines the limit from an operation with the alignment, `max_op_size` and the total
+    /// limit.
+    #[inline]
+    fn cap(&self, limit: Option<usize>, remaining: usize) -> Option<usize> {
+        let limit = match limit {
+            Some(n) => Some(cmp::min(
+                cmp::min(self.align(n), self.max_op_size),
+                remaining,
+            )),
+            None if self.max_op_size < remaining => Some(self.max_op_size),
+            None => None,
+        };
+        match self.total_left() {
+            Some(left) => match limit {
+                Some(n) if n as u64 <= left => Some(n),
+                None if remaining as u64 <= left => None,
+                _ => Some(left as usize),
+            },
+            None => limit,
+        }
+    }
+
+    /// Records the name of the method being performed, such as `"read"`, for [`record_ops`] and
+    /// [`record_calls`].
+    ///
+    /// The wrappers in this crate call this at the start of each method. Custom wrappers only need
+    /// to call it to have method names show up in reports and timelines.
+    ///
+    /// [`record_ops`]: struct.OpEngine.html#method.record_ops
+    /// [`record_calls`]: struct.OpEngine.html#method.record_calls
+    #[inline]
+    pub fn begin_call(&mut self, method: &'static str) {
+        self.method = method;
+        self.call_count += 1;
+        self.limited = false;
+        if let Some(calls) = &mut self.calls {
+            calls.push(method);
+        }
+    }
+
+    // Returns the number of calls reported through `begin_call` so far, including the current one.
+    #[cfg(feature = "futures03")]
+    pub(crate) fn call_count(&self) -> usize {
+        self.call_count
+    }
+
+    // Returns true if the last operation taken since `begin_call` was `PartialOp::Limited` or
+    // `PartialOp::Fragment`, for methods that can't make partial progress by themselves.
+    pub(crate) fn took_limited(&self) -> bool {
+        self.limited
+    }
+
+    /// Starts recording the timeline of method calls, as reported through [`begin_call`].
+    ///
+    /// Any calls recorded earlier are cleared. Every call is recorded, including retries after
+    /// `Poll::Pending`. See the `timeline` module for how to make assertions on the timeline.
+    ///
+    /// [`begin_call`]: struct.OpEngine.html#method.begin_call
+    pub fn record_calls(&mut self) {
+        self.calls = Some(Vec::new());
+    }
+
+    /// Returns the method calls recorded since [`record_calls`] was called, in order.
+    ///
+    /// [`record_calls`]: struct.OpEngine.html#method.record_calls
+    pub fn calls(&self) -> &[&'static str] {
+        self.calls.as_deref().unwrap_or(&[])
+    }
+
+    /// Starts recording the operations that are applied, and the methods they are applied to.
+    ///
+    /// Any operations recorded earlier are cleared.
+    pub fn record_ops(&mut self) {
+        self.applied = Some(Vec::new());
+    }
+
+    /// Returns the operations recorded since [`record_ops`] was called.
+    ///
+    /// [`record_ops`]: struct.OpEngine.html#method.record_ops
+    pub fn applied_ops(&self) -> &[AppliedOp] {
+        self.applied.as_deref().unwrap_or(&[])
+    }
+
+    /// Starts recording the size of every buffer passed in by callers, along with the size
+    /// forwarded to the inner type after limiting.
+    ///
+    /// Any sizes recorded earlier are cleared. Calls that fail with an injected error don't reach
+    /// the inner type, and calls that don't take a buffer, such as `flush`, aren't recorded. In
+    /// passthrough mode, nothing is recorded.
+    pub fn record_sizes(&mut self) {
+        self.sizes = Some(Vec::new());
+    }
+
+    /// Returns the buffer sizes recorded since [`record_sizes`] was called, in order.
+    ///
+    /// [`record_sizes`]: struct.OpEngine.html#method.record_sizes
+    pub fn buf_sizes(&self) -> &[BufSize] {
+        self.sizes.as_deref().unwrap_or(&[])
+    }
+
+    /// Consumes this engine, returning a report of the operations that were applied since
+    /// [`record_ops`] was called, and the operations that were never reached.
+    ///
+    /// The remaining operations are collected into the report, so this doesn't return if they are
+    /// infinite.
+    ///
+    /// [`record_ops`]: struct.OpEngine.html#method.record_ops
+    pub fn into_report(mut self) -> OpReport {
+        let applied = self.applied.take().unwrap_or_default();
+        OpReport {
+            applied,
+            unreached: self.into_ops().collect(),
+        }
+    }
+
+    /// Consumes and returns the next operation, or `None` if there are no operations left.
+    ///
+    /// This is the lowest-level building block, for wrappers that need to interpret operations
+    /// themselves.
+    ///
+    /// In passthrough mode, this always returns `None`.
+    #[inline]
+    pub fn next_op(&mut self) -> Option<PartialOp> {
+        if self.is_passthrough() {
+            return None;
+        }
+        let op = match self.repeat.take() {
+            Some((kind, n)) => Some(self.start_repeat(kind, n)),
+            None => loop {
+                let mut op = self.ops.as_mut()?.get_mut().next();
+                if op.is_some() {
+                    self.op_index = self.taken;
+                    self.taken += 1;
+                    self.label = None;
+                }
+                while let Some(PartialOp::Labeled(inner, label)) = op {
+                    self.label.get_or_insert(label);
+                    op = Some(*inner);
+                }
+                match op {
+                    // Proceed immediately if there are no errors to repeat.
+                    Some(PartialOp::ErrTimes(_, 0)) => continue,
+                    Some(PartialOp::ErrTimes(kind, n)) => break Some(self.start_repeat(kind, n)),
+                    op => break op,
+                }
+            },
+        };
+        self.limited = matches!(
+            op,
+            Some(PartialOp::Limited(_))
+                | Some(PartialOp::LimitedFraction(..))
+                | Some(PartialOp::Fragment(_))
+        );
+        if let (Some(applied), Some(op)) = (&mut self.applied, &op) {
+            applied.push(AppliedOp {
+                method: self.method,
+                op: op.clone(),
+                label: self.label,
+            });
+        }
+        op
+    }
+
+    /// Creates the error for a `PartialOp::Err` or `PartialOp::ErrTimes` operation that was just
+    /// consumed.
+    ///
+    /// The message says which operation injected the error, and during which method, such as
+    /// `"<err_str>: injected at op #7 during poll_flush"`. Operations are numbered from 0, in
+    /// the order they were set; a `PartialOp::ErrTimes` keeps its number for all of its errors.
+    /// If the operation is labeled, the label follows, as in `", in 'header-retry'"`.
+    pub(crate) fn injected_error(&self, kind: io::ErrorKind, err_str: &str) -> io::Error {
+        let mut message = format!(
+            "{}: injected at op #{} during {}",
+            err_str, self.op_index, self.method
+        );
+        if let Some(label) = self.label {
+            message.push_str(&format!(", in '{}'", label));
+        }
+        io::Error::new(kind, message)
+    }
+
+    // Describes the state of this engine for a diagnostic message, including the operations that
+    // haven't been taken yet. Only schedules stored as-is can be listed without consuming them.
+    #[cfg(feature = "futures03")]
+    pub(crate) fn diagnostics(&mut self) -> String {
+        const MAX_LISTED: usize = 8;
+
+        let list = |ops: &[PartialOp]| {
+            let listed: Vec<_> = ops
+                .iter()
+                .take(MAX_LISTED)
+                .map(|op| format!("{:?}", op))
+                .collect();
+            let more = if ops.len() > MAX_LISTED { ", ..." } else { "" };
+            format!("{} left: [{}{}]", ops.len(), listed.join(", "), more)
+        };
+        let remaining = match self.ops.as_mut().map(|ops| ops.get_mut()) {
+            None => "none, in passthrough mode".to_owned(),
+            Some(Ops::Vec(ops)) => list(ops.as_slice()),
+            Some(Ops::Static(ops)) => list(ops.as_slice()),
+            Some(Ops::ByteByByte) => "Limited(1) forever".to_owned(),
+            Some(Ops::Boxed(_)) => "unknown, from an iterator that can't be inspected".to_owned(),
+        };
+        format!(
+            "method: {}, ops taken: {}, bytes processed: {}\nremaining ops: {}",
+            self.method, self.taken, self.offset, remaining
+        )
+    }
+
+    /// Panics for a `PartialOp::Panic` operation that was just consumed.
+    pub(crate) fn injected_panic(&self, message: &'static str) -> ! {
+        match self.label {
+            Some(label) => panic!("{}, in '{}'", message, label),
+            None => panic::panic_any(message),
+        }
+    }
+
+    /// Returns the label of the operation consumed last, if it was labeled with
+    /// `PartialOp::Labeled`.
+    ///
+    /// Custom wrappers can include this in their own diagnostics.
+    pub fn label(&self) -> Option<&'static str> {
+        self.label
+    }
+
+    // Performs one of the `n` errors of a `PartialOp::ErrTimes(kind, n)`, where `n > 0`.
+    fn start_repeat(&mut self, kind: io::ErrorKind, n: usize) -> PartialOp {
+        if n > 1 {
+            self.repeat = Some((kind, n - 1));
+        }
+        PartialOp::Err(kind)
+    }
+
+    /// Consumes this engine, returning the operations that haven't been consumed yet.
+    ///
+    /// If a `PartialOp::ErrTimes` is partway done, the iterator starts with a `PartialOp::ErrTimes`
+    /// for the errors that are left.
+    ///
+    /// The iterator is fused: once it has returned `None`, it always returns `None`.
+    pub fn into_ops(self) -> Box<dyn Iterator<Item = PartialOp> + Send> {
+        let ops = match self.ops {
+            Some(ops) => ops.into_inner().into_boxed(),
+            None => make_ops(None),
+        };
+        match self.repeat {
+            Some((kind, n)) => {
+                let mut op = PartialOp::ErrTimes(kind, n);
+                if let Some(label) = self.label {
+                    op = op.labeled(label);
+                }
+                Box::new(std::iter::once(op).chain(ops))
+            }
+            None => ops,
+        }
+    }
+
+    /// Helper for IO methods.
+    ///
+    /// `cb` is the callback that implements the actual logic. Its argument is `Some(n)` to limit
+    /// the number of bytes being processed, or `None` for unlimited. `remaining` is the maximum
+    /// number of bytes the operation could process, and `err_str` is the message for injected
+    /// errors.
+    #[inline]
+    pub fn run<T>(
+        &mut self,
+        cb: impl FnOnce(Option<usize>) -> io::Result<T>,
+        remaining: usize,
+        err_str: &'static str,
+    ) -> io::Result<T> {
+        if let Some(err) = self.total_limit_reached(err_str) {
+            return Err(err);
+        }
+        self.run_impl(
+            |engine, limit| engine.forward(limit, remaining),
+            cb,
+            remaining,
+            err_str,
+        )
+    }
+
+    /// Helper for IO methods that can split a call into several calls into the inner type, for
+    /// `PartialOp::Fragment`.
+    ///
+    /// `cb` is called with the offset in the caller's buffer to start from, and the limit. For
+    /// other operations, this behaves the same as [`run`] with an offset of 0. Unlike `run`, this
+    /// reports the number of bytes processed through [`advance`] itself.
+    ///
+    /// [`run`]: struct.OpEngine.html#method.run
+    /// [`advance`]: struct.OpEngine.html#method.advance
+    #[inline]
+    pub fn run_fragmented(
+        &mut self,
+        mut cb: impl FnMut(usize, Option<usize>) -> io::Result<usize>,
+        remaining: usize,
+        err_str: &'static str,
+    ) -> io::Result<usize> {
+        let res = if self.is_passthrough() {
+            cb(0, None)
+        } else if let Some(err) = self.total_limit_reached(err_str) {
+            Err(err)
+        } else if self.is_exhausted() {
+            cb(0, self.forward(None, remaining))
+        } else {
+            match self.next_op() {
+                Some(PartialOp::Fragment(n)) => return self.fragments(n, cb, remaining),
+                op => self.apply(
+                    op,
+                    |engine, limit| engine.forward(limit, remaining),
+                    |limit| cb(0, limit),
+                    remaining,
+                    err_str,
+                ),
+            }
+        };
+        self.record(res)
+    }
+
+    // Performs a `PartialOp::Fragment(n)` through calls of at most `n` bytes each.
+    fn fragments(
+        &mut self,
+        n: usize,
+        mut cb: impl FnMut(usize, Option<usize>) -> io::Result<usize>,
+        remaining: usize,
+    ) -> io::Result<usize> {
+        let n = cmp::max(n, 1);
+        let mut done = 0;
+        loop {
+            let limit = self.forward(Some(n), remaining - done);
+            let chunk = limit.unwrap_or(remaining - done);
+            match cb(done, limit) {
+                Ok(k) => {
+                    self.advance(k);
+                    done += k;
+                    // Stop after a short call, which might block if repeated, or once the total
+                    // limit has been reached.
+                    if k < chunk || k == 0 || done == remaining {
+                        return Ok(done);
+                    }
+                }
+                Err(err) if done == 0 => return Err(err),
+                // The error is likely to be returned again by the next call.
+                Err(_) => return Ok(done),
+            }
+        }
+    }
+
+    /// Helper for IO methods that ignore the length specified in `PartialOp::Limited`.
+    #[inline]
+    pub fn run_no_limit<T>(
+        &mut self,
+        cb: impl FnOnce() -> io::Result<T>,
+        err_str: &'static str,
+    ) -> io::Result<T> {
+        self.run_impl(|_, _| None, |_| cb(), 0, err_str)
+    }
+
+    #[inline]
+    fn run_impl<T>(
+        &mut self,
+        mut limit_fn: impl FnMut(&mut Self, Option<usize>) -> Option<usize>,
+        cb: impl FnOnce(Option<usize>) -> io::Result<T>,
+        remaining: usize,
+        err_str: &'static str,
+    ) -> io::Result<T> {
+        if self.is_passthrough() {
+            return cb(None);
+        }
+        if self.is_exhausted() {
+            return cb(limit_fn(self, None));
+        }
+        let op = self.next_op();
+        self.apply(op, limit_fn, cb, remaining, err_str)
+    }
+
+    // Returns true if the operations are known to have run out, so that the call can skip
+    // `next_op`. Only schedules stored as a `Vec` or a `StaticOps` can be checked without
+    // advancing them.
+    #[inline]
+    fn is_exhausted(&mut self) -> bool {
+        match self.ops.as_mut().map(Exclusive::get_mut) {
+            Some(Ops::Vec(ops)) => self.repeat.is_none() && ops.len() == 0,
+            Some(Ops::Static(ops)) => self.repeat.is_none() && ops.len() == 0,
+            _ => false,
+        }
+    }
+
+    #[inline]
+    fn apply<T>(
+        &mut self,
+        op: Option<PartialOp>,
+        mut limit_fn: impl FnMut(&mut Self, Option<usize>) -> Option<usize>,
+        cb: impl FnOnce(Option<usize>) -> io::Result<T>,
+        remaining: usize,
+        err_str: &'static str,
+    ) -> io::Result<T> {
+        match op {
+            Some(PartialOp::Limited(n)) | Some(PartialOp::Fragment(n)) => {
+                cb(limit_fn(self, Some(n)))
+            }
+            Some(PartialOp::LimitedFraction(num, den)) => {
+                cb(limit_fn(self, Some(fraction_of(num, den, remaining))))
+            }
+            Some(PartialOp::Err(kind)) | Some(PartialOp::ErrTimes(kind, _)) => {
+                Err(self.injected_error(kind, err_str))
+            }
+            Some(PartialOp::OsErr(code)) => Err(io::Error::from_raw_os_error(code)),
+            Some(PartialOp::ErrWith(factory)) => Err(factory.make_error()),
+            Some(PartialOp::Delay(duration)) => {
+                thread::sleep(duration);
+                cb(limit_fn(self, None))
+            }
+            Some(PartialOp::Panic(message)) => self.injected_panic(message),
+            Some(PartialOp::Labeled(..)) => unreachable!("labels are removed by next_op"),
+            Some(PartialOp::Unlimited) | None => cb(limit_fn(self, None)),
+        }
+    }
+}
+
+/// Returns the limit for a `PartialOp::LimitedFraction(num, den)` on a call for `len` bytes.
+#[inline]
+pub(crate) fn fraction_of(num: usize, den: usize, len: usize) -> usize {
+    assert!(
+        den > 0,
+        "PartialOp::LimitedFraction with a denominator of 0"
+    );
+    let limit = (len as u128 * num as u128 / den as u128).min(len as u128) as usize;
+    if num > 0 && len > 0 {
+        cmp::max(limit, 1)
+    } else {
+        limit
+    }
+}
+
+impl fmt::Debug for OpEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpEngine")
+            .field("passthrough", &self.is_passthrough())
+            .field("max_op_size", &self.max_op_size)
+            .field("alignment", &self.alignment)
+            .field("offset", &self.offset)
+            .field("total_limit", &self.total_limit)
+            .field("method", &self.method)
+            .finish()
+    }
+}
+
+/// An iterator over a `'static` slice of `PartialOp`s, which wrappers take without allocating.
+///
+/// Other iterators are boxed when they are passed to a wrapper, except for `Vec`s. A schedule
+/// that is known up front can instead be written as a `static` or `const` slice or array, and
+/// passed in through a `StaticOps`. `StaticOps::new` is a `const fn`, so the whole schedule can be
+/// a constant. This applies to the async wrappers as well.
+///
+/// Operations are cloned out of the slice as they are consumed.
+///
+/// # Examples
+///
+/// ```rust
+/// use partial_io::{PartialOp, PartialRead, StaticOps};
+/// use std::io::{self, Read};
+///
+/// const OPS: StaticOps = StaticOps::new(&[
+///     PartialOp::Limited(1),
+///     PartialOp::Err(io::ErrorKind::Interrupted),
+/// ]);
+///
+/// let mut partial_read = PartialRead::new(&b"abc"[..], OPS);
+/// let mut buf = Vec::new();
+/// partial_read.read_to_end(&mut buf).unwrap();
+/// assert_eq!(&buf, b"abc");
+/// ```
+#[derive(Clone, Debug)]
+pub struct StaticOps {
+    ops: &'static [PartialOp],
+}
+
+impl StaticOps {
+    /// Creates a new iterator over `ops`.
+    pub const fn new(ops: &'static [PartialOp]) -> Self {
+        StaticOps { ops }
+    }
+
+    /// Returns the operations that haven't been consumed yet.
+    pub fn as_slice(&self) -> &'static [PartialOp] {
+        self.ops
+    }
+}
+
+impl Iterator for StaticOps {
+    type Item = PartialOp;
+
+    #[inline]
+    fn next(&mut self) -> Option<PartialOp> {
+        let (op, rest) = self.ops.split_first()?;
+        self.ops = rest;
+        Some(op.clone())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.ops.len(), Some(self.ops.len()))
+    }
+}
+
+impl ExactSizeIterator for StaticOps {}
+
+impl iter::FusedIterator for StaticOps {}
+
+// The operations of an engine that isn't in passthrough mode.
+//
+// Schedules are usually a `Vec`, such as the ones generated by `quickcheck`, or a `StaticOps`.
+// Those are stored as-is, so that taking the next operation doesn't allocate or go through a
+// virtual call. Other iterators are boxed.
+enum Ops {
+    Vec(vec::IntoIter<PartialOp>),
+    Static(StaticOps),
+    ByteByByte,
+    Boxed(Box<dyn Iterator<Item = PartialOp> + Send>),
+}
+
+impl Ops {
+    fn new<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        let mut iter = Some(iter.into_iter());
+        // These iterators can't be told apart from others through the type system, so check
+        // their type at runtime. This doesn't allocate.
+        let any = &mut iter as &mut dyn Any;
+        if let Some(ops) = any.downcast_mut::<Option<vec::IntoIter<PartialOp>>>() {
+            return Ops::Vec(ops.take().expect("iterator was just set"));
+        }
+        if let Some(ops) = any.downcast_mut::<Option<StaticOps>>() {
+            return Ops::Static(ops.take().expect("iterator was just set"));
+        }
+        Ops::Boxed(make_ops(iter.expect("iterator was just set")))
+    }
+
+    #[inline]
+    fn next(&mut self) -> Option<PartialOp> {
+        match self {
+            Ops::Vec(ops) => ops.next(),
+            Ops::Static(ops) => ops.next(),
+            Ops::ByteByByte => Some(PartialOp::Limited(1)),
+            Ops::Boxed(ops) => ops.next(),
+        }
+    }
+
+    fn into_boxed(self) -> Box<dyn Iterator<Item = PartialOp> + Send> {
+        match self {
+            // `vec::IntoIter` is already fused.
+            Ops::Vec(ops) => Box::new(ops),
+            Ops::Static(ops) => Box::new(ops),
+            Ops::ByteByByte => Box::new(byte_by_byte_ops()),
+            Ops::Boxed(ops) => ops,
+        }
+    }
+}
+
+// A zero-sized iterator, so that boxing it doesn't allocate.
+fn byte_by_byte_ops() -> impl Iterator<Item = PartialOp> + Send {
+    std::iter::repeat_with(|| PartialOp::Limited(1))
+}
+
+/// A partial wrapper built on an [`OpEngine`].
+///
+/// Implementors provide the required methods, and get constructors and setters that behave
+/// the same way as the wrappers in this crate. `PartialRead`, `PartialWrite`, `PartialAsyncRead`
+/// and `PartialAsyncWrite` implement this trait.
+///
+/// See [`OpEngine`] for an example.
+///
+/// [`OpEngine`]: struct.OpEngine.html
+pub trait PartialWrap: Sized {
+    /// The type being wrapped.
+    type Inner;
+
+    /// Creates a wrapper over `inner` that applies operations from `engine`.
+    fn from_engine(inner: Self::Inner, engine: OpEngine) -> Self;
+
+    /// Returns the engine for this wrapper.
+    fn engine_mut(&mut self) -> &mut OpEngine;
+
+    /// Consumes this wrapper, returning the inner value and the engine.
+    fn into_parts(self) -> (Self::Inner, OpEngine);
+
+    /// Creates a wrapper over `inner` with the specified `PartialOp`s.
+    fn wrap<I>(inner: Self::Inner, iter: I) -> Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        Self::from_engine(inner, OpEngine::new(iter))
+    }
+
+    /// Creates a wrapper over `inner` in passthrough mode, which forwards every call as-is.
+    ///
+    /// See `OpEngine::passthrough` for more.
+    fn passthrough(inner: Self::Inner) -> Self {
+        Self::from_engine(inner, OpEngine::passthrough())
+    }
+
+    /// Creates a wrapper over `inner` that limits every operation to 1 byte, forever.
+    ///
+    /// See `OpEngine::byte_by_byte` for more.
+    fn byte_by_byte(inner: Self::Inner) -> Self {
+        Self::from_engine(inner, OpEngine::byte_by_byte())
+    }
+
+    /// Replaces the `PartialOp`s for this wrapper.
+    fn replace_ops<I>(&mut self, iter: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        self.engine_mut().replace(iter);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Cursor, Read, Write};
+
+    use crate::{
+        tests::{assert_send, assert_sync},
+        PartialRead, PartialWrite,
+    };
+
+    #[test]
+    fn test_sendable() {
+        assert_send::<OpEngine>();
+        assert_sync::<OpEngine>();
+    }
+
+    #[test]
+    fn test_wrap() {
+        fn read_once<W: PartialWrap + Read>(wrapper: &mut W) -> io::Result<usize> {
+            wrapper.replace_ops(vec![PartialOp::Limited(1)]);
+            wrapper.read(&mut [0; 4])
+        }
+
+        let mut partial_read = PartialRead::wrap(
+            Cursor::new(b"abcd".to_vec()),
+            vec![PartialOp::Err(io::ErrorKind::InvalidData)],
+        );
+        assert_eq!(
+            partial_read.read(&mut [0; 4]).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(read_once(&mut partial_read).unwrap(), 1);
+        assert_eq!(partial_read.read(&mut [0; 4]).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_into_parts() {
+        let ops = vec![
+            PartialOp::Limited(1),
+            PartialOp::Limited(2),
+            PartialOp::Unlimited,
+        ];
+        let mut partial_read = PartialRead::new(Cursor::new(b"abcd".to_vec()), ops);
+        assert_eq!(partial_read.read(&mut [0; 4]).unwrap(), 1);
+
+        let (cursor, engine) = partial_read.into_parts();
+        assert_eq!(cursor.position(), 1);
+        assert_eq!(engine.into_ops().count(), 2, "two ops were left unused");
+    }
+
+    #[test]
+    fn test_max_op_size() {
+        let ops = vec![PartialOp::Limited(2), PartialOp::Limited(10)];
+        let mut partial_read = PartialRead::new(Cursor::new(vec![0; 20]), ops);
+        partial_read.set_max_op_size(4);
+        let mut buf = [0; 8];
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 2);
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 4);
+        assert_eq!(
+            partial_read.read(&mut buf).unwrap(),
+            4,
+            "Unlimited is capped too"
+        );
+        assert_eq!(partial_read.read(&mut buf[..3]).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_total_limit() {
+        let ops = vec![
+            PartialOp::Limited(3),
+            PartialOp::Fragment(4),
+            PartialOp::Err(io::ErrorKind::WouldBlock),
+        ];
+        let mut partial_read = PartialRead::new(Cursor::new(vec![1; 20]), ops);
+        partial_read.set_total_limit(Some(5));
+        let mut buf = Vec::new();
+        let err = partial_read.read_to_end(&mut buf).unwrap_err();
+        assert_eq!(
+            err.kind(),
+            io::ErrorKind::WouldBlock,
+            "the schedule goes on"
+        );
+        partial_read.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, [1; 5]);
+
+        let mut partial_write = PartialWrite::new(Vec::new(), vec![PartialOp::Limited(2)]);
+        partial_write.set_total_limit(Some(3));
+        let err = partial_write.write_all(b"abcd").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        partial_write.set_total_limit_error(Some(io::ErrorKind::StorageFull));
+        let err = partial_write.write(b"d").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert!(
+            err.to_string()
+                .contains("total limit of 3 bytes reached during write"),
+            "{}",
+            err
+        );
+        partial_write.flush().unwrap();
+        assert_eq!(partial_write.get_ref(), b"abc");
+    }
+
+    #[test]
+    fn test_alignment() {
+        let ops = vec![
+            PartialOp::Limited(1000),
+            PartialOp::Limited(100),
+            PartialOp::Limited(1500),
+            PartialOp::Limited(0),
+        ];
+        let mut partial_read = PartialRead::new(Cursor::new(vec![0; 4096]), ops.clone());
+        partial_read.set_alignment(Some(Alignment::Multiple(512)));
+        let mut buf = [0; 2048];
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 512);
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 512);
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 1024);
+        assert_eq!(
+            partial_read.read(&mut buf).unwrap(),
+            0,
+            "0 isn't rounded up"
+        );
+
+        let mut partial_read = PartialRead::new(Cursor::new(vec![0; 4096]), ops);
+        partial_read.set_alignment(Some(Alignment::Boundary(1024)));
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 1000);
+        assert_eq!(
+            partial_read.read(&mut buf).unwrap(),
+            24,
+            "up to the boundary"
+        );
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 1024);
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let mut partial_read = PartialRead::passthrough(Cursor::new(vec![0; 8]));
+        partial_read.set_max_op_size(1);
+        assert_eq!(partial_read.read(&mut [0; 4]).unwrap(), 4);
+
+        partial_read.set_ops(vec![PartialOp::Limited(2)]);
+        let expected = if cfg!(partial_io_passthrough) { 4 } else { 1 };
+        assert_eq!(partial_read.read(&mut [0; 4]).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_report() {
+        use std::io::Write;
+
+        let ops = vec![
+            PartialOp::Limited(2),
+            PartialOp::Err(io::ErrorKind::Interrupted),
+            PartialOp::Unlimited,
+            PartialOp::Err(io::ErrorKind::BrokenPipe),
+        ];
+        let fingerprint = crate::corpus::fingerprint(&ops);
+        let mut partial_write = crate::PartialWrite::new(Vec::new(), ops);
+        partial_write.record_ops();
+        partial_write.write_all(b"abc").unwrap();
+
+        let (_, engine) = partial_write.into_parts();
+        let methods: Vec<_> = engine.applied_ops().iter().map(|a| a.method).collect();
+        assert_eq!(methods, ["write", "write", "write"]);
+        let report = engine.into_report();
+        assert_eq!(report.applied.len(), 3);
+        assert_eq!(
+            report.unreached_errors().count(),
+            1,
+            "BrokenPipe was never reached"
+        );
+        assert_eq!(report.fingerprint(), fingerprint);
+    }
+
+    #[test]
+    fn test_buf_sizes() {
+        use std::io::Write;
+
+        let ops = vec![
+            PartialOp::Limited(3),
+            PartialOp::Err(io::ErrorKind::Interrupted),
+            PartialOp::Unlimited,
+        ];
+        let mut partial_write = crate::PartialWrite::new(Vec::new(), ops);
+        partial_write.record_sizes();
+        {
+            // The small writes are coalesced into a single write of 6 bytes.
+            let mut writer = io::BufWriter::with_capacity(8, &mut partial_write);
+            for chunk in [&b"ab"[..], b"cd", b"ef"] {
+                writer.write_all(chunk).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+        let sizes: Vec<_> = partial_write
+            .buf_sizes()
+            .iter()
+            .map(|size| (size.method, size.requested, size.forwarded))
+            .collect();
+        assert_eq!(sizes, [("write", 6, 3), ("write", 3, 3)]);
+        assert_eq!(partial_write.get_ref(), b"abcdef");
+    }
+
+    #[test]
+    fn test_fragment() {
+        let mut partial_read = PartialRead::new(
+            Cursor::new(b"abcdef".to_vec()),
+            vec![PartialOp::Fragment(2)],
+        );
+        partial_read.record_sizes();
+        let mut buf = [0; 5];
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 5, "a single call");
+        assert_eq!(&buf, b"abcde");
+        let forwarded: Vec<_> = partial_read
+            .buf_sizes()
+            .iter()
+            .map(|s| s.forwarded)
+            .collect();
+        assert_eq!(forwarded, [2, 2, 1]);
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 1);
+
+        // Fragments stop after a short read.
+        let inner = PartialRead::new(Cursor::new(b"abcdef".to_vec()), vec![PartialOp::Limited(1)]);
+        let mut partial_read = PartialRead::new(inner, vec![PartialOp::Fragment(4)]);
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 1);
+
+        // Errors after some progress are dropped.
+        let ops = vec![PartialOp::Unlimited, PartialOp::Err(io::ErrorKind::Other)];
+        let inner = PartialRead::new(Cursor::new(b"abcdef".to_vec()), ops);
+        let mut partial_read = PartialRead::new(inner, vec![PartialOp::Fragment(2)]);
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_limited_fraction() {
+        let ops = vec![
+            PartialOp::LimitedFraction(1, 4),
+            PartialOp::LimitedFraction(1, 4),
+            PartialOp::LimitedFraction(0, 1),
+            PartialOp::LimitedFraction(3, 2),
+        ];
+        let mut partial_read = PartialRead::new(Cursor::new(vec![0; 64]), ops);
+        let mut buf = [0; 16];
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 4);
+        assert_eq!(
+            partial_read.read(&mut buf[..3]).unwrap(),
+            1,
+            "at least 1 byte"
+        );
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 0);
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 16);
+    }
+
+    #[test]
+    fn test_injected_error_context() {
+        use std::io::Write;
+
+        let ops = vec![
+            PartialOp::Unlimited,
+            PartialOp::ErrTimes(io::ErrorKind::BrokenPipe, 2),
+        ];
+        let mut partial_write = crate::PartialWrite::new(Vec::new(), ops);
+        partial_write.write_all(b"ab").unwrap();
+        for method in ["flush", "write"] {
+            let err = match method {
+                "flush" => partial_write.flush(),
+                _ => partial_write.write(b"c").map(|_| ()),
+            }
+            .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+            assert!(
+                err.to_string()
+                    .ends_with(&format!("injected at op #1 during {}", method)),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_panic() {
+        use std::{io::Write, panic::AssertUnwindSafe, sync::Mutex};
+
+        let ops = vec![PartialOp::Limited(1), PartialOp::Panic("disk on fire")];
+        let writer = Mutex::new(crate::PartialWrite::new(Vec::new(), ops));
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            writer.lock().unwrap().write_all(b"ab").unwrap();
+        }));
+        let payload = res.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"disk on fire"));
+
+        // The lock was held while the write panicked.
+        assert!(writer.is_poisoned());
+        let mut partial_write = writer.into_inner().unwrap_err().into_inner();
+        assert_eq!(partial_write.get_ref(), b"a");
+        partial_write.write_all(b"b").unwrap();
+        assert_eq!(partial_write.get_ref(), b"ab");
+    }
+
+    #[test]
+    fn test_labeled() {
+        use std::io::Write;
+
+        let ops = vec![
+            PartialOp::Limited(1).labeled("header"),
+            PartialOp::ErrTimes(io::ErrorKind::BrokenPipe, 2)
+                .labeled("inner")
+                .labeled("retry"),
+            PartialOp::Panic("boom").labeled("body"),
+        ];
+        let mut partial_write = crate::PartialWrite::new(Vec::new(), ops);
+        partial_write.record_ops();
+        assert_eq!(partial_write.write(b"ab").unwrap(), 1);
+        for _ in 0..2 {
+            let err = partial_write.write(b"b").unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+            assert!(err.to_string().ends_with(", in 'retry'"), "{}", err);
+        }
+        let labels: Vec<_> = partial_write
+            .applied_ops()
+            .iter()
+            .map(|applied| applied.label)
+            .collect();
+        assert_eq!(labels, [Some("header"), Some("retry"), Some("retry")]);
+        assert!(partial_write.applied_ops()[1].op.is_error());
+
+        let payload =
+            panic::catch_unwind(panic::AssertUnwindSafe(|| partial_write.write(b"b"))).unwrap_err();
+        assert_eq!(
+            payload.downcast_ref::<String>().map(String::as_str),
+            Some("boom, in 'body'")
+        );
+    }
+
+    #[test]
+    fn test_err_times() {
+        let ops = vec![
+            PartialOp::ErrTimes(io::ErrorKind::TimedOut, 0),
+            PartialOp::ErrTimes(io::ErrorKind::TimedOut, 3),
+            PartialOp::Limited(1),
+        ];
+        let mut engine = OpEngine::new(ops);
+        let mut run = || engine.run(Ok, 4, "error");
+        for _ in 0..2 {
+            assert_eq!(run().unwrap_err().kind(), io::ErrorKind::TimedOut);
+        }
+
+        // The rest of the repeat is returned as unreached.
+        let unreached: Vec<_> = engine.into_ops().collect();
+        assert!(matches!(
+            unreached[..],
+            [
+                PartialOp::ErrTimes(io::ErrorKind::TimedOut, 1),
+                PartialOp::Limited(1)
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_static_ops() {
+        static OPS: [PartialOp; 3] = [
+            PartialOp::Limited(1),
+            PartialOp::Unlimited,
+            PartialOp::Limited(2),
+        ];
+
+        // The allocations are counted in tests/alloc.rs.
+        let mut ops = StaticOps::new(&OPS);
+        ops.next();
+        assert_eq!(ops.len(), 2);
+        let engine = OpEngine::new(ops);
+        let unreached: Vec<_> = engine.into_ops().collect();
+        assert!(matches!(
+            unreached[..],
+            [PartialOp::Unlimited, PartialOp::Limited(2)]
+        ));
+    }
+
+    #[test]
+    fn test_vec_ops() {
+        let mut engine = OpEngine::new(vec![PartialOp::Limited(2), PartialOp::Unlimited]);
+        assert!(matches!(
+            engine.ops.as_mut().map(Exclusive::get_mut),
+            Some(Ops::Vec(_))
+        ));
+        engine.set_max_op_size(3);
+        let mut run = || engine.run(Ok, 4, "error");
+        assert_eq!(run().unwrap(), Some(2));
+        assert_eq!(run().unwrap(), Some(3));
+        // The schedule has run out, but max_op_size still applies.
+        assert_eq!(run().unwrap(), Some(3));
+
+        engine.replace(std::iter::once(PartialOp::Limited(1)));
+        assert!(matches!(
+            engine.ops.as_mut().map(Exclusive::get_mut),
+            Some(Ops::Boxed(_))
+        ));
+        engine.replace(vec![PartialOp::ErrTimes(io::ErrorKind::Other, 2)]);
+        assert!(engine.run(|_| Ok(()), 4, "error").is_err());
+        assert!(!engine.is_exhausted(), "an ErrTimes is partway done");
+        let unreached: Vec<_> = engine.into_ops().collect();
+        assert!(matches!(
+            unreached[..],
+            [PartialOp::ErrTimes(io::ErrorKind::Other, 1)]
+        ));
+    }
+
+    #[test]
+    fn test_byte_by_byte() {
+        use std::io::Read;
+
+        assert_eq!(std::mem::size_of_val(&byte_by_byte_ops()), 0);
+        let mut partial_read = crate::PartialRead::byte_by_byte(&b"abc"[..]);
+        let mut buf = [0; 3];
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 1);
+        assert_eq!(partial_read.read(&mut buf[1..]).unwrap(), 1);
+        assert_eq!(partial_read.read(&mut buf[2..]).unwrap(), 1);
+        assert_eq!(&buf, b"abc");
+    }
+}
@@ -0,0 +1,37 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+/// A wrapper that makes a `Send` value `Sync` by only allowing access to it through `&mut self`.
+///
+/// Sharing a `&Exclusive<T>` across threads is safe because nothing can be done with it: the
+/// value is only ever reached through a unique reference, which can't be shared. This lets
+/// wrappers hold boxed iterators and futures that are only `Send`, while still being `Sync`.
+pub(crate) struct Exclusive<T: ?Sized> {
+    inner: T,
+}
+
+impl<T> Exclusive<T> {
+    #[inline]
+    pub(crate) fn new(inner: T) -> Self {
+        Exclusive { inner }
+    }
+
+    #[inline]
+    pub(crate) fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: ?Sized> Exclusive<T> {
+    #[inline]
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+// SAFETY: no method gives access to the inner value through a shared reference.
+unsafe impl<T: ?Sized + Send> Sync for Exclusive<T> {}
@@ -0,0 +1,169 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! A minimal executor that counts polls and wakes.
+//!
+//! Given an op schedule, it's often useful to assert how many times a future is polled before it
+//! completes. [`run_and_count`] drives a future to completion on the current thread and returns
+//! [`PollStats`] along with its output.
+//!
+//! Wakes are split up into wakes issued by partial-io wrappers (through
+//! `PartialOp::Err(WouldBlock)`) while the future was being polled, and all other wakes. Other
+//! wakes include wakes from other threads, timers and channels, as well as wakes issued by the
+//! code under test.
+//!
+//! Available with the `futures03` feature.
+//!
+//! # Examples
+//!
+//! ```rust
+//! # #[cfg(feature = "futures03")]
+//! use futures::{io::Cursor, prelude::*};
+//! # #[cfg(feature = "futures03")]
+//! use partial_io::{executor::run_and_count, PartialAsyncRead, PartialOp};
+//! # #[cfg(feature = "futures03")]
+//! use std::io;
+//!
+//! # #[cfg(feature = "futures03")]
+//! # fn main() {
+//! let ops = vec![
+//!     PartialOp::Err(io::ErrorKind::WouldBlock),
+//!     PartialOp::Limited(2),
+//!     PartialOp::Err(io::ErrorKind::WouldBlock),
+//! ];
+//! let mut reader = PartialAsyncRead::new(Cursor::new(b"abcd".to_vec()), ops);
+//! let fut = async {
+//!     let mut buf = [0; 4];
+//!     reader.read_exact(&mut buf).await.unwrap();
+//!     buf
+//! };
+//!
+//! let (buf, stats) = run_and_count(fut);
+//! assert_eq!(&buf, b"abcd");
+//! assert_eq!(stats.polls, 3);
+//! assert_eq!(stats.partial_io_wakes, 2);
+//! assert_eq!(stats.other_wakes, 0);
+//! # }
+//!
+//! # #[cfg(not(feature = "futures03"))]
+//! # fn main() {
+//! #     assert!(true, "dummy test");
+//! # }
+//! ```
+//!
+//! [`run_and_count`]: fn.run_and_count.html
+//! [`PollStats`]: struct.PollStats.html
+
+use crate::futures_util::wakes_issued;
+use futures::task::{waker, ArcWake};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    thread::{self, Thread},
+};
+
+/// Poll and wake counts for a future driven by [`run_and_count`].
+///
+/// [`run_and_count`]: fn.run_and_count.html
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct PollStats {
+    /// The number of times the future was polled, including the final poll.
+    pub polls: usize,
+    /// The number of wakes issued by partial-io wrappers.
+    pub partial_io_wakes: usize,
+    /// The number of wakes from any other source.
+    pub other_wakes: usize,
+}
+
+/// Drives `fut` to completion on the current thread, returning its output and the number of
+/// polls and wakes.
+///
+/// See [the module level documentation](index.html) for more.
+pub fn run_and_count<F: Future>(fut: F) -> (F::Output, PollStats) {
+    let mut fut = Box::pin(fut);
+    let signal = Arc::new(Signal {
+        thread: thread::current(),
+        woken: AtomicBool::new(false),
+        wakes: AtomicUsize::new(0),
+    });
+    let waker = waker(signal.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut stats = PollStats::default();
+
+    loop {
+        let before = wakes_issued();
+        stats.polls += 1;
+        let res = Pin::new(&mut fut).poll(&mut cx);
+        stats.partial_io_wakes += wakes_issued() - before;
+        if let Poll::Ready(output) = res {
+            let wakes = signal.wakes.load(Ordering::SeqCst);
+            stats.other_wakes = wakes.saturating_sub(stats.partial_io_wakes);
+            return (output, stats);
+        }
+
+        while !signal.woken.swap(false, Ordering::SeqCst) {
+            thread::park();
+        }
+    }
+}
+
+/// Drives `fut` to completion on the current thread, returning the number of times it was
+/// polled.
+pub fn poll_count<F: Future>(fut: F) -> usize {
+    run_and_count(fut).1.polls
+}
+
+struct Signal {
+    thread: Thread,
+    woken: AtomicBool,
+    wakes: AtomicUsize,
+}
+
+impl ArcWake for Signal {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.wakes.fetch_add(1, Ordering::SeqCst);
+        arc_self.woken.store(true, Ordering::SeqCst);
+        arc_self.thread.unpark();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::future;
+
+    #[test]
+    fn test_other_wakes() {
+        let mut spawned = false;
+        let fut = future::poll_fn(|cx| {
+            if spawned {
+                return Poll::Ready(());
+            }
+            spawned = true;
+            let waker = cx.waker().clone();
+            thread::spawn(move || waker.wake());
+            Poll::Pending
+        });
+
+        let ((), stats) = run_and_count(fut);
+        assert_eq!(
+            stats,
+            PollStats {
+                polls: 2,
+                partial_io_wakes: 0,
+                other_wakes: 1,
+            }
+        );
+        assert_eq!(poll_count(future::ready(())), 1);
+    }
+}
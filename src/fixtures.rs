@@ -0,0 +1,414 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Readers and writers that are useful as test fixtures.
+//!
+//! Combined with the wrappers in this crate, these make tests deterministic from end to end
+//! without storing large fixture files.
+//!
+//! [`CountingBytes`] and [`RepeatingPattern`] produce data whose content makes offset mistakes
+//! visible: if a chunk is duplicated or dropped, the failure output shows exactly where.
+//!
+//! [`HashingWriter`] records the length and checksum of everything written to it, so the input
+//! and output of large-volume tests can be compared without buffering them.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use partial_io::{fixtures::DataGen, PartialOp, PartialRead};
+//! use std::io::Read;
+//!
+//! let ops = std::iter::repeat(PartialOp::Limited(7));
+//! let mut partial_read = PartialRead::new(DataGen::new(42, 1000), ops);
+//! let mut out = vec![];
+//! partial_read.read_to_end(&mut out).unwrap();
+//!
+//! let mut expected = vec![];
+//! DataGen::new(42, 1000).read_to_end(&mut expected).unwrap();
+//! assert_eq!(out, expected);
+//! ```
+//!
+//! [`CountingBytes`]: struct.CountingBytes.html
+//! [`RepeatingPattern`]: struct.RepeatingPattern.html
+//! [`HashingWriter`]: struct.HashingWriter.html
+
+use std::io::{self, Read, Write};
+
+/// A reader that produces `len` reproducible pseudo-random bytes.
+///
+/// The same `seed` always produces the same bytes, regardless of how reads are split up. This is
+/// a better fixture than `io::repeat` for compression or framing code, which can hide bugs when
+/// every byte is identical.
+///
+/// The bytes are generated with SplitMix64: they are not suitable for cryptographic purposes.
+#[derive(Clone, Debug)]
+pub struct DataGen {
+    state: u64,
+    // The bytes of the current word that haven't been returned yet.
+    word: [u8; 8],
+    word_pos: usize,
+    remaining: u64,
+}
+
+impl DataGen {
+    /// Creates a new `DataGen` that produces `len` bytes derived from `seed`.
+    pub fn new(seed: u64, len: u64) -> Self {
+        DataGen {
+            state: seed,
+            word: [0; 8],
+            word_pos: 8,
+            remaining: len,
+        }
+    }
+
+    /// Returns the number of bytes left to read.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        if self.word_pos == self.word.len() {
+            self.word = splitmix64(&mut self.state).to_le_bytes();
+            self.word_pos = 0;
+        }
+        let byte = self.word[self.word_pos];
+        self.word_pos += 1;
+        byte
+    }
+}
+
+impl Read for DataGen {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = read_len(buf, self.remaining);
+        for byte in &mut buf[..len] {
+            *byte = self.next_byte();
+        }
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+}
+
+// Advances a SplitMix64 generator, returning the next word.
+pub(crate) fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// A reader that produces `len` bytes counting up from 0: `0, 1, 2, ..., 255, 0, 1, ...`.
+///
+/// The byte at offset `n` is `n % 256`, so a duplicated or dropped chunk shows up as a break in
+/// the sequence.
+#[derive(Clone, Debug)]
+pub struct CountingBytes {
+    pos: u64,
+    len: u64,
+}
+
+impl CountingBytes {
+    /// Creates a new `CountingBytes` that produces `len` bytes.
+    pub fn new(len: u64) -> Self {
+        CountingBytes { pos: 0, len }
+    }
+
+    /// Returns the number of bytes left to read.
+    pub fn remaining(&self) -> u64 {
+        self.len - self.pos
+    }
+}
+
+impl Read for CountingBytes {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = read_len(buf, self.remaining());
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = (self.pos + i as u64) as u8;
+        }
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+/// A reader that produces `len` bytes by repeating `pattern`.
+///
+/// For example, with a pattern like `b"0123456789"`, the digit at each position shows the offset
+/// modulo 10. A pattern with a marker, such as `b"|......."`, makes chunk boundaries easy to spot.
+#[derive(Clone, Debug)]
+pub struct RepeatingPattern<'a> {
+    pattern: &'a [u8],
+    pos: u64,
+    len: u64,
+}
+
+impl<'a> RepeatingPattern<'a> {
+    /// Creates a new `RepeatingPattern` that produces `len` bytes of `pattern`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` is empty and `len` is non-zero.
+    pub fn new(pattern: &'a [u8], len: u64) -> Self {
+        assert!(
+            !pattern.is_empty() || len == 0,
+            "RepeatingPattern requires a non-empty pattern"
+        );
+        RepeatingPattern {
+            pattern,
+            pos: 0,
+            len,
+        }
+    }
+
+    /// Returns the number of bytes left to read.
+    pub fn remaining(&self) -> u64 {
+        self.len - self.pos
+    }
+}
+
+impl<'a> Read for RepeatingPattern<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = read_len(buf, self.remaining());
+        if len == 0 {
+            return Ok(0);
+        }
+        let pattern_len = self.pattern.len() as u64;
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = self.pattern[((self.pos + i as u64) % pattern_len) as usize];
+        }
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+/// A writer that computes the length and a checksum of everything written to it.
+///
+/// The checksum only depends on the bytes written, not on how the writes were split up, so it can
+/// be compared with the checksum of the input. It is 64-bit FNV-1a, which is not suitable for
+/// cryptographic purposes.
+///
+/// With the `futures03` and `tokio1` features, `HashingWriter` also implements `AsyncWrite`.
+///
+/// # Examples
+///
+/// ```rust
+/// use partial_io::{fixtures::{DataGen, HashingWriter}, PartialOp, PartialWrite};
+/// use std::io;
+///
+/// let mut input = HashingWriter::new();
+/// io::copy(&mut DataGen::new(7, 1 << 20), &mut input).unwrap();
+///
+/// let ops = std::iter::repeat(PartialOp::Limited(1460));
+/// let mut partial_write = PartialWrite::new(HashingWriter::new(), ops);
+/// io::copy(&mut DataGen::new(7, 1 << 20), &mut partial_write).unwrap();
+///
+/// assert_eq!(partial_write.get_ref(), &input);
+/// assert_eq!(input.len(), 1 << 20);
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HashingWriter {
+    len: u64,
+    checksum: u64,
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+impl HashingWriter {
+    /// Creates a new `HashingWriter` that hasn't been written to.
+    pub fn new() -> Self {
+        HashingWriter {
+            len: 0,
+            checksum: FNV_OFFSET_BASIS,
+        }
+    }
+
+    /// Returns the checksum of `data`, as computed by a `HashingWriter` that `data` is written to.
+    pub fn checksum_of(data: &[u8]) -> u64 {
+        let mut writer = Self::new();
+        writer.update(data);
+        writer.checksum()
+    }
+
+    /// Returns the number of bytes written so far.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true if no bytes have been written so far.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the checksum of the bytes written so far.
+    pub fn checksum(&self) -> u64 {
+        self.checksum
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        for &byte in buf {
+            self.checksum = (self.checksum ^ u64::from(byte)).wrapping_mul(FNV_PRIME);
+        }
+        self.len += buf.len() as u64;
+    }
+}
+
+impl Default for HashingWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "futures03")]
+mod futures_impl {
+    use super::HashingWriter;
+
+    use futures::io::AsyncWrite;
+    use std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    impl AsyncWrite for HashingWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().update(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+#[cfg(feature = "tokio1")]
+mod tokio_impl {
+    use super::HashingWriter;
+
+    use std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::io::AsyncWrite;
+
+    impl AsyncWrite for HashingWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().update(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+/// Returns the number of bytes to fill in `buf`, given the number of bytes remaining.
+fn read_len(buf: &[u8], remaining: u64) -> usize {
+    if (buf.len() as u64) < remaining {
+        buf.len()
+    } else {
+        remaining as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{PartialOp, PartialRead};
+
+    #[test]
+    fn test_data_gen() {
+        let mut expected = vec![];
+        DataGen::new(1, 100).read_to_end(&mut expected).unwrap();
+        assert_eq!(expected.len(), 100);
+        // Not all the same byte.
+        assert!(expected.iter().any(|&b| b != expected[0]));
+
+        let ops = vec![
+            PartialOp::Limited(3),
+            PartialOp::Limited(1),
+            PartialOp::Limited(13),
+        ];
+        let mut partial_read = PartialRead::new(DataGen::new(1, 100), ops);
+        let mut out = vec![];
+        partial_read.read_to_end(&mut out).unwrap();
+        assert_eq!(out, expected);
+
+        let mut other = vec![];
+        DataGen::new(2, 100).read_to_end(&mut other).unwrap();
+        assert_ne!(other, expected);
+    }
+
+    #[test]
+    fn test_patterns() {
+        let ops = vec![PartialOp::Limited(100), PartialOp::Limited(200)];
+        let mut partial_read = PartialRead::new(CountingBytes::new(300), ops);
+        let mut out = vec![];
+        partial_read.read_to_end(&mut out).unwrap();
+        assert_eq!(out.len(), 300);
+        assert!(out.iter().enumerate().all(|(i, &b)| b == i as u8));
+
+        let ops = vec![PartialOp::Limited(4), PartialOp::Limited(1)];
+        let mut partial_read = PartialRead::new(RepeatingPattern::new(b"abc", 8), ops);
+        let mut out = vec![];
+        partial_read.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"abcabcab");
+
+        let mut empty = RepeatingPattern::new(b"", 0);
+        assert_eq!(empty.read(&mut [0; 4]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_hashing_writer() {
+        let mut data = vec![];
+        DataGen::new(3, 10_000).read_to_end(&mut data).unwrap();
+
+        let ops = vec![
+            PartialOp::Limited(1),
+            PartialOp::Err(io::ErrorKind::Interrupted),
+            PartialOp::Limited(999),
+        ];
+        let mut partial_write = crate::PartialWrite::new(HashingWriter::new(), ops);
+        partial_write.write_all(&data).unwrap();
+
+        let writer = partial_write.into_inner();
+        assert_eq!(writer.len(), 10_000);
+        assert_eq!(writer.checksum(), HashingWriter::checksum_of(&data));
+        assert_ne!(writer.checksum(), HashingWriter::checksum_of(&data[1..]));
+        assert!(HashingWriter::new().is_empty());
+    }
+}
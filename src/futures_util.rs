@@ -5,95 +5,914 @@
  * LICENSE file in the root directory of this source tree.
  */
 
-use crate::{make_ops, PartialOp};
+use crate::{
+    engine::fraction_of,
+    exclusive::Exclusive,
+    sync,
+    time::{Clock, DefaultClock, Sleep},
+    OpEngine, PartialOp,
+};
+use futures::{
+    ready,
+    task::{waker, ArcWake},
+};
 use std::{
-    cmp, io,
-    task::{Context, Poll},
+    cell::Cell,
+    collections::VecDeque,
+    fmt, io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
 };
 
-pub(crate) struct FuturesOps {
-    ops: Box<dyn Iterator<Item = PartialOp> + Send>,
+thread_local! {
+    // The number of wakes issued by partial-io on this thread, used to tell them apart from other
+    // wakes.
+    static WAKES_ISSUED: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Returns the number of wakes issued by partial-io wrappers on this thread so far.
+pub(crate) fn wakes_issued() -> usize {
+    WAKES_ISSUED.with(|wakes| wakes.get())
+}
+
+/// How async wrappers deliver the wake for `PartialOp::Err(WouldBlock)`, or whether they return
+/// the error instead.
+///
+/// Available with the `futures03` feature.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum WakeDelivery {
+    /// Wake the task with `wake_by_ref` on the polling thread, before returning `Poll::Pending`.
+    ///
+    /// This is the default.
+    #[default]
+    Inline,
+    /// Send the waker to a separate thread, which wakes the task after the given delay.
+    ///
+    /// This reproduces bugs that only show up when a wake arrives from another thread, possibly
+    /// while the task is still being polled. Not available on `wasm32-unknown-unknown`, which has
+    /// no threads.
+    Thread(Duration),
+    /// Don't wake the task, and return the `WouldBlock` error instead of `Poll::Pending`.
+    ///
+    /// This is for testing adapters over raw nonblocking sources, which are responsible for
+    /// translating `WouldBlock` into `Poll::Pending` themselves.
+    ReturnError,
+}
+
+/// The engine that applies `PartialOp`s to poll methods, for use in custom async wrappers.
+///
+/// This is what the async wrappers in this crate use internally. Each call to a poll method
+/// consumes the next operation:
+///
+/// * `PartialOp::Limited(n)` calls the callback with `Some(n)`, capped to the number of bytes
+///   remaining.
+/// * `PartialOp::Unlimited` (and the end of the iterator) calls the callback with `None`.
+/// * `PartialOp::Err(WouldBlock)` wakes the task and returns `Poll::Pending`, unless the wake
+///   delivery is `WakeDelivery::ReturnError`.
+/// * `PartialOp::Err(Interrupted)` is retried with the next operation, up to the limit set with
+///   `set_max_interrupted_retries`.
+/// * Any other `PartialOp::Err` returns an `io::Error` of that kind.
+/// * `PartialOp::ErrTimes(kind, n)` is handled like `PartialOp::Err(kind)` for the next `n` polls.
+/// * `PartialOp::OsErr` and `PartialOp::ErrWith` are handled like `PartialOp::Err` with the kind
+///   of the error they create, and return that error.
+/// * `PartialOp::Delay(duration)` returns `Poll::Pending` until the delay has elapsed, then calls
+///   the callback with `None`.
+///
+/// As with `OpEngine`, limits are capped to the engine's `max_op_size`. If
+/// [`set_call_rate_limit`] was called, a poll that would go over the limit returns
+/// `Poll::Pending` until it can take its operation.
+///
+/// Wrappers built on `FuturesOps` behave consistently with the ones in this crate.
+/// Those return their `FuturesOps` from `ops` and `ops_mut`, which is how their clock, wake
+/// delivery and checks are set.
+///
+/// Available with the `futures03` feature.
+///
+/// # Examples
+///
+/// A wrapper for a custom datagram trait:
+///
+/// ```rust
+/// # #[cfg(feature = "futures03")]
+/// use partial_io::{FuturesOps, PartialOp};
+/// # #[cfg(feature = "futures03")]
+/// use std::{io, pin::Pin, task::{Context, Poll}};
+///
+/// # #[cfg(feature = "futures03")]
+/// trait AsyncDatagram {
+///     fn poll_send(self: Pin<&mut Self>, cx: &mut Context, datagram: &[u8])
+///         -> Poll<io::Result<usize>>;
+/// }
+///
+/// # #[cfg(feature = "futures03")]
+/// struct PartialDatagram<D> {
+///     inner: D,
+///     ops: FuturesOps,
+/// }
+///
+/// # #[cfg(feature = "futures03")]
+/// impl<D: AsyncDatagram + Unpin> AsyncDatagram for PartialDatagram<D> {
+///     fn poll_send(self: Pin<&mut Self>, cx: &mut Context, datagram: &[u8])
+///         -> Poll<io::Result<usize>>
+///     {
+///         let this = self.get_mut();
+///         let inner = &mut this.inner;
+///         this.ops.poll_impl(
+///             cx,
+///             // Truncate the datagram to the limit.
+///             |cx, len| {
+///                 let len = len.unwrap_or(datagram.len());
+///                 Pin::new(inner).poll_send(cx, &datagram[..len])
+///             },
+///             datagram.len(),
+///             "error during poll_send, generated by partial-io",
+///         )
+///     }
+/// }
+/// #
+/// # #[cfg(feature = "futures03")]
+/// # fn main() {
+/// #     let _ = PartialDatagram { inner: (), ops: FuturesOps::new(vec![PartialOp::Unlimited]) };
+/// # }
+/// #
+/// # #[cfg(not(feature = "futures03"))]
+/// # fn main() {
+/// #     assert!(true, "dummy test");
+/// # }
+/// ```
+///
+/// [`set_call_rate_limit`]: struct.FuturesOps.html#method.set_call_rate_limit
+pub struct FuturesOps {
+    engine: OpEngine,
+    // `None` for `DefaultClock`, so that creating an instance doesn't allocate.
+    clock: Option<Arc<dyn Clock>>,
+    // A delay started by `PartialOp::Delay` that hasn't completed yet.
+    delay: Option<Exclusive<Sleep>>,
+    wakes_issued: usize,
+    wake_delivery: WakeDelivery,
+    // The waker that was woken by the last `WouldBlock`, until the next poll.
+    last_woken: Option<Waker>,
+    // The waker passed to the last poll, if it returned `Poll::Pending`.
+    registered: Option<Waker>,
+    // The number of polls that returned `Poll::Pending`, and the number of those whose waker won't
+    // wake the previous one's task.
+    pendings: usize,
+    waker_changes: usize,
+    stale_retries: usize,
+    max_interrupted_retries: Option<usize>,
+    call_rate_limit: Option<Box<CallRateLimit>>,
+    watchdog: Option<Watchdog>,
+    busy_polls: Option<Box<BusyPolls>>,
+    waker_leaks: Option<WakerLeaks>,
+}
+
+// The state of a limit set with `FuturesOps::set_call_rate_limit`.
+struct CallRateLimit {
+    calls: usize,
+    per: Duration,
+    // When each of the calls within the last `per` was made, oldest first.
+    window: VecDeque<Instant>,
+    // A wait for the oldest call to fall out of the window that hasn't completed yet.
+    wait: Option<Exclusive<Sleep>>,
+}
+
+// The state of a watchdog set with `FuturesOps::set_watchdog`.
+#[derive(Debug)]
+struct Watchdog {
+    max_idle_polls: usize,
+    // The number of consecutive polls that returned `Poll::Pending` for an injected `WouldBlock`.
+    idle_polls: usize,
+}
+
+// The state of busy-poll detection, enabled with `FuturesOps::set_busy_poll_detection`.
+#[derive(Default)]
+struct BusyPolls {
+    // The waker passed down during the last poll, which records whether it was woken.
+    flag: Option<Arc<WakeFlag>>,
+    // Whether the last poll returned `Poll::Pending`.
+    pending: bool,
+    count: usize,
+}
+
+impl BusyPolls {
+    /// Counts this poll if the task wasn't woken since the last `Poll::Pending`, and returns the
+    /// waker to pass down.
+    fn begin_poll(&mut self, cx: &Context) -> Waker {
+        if self.pending
+            && !self
+                .flag
+                .as_ref()
+                .is_some_and(|flag| flag.woken.load(Ordering::SeqCst))
+        {
+            self.count += 1;
+        }
+        // Reuse the waker while the task's waker stays the same, so that wakers passed down in
+        // different polls compare equal with `Waker::will_wake`.
+        let flag = match &self.flag {
+            Some(flag) if flag.waker.will_wake(cx.waker()) => flag.clone(),
+            _ => {
+                let flag = Arc::new(WakeFlag {
+                    woken: AtomicBool::new(false),
+                    waker: cx.waker().clone(),
+                });
+                self.flag = Some(flag.clone());
+                flag
+            }
+        };
+        flag.woken.store(false, Ordering::SeqCst);
+        waker(flag)
+    }
+}
+
+// The wakers passed to the inner object, enabled with `FuturesOps::set_waker_leak_detection`.
+#[derive(Default)]
+struct WakerLeaks {
+    // Wakers that weren't woken, and that the inner object may still hold clones of.
+    wakers: Vec<Arc<WakeFlag>>,
+}
+
+impl WakerLeaks {
+    /// Returns the waker to pass to the inner object.
+    fn waker(&mut self, cx: &Context) -> Waker {
+        // Reuse the waker while it wasn't woken and the task's waker stays the same, so that an
+        // inner object that keeps the waker from an earlier poll doesn't need to replace it.
+        let flag = match self.wakers.last() {
+            Some(flag)
+                if !flag.woken.load(Ordering::SeqCst) && flag.waker.will_wake(cx.waker()) =>
+            {
+                flag.clone()
+            }
+            _ => {
+                let flag = Arc::new(WakeFlag {
+                    woken: AtomicBool::new(false),
+                    waker: cx.waker().clone(),
+                });
+                self.wakers.push(flag.clone());
+                flag
+            }
+        };
+        // Forget the wakers that were woken, or that nothing else holds anymore.
+        self.wakers
+            .retain(|flag| !flag.woken.load(Ordering::SeqCst) && Arc::strong_count(flag) > 1);
+        waker(flag)
+    }
+
+    /// Returns the number of clones held outside this list of wakers that weren't woken.
+    fn outstanding(&self) -> usize {
+        self.wakers
+            .iter()
+            .filter(|flag| !flag.woken.load(Ordering::SeqCst))
+            .map(|flag| Arc::strong_count(flag) - 1)
+            .sum()
+    }
+}
+
+// Wakes the task, recording that it was woken.
+struct WakeFlag {
+    woken: AtomicBool,
+    waker: Waker,
+}
+
+impl ArcWake for WakeFlag {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.woken.store(true, Ordering::SeqCst);
+        arc_self.waker.wake_by_ref();
+    }
+}
+
+impl fmt::Debug for FuturesOps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FuturesOps")
+            .field("clock", &self.clock())
+            .field("wakes_issued", &self.wakes_issued)
+            .field("wake_delivery", &self.wake_delivery)
+            .field("max_interrupted_retries", &self.max_interrupted_retries)
+            .field(
+                "call_rate_limit",
+                &self
+                    .call_rate_limit
+                    .as_ref()
+                    .map(|limit| (limit.calls, limit.per)),
+            )
+            .field("watchdog", &self.watchdog)
+            .field(
+                "busy_polls",
+                &self.busy_polls.as_ref().map(|busy| busy.count),
+            )
+            .field("outstanding_wakers", &self.outstanding_wakers())
+            .finish()
+    }
 }
 
 impl FuturesOps {
-    /// Creates a new instance of `TokioOps`.
-    pub(crate) fn new<I>(iter: I) -> Self
+    /// Creates a new instance of `FuturesOps` with the specified `PartialOp`s.
+    pub fn new<I>(iter: I) -> Self
     where
         I: IntoIterator<Item = PartialOp> + 'static,
         I::IntoIter: Send,
     {
+        Self::from_engine(OpEngine::new(iter))
+    }
+
+    /// Creates a new instance of `FuturesOps` in passthrough mode, which forwards every call as-is.
+    ///
+    /// See `OpEngine::passthrough` for more.
+    pub fn passthrough() -> Self {
+        Self::from_engine(OpEngine::passthrough())
+    }
+
+    /// Creates a new instance of `FuturesOps` that consumes operations from `engine`.
+    pub fn from_engine(engine: OpEngine) -> Self {
         Self {
-            ops: make_ops(iter),
+            engine,
+            clock: None,
+            delay: None,
+            wakes_issued: 0,
+            wake_delivery: WakeDelivery::Inline,
+            last_woken: None,
+            registered: None,
+            pendings: 0,
+            waker_changes: 0,
+            stale_retries: 0,
+            max_interrupted_retries: None,
+            call_rate_limit: None,
+            watchdog: None,
+            busy_polls: None,
+            waker_leaks: None,
         }
     }
 
     /// Replaces ops with a new iterator.
-    pub(crate) fn replace<I>(&mut self, iter: I)
+    pub fn replace<I>(&mut self, iter: I)
     where
         I: IntoIterator<Item = PartialOp> + 'static,
         I::IntoIter: Send,
     {
-        self.ops = make_ops(iter)
+        self.engine.replace(iter)
+    }
+
+    /// Consumes this instance, returning the underlying engine.
+    pub fn into_engine(self) -> OpEngine {
+        self.engine
+    }
+
+    /// Returns a shared reference to the underlying engine.
+    pub fn engine(&self) -> &OpEngine {
+        &self.engine
+    }
+
+    /// Returns the underlying engine.
+    pub fn engine_mut(&mut self) -> &mut OpEngine {
+        &mut self.engine
+    }
+
+    /// Replaces the clock used for `PartialOp::Delay`.
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Some(Arc::new(clock));
+    }
+
+    fn clock(&self) -> &dyn Clock {
+        match &self.clock {
+            Some(clock) => &**clock,
+            None => &DefaultClock,
+        }
+    }
+
+    /// Sets how the wake for `PartialOp::Err(WouldBlock)` is delivered.
+    pub fn set_wake_delivery(&mut self, wake_delivery: WakeDelivery) {
+        self.wake_delivery = wake_delivery;
+    }
+
+    /// Sets the number of `PartialOp::Err(Interrupted)` errors retried within a single poll.
+    ///
+    /// Once `max` retries have been made, the next `Interrupted` error is returned to the caller.
+    /// `None`, the default, retries without a bound.
+    pub fn set_max_interrupted_retries(&mut self, max: Option<usize>) {
+        self.max_interrupted_retries = max;
+    }
+
+    /// Limits polls to at most `calls` operations in any period of `per`, measured by the clock.
+    ///
+    /// Once the limit is reached, polls return `Poll::Pending` until the oldest call within the
+    /// period is older than `per`, much like a socket that only becomes ready so often. This
+    /// limits how often the underlying object is polled, independently of how many bytes each
+    /// poll processes. Every poll that takes an operation counts, including ones that return an
+    /// error, or `Poll::Pending` for `PartialOp::Err(WouldBlock)`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `calls` is 0.
+    pub fn set_call_rate_limit(&mut self, calls: usize, per: Duration) {
+        assert!(
+            calls > 0,
+            "the call rate limit must allow at least one call"
+        );
+        self.call_rate_limit = Some(Box::new(CallRateLimit {
+            calls,
+            per,
+            window: VecDeque::with_capacity(calls),
+            wait: None,
+        }));
+    }
+
+    /// Panics if `max_idle_polls` consecutive polls return `Poll::Pending` because of
+    /// `PartialOp::Err(WouldBlock)`, or turns the watchdog off if `max_idle_polls` is `None`.
+    ///
+    /// Such polls transfer no bytes, and the task is only woken again by partial-io itself. A
+    /// poll that returns `Poll::Ready` resets the count, and so does one that returns
+    /// `Poll::Pending` for any other reason, such as the inner object or a `PartialOp::Delay`,
+    /// since something outside partial-io will wake the task. A long enough run of injected
+    /// `Poll::Pending`s means the code under test is stuck retrying without making progress.
+    ///
+    /// Instead of spinning until the test times out, the panic message describes the state of
+    /// the wrapper: the current method, the operations taken and the bytes processed, the
+    /// operations that are left, and the last wake.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_idle_polls` is `Some(0)`.
+    pub fn set_watchdog(&mut self, max_idle_polls: Option<usize>) {
+        assert!(
+            max_idle_polls != Some(0),
+            "the watchdog must allow at least one idle poll"
+        );
+        self.watchdog = max_idle_polls.map(|max_idle_polls| Watchdog {
+            max_idle_polls,
+            idle_polls: 0,
+        });
+    }
+
+    /// Updates the watchdog after a poll, panicking if it has fired.
+    ///
+    /// `wakes_before` is the number of wakes issued before the poll.
+    fn check_watchdog<T>(&mut self, res: &Poll<T>, wakes_before: usize) {
+        let watchdog = match &mut self.watchdog {
+            Some(watchdog) => watchdog,
+            None => return,
+        };
+        if res.is_ready() || self.wakes_issued == wakes_before {
+            watchdog.idle_polls = 0;
+            return;
+        }
+        watchdog.idle_polls += 1;
+        if watchdog.idle_polls < watchdog.max_idle_polls {
+            return;
+        }
+        let idle_polls = watchdog.idle_polls;
+        panic!(
+            "partial-io watchdog: {} consecutive polls returned Poll::Pending because of \
+             PartialOp::Err(WouldBlock), with no progress and no wake from outside partial-io\n\
+             {}\nlast wake: {:?} by partial-io, {} wakes issued, {} retries with a stale waker",
+            idle_polls,
+            self.engine.diagnostics(),
+            self.wake_delivery,
+            self.wakes_issued,
+            self.stale_retries
+        );
+    }
+
+    /// Enables or disables counting polls made without a wake since the last `Poll::Pending`.
+    ///
+    /// A future that gets `Poll::Pending` from an IO object should wait until the task is woken
+    /// before polling it again. One that polls again right away is spinning: it still works,
+    /// which is why tests that inject `Poll::Pending` pass, but it burns CPU instead of waiting for
+    /// readiness. When enabled, every poll passes a waker down that records whether it was woken,
+    /// whether by partial-io, by the inner object or by a clock, and polls that come after a
+    /// `Poll::Pending` without such a wake are counted in [`busy_polls`].
+    ///
+    /// This allocates a waker whenever the task's waker changes.
+    ///
+    /// [`busy_polls`]: struct.FuturesOps.html#method.busy_polls
+    pub fn set_busy_poll_detection(&mut self, enabled: bool) {
+        self.busy_polls = if enabled { Some(Box::default()) } else { None };
+    }
+
+    /// Returns the number of polls made without a wake since the last `Poll::Pending`, while
+    /// busy-poll detection was enabled.
+    pub fn busy_polls(&self) -> usize {
+        self.busy_polls.as_ref().map_or(0, |busy| busy.count)
+    }
+
+    /// Panics if any poll was made without a wake since the last `Poll::Pending`, while busy-poll
+    /// detection was enabled.
+    ///
+    /// `wrapper` is the name of the wrapper, used in the panic message.
+    #[track_caller]
+    pub fn assert_no_busy_polls(&self, wrapper: &str) {
+        assert_eq!(
+            self.busy_polls(),
+            0,
+            "{} was polled again after Poll::Pending without being woken up ({} times), so the \
+             caller is busy-polling instead of waiting for readiness",
+            wrapper,
+            self.busy_polls(),
+        );
+    }
+
+    /// Enables or disables tracking the wakers passed to the inner object.
+    ///
+    /// An object that clones the task's waker should either wake it or drop it. A clone that's
+    /// neither, for example one stored in a registration that's never cleaned up, keeps whatever
+    /// the waker references alive, which can be the state of a whole connection. When enabled,
+    /// every poll of the inner object is passed a waker that's tracked until it's woken or every
+    /// clone of it is dropped, and the clones still held are counted by [`outstanding_wakers`].
+    ///
+    /// This allocates a waker whenever the task's waker changes, or after the last one was woken.
+    ///
+    /// [`outstanding_wakers`]: struct.FuturesOps.html#method.outstanding_wakers
+    pub fn set_waker_leak_detection(&mut self, enabled: bool) {
+        self.waker_leaks = if enabled {
+            Some(WakerLeaks::default())
+        } else {
+            None
+        };
+    }
+
+    /// Returns the number of clones of wakers passed to the inner object that were neither woken
+    /// nor dropped, while waker leak detection was enabled.
+    ///
+    /// An inner object that returned `Poll::Pending` holds on to a waker until it wakes the task,
+    /// so this is only expected to be 0 once every poll has completed.
+    pub fn outstanding_wakers(&self) -> usize {
+        self.waker_leaks.as_ref().map_or(0, WakerLeaks::outstanding)
+    }
+
+    /// Panics if the inner object holds clones of wakers that were neither woken nor dropped,
+    /// while waker leak detection was enabled.
+    ///
+    /// Call this at the end of the test, once the inner object has no polls left to complete.
+    ///
+    /// `wrapper` is the name of the wrapper, used in the panic message.
+    #[track_caller]
+    pub fn assert_no_leaked_wakers(&self, wrapper: &str) {
+        assert_eq!(
+            self.outstanding_wakers(),
+            0,
+            "the object inside {} holds {} clones of wakers that were neither woken nor dropped",
+            wrapper,
+            self.outstanding_wakers(),
+        );
+    }
+
+    /// Returns the waker passed to the last poll, if it returned `Poll::Pending`.
+    ///
+    /// This is the waker that the wrapper or the inner object will wake once the poll can make
+    /// progress. It's `None` after a poll that returned `Poll::Ready`.
+    pub fn registered_waker(&self) -> Option<&Waker> {
+        self.registered.as_ref()
+    }
+
+    /// Returns the number of polls that returned `Poll::Pending`, and the number of those that
+    /// were passed a waker that won't wake the same task as the waker of the `Poll::Pending`
+    /// before.
+    ///
+    /// Combinators that wrap wakers, such as `FuturesUnordered`, pass a different waker on every
+    /// poll, so a change on its own isn't a bug.
+    pub fn waker_stats(&self) -> (usize, usize) {
+        (self.pendings, self.waker_changes)
+    }
+
+    /// Panics if the last poll returned `Poll::Pending` with a waker that won't wake the same task
+    /// as `waker`.
+    ///
+    /// Call this with the task's current waker, after polling the code under test. If it panics,
+    /// the code under test polled this wrapper with some other waker, typically a stale one kept
+    /// from an earlier poll, so the wake for this `Poll::Pending` won't reach the task. This
+    /// doesn't panic if the last poll returned `Poll::Ready`.
+    ///
+    /// This compares wakers with `Waker::will_wake`, so it's meant for code that passes its
+    /// task's waker through unchanged, such as hand-written futures and combinators like
+    /// `futures::join!`. Combinators that wrap wakers fail it even if they forward wakes
+    /// correctly.
+    ///
+    /// `wrapper` is the name of the wrapper, used in the panic message.
+    #[track_caller]
+    pub fn assert_waker_registered(&self, wrapper: &str, waker: &Waker) {
+        if let Some(registered) = &self.registered {
+            assert!(
+                registered.will_wake(waker),
+                "{} returned Poll::Pending to a poll with a waker that won't wake the current \
+                 task, so the task won't be woken ({} of {} Poll::Pending returns changed wakers)",
+                wrapper,
+                self.waker_changes,
+                self.pendings,
+            );
+        }
+    }
+
+    /// Returns the number of times the task was woken because of `PartialOp::Err(WouldBlock)`.
+    pub fn wakes_issued(&self) -> usize {
+        self.wakes_issued
+    }
+
+    /// Panics if any retry after a `WouldBlock` was made with a waker that wasn't woken up.
+    ///
+    /// `wrapper` is the name of the wrapper, used in the panic message.
+    #[track_caller]
+    pub fn assert_woken_before_retries(&self, wrapper: &str) {
+        assert_eq!(
+            self.stale_retries, 0,
+            "{} was polled again after Poll::Pending with a waker that wasn't woken up \
+             ({} times, out of {} wakes)",
+            wrapper, self.stale_retries, self.wakes_issued,
+        );
+    }
+
+    /// Wakes the task, recording that the wake came from partial-io.
+    fn wake(&mut self, cx: &mut Context) {
+        WAKES_ISSUED.with(|wakes| wakes.set(wakes.get() + 1));
+        self.wakes_issued += 1;
+        self.last_woken = Some(cx.waker().clone());
+        match self.wake_delivery {
+            // With ReturnError, the error is returned without calling this method.
+            WakeDelivery::Inline | WakeDelivery::ReturnError => cx.waker().wake_by_ref(),
+            WakeDelivery::Thread(delay) => {
+                let waker = cx.waker().clone();
+                sync::thread::spawn(move || {
+                    thread::sleep(delay);
+                    waker.wake();
+                });
+            }
+        }
+    }
+
+    /// Injects a `WouldBlock` that isn't part of the operations, the same way as
+    /// `PartialOp::Err(WouldBlock)`.
+    pub(crate) fn would_block<T>(
+        &mut self,
+        cx: &mut Context,
+        err_str: &'static str,
+    ) -> Poll<io::Result<T>> {
+        if self.wake_delivery == WakeDelivery::ReturnError {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::WouldBlock, err_str)));
+        }
+        self.wake(cx);
+        Poll::Pending
+    }
+
+    /// Records whether this poll is a retry with the waker that was woken up.
+    fn check_retry(&mut self, cx: &mut Context) {
+        if let Some(waker) = self.last_woken.take() {
+            if !waker.will_wake(cx.waker()) {
+                self.stale_retries += 1;
+            }
+        }
+    }
+
+    /// Polls the current delay, if any, clearing it once it has completed.
+    fn poll_delay(&mut self, cx: &mut Context) -> Poll<()> {
+        if let Some(delay) = &mut self.delay {
+            ready!(delay.get_mut().as_mut().poll(cx));
+            self.delay = None;
+        }
+        Poll::Ready(())
+    }
+
+    /// Starts a delay of `duration` and polls it once.
+    fn start_delay(&mut self, cx: &mut Context, duration: Duration) -> Poll<()> {
+        self.delay = Some(Exclusive::new(self.clock().sleep(duration)));
+        self.poll_delay(cx)
+    }
+
+    /// Waits until the call rate limit allows another call, and records the call.
+    fn poll_call_rate_limit(&mut self, cx: &mut Context) -> Poll<()> {
+        let clock: &dyn Clock = self.clock.as_deref().unwrap_or(&DefaultClock);
+        let limit = match &mut self.call_rate_limit {
+            Some(limit) => limit,
+            None => return Poll::Ready(()),
+        };
+        loop {
+            if let Some(wait) = &mut limit.wait {
+                ready!(wait.get_mut().as_mut().poll(cx));
+                limit.wait = None;
+            }
+            let now = clock.now();
+            while limit
+                .window
+                .front()
+                .is_some_and(|&at| now.saturating_duration_since(at) >= limit.per)
+            {
+                limit.window.pop_front();
+            }
+            if limit.window.len() < limit.calls {
+                limit.window.push_back(now);
+                return Poll::Ready(());
+            }
+            let remaining = limit.per - now.saturating_duration_since(limit.window[0]);
+            limit.wait = Some(Exclusive::new(clock.sleep(remaining)));
+        }
     }
 
     /// Helper for poll methods.
     ///
     /// `cb` is the callback that implements the actual logic. The second argument is `Some(n)` to
-    /// limit the number of bytes being written, or `None` for unlimited.
-    pub(crate) fn poll_impl<T>(
+    /// limit the number of bytes being written, or `None` for unlimited. `remaining` is the
+    /// maximum number of bytes the operation could process, and `err_str` is the message for
+    /// injected errors.
+    #[inline]
+    pub fn poll_impl<T>(
+        &mut self,
+        cx: &mut Context,
+        cb: impl FnOnce(&mut Context, Option<usize>) -> Poll<io::Result<T>>,
+        remaining: usize,
+        err_str: &'static str,
+    ) -> Poll<io::Result<T>> {
+        self.poll_tracked(cx, |this, cx| match this.leak_tracked_waker(cx) {
+            Some(waker) => this.poll_ops(
+                cx,
+                |_, limit| cb(&mut Context::from_waker(&waker), limit),
+                remaining,
+                err_str,
+            ),
+            None => this.poll_ops(cx, cb, remaining, err_str),
+        })
+    }
+
+    #[inline]
+    fn poll_ops<T>(
         &mut self,
         cx: &mut Context,
         cb: impl FnOnce(&mut Context, Option<usize>) -> Poll<io::Result<T>>,
         remaining: usize,
         err_str: &'static str,
     ) -> Poll<io::Result<T>> {
+        if self.engine.is_passthrough() {
+            return cb(cx, None);
+        }
+        self.check_retry(cx);
+        if self.delay.is_some() {
+            // A delay was started by an earlier call: finish it, then perform the operation.
+            ready!(self.poll_delay(cx));
+            return cb(cx, self.engine.forward(None, remaining));
+        }
+        if self.call_rate_limit.is_some() {
+            ready!(self.poll_call_rate_limit(cx));
+        }
+        if let Some(err) = self.engine.total_limit_reached(err_str) {
+            return Poll::Ready(Err(err));
+        }
+        let mut retries = 0;
         loop {
-            match self.ops.next() {
-                Some(PartialOp::Limited(n)) => {
-                    let len = cmp::min(n, remaining);
-                    break cb(cx, Some(len));
+            let err = match self.engine.next_op() {
+                Some(PartialOp::Limited(n)) | Some(PartialOp::Fragment(n)) => {
+                    break cb(cx, self.engine.forward(Some(n), remaining))
+                }
+                Some(PartialOp::LimitedFraction(num, den)) => {
+                    let n = fraction_of(num, den, remaining);
+                    break cb(cx, self.engine.forward(Some(n), remaining));
+                }
+                Some(PartialOp::Err(kind)) | Some(PartialOp::ErrTimes(kind, _)) => {
+                    self.engine.injected_error(kind, err_str)
+                }
+                Some(PartialOp::OsErr(code)) => io::Error::from_raw_os_error(code),
+                Some(PartialOp::ErrWith(factory)) => factory.make_error(),
+                Some(PartialOp::Delay(duration)) => {
+                    ready!(self.start_delay(cx, duration));
+                    break cb(cx, self.engine.forward(None, remaining));
+                }
+                Some(PartialOp::Panic(message)) => self.engine.injected_panic(message),
+                Some(PartialOp::Labeled(..)) => unreachable!("labels are removed by next_op"),
+                Some(PartialOp::Unlimited) | None => {
+                    break cb(cx, self.engine.forward(None, remaining))
                 }
-                Some(PartialOp::Err(kind)) => {
-                    if kind == io::ErrorKind::WouldBlock {
-                        // Async* instances must convert WouldBlock errors to Poll::Pending and
-                        // reschedule the task.
-                        cx.waker().wake_by_ref();
-                        break Poll::Pending;
-                    } else if kind == io::ErrorKind::Interrupted {
-                        // Async* instances must retry on Interrupted errors.
-                        continue;
-                    } else {
-                        break Poll::Ready(Err(io::Error::new(kind, err_str)));
-                    }
+            };
+            match err.kind() {
+                io::ErrorKind::WouldBlock if self.wake_delivery != WakeDelivery::ReturnError => {
+                    // Async* instances must convert WouldBlock errors to Poll::Pending and
+                    // reschedule the task.
+                    self.wake(cx);
+                    break Poll::Pending;
                 }
-                Some(PartialOp::Unlimited) | None => break cb(cx, None),
+                // Async* instances must retry on Interrupted errors.
+                io::ErrorKind::Interrupted
+                    if self.max_interrupted_retries.is_none_or(|max| retries < max) =>
+                {
+                    retries += 1;
+                    continue;
+                }
+                _ => break Poll::Ready(Err(err)),
             }
         }
     }
 
     /// Helper for poll methods that ignore the length specified in `PartialOp::Limited`.
-    pub(crate) fn poll_impl_no_limit<T>(
+    #[inline]
+    pub fn poll_impl_no_limit<T>(
+        &mut self,
+        cx: &mut Context,
+        cb: impl FnOnce(&mut Context) -> Poll<io::Result<T>>,
+        err_str: &'static str,
+    ) -> Poll<io::Result<T>> {
+        self.poll_tracked(cx, |this, cx| match this.leak_tracked_waker(cx) {
+            Some(waker) => {
+                this.poll_ops_no_limit(cx, |_| cb(&mut Context::from_waker(&waker)), err_str)
+            }
+            None => this.poll_ops_no_limit(cx, cb, err_str),
+        })
+    }
+
+    /// Returns the waker to pass to the inner object, if waker leak detection is enabled.
+    fn leak_tracked_waker(&mut self, cx: &Context) -> Option<Waker> {
+        self.waker_leaks.as_mut().map(|leaks| leaks.waker(cx))
+    }
+
+    /// Performs a poll with `f`, tracking it for the watchdog and busy-poll detection.
+    #[inline]
+    fn poll_tracked<T>(
+        &mut self,
+        cx: &mut Context,
+        f: impl FnOnce(&mut Self, &mut Context) -> Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        let wakes_before = self.wakes_issued;
+        let res = match self.busy_polls.take() {
+            Some(mut busy) => {
+                let waker = busy.begin_poll(cx);
+                let res = f(self, &mut Context::from_waker(&waker));
+                busy.pending = res.is_pending();
+                self.busy_polls = Some(busy);
+                res
+            }
+            None => f(self, cx),
+        };
+        self.check_watchdog(&res, wakes_before);
+        self.register(cx, &res);
+        res
+    }
+
+    /// Records the waker of a poll that returned `Poll::Pending`.
+    fn register<T>(&mut self, cx: &Context, res: &Poll<T>) {
+        if res.is_ready() {
+            self.registered = None;
+            return;
+        }
+        self.pendings += 1;
+        match &self.registered {
+            Some(registered) if registered.will_wake(cx.waker()) => {}
+            registered => {
+                self.waker_changes += registered.is_some() as usize;
+                self.registered = Some(cx.waker().clone());
+            }
+        }
+    }
+
+    #[inline]
+    fn poll_ops_no_limit<T>(
         &mut self,
         cx: &mut Context,
         cb: impl FnOnce(&mut Context) -> Poll<io::Result<T>>,
         err_str: &'static str,
     ) -> Poll<io::Result<T>> {
+        if self.engine.is_passthrough() {
+            return cb(cx);
+        }
+        self.check_retry(cx);
+        if self.delay.is_some() {
+            ready!(self.poll_delay(cx));
+            return cb(cx);
+        }
+        if self.call_rate_limit.is_some() {
+            ready!(self.poll_call_rate_limit(cx));
+        }
+        let mut retries = 0;
         loop {
-            match self.ops.next() {
-                Some(PartialOp::Err(kind)) => {
-                    if kind == io::ErrorKind::WouldBlock {
-                        // Async* instances must convert WouldBlock errors to Poll::Pending and
-                        // reschedule the task.
-                        cx.waker().wake_by_ref();
-                        break Poll::Pending;
-                    } else if kind == io::ErrorKind::Interrupted {
-                        // Async* instances must retry on interrupted errors.
-                        continue;
-                    } else {
-                        break Poll::Ready(Err(io::Error::new(kind, err_str)));
-                    }
+            let err = match self.engine.next_op() {
+                Some(PartialOp::Err(kind)) | Some(PartialOp::ErrTimes(kind, _)) => {
+                    self.engine.injected_error(kind, err_str)
+                }
+                Some(PartialOp::OsErr(code)) => io::Error::from_raw_os_error(code),
+                Some(PartialOp::ErrWith(factory)) => factory.make_error(),
+                Some(PartialOp::Delay(duration)) => {
+                    ready!(self.start_delay(cx, duration));
+                    break cb(cx);
+                }
+                Some(PartialOp::Panic(message)) => self.engine.injected_panic(message),
+                Some(PartialOp::Labeled(..)) => unreachable!("labels are removed by next_op"),
+                Some(PartialOp::Limited(_))
+                | Some(PartialOp::LimitedFraction(..))
+                | Some(PartialOp::Fragment(_))
+                | Some(PartialOp::Unlimited)
+                | None => break cb(cx),
+            };
+            match err.kind() {
+                io::ErrorKind::WouldBlock if self.wake_delivery != WakeDelivery::ReturnError => {
+                    // Async* instances must convert WouldBlock errors to Poll::Pending and
+                    // reschedule the task.
+                    self.wake(cx);
+                    break Poll::Pending;
+                }
+                // Async* instances must retry on Interrupted errors.
+                io::ErrorKind::Interrupted
+                    if self.max_interrupted_retries.is_none_or(|max| retries < max) =>
+                {
+                    retries += 1;
+                    continue;
                 }
-                _ => break cb(cx),
+                _ => break Poll::Ready(Err(err)),
             }
         }
     }
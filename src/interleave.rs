@@ -0,0 +1,447 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Systematically explore the orders in which two tasks are polled.
+//!
+//! Bugs that depend on how two tasks sharing IO resources interleave are rarely found by placing
+//! `WouldBlock` errors at random. An [`Explorer`] runs two futures on a controlled single-threaded
+//! executor. Whenever both tasks are ready to make progress, it picks one to poll, and it reruns
+//! the scenario until every combination of choices (up to a bound) has been tried.
+//!
+//! Each run starts from scratch with fresh futures, so the futures must be deterministic: given
+//! the same order of polls, they must behave the same way.
+//!
+//! Available with the `futures03` feature.
+//!
+//! # Examples
+//!
+//! ```rust
+//! # #[cfg(feature = "futures03")]
+//! use futures::{io::Cursor, prelude::*};
+//! # #[cfg(feature = "futures03")]
+//! use partial_io::{interleave::Explorer, PartialAsyncRead, PartialOp};
+//! # #[cfg(feature = "futures03")]
+//! use std::{cell::RefCell, io, rc::Rc};
+//!
+//! # #[cfg(feature = "futures03")]
+//! # fn main() {
+//! let report = Explorer::new().run(|| {
+//!     let counter = Rc::new(RefCell::new(0));
+//!     let increment = |counter: Rc<RefCell<u32>>| async move {
+//!         let value = *counter.borrow();
+//!         // Reading from this reader yields once, like a network read might.
+//!         let ops = vec![PartialOp::Err(io::ErrorKind::WouldBlock)];
+//!         let mut reader = PartialAsyncRead::new(Cursor::new(vec![0]), ops);
+//!         reader.read(&mut [0]).await.unwrap();
+//!         *counter.borrow_mut() = value + 1;
+//!     };
+//!     let (a, b) = (increment(counter.clone()), increment(counter.clone()));
+//!     let check = move |(), ()| match *counter.borrow() {
+//!         2 => Ok(()),
+//!         value => Err(value),
+//!     };
+//!     (a, b, check)
+//! });
+//!
+//! // Polling the tasks in the order A, B, A, B loses one of the increments.
+//! assert!(!report.is_ok());
+//! assert_eq!(report.failures[0].reason.check(), Some(&1));
+//! # }
+//!
+//! # #[cfg(not(feature = "futures03"))]
+//! # fn main() {
+//! #     assert!(true, "dummy test");
+//! # }
+//! ```
+//!
+//! [`Explorer`]: struct.Explorer.html
+
+use futures::task::{waker, ArcWake};
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+/// The default value for [`Explorer::max_choices`].
+///
+/// [`Explorer::max_choices`]: struct.Explorer.html#method.max_choices
+pub const DEFAULT_MAX_CHOICES: usize = 12;
+
+/// The default value for [`Explorer::max_polls`].
+///
+/// [`Explorer::max_polls`]: struct.Explorer.html#method.max_polls
+pub const DEFAULT_MAX_POLLS: usize = 10_000;
+
+/// One of the two tasks run by an [`Explorer`].
+///
+/// [`Explorer`]: struct.Explorer.html
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Task {
+    /// The first task.
+    A,
+    /// The second task.
+    B,
+}
+
+impl Task {
+    fn other(self) -> Self {
+        match self {
+            Task::A => Task::B,
+            Task::B => Task::A,
+        }
+    }
+}
+
+/// Runs two tasks in every order of polls, up to a bound.
+///
+/// See [the module level documentation](index.html) for more.
+#[derive(Clone, Debug)]
+pub struct Explorer {
+    max_choices: usize,
+    max_polls: usize,
+}
+
+impl Default for Explorer {
+    fn default() -> Self {
+        Explorer {
+            max_choices: DEFAULT_MAX_CHOICES,
+            max_polls: DEFAULT_MAX_POLLS,
+        }
+    }
+}
+
+impl Explorer {
+    /// Creates a new explorer with the default bounds.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum number of choices between the two tasks to explore in each run.
+    ///
+    /// Up to `2^max_choices` runs are performed. Once a run has made this many choices, the
+    /// explorer alternates between the tasks for the rest of the run. Defaults to
+    /// [`DEFAULT_MAX_CHOICES`].
+    ///
+    /// [`DEFAULT_MAX_CHOICES`]: constant.DEFAULT_MAX_CHOICES.html
+    pub fn max_choices(mut self, max_choices: usize) -> Self {
+        self.max_choices = max_choices;
+        self
+    }
+
+    /// Sets the maximum number of polls in a single run.
+    ///
+    /// A run that doesn't finish within this many polls is reported as a failure, since it is
+    /// likely to be stuck in a loop. Defaults to [`DEFAULT_MAX_POLLS`].
+    ///
+    /// [`DEFAULT_MAX_POLLS`]: constant.DEFAULT_MAX_POLLS.html
+    pub fn max_polls(mut self, max_polls: usize) -> Self {
+        self.max_polls = max_polls;
+        self
+    }
+
+    /// Explores the interleavings of the tasks returned by `setup`.
+    ///
+    /// `setup` is called once per run and returns the two tasks, along with a check that is passed
+    /// the outputs of both tasks once they have completed. A run fails if the check returns an
+    /// error, if both tasks are stuck, or if the run takes too many polls.
+    pub fn run<F, A, B, C, E>(&self, mut setup: F) -> Report<E>
+    where
+        F: FnMut() -> (A, B, C),
+        A: Future,
+        B: Future,
+        C: FnOnce(A::Output, B::Output) -> Result<(), E>,
+    {
+        let mut report = Report {
+            runs: 0,
+            failures: vec![],
+        };
+        let mut prefix = vec![];
+        loop {
+            let (a, b, check) = setup();
+            let run = self.run_once(&prefix, a, b);
+            report.runs += 1;
+
+            let res = match run.outputs {
+                Ok((a, b)) => check(a, b).map_err(FailureReason::Check),
+                Err(reason) => Err(reason),
+            };
+            if let Err(reason) = res {
+                report.failures.push(Failure {
+                    schedule: run.schedule,
+                    reason,
+                });
+            }
+
+            // Flip the last choice that was made by default, and drop everything after it.
+            match run.choices.iter().rposition(|choice| !choice.flipped) {
+                Some(idx) => {
+                    prefix.truncate(0);
+                    prefix.extend(run.choices[..idx].iter().map(|choice| choice.task));
+                    prefix.push(run.choices[idx].task.other());
+                }
+                None => break,
+            }
+        }
+        report
+    }
+
+    fn run_once<A, B, E>(&self, prefix: &[Task], a: A, b: B) -> Run<A::Output, B::Output, E>
+    where
+        A: Future,
+        B: Future,
+    {
+        let mut a = Slot::new(a);
+        let mut b = Slot::new(b);
+        let mut choices = vec![];
+        let mut schedule = vec![];
+        let mut last = Task::B;
+
+        let outputs = loop {
+            if a.output.is_some() && b.output.is_some() {
+                break Ok((a.output.take().unwrap(), b.output.take().unwrap()));
+            }
+            if schedule.len() >= self.max_polls {
+                break Err(FailureReason::PollLimit);
+            }
+
+            let task = match (a.is_ready(), b.is_ready()) {
+                (true, true) => {
+                    let default = last.other();
+                    if choices.len() < self.max_choices {
+                        let task = prefix.get(choices.len()).copied().unwrap_or(default);
+                        choices.push(Choice {
+                            task,
+                            flipped: task != default,
+                        });
+                        task
+                    } else {
+                        default
+                    }
+                }
+                (true, false) => Task::A,
+                (false, true) => Task::B,
+                (false, false) => break Err(FailureReason::Deadlock),
+            };
+
+            schedule.push(task);
+            last = task;
+            match task {
+                Task::A => a.poll(),
+                Task::B => b.poll(),
+            }
+        };
+
+        Run {
+            choices,
+            schedule,
+            outputs,
+        }
+    }
+}
+
+/// The results of exploring interleavings with an [`Explorer`].
+///
+/// [`Explorer`]: struct.Explorer.html
+#[derive(Clone, Debug)]
+pub struct Report<E> {
+    /// The number of runs performed.
+    pub runs: usize,
+    /// The runs that failed.
+    pub failures: Vec<Failure<E>>,
+}
+
+impl<E> Report<E> {
+    /// Returns true if no runs failed.
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl<E: fmt::Debug> Report<E> {
+    /// Panics if any runs failed, reporting the first failure.
+    pub fn assert_ok(&self) {
+        if let Some(failure) = self.failures.first() {
+            panic!(
+                "{} of {} interleavings failed, first failure: {:?}",
+                self.failures.len(),
+                self.runs,
+                failure
+            );
+        }
+    }
+}
+
+/// A single failed run.
+#[derive(Clone, Debug)]
+pub struct Failure<E> {
+    /// The tasks that were polled, in order.
+    pub schedule: Vec<Task>,
+    /// Why the run failed.
+    pub reason: FailureReason<E>,
+}
+
+/// Why a run failed.
+#[derive(Clone, Debug)]
+pub enum FailureReason<E> {
+    /// The check returned an error.
+    Check(E),
+    /// Neither task was woken up, so neither could make progress.
+    Deadlock,
+    /// The run didn't finish within the maximum number of polls.
+    PollLimit,
+}
+
+impl<E> FailureReason<E> {
+    /// Returns the error returned by the check, if any.
+    pub fn check(&self) -> Option<&E> {
+        match self {
+            FailureReason::Check(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Choice {
+    task: Task,
+    flipped: bool,
+}
+
+struct Run<A, B, E> {
+    choices: Vec<Choice>,
+    schedule: Vec<Task>,
+    outputs: Result<(A, B), FailureReason<E>>,
+}
+
+// A task along with its output, once it has completed.
+struct Slot<F: Future> {
+    future: Pin<Box<F>>,
+    woken: Arc<WakeFlag>,
+    output: Option<F::Output>,
+}
+
+impl<F: Future> Slot<F> {
+    fn new(future: F) -> Self {
+        Slot {
+            future: Box::pin(future),
+            // Every task is polled at least once.
+            woken: Arc::new(WakeFlag(AtomicBool::new(true))),
+            output: None,
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        self.output.is_none() && self.woken.0.load(Ordering::SeqCst)
+    }
+
+    fn poll(&mut self) {
+        self.woken.0.store(false, Ordering::SeqCst);
+        let waker = waker(self.woken.clone());
+        let mut cx = Context::from_waker(&waker);
+        if let Poll::Ready(output) = self.future.as_mut().poll(&mut cx) {
+            self.output = Some(output);
+        }
+    }
+}
+
+struct WakeFlag(AtomicBool);
+
+impl ArcWake for WakeFlag {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{io::AsyncWrite, prelude::*};
+    use std::{cell::RefCell, io, rc::Rc};
+
+    use crate::{PartialAsyncWrite, PartialOp};
+
+    // A writer that appends to a buffer shared between tasks.
+    struct SharedWriter(Rc<RefCell<Vec<u8>>>);
+
+    impl AsyncWrite for SharedWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn test_interleaved_writes() {
+        let report = Explorer::new().run(|| {
+            let buf = Rc::new(RefCell::new(vec![]));
+            let write = |data: &'static [u8]| {
+                let ops = vec![
+                    PartialOp::Limited(1),
+                    PartialOp::Err(io::ErrorKind::WouldBlock),
+                ];
+                let mut writer = PartialAsyncWrite::new(SharedWriter(buf.clone()), ops);
+                async move { writer.write_all(data).await }
+            };
+            let (a, b) = (write(b"ab"), write(b"xy"));
+            let check = move |a: io::Result<()>, b: io::Result<()>| {
+                a.unwrap();
+                b.unwrap();
+                let buf = buf.borrow();
+                if &buf[..] == b"abxy" || &buf[..] == b"xyab" {
+                    Ok(())
+                } else {
+                    Err(String::from_utf8(buf.clone()).unwrap())
+                }
+            };
+            (a, b, check)
+        });
+
+        // Each task is polled twice, so there are 4! / (2! * 2!) = 6 orders of polls.
+        assert_eq!(report.runs, 6);
+        let mut outputs: Vec<_> = report
+            .failures
+            .iter()
+            .map(|failure| failure.reason.check().unwrap().clone())
+            .collect();
+        outputs.sort();
+        assert_eq!(outputs, vec!["axby", "axyb", "xaby", "xayb"]);
+    }
+
+    #[test]
+    fn test_deadlock() {
+        let report = Explorer::new().run(|| {
+            let a = future::pending::<()>();
+            let b = future::ready(());
+            (a, b, |(), ()| Ok::<_, ()>(()))
+        });
+        // Both tasks are ready to be polled first, so there are two runs.
+        assert_eq!(report.runs, 2);
+        for failure in &report.failures {
+            assert!(matches!(failure.reason, FailureReason::Deadlock));
+        }
+        assert_eq!(report.failures.len(), 2);
+    }
+}
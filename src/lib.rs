@@ -14,13 +14,92 @@
 //! * `PartialRead` and `PartialWrite`, which wrap existing `Read` and
 //!   `Write` implementations and allow specifying arbitrary behavior on the
 //!   next `read`, `write` or `flush` call.
+//! * `OpEngine` and the `PartialWrap` trait, to build partial wrappers for
+//!   other IO-like traits that behave the same way as the ones in this crate.
+//! * `with_seed` constructors, which build a reproducible randomized sequence
+//!   of operations from a seed and a `Profile`, and print the seed if a test
+//!   panics so that the failure can be replayed.
+//! * A `fixtures` module with readers that produce reproducible test data,
+//!   and a writer that checksums its output.
+//! * A `lockstep` module to advance the operations of several wrappers in a
+//!   single global order.
+//! * A `timeline` module to make assertions on the order of method calls made
+//!   on a wrapper.
+//! * A `corpus` module to save sequences of operations to disk and replay them.
+//! * A `strace` module to import sequences of operations from the read and
+//!   write calls in an `strace` log, to replay an IO pattern seen in production.
+//! * A `scenarios` module of named sequences of operations for classic bug
+//!   patterns, as a first set of regression tests, checks that custom
+//!   `read_exact` and `write_all` loops follow the `std` semantics, and a
+//!   seeded preset of realistic TCP read sizes.
+//! * A `mutate` module to derive new sequences of operations from existing
+//!   ones, such as by halving every limit or shuffling the errors, and a fuzz
+//!   loop that mutates a recorded trace and shrinks failures back toward it.
+//! * A `verify` module with harnesses that check common kinds of wrappers, such
+//!   as buffered writers, for whole families of bugs.
+//! * A `stack` module to apply several layers of operations to a single reader
+//!   or writer, with a combined trace of what each layer did.
+//! * `PartialPipe`, which feeds data into a real TCP or Unix domain socket
+//!   according to a sequence of operations, for code that needs to own a file
+//!   descriptor.
+//! * A `server` module with a scripted server that accepts a single
+//!   connection over TCP, a Unix domain socket or a Windows named pipe, for
+//!   testing clients against a real peer.
+//! * A `chaos` module with a TCP proxy that applies operations, latency and
+//!   corruption to the connections through it, and whose settings can be
+//!   changed while they're open.
 //! * With the optional `futures03` and `tokio1` features, `PartialAsyncRead` and
 //!   `PartialAsyncWrite` to wrap existing `AsyncRead` and `AsyncWrite`
 //!   implementations. These implementations are task-aware, so they will know
 //!   how to pause and unpause tasks if they return a `WouldBlock` error.
+//!   `FuturesOps` can be used to build wrappers for other async traits with
+//!   the same behavior.
+//!   `PartialDuplex` wraps streams that are both readable and writable, with
+//!   separate operations, statistics and traces for each direction.
+//! * With the optional `futures03` feature, a minimal executor that counts
+//!   polls and wakes. See the `executor` documentation for more.
+//! * With the optional `futures03` feature, an explorer that runs two tasks in
+//!   every order of polls, up to a bound. See the `interleave` documentation
+//!   for more.
+//! * With the optional `futures03` feature, a harness that times out an
+//!   operation at each of the `Poll::Pending`s in its schedule in turn. See
+//!   the `timeout` documentation for more.
+//! * With the optional `futures03` feature, a `time` module with the clocks
+//!   that drive `PartialOp::Delay`, for `std`, `tokio` and custom runtimes.
+//!   `MockClock` only moves when it's advanced, to test delays
+//!   deterministically.
+//! * With the optional `futures03` feature, `PartialConnector` to inject
+//!   faults into each connection made by a client's connector, such as
+//!   `hyper`'s. With the `tower03` feature, it's also a `tower` service and
+//!   layer. See the `connector` documentation for more.
+//! * With the optional `turmoil06` feature, helpers to wrap the TCP streams
+//!   of `turmoil` simulations with seeded, TCP-like segment sizes. See the
+//!   `simulation` documentation for more.
+//! * With the optional `tokio1` feature, helpers for testing proxies and
+//!   tunnels between two `PartialDuplex` endpoints. See the `proxy`
+//!   documentation for more.
+//! * With the optional `futures03` feature, `PartialSink` to wrap existing
+//!   `Sink` implementations, and together with the `bytes1` feature,
+//!   `PartialStream` to wrap existing `Stream`s of byte chunks and split,
+//!   duplicate or reorder the chunks.
+//! * With the optional `bytes1` feature, `PartialBuf` and `PartialBufMut` to
+//!   wrap existing `bytes::Buf` and `bytes::BufMut` implementations and break
+//!   their chunks up, and `BufMutExt` to limit reads into a `bytes::BufMut`.
+//! * With the optional `http-body1` feature, `PartialBody` to wrap existing
+//!   `http_body::Body` implementations and split their data frames up.
+//! * With the optional `tokio-test04` feature, a `tokio_test::io` mock builder
+//!   that scripts both data and `PartialOp`s. See the `mock` documentation for
+//!   more.
+//! * With the optional `tokio-util07` feature, helpers for testing
+//!   `tokio_util::codec` decoders and encoders. See the `codec` documentation
+//!   for more.
+//! * With the optional `pcap` feature, a `pcap` module to import sequences of
+//!   operations from the TCP segment sizes in a packet capture. See the `pcap`
+//!   documentation for more.
 //! * With the optional `quickcheck1` feature, generation of random sequences of
-//!   operations which can be provided to one of the wrappers. See the
-//!   `quickcheck_types` documentation for more.
+//!   operations which can be provided to one of the wrappers, and a
+//!   `#[partial_test]` attribute to turn a function into a `quickcheck` test.
+//!   See the `quickcheck_types` documentation for more.
 //!
 //! # Motivation
 //!
@@ -77,37 +156,142 @@
 //!
 //! For a real-world example, see the [tests in `zstd-rs`].
 //!
+//! # Model checking with `loom`
+//!
+//! Built with `RUSTFLAGS="--cfg loom"`, the state that wrappers share across threads uses
+//! [`loom`]'s primitives instead of the standard library's. This covers the schedules shared
+//! through `lockstep`, connection counts in `connector`, `StdClock` timers and wakes sent with
+//! `WakeDelivery::Thread`, so that code sharing these between threads can be checked with
+//! `loom::model`. As with any `loom` type, they must be created inside the model.
+//!
+//! Delays are still real sleeps, since `loom` doesn't model time. The helpers that talk to the OS,
+//! such as `server`, `chaos` and `PartialPipe`, use the standard library either way.
+//!
+//! `tokio` reads the same cfg to model-check itself, which leaves its runtime unusable, so enable
+//! `futures03` rather than the `tokio` features for these builds. `mock` isn't available with
+//! `--cfg loom`, since `tokio_test::io` is left out too.
+//!
 //! [1]: https://github.com/gyscos/zstd-rs/commit/3123e418595f6badd5b06db2a14c4ff4555e7705
 //! [2]: https://github.com/gyscos/zstd-rs/commit/02dc9d9a3419618fc729542b45c96c32b0f178bb
 //! [tests in `zstd-rs`]: https://github.com/gyscos/zstd-rs/blob/master/src/stream/mod.rs
+//! [`loom`]: https://docs.rs/loom
+
+// Allows `#[partial_test]`, which refers to `::partial_io`, to be used in this crate's tests.
+#[cfg(all(test, feature = "quickcheck1"))]
+extern crate self as partial_io;
 
 #[cfg(feature = "futures03")]
 mod async_read;
 #[cfg(feature = "futures03")]
 mod async_write;
+#[cfg(feature = "http-body1")]
+mod body;
+#[cfg(feature = "bytes1")]
+mod buf;
+pub mod chaos;
+#[cfg(feature = "tokio-util07")]
+pub mod codec;
+#[cfg(feature = "futures03")]
+pub mod connector;
+pub mod corpus;
+#[cfg(feature = "futures03")]
+mod duplex;
+mod engine;
+mod exclusive;
+#[cfg(feature = "futures03")]
+pub mod executor;
+pub mod fixtures;
 #[cfg(feature = "futures03")]
 mod futures_util;
+#[cfg(feature = "futures03")]
+pub mod interleave;
+mod local;
+pub mod lockstep;
+#[cfg(all(feature = "tokio-test04", not(loom)))]
+pub mod mock;
+pub mod mutate;
+#[cfg(feature = "pcap")]
+pub mod pcap;
+mod pipe;
+#[cfg(feature = "tokio1")]
+pub mod proxy;
 #[cfg(feature = "quickcheck1")]
 pub mod quickcheck_types;
 mod read;
+pub mod scenarios;
+mod seeded;
+mod seek;
+pub mod server;
+#[cfg(feature = "turmoil06")]
+pub mod simulation;
+#[cfg(feature = "futures03")]
+mod sink;
+pub mod stack;
+pub mod strace;
+#[cfg(all(feature = "futures03", feature = "bytes1"))]
+mod stream;
+mod sync;
+#[cfg(feature = "futures03")]
+pub mod time;
+pub mod timeline;
+#[cfg(feature = "futures03")]
+pub mod timeout;
+pub mod verify;
 mod write;
 
-use std::io;
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    io, iter,
+    sync::Arc,
+    time::Duration,
+};
 
 #[cfg(feature = "tokio1")]
 pub use crate::async_read::tokio_impl::ReadBufExt;
 #[cfg(feature = "futures03")]
 pub use crate::async_read::PartialAsyncRead;
 #[cfg(feature = "futures03")]
-pub use crate::async_write::PartialAsyncWrite;
-pub use crate::{read::PartialRead, write::PartialWrite};
+pub use crate::async_write::{PartialAsyncWrite, WriteAfterShutdown};
+#[cfg(feature = "http-body1")]
+pub use crate::body::{BoxError, PartialBody};
+#[cfg(feature = "bytes1")]
+pub use crate::buf::{BufMutExt, PartialBuf, PartialBufMut};
+#[cfg(feature = "futures03")]
+pub use crate::duplex::{DuplexPoll, DuplexSide, DuplexStats, PartialDuplex};
+#[cfg(feature = "futures03")]
+pub use crate::futures_util::{FuturesOps, WakeDelivery};
+#[cfg(feature = "futures03")]
+pub use crate::sink::PartialSink;
+#[cfg(all(feature = "futures03", feature = "bytes1"))]
+pub use crate::stream::{ChunkOp, PartialStream};
+pub use crate::{
+    engine::{Alignment, AppliedOp, BufSize, OpEngine, OpReport, PartialWrap, StaticOps},
+    local::LocalOps,
+    pipe::PartialPipe,
+    read::PartialRead,
+    seeded::{Profile, SeededOps},
+    seek::SeekOp,
+    write::{FlushReport, PartialWrite},
+};
+#[cfg(feature = "quickcheck1")]
+pub use partial_io_macros::partial_test;
 
 /// What to do the next time an IO operation is performed.
 ///
 /// This is not the same as `io::Result<Option<usize>>` because it contains
 /// `io::ErrorKind` instances, not `io::Error` instances. This allows it to be
 /// clonable.
-#[derive(Clone, Debug)]
+///
+/// Operations compare equal if they're the same kind of operation with the
+/// same arguments. `PartialOp::ErrWith` operations are only equal if they
+/// share a factory, as created by cloning. `Display` prints the operation as
+/// a line of the `corpus` format, such as `limited 3` or `err Interrupted`.
+///
+/// New kinds of operations may be added in minor releases, so matches on
+/// `PartialOp` outside this crate need a wildcard arm.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
 pub enum PartialOp {
     /// Limit the next IO operation to a certain number of bytes.
     ///
@@ -120,6 +304,24 @@ pub enum PartialOp {
     /// `Unlimited`.
     Limited(usize),
 
+    /// Limit the next IO operation to a fraction of the number of bytes the
+    /// caller asked for: `LimitedFraction(num, den)` allows `num / den` of
+    /// them, rounded down.
+    ///
+    /// Unlike `Limited(n)`, this stays meaningful when the code under test
+    /// changes the size of its buffers, so a schedule written against 16-byte
+    /// reads still splits 64 KiB ones. The limit is at least 1 byte, unless
+    /// `num` is 0 or the caller asked for 0 bytes, since an empty read looks
+    /// like the end of the stream. Fractions of 1 or more don't limit the
+    /// operation.
+    ///
+    /// Methods without a limit treat this like `Limited(n)`. `PartialBuf` and
+    /// `PartialBufMut`, which aren't asked for a number of bytes, treat it as
+    /// `Unlimited`.
+    ///
+    /// Wrappers panic when they perform this operation if `den` is 0.
+    LimitedFraction(usize, usize),
+
     /// Do not limit the next IO operation.
     ///
     /// The wrapper will call into the inner `Read` or `Write`
@@ -129,11 +331,301 @@ pub enum PartialOp {
 
     /// Return an error instead of calling into the underlying operation.
     ///
+    /// The message of the error says which operation injected it and during
+    /// which method, such as `injected at op #7 during poll_flush`, to tell
+    /// scheduled faults apart once they surface in application code.
+    ///
     /// For methods on `Async` traits:
     /// * `ErrorKind::WouldBlock` is translated to `Poll::Pending` and the task
     ///   is scheduled to be woken up in the future.
     /// * `ErrorKind::Interrupted` causes a retry.
     Err(io::ErrorKind),
+
+    /// Return an error with the given raw OS error code, created with
+    /// `io::Error::from_raw_os_error`, instead of calling into the underlying
+    /// operation.
+    ///
+    /// This reaches code that matches on `io::Error::raw_os_error`, for
+    /// example to tell `ECONNRESET` apart from `EPIPE`. For methods on `Async`
+    /// traits, codes that map to `ErrorKind::WouldBlock` or
+    /// `ErrorKind::Interrupted` are handled the same way as in `Err`.
+    OsErr(i32),
+
+    /// Return an error created by the given factory instead of calling into
+    /// the underlying operation.
+    ///
+    /// This allows injected errors to carry a custom payload or `source()`
+    /// chain, for code that downcasts errors to decide what to do. For methods
+    /// on `Async` traits, errors of kind `ErrorKind::WouldBlock` or
+    /// `ErrorKind::Interrupted` are handled the same way as in `Err`.
+    ///
+    /// Use [`PartialOp::err_with`] to create this operation.
+    ///
+    /// [`PartialOp::err_with`]: enum.PartialOp.html#method.err_with
+    ErrWith(ErrorFactory),
+
+    /// Return an error of the given kind for the next `n` IO operations,
+    /// then proceed with the operations that follow.
+    ///
+    /// This behaves the same as `n` consecutive `Err(kind)` operations, but
+    /// is easier to write and shrinks on `n`. `ErrTimes(kind, 0)` is skipped.
+    ErrTimes(io::ErrorKind, usize),
+
+    /// Wait for the given duration, then perform the next IO operation
+    /// without a limit.
+    ///
+    /// Sync wrappers block the current thread. Async wrappers return
+    /// `Poll::Pending` until the delay has elapsed according to their clock.
+    /// Within a `tokio` runtime, the default clock uses `tokio::time`, so
+    /// delays can be controlled with `tokio::time::pause`. See the `time`
+    /// documentation for more.
+    ///
+    /// Wrappers that aren't time-aware, such as `PartialBuf`, treat this as
+    /// `Unlimited`.
+    Delay(Duration),
+
+    /// Perform the next IO operation through several calls into the inner
+    /// `Read` or `Write` instance, each limited to at most `n` bytes, and
+    /// return the total once.
+    ///
+    /// This simulates inner sources that only hand out tiny chunks, and
+    /// exercises loops inside the code under test rather than loops around
+    /// it. The calls stop early after a short read or write, or an error. If
+    /// some bytes were processed before the error, they're returned and the
+    /// error is dropped.
+    ///
+    /// Only `PartialRead` and `PartialWrite` split calls up. Other wrappers
+    /// treat this as `Limited(n)`.
+    Fragment(usize),
+
+    /// Panic with the given message instead of calling into the underlying
+    /// operation.
+    ///
+    /// Error injection can't reach code that only runs while unwinding, such
+    /// as a `Mutex` around a writer being poisoned, or a `catch_unwind`
+    /// recovery path. The panic payload is the `&'static str`, the same as
+    /// with `panic!("literal")`, so it can be downcast after `catch_unwind`.
+    /// If the operation is labeled, the payload is a `String` that includes
+    /// the label instead.
+    Panic(&'static str),
+
+    /// Perform the given operation, with a label that identifies it in
+    /// diagnostics.
+    ///
+    /// The label shows up in recorded operations such as `AppliedOp`, and in
+    /// the messages of injected errors and panics. In long generated
+    /// schedules, this says which part of the schedule a failure came from,
+    /// rather than just its index.
+    ///
+    /// Use [`PartialOp::labeled`] to create this operation.
+    ///
+    /// [`PartialOp::labeled`]: enum.PartialOp.html#method.labeled
+    Labeled(Box<PartialOp>, &'static str),
+}
+
+impl PartialOp {
+    /// Returns true if this operation injects an error.
+    pub fn is_error(&self) -> bool {
+        match self {
+            PartialOp::Labeled(op, _) => op.is_error(),
+            op => matches!(
+                op,
+                PartialOp::Err(_)
+                    | PartialOp::OsErr(_)
+                    | PartialOp::ErrWith(_)
+                    | PartialOp::ErrTimes(_, 1..)
+            ),
+        }
+    }
+
+    /// Labels this operation, so that the label shows up in diagnostics.
+    ///
+    /// If the operation is already labeled, the outer label is the one reported.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use partial_io::{PartialOp, PartialWrite};
+    /// use std::io::{self, Write};
+    ///
+    /// let ops = vec![
+    ///     PartialOp::Unlimited.labeled("header"),
+    ///     PartialOp::Err(io::ErrorKind::BrokenPipe).labeled("header-retry"),
+    /// ];
+    /// let mut partial_write = PartialWrite::new(Vec::new(), ops);
+    /// partial_write.write_all(b"header").unwrap();
+    /// let err = partial_write.write_all(b"body").unwrap_err();
+    /// assert!(err.to_string().ends_with("during write, in 'header-retry'"), "{}", err);
+    /// ```
+    pub fn labeled(self, label: &'static str) -> Self {
+        PartialOp::Labeled(Box::new(self), label)
+    }
+
+    /// Returns `k` consecutive `PartialOp::Err(Interrupted)` operations,
+    /// followed by a `PartialOp::Unlimited` that allows progress.
+    ///
+    /// This tests that retry loops are bounded and don't livelock. Chain
+    /// several storms together to interrupt more than one operation. To
+    /// generate `k` randomly, see `quickcheck_types::InterruptedStorm`.
+    ///
+    /// Async wrappers retry `Interrupted` errors internally; call
+    /// `FuturesOps::set_max_interrupted_retries` through their `ops_mut` to
+    /// surface the errors past a bound.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use partial_io::{PartialOp, PartialRead};
+    /// use std::io::{self, Read};
+    ///
+    /// let mut partial_read = PartialRead::new(io::repeat(0), PartialOp::interrupted_storm(3));
+    /// let mut attempts = 0;
+    /// let n = loop {
+    ///     attempts += 1;
+    ///     assert!(attempts <= 10, "retry loop should make progress");
+    ///     match partial_read.read(&mut [0; 4]) {
+    ///         Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+    ///         res => break res.unwrap(),
+    ///     }
+    /// };
+    /// assert_eq!((n, attempts), (4, 4));
+    /// ```
+    pub fn interrupted_storm(k: usize) -> impl Iterator<Item = PartialOp> + Clone + Send {
+        iter::repeat_n(PartialOp::Err(io::ErrorKind::Interrupted), k)
+            .chain(iter::once(PartialOp::Unlimited))
+    }
+
+    /// Creates a `PartialOp::ErrWith` that calls `factory` every time the
+    /// operation is performed.
+    ///
+    /// The factory is also called to find the kind of its error when the
+    /// operation is displayed, saved to a corpus or fingerprinted, so it
+    /// shouldn't have side effects.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use partial_io::{PartialOp, PartialRead};
+    /// use std::{error::Error, fmt, io::{self, Read}};
+    ///
+    /// #[derive(Debug)]
+    /// struct TlsError;
+    ///
+    /// impl fmt::Display for TlsError {
+    ///     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    ///         f.write_str("bad record mac")
+    ///     }
+    /// }
+    ///
+    /// impl Error for TlsError {}
+    ///
+    /// let ops = vec![PartialOp::err_with(|| io::Error::new(io::ErrorKind::Other, TlsError))];
+    /// let mut partial_read = PartialRead::new(io::repeat(0), ops);
+    /// let err = partial_read.read(&mut [0; 4]).unwrap_err();
+    /// assert!(err.get_ref().unwrap().is::<TlsError>());
+    /// ```
+    pub fn err_with<F>(factory: F) -> Self
+    where
+        F: Fn() -> io::Error + Send + Sync + 'static,
+    {
+        PartialOp::ErrWith(ErrorFactory(Arc::new(factory)))
+    }
+}
+
+impl fmt::Display for PartialOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartialOp::Limited(n) => write!(f, "limited {}", n),
+            PartialOp::LimitedFraction(num, den) => write!(f, "limited_fraction {}/{}", num, den),
+            PartialOp::Unlimited => f.write_str("unlimited"),
+            PartialOp::Err(kind) => write!(f, "err {:?}", kind),
+            PartialOp::OsErr(code) => write!(f, "os_err {}", code),
+            PartialOp::ErrWith(factory) => write!(f, "err_with {:?}", factory.make_error().kind()),
+            PartialOp::ErrTimes(kind, n) => write!(f, "err_times {:?} {}", kind, n),
+            PartialOp::Delay(duration) => write!(f, "delay_ns {}", duration.as_nanos()),
+            PartialOp::Fragment(n) => write!(f, "fragment {}", n),
+            // Line breaks would split the operation, and a closing bracket would end the label.
+            PartialOp::Panic(message) => write!(f, "panic {}", message.replace('\n', " ")),
+            PartialOp::Labeled(op, label) => {
+                write!(f, "[{}] {}", label.replace(['\n', ']'], " "), op)
+            }
+        }
+    }
+}
+
+/// Creates a `PartialOp::Limited(n)`.
+///
+/// # Examples
+///
+/// ```rust
+/// use partial_io::{PartialOp, PartialRead};
+/// use std::io::{self, Read};
+///
+/// let ops: Vec<PartialOp> = vec![7.into(), io::ErrorKind::Interrupted.into()];
+/// let mut partial_read = PartialRead::new(io::repeat(0), ops);
+/// assert_eq!(partial_read.read(&mut [0; 16]).unwrap(), 7);
+/// let err = partial_read.read(&mut [0; 16]).unwrap_err();
+/// assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+/// ```
+impl From<usize> for PartialOp {
+    fn from(n: usize) -> Self {
+        PartialOp::Limited(n)
+    }
+}
+
+/// Creates a `PartialOp::Err(kind)`.
+impl From<io::ErrorKind> for PartialOp {
+    fn from(kind: io::ErrorKind) -> Self {
+        PartialOp::Err(kind)
+    }
+}
+
+/// Creates the operation that replays an observed result: `PartialOp::Limited(n)` for `Ok(n)`,
+/// and `PartialOp::Err(kind)` for `Err(kind)`.
+impl From<Result<usize, io::ErrorKind>> for PartialOp {
+    fn from(res: Result<usize, io::ErrorKind>) -> Self {
+        match res {
+            Ok(n) => PartialOp::Limited(n),
+            Err(kind) => PartialOp::Err(kind),
+        }
+    }
+}
+
+/// A function that creates errors for `PartialOp::ErrWith`.
+///
+/// Created with [`PartialOp::err_with`].
+///
+/// [`PartialOp::err_with`]: enum.PartialOp.html#method.err_with
+#[derive(Clone)]
+pub struct ErrorFactory(Arc<dyn Fn() -> io::Error + Send + Sync>);
+
+impl ErrorFactory {
+    /// Creates a new error.
+    pub fn make_error(&self) -> io::Error {
+        (self.0)()
+    }
+}
+
+/// Factories are compared by identity: a factory is only equal to its clones.
+impl PartialEq for ErrorFactory {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ErrorFactory {}
+
+impl Hash for ErrorFactory {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Hash the address without the vtable, as `Arc::ptr_eq` compares.
+        (Arc::as_ptr(&self.0) as *const ()).hash(state);
+    }
+}
+
+impl fmt::Debug for ErrorFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ErrorFactory").finish()
+    }
 }
 
 #[inline]
@@ -147,5 +639,54 @@ where
 
 #[cfg(test)]
 mod tests {
+    use super::PartialOp;
+    use std::{collections::HashSet, io, time::Duration};
+
     pub fn assert_send<S: Send>() {}
+
+    pub fn assert_sync<S: Sync>() {}
+
+    #[test]
+    fn test_eq_hash_display() {
+        let factory = PartialOp::err_with(|| io::ErrorKind::Other.into());
+        let op = PartialOp::ErrTimes(io::ErrorKind::WouldBlock, 2).labeled("retry");
+        assert_eq!(op, op.clone());
+        assert_ne!(op, PartialOp::ErrTimes(io::ErrorKind::WouldBlock, 2));
+        assert_eq!(factory, factory.clone());
+        assert_ne!(factory, PartialOp::err_with(|| io::ErrorKind::Other.into()));
+
+        let ops = [
+            PartialOp::Limited(3),
+            PartialOp::Limited(3),
+            op.clone(),
+            op.clone(),
+            factory.clone(),
+            factory,
+        ];
+        assert_eq!(ops.iter().collect::<HashSet<_>>().len(), 3);
+
+        assert_eq!(op.to_string(), "[retry] err_times WouldBlock 2");
+        assert_eq!(
+            PartialOp::LimitedFraction(1, 4).to_string(),
+            "limited_fraction 1/4"
+        );
+        assert_eq!(
+            PartialOp::Delay(Duration::from_micros(1500)).to_string(),
+            "delay_ns 1500000"
+        );
+    }
+
+    #[test]
+    fn test_from() {
+        let observed = [Ok(3), Err(io::ErrorKind::WouldBlock), Ok(0)];
+        let ops: Vec<PartialOp> = observed.iter().copied().map(PartialOp::from).collect();
+        assert_eq!(
+            ops,
+            [
+                3.into(),
+                io::ErrorKind::WouldBlock.into(),
+                PartialOp::Limited(0)
+            ]
+        );
+    }
 }
@@ -0,0 +1,183 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Operation sources and futures that aren't `Send`, for single-threaded executors.
+
+use crate::PartialOp;
+use std::{
+    fmt,
+    mem::ManuallyDrop,
+    thread::{self, ThreadId},
+};
+
+/// An adapter that lets a wrapper take an iterator of `PartialOp`s that isn't `Send`.
+///
+/// Wrappers require their operations to be `Send`, so that they can be moved across threads along
+/// with the wrapper. This rules out schedules that share state through an `Rc`, as is common in
+/// tests that run on a single-threaded executor such as a `tokio::task::LocalSet`.
+///
+/// A `LocalOps` is `Send`, but can only be used on the thread that created it. This is checked at
+/// runtime instead of by the compiler:
+///
+/// * Taking an operation from another thread panics.
+/// * Dropping a `LocalOps` on another thread leaks the iterator instead of dropping it.
+///
+/// `PartialAsyncRead::new_local` and `PartialAsyncWrite::new_local` wrap their operations in a
+/// `LocalOps`. It can also be passed to any other wrapper directly.
+///
+/// # Examples
+///
+/// ```rust
+/// use partial_io::{LocalOps, PartialOp, PartialRead};
+/// use std::{cell::Cell, io::Read, rc::Rc};
+///
+/// // Count the operations taken by the reader through an `Rc`.
+/// let taken = Rc::new(Cell::new(0));
+/// let counter = taken.clone();
+/// let ops = std::iter::repeat_with(move || {
+///     counter.set(counter.get() + 1);
+///     PartialOp::Limited(1)
+/// });
+///
+/// let mut partial_read = PartialRead::new(&b"abc"[..], LocalOps::new(ops));
+/// let mut buf = Vec::new();
+/// partial_read.read_to_end(&mut buf).unwrap();
+/// assert_eq!(taken.get(), 4, "3 bytes and EOF");
+/// ```
+pub struct LocalOps<I> {
+    iter: Local<I>,
+}
+
+impl<I> LocalOps<I>
+where
+    I: Iterator<Item = PartialOp>,
+{
+    /// Creates a new `LocalOps` that can be used on the current thread.
+    pub fn new<T>(iter: T) -> Self
+    where
+        T: IntoIterator<Item = PartialOp, IntoIter = I>,
+    {
+        LocalOps {
+            iter: Local::new(iter.into_iter()),
+        }
+    }
+}
+
+impl<I> Iterator for LocalOps<I>
+where
+    I: Iterator<Item = PartialOp>,
+{
+    type Item = PartialOp;
+
+    fn next(&mut self) -> Option<PartialOp> {
+        self.iter.get_mut("LocalOps").next()
+    }
+}
+
+impl<I> fmt::Debug for LocalOps<I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalOps")
+            .field("thread", &self.iter.thread)
+            .finish()
+    }
+}
+
+// A value that is `Send`, but can only be used on the thread that created it, and is leaked if
+// it's dropped on another thread.
+pub(crate) struct Local<T> {
+    thread: ThreadId,
+    value: ManuallyDrop<T>,
+}
+
+impl<T> Local<T> {
+    pub(crate) fn new(value: T) -> Self {
+        Local {
+            thread: thread::current().id(),
+            value: ManuallyDrop::new(value),
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, name: &str) -> &mut T {
+        assert!(
+            thread::current().id() == self.thread,
+            "{} used on a different thread than the one it was created on",
+            name
+        );
+        &mut self.value
+    }
+}
+
+impl<T> Drop for Local<T> {
+    fn drop(&mut self) {
+        if thread::current().id() == self.thread {
+            // SAFETY: the value isn't used again.
+            unsafe { ManuallyDrop::drop(&mut self.value) }
+        }
+    }
+}
+
+// SAFETY: the value is only used, and only dropped, on the thread that created it.
+unsafe impl<T> Send for Local<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PartialRead;
+    use std::{cell::RefCell, io::Read, rc::Rc};
+
+    #[test]
+    fn test_local_ops() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let ops = {
+            let log = log.clone();
+            vec![PartialOp::Limited(1), PartialOp::Limited(2)]
+                .into_iter()
+                .inspect(move |op| log.borrow_mut().push(format!("{:?}", op)))
+        };
+        let mut partial_read = PartialRead::new(&b"abcdef"[..], LocalOps::new(ops));
+        let mut buf = [0; 4];
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 1);
+        assert_eq!(*log.borrow(), ["Limited(1)"]);
+
+        // Using the wrapper on another thread panics, and dropping it there leaks the iterator.
+        let res = thread::spawn(move || {
+            let _ = partial_read.read(&mut buf);
+        })
+        .join();
+        assert!(res.is_err());
+        assert_eq!(Rc::strong_count(&log), 2, "the iterator was leaked");
+    }
+
+    #[cfg(feature = "tokio1")]
+    #[test]
+    fn test_local_set() {
+        use crate::PartialAsyncWrite;
+        use std::cell::Cell;
+        use tokio::io::AsyncWriteExt;
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let local = tokio::task::LocalSet::new();
+        local.block_on(&rt, async {
+            let writes = Rc::new(Cell::new(0));
+            let counter = writes.clone();
+            let ops = std::iter::repeat_with(move || {
+                counter.set(counter.get() + 1);
+                PartialOp::Limited(2)
+            });
+            let mut partial_write = PartialAsyncWrite::new_local(Vec::new(), ops);
+            tokio::task::spawn_local(async move {
+                partial_write.write_all(b"hello").await.unwrap();
+                assert_eq!(partial_write.get_ref(), b"hello");
+            })
+            .await
+            .unwrap();
+            assert_eq!(writes.get(), 3);
+        });
+    }
+}
@@ -0,0 +1,379 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Advance the `PartialOp`s of several wrappers in a single global order.
+//!
+//! Each wrapper normally consumes its own iterator of `PartialOp`s, independently of every other
+//! wrapper. That can't express orderings across wrappers, such as "the reader's first operation
+//! happens before the writer's first operation". A [`Scenario`] holds a single sequence of steps,
+//! each belonging to one [`Participant`], and hands out an iterator of ops for each participant.
+//!
+//! A participant that performs an operation out of turn gets `PartialOp::Err(WouldBlock)` without
+//! consuming a step. For async wrappers this means the task is woken up and retries later, once
+//! the other participants have caught up. Once every step has been consumed, all operations are
+//! unlimited.
+//!
+//! Wrappers stop consuming an iterator once it has returned `None`, so all the steps should be
+//! added before the wrappers start performing operations.
+//!
+//! To express faults by their global ordinal instead, such as "the 5th IO operation anywhere
+//! fails", use [`SharedOps`]: every wrapper consumes the next operation from a single shared
+//! sequence, in whichever order the wrappers perform their operations.
+//!
+//! # Examples
+//!
+//! ```rust
+//! # #[cfg(feature = "futures03")]
+//! use futures::{executor::block_on, io::Cursor, prelude::*};
+//! use partial_io::{lockstep::Scenario, PartialOp};
+//! # #[cfg(feature = "futures03")]
+//! use partial_io::{PartialAsyncRead, PartialAsyncWrite};
+//!
+//! # #[cfg(feature = "futures03")]
+//! # fn main() {
+//! let mut scenario = Scenario::new();
+//! let reader = scenario.participant();
+//! let writer = scenario.participant();
+//! scenario
+//!     .step(reader, PartialOp::Limited(1))
+//!     .step(writer, PartialOp::Limited(2))
+//!     .steps(reader, vec![PartialOp::Limited(2), PartialOp::Limited(3)]);
+//!
+//! let cursor = Cursor::new(b"request".to_vec());
+//! let mut partial_read = PartialAsyncRead::new(cursor, scenario.ops(reader));
+//! let mut partial_write = PartialAsyncWrite::new(Vec::new(), scenario.ops(writer));
+//!
+//! block_on(async {
+//!     let mut buf = [0; 8];
+//!     let read = async { partial_read.read(&mut buf).await.unwrap() };
+//!     let write = async { partial_write.write(b"response").await.unwrap() };
+//!     // The reader's first step comes before the writer's, whichever is polled first.
+//!     let (read, write) = futures::join!(read, write);
+//!     assert_eq!((read, write), (1, 2));
+//! });
+//! assert_eq!(scenario.remaining(), 2);
+//! # }
+//!
+//! # #[cfg(not(feature = "futures03"))]
+//! # fn main() {
+//! #     assert!(true, "dummy test");
+//! # }
+//! ```
+//!
+//! [`Scenario`]: struct.Scenario.html
+//! [`Participant`]: struct.Participant.html
+//! [`SharedOps`]: struct.SharedOps.html
+
+use crate::{
+    make_ops,
+    sync::{Arc, Mutex},
+    PartialOp,
+};
+use std::{collections::VecDeque, fmt, io};
+
+/// A single global order of `PartialOp`s across several participants.
+///
+/// See [the module level documentation](index.html) for more.
+#[derive(Clone, Debug, Default)]
+pub struct Scenario {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    participants: usize,
+    steps: VecDeque<(Participant, PartialOp)>,
+}
+
+/// A handle to one of the participants in a [`Scenario`].
+///
+/// [`Scenario`]: struct.Scenario.html
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Participant(usize);
+
+impl Scenario {
+    /// Creates a new scenario with no participants and no steps.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a new participant to this scenario.
+    pub fn participant(&mut self) -> Participant {
+        let mut state = self.state.lock().unwrap();
+        let participant = Participant(state.participants);
+        state.participants += 1;
+        participant
+    }
+
+    /// Appends a step for `participant` to the global order.
+    pub fn step(&mut self, participant: Participant, op: PartialOp) -> &mut Self {
+        self.steps(participant, Some(op))
+    }
+
+    /// Appends several consecutive steps for `participant` to the global order.
+    pub fn steps<I>(&mut self, participant: Participant, ops: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp>,
+    {
+        self.state
+            .lock()
+            .unwrap()
+            .steps
+            .extend(ops.into_iter().map(|op| (participant, op)));
+        self
+    }
+
+    /// Returns the `PartialOp`s for `participant`, to be passed into a wrapper.
+    pub fn ops(&self, participant: Participant) -> ScenarioOps {
+        ScenarioOps {
+            state: self.state.clone(),
+            participant,
+        }
+    }
+
+    /// Returns the number of steps that haven't been consumed yet.
+    pub fn remaining(&self) -> usize {
+        self.state.lock().unwrap().steps.len()
+    }
+}
+
+/// An iterator over the `PartialOp`s for a single participant in a [`Scenario`].
+///
+/// Returned by [`Scenario::ops`].
+///
+/// [`Scenario`]: struct.Scenario.html
+/// [`Scenario::ops`]: struct.Scenario.html#method.ops
+#[derive(Clone, Debug)]
+pub struct ScenarioOps {
+    state: Arc<Mutex<State>>,
+    participant: Participant,
+}
+
+impl Iterator for ScenarioOps {
+    type Item = PartialOp;
+
+    fn next(&mut self) -> Option<PartialOp> {
+        let mut state = self.state.lock().unwrap();
+        match state.steps.front() {
+            Some((participant, _)) if *participant == self.participant => {
+                state.steps.pop_front().map(|(_, op)| op)
+            }
+            // It isn't this participant's turn yet.
+            Some(_) => Some(PartialOp::Err(io::ErrorKind::WouldBlock)),
+            None => None,
+        }
+    }
+}
+
+/// A single sequence of `PartialOp`s shared by several wrappers.
+///
+/// Each wrapper that is passed an iterator from [`ops`] consumes the next operation from the
+/// shared sequence, so operations are assigned in the order they are performed across all the
+/// wrappers. `SharedOps` is a cheap handle that can be cloned and sent across threads.
+///
+/// Once the sequence is exhausted, all operations are unlimited.
+///
+/// # Examples
+///
+/// ```rust
+/// use partial_io::{lockstep::SharedOps, PartialOp, PartialRead, PartialWrite};
+/// use std::io::{self, Read, Write};
+///
+/// // The third operation anywhere fails.
+/// let shared = SharedOps::new(vec![
+///     PartialOp::Unlimited,
+///     PartialOp::Unlimited,
+///     PartialOp::Err(io::ErrorKind::BrokenPipe),
+/// ]);
+/// let mut partial_read = PartialRead::new(io::repeat(0), shared.ops());
+/// let mut partial_write = PartialWrite::new(Vec::new(), shared.ops());
+///
+/// partial_read.read(&mut [0; 4]).unwrap();
+/// partial_write.write(b"abc").unwrap();
+/// assert_eq!(partial_read.read(&mut [0; 4]).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+/// assert_eq!(shared.consumed(), 3);
+/// ```
+///
+/// [`ops`]: struct.SharedOps.html#method.ops
+#[derive(Clone, Debug)]
+pub struct SharedOps {
+    state: Arc<Mutex<SharedState>>,
+}
+
+struct SharedState {
+    ops: Box<dyn Iterator<Item = PartialOp> + Send>,
+    consumed: usize,
+}
+
+impl fmt::Debug for SharedState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedState")
+            .field("consumed", &self.consumed)
+            .finish()
+    }
+}
+
+impl SharedOps {
+    /// Creates a new shared sequence with the specified `PartialOp`s.
+    pub fn new<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        SharedOps {
+            state: Arc::new(Mutex::new(SharedState {
+                ops: make_ops(iter),
+                consumed: 0,
+            })),
+        }
+    }
+
+    /// Returns an iterator that consumes operations from the shared sequence, to be passed into a
+    /// wrapper.
+    pub fn ops(&self) -> SharedOpsIter {
+        SharedOpsIter {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Returns the number of operations consumed so far, across all wrappers.
+    pub fn consumed(&self) -> usize {
+        self.state.lock().unwrap().consumed
+    }
+}
+
+/// An iterator that consumes operations from a [`SharedOps`].
+///
+/// Returned by [`SharedOps::ops`].
+///
+/// [`SharedOps`]: struct.SharedOps.html
+/// [`SharedOps::ops`]: struct.SharedOps.html#method.ops
+#[derive(Clone, Debug)]
+pub struct SharedOpsIter {
+    state: Arc<Mutex<SharedState>>,
+}
+
+impl Iterator for SharedOpsIter {
+    type Item = PartialOp;
+
+    fn next(&mut self) -> Option<PartialOp> {
+        let mut state = self.state.lock().unwrap();
+        let op = state.ops.next();
+        if op.is_some() {
+            state.consumed += 1;
+        }
+        op
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Cursor, Read, Write};
+
+    use crate::{tests::assert_send, PartialRead, PartialWrite};
+
+    #[test]
+    fn test_sendable() {
+        assert_send::<ScenarioOps>();
+        assert_send::<SharedOpsIter>();
+    }
+
+    #[test]
+    fn test_lockstep_order() {
+        crate::sync::model(|| {
+            let mut scenario = Scenario::new();
+            let reader = scenario.participant();
+            let writer = scenario.participant();
+            scenario
+                .step(reader, PartialOp::Limited(1))
+                .step(writer, PartialOp::Limited(2))
+                .steps(reader, vec![PartialOp::Limited(2), PartialOp::Limited(3)]);
+
+            let mut partial_read =
+                PartialRead::new(Cursor::new(b"abcdefgh".to_vec()), scenario.ops(reader));
+            let mut partial_write = PartialWrite::new(Vec::new(), scenario.ops(writer));
+            let mut buf = [0; 8];
+
+            // The writer is out of turn, and doesn't consume a step.
+            assert_eq!(
+                partial_write.write(b"xyz").unwrap_err().kind(),
+                io::ErrorKind::WouldBlock
+            );
+            assert_eq!(scenario.remaining(), 4);
+
+            assert_eq!(partial_read.read(&mut buf).unwrap(), 1);
+            assert_eq!(
+                partial_read.read(&mut buf).unwrap_err().kind(),
+                io::ErrorKind::WouldBlock
+            );
+            assert_eq!(partial_write.write(b"xyz").unwrap(), 2);
+            assert_eq!(partial_read.read(&mut buf).unwrap(), 2);
+            assert_eq!(partial_read.read(&mut buf).unwrap(), 3);
+            assert_eq!(scenario.remaining(), 0);
+
+            // Once all the steps have been consumed, operations are unlimited.
+            assert_eq!(partial_write.write(b"xyz").unwrap(), 3);
+            assert_eq!(partial_read.read(&mut buf).unwrap(), 2);
+        });
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn test_shared_ops_threads() {
+        let ops = (0..100).map(|n| {
+            if n == 50 {
+                PartialOp::Err(io::ErrorKind::ConnectionReset)
+            } else {
+                PartialOp::Unlimited
+            }
+        });
+        let shared = SharedOps::new(ops);
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let mut partial_write = PartialWrite::new(Vec::new(), shared.ops());
+                std::thread::spawn(move || {
+                    (0..25)
+                        .filter(|_| partial_write.write(b"x").is_err())
+                        .count()
+                })
+            })
+            .collect();
+        let errors: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(errors, 1, "exactly one operation fails across all threads");
+        assert_eq!(shared.consumed(), 100);
+    }
+    #[cfg(loom)]
+    #[test]
+    fn test_shared_ops_loom() {
+        loom::model(|| {
+            let ops = vec![
+                PartialOp::Unlimited,
+                PartialOp::Err(io::ErrorKind::ConnectionReset),
+                PartialOp::Unlimited,
+            ];
+            let shared = SharedOps::new(ops);
+            let handles: Vec<_> = (1..=2)
+                .map(|writes| {
+                    let mut partial_write = PartialWrite::new(Vec::new(), shared.ops());
+                    loom::thread::spawn(move || {
+                        (0..writes)
+                            .filter(|_| partial_write.write(b"x").is_err())
+                            .count()
+                    })
+                })
+                .collect();
+            let errors: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+            assert_eq!(
+                errors, 1,
+                "exactly one operation fails in every interleaving"
+            );
+            assert_eq!(shared.consumed(), 3);
+        });
+    }
+}
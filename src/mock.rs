@@ -0,0 +1,205 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Interoperability with `tokio_test::io` mocks.
+//!
+//! A `tokio_test::io::Mock` scripts the data that is read and written, while `partial-io` scripts
+//! how that data is broken up. [`Builder`] scripts both in one place, so the two can't drift apart.
+//!
+//! `tokio_test::io::Builder` doesn't expose the actions it has been given, so an existing builder
+//! can't be converted into `PartialOp`s. Use [`Builder`] in its place instead: it has the same
+//! methods, plus methods that add `PartialOp`s for reads and writes.
+//!
+//! Available with the `tokio-test04` feature.
+//!
+//! # Examples
+//!
+//! ```rust
+//! # #[cfg(feature = "tokio-test04")]
+//! use partial_io::{mock::Builder, PartialOp};
+//! # #[cfg(feature = "tokio-test04")]
+//! use std::io;
+//! # #[cfg(feature = "tokio-test04")]
+//! use tokio::io::{AsyncReadExt, AsyncWriteExt};
+//!
+//! # #[cfg(feature = "tokio-test04")]
+//! #[tokio::main]
+//! async fn main() -> io::Result<()> {
+//!     let mut mock = Builder::new()
+//!         // "ping" is written in two pieces, with a pending state in between.
+//!         .write_with(b"ping", vec![
+//!             PartialOp::Limited(2),
+//!             PartialOp::Err(io::ErrorKind::WouldBlock),
+//!         ])
+//!         // "pong" is read one byte at a time.
+//!         .read_with(b"pong", vec![PartialOp::Limited(1); 4])
+//!         .build();
+//!
+//!     mock.write_all(b"ping").await?;
+//!     let mut buf = [0; 4];
+//!     mock.read_exact(&mut buf).await?;
+//!     assert_eq!(&buf, b"pong");
+//!
+//!     Ok(())
+//! }
+//!
+//! # #[cfg(not(feature = "tokio-test04"))]
+//! # fn main() {
+//! #     assert!(true, "dummy test");
+//! # }
+//! ```
+//!
+//! [`Builder`]: struct.Builder.html
+
+use crate::{PartialAsyncRead, PartialAsyncWrite, PartialOp};
+use std::{io, time::Duration};
+use tokio_test::io::Mock;
+
+/// A `tokio_test::io::Mock` wrapped with `PartialOp`s for reads and writes.
+pub type PartialMock = PartialAsyncRead<PartialAsyncWrite<Mock>>;
+
+/// Builds a [`PartialMock`], scripting both data and `PartialOp`s.
+///
+/// Data is scripted the same way as with `tokio_test::io::Builder`. `PartialOp`s are consumed in
+/// the order they were added: read ops by `poll_read`, and write ops by `poll_write`,
+/// `poll_flush` and `poll_shutdown`. Once the ops run out, reads and writes are unlimited.
+///
+/// See [the module level documentation](index.html) for more.
+///
+/// [`PartialMock`]: type.PartialMock.html
+#[derive(Debug, Default)]
+pub struct Builder {
+    inner: tokio_test::io::Builder,
+    read_ops: Vec<PartialOp>,
+    write_ops: Vec<PartialOp>,
+}
+
+impl Builder {
+    /// Creates a new, empty builder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sequences a read of `data` from the mock.
+    pub fn read(&mut self, data: &[u8]) -> &mut Self {
+        self.inner.read(data);
+        self
+    }
+
+    /// Sequences a read of `data` from the mock, broken up according to `ops`.
+    pub fn read_with<I>(&mut self, data: &[u8], ops: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp>,
+    {
+        self.inner.read(data);
+        self.read_ops.extend(ops);
+        self
+    }
+
+    /// Sequences a read error from the mock.
+    pub fn read_error(&mut self, error: io::Error) -> &mut Self {
+        self.inner.read_error(error);
+        self
+    }
+
+    /// Sequences `PartialOp`s for the next reads, without any data.
+    pub fn read_ops<I>(&mut self, ops: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp>,
+    {
+        self.read_ops.extend(ops);
+        self
+    }
+
+    /// Sequences a write of `data` to the mock.
+    pub fn write(&mut self, data: &[u8]) -> &mut Self {
+        self.inner.write(data);
+        self
+    }
+
+    /// Sequences a write of `data` to the mock, broken up according to `ops`.
+    pub fn write_with<I>(&mut self, data: &[u8], ops: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp>,
+    {
+        self.inner.write(data);
+        self.write_ops.extend(ops);
+        self
+    }
+
+    /// Sequences a write error from the mock.
+    pub fn write_error(&mut self, error: io::Error) -> &mut Self {
+        self.inner.write_error(error);
+        self
+    }
+
+    /// Sequences `PartialOp`s for the next writes, without any data.
+    pub fn write_ops<I>(&mut self, ops: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp>,
+    {
+        self.write_ops.extend(ops);
+        self
+    }
+
+    /// Sequences a wait in the mock.
+    ///
+    /// See `tokio_test::io::Builder::wait` for more.
+    pub fn wait(&mut self, duration: Duration) -> &mut Self {
+        self.inner.wait(duration);
+        self
+    }
+
+    /// Builds a `PartialMock` from the data and ops sequenced so far.
+    ///
+    /// The builder's sequences of `PartialOp`s are cleared, so it can be reused.
+    pub fn build(&mut self) -> PartialMock {
+        let mock = self.inner.build();
+        let read_ops = std::mem::take(&mut self.read_ops);
+        let write_ops = std::mem::take(&mut self.write_ops);
+        PartialAsyncRead::new(PartialAsyncWrite::new(mock, write_ops), read_ops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_mock() {
+        let mut mock = Builder::new()
+            .read_with(
+                b"hello",
+                vec![
+                    PartialOp::Err(io::ErrorKind::WouldBlock),
+                    PartialOp::Limited(2),
+                ],
+            )
+            .write(b"world")
+            .write_ops(vec![
+                PartialOp::Limited(1),
+                PartialOp::Err(io::ErrorKind::Interrupted),
+                PartialOp::Err(io::ErrorKind::BrokenPipe),
+            ])
+            .build();
+
+        let mut buf = [0; 8];
+        assert_eq!(mock.read(&mut buf).await.unwrap(), 2);
+        assert_eq!(mock.read(&mut buf[2..]).await.unwrap(), 3);
+        assert_eq!(&buf[..5], b"hello");
+
+        assert_eq!(mock.write(b"world").await.unwrap(), 1);
+        // Interrupted is retried, then the BrokenPipe error is returned.
+        assert_eq!(
+            mock.write(b"orld").await.unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+        mock.write_all(b"orld").await.unwrap();
+    }
+}
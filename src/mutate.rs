@@ -0,0 +1,448 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Mutators that derive new sequences of `PartialOp`s from existing ones.
+//!
+//! A schedule that's already known to be interesting, such as one saved with `corpus`, imported
+//! with `strace` or written by hand, is often a better starting point than a random one: its
+//! variations keep most of what made it interesting. Each function here takes a sequence and
+//! returns a new one, for additional test passes over the same code.
+//!
+//! Labels are kept on the operations they were on.
+//!
+//! [`Fuzzer`] combines these into a fuzz loop over a recorded trace: it applies a few random
+//! [`Mutation`]s to the trace at a time, runs a property on each result, and shrinks a failure
+//! back toward the original trace by dropping the mutations that aren't needed to reproduce it.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use partial_io::{mutate, PartialOp, PartialWrite};
+//! use std::io::{self, Write};
+//!
+//! let ops = vec![PartialOp::Limited(4), PartialOp::Unlimited];
+//! let passes = vec![
+//!     ops.clone(),
+//!     mutate::halve_all_limits(&ops),
+//!     mutate::insert_error_after_each(&ops, io::ErrorKind::Interrupted),
+//! ];
+//! for ops in passes {
+//!     let mut partial_write = PartialWrite::new(Vec::new(), ops);
+//!     // write_all retries Interrupted errors.
+//!     partial_write.write_all(b"hello world").unwrap();
+//!     assert_eq!(partial_write.get_ref(), b"hello world");
+//! }
+//! ```
+//!
+//! [`Fuzzer`]: struct.Fuzzer.html
+//! [`Mutation`]: enum.Mutation.html
+
+use crate::{corpus, fixtures::splitmix64, PartialOp};
+use std::{fmt, io};
+
+/// Returns `ops` with a `PartialOp::Err(kind)` inserted after each operation.
+///
+/// With `Interrupted`, this checks that every call is retried. With other kinds, it checks that
+/// an error is handled after every amount of progress the original sequence made.
+pub fn insert_error_after_each(ops: &[PartialOp], kind: io::ErrorKind) -> Vec<PartialOp> {
+    ops.iter()
+        .flat_map(|op| vec![op.clone(), PartialOp::Err(kind)])
+        .collect()
+}
+
+/// Returns `ops` with the length of every `PartialOp::Limited` and `PartialOp::Fragment` halved,
+/// rounding down, and every `PartialOp::LimitedFraction` halved.
+///
+/// Lengths of 1 are kept, since a length of 0 is reported as the end of a stream. Lengths of 0
+/// are kept as well. Applying this repeatedly converges on reading or writing a byte at a time.
+pub fn halve_all_limits(ops: &[PartialOp]) -> Vec<PartialOp> {
+    ops.iter().map(halve_limit).collect()
+}
+
+fn halve_limit(op: &PartialOp) -> PartialOp {
+    let halve = |n: usize| if n > 1 { n / 2 } else { n };
+    match op {
+        PartialOp::Limited(n) => PartialOp::Limited(halve(*n)),
+        PartialOp::Fragment(n) => PartialOp::Fragment(halve(*n)),
+        PartialOp::LimitedFraction(num, den) => {
+            PartialOp::LimitedFraction(*num, den.saturating_mul(2))
+        }
+        PartialOp::Labeled(op, label) => PartialOp::Labeled(Box::new(halve_limit(op)), label),
+        op => op.clone(),
+    }
+}
+
+/// Returns `ops` with its errors moved to pseudo-random positions, picked with `seed`.
+///
+/// The operations that aren't errors, as reported by `PartialOp::is_error`, stay in the same
+/// order. The errors are shuffled among themselves too. The same seed always gives the same
+/// sequence.
+pub fn shuffle_errors(ops: &[PartialOp], seed: u64) -> Vec<PartialOp> {
+    let mut state = seed;
+    let (mut errors, others): (Vec<_>, Vec<_>) = ops.iter().cloned().partition(PartialOp::is_error);
+    shuffle(&mut errors, &mut state);
+    // Which positions of the result hold errors.
+    let mut is_error = vec![false; ops.len()];
+    is_error[..errors.len()].fill(true);
+    shuffle(&mut is_error, &mut state);
+
+    let (mut errors, mut others) = (errors.into_iter(), others.into_iter());
+    is_error
+        .into_iter()
+        .map(|error| {
+            let op = if error { errors.next() } else { others.next() };
+            op.expect("every position is filled once")
+        })
+        .collect()
+}
+
+/// A change to a sequence of `PartialOp`s, applied by [`Fuzzer`].
+///
+/// Indexes are taken modulo the length of the sequence when the mutation is applied, so that a
+/// list of mutations still applies once some of them have been removed while shrinking.
+///
+/// [`Fuzzer`]: struct.Fuzzer.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mutation {
+    /// Inserts a `PartialOp::Err(kind)` before the operation at this index, or at the end.
+    InsertError(usize, io::ErrorKind),
+    /// Halves the length of the operation at this index, as [`halve_all_limits`] does.
+    ///
+    /// [`halve_all_limits`]: fn.halve_all_limits.html
+    HalveLimit(usize),
+    /// Removes the operation at this index.
+    Remove(usize),
+    /// Applies [`insert_error_after_each`].
+    ///
+    /// [`insert_error_after_each`]: fn.insert_error_after_each.html
+    InsertErrorAfterEach(io::ErrorKind),
+    /// Applies [`halve_all_limits`].
+    ///
+    /// [`halve_all_limits`]: fn.halve_all_limits.html
+    HalveAllLimits,
+    /// Applies [`shuffle_errors`] with this seed.
+    ///
+    /// [`shuffle_errors`]: fn.shuffle_errors.html
+    ShuffleErrors(u64),
+}
+
+impl Mutation {
+    /// Returns `ops` with this mutation applied.
+    pub fn apply(&self, ops: &[PartialOp]) -> Vec<PartialOp> {
+        let mut ops = ops.to_vec();
+        match *self {
+            Mutation::InsertError(at, kind) => {
+                ops.insert(at % (ops.len() + 1), PartialOp::Err(kind))
+            }
+            Mutation::HalveLimit(at) if !ops.is_empty() => {
+                let at = at % ops.len();
+                ops[at] = halve_limit(&ops[at]);
+            }
+            Mutation::Remove(at) if !ops.is_empty() => {
+                ops.remove(at % ops.len());
+            }
+            Mutation::HalveLimit(_) | Mutation::Remove(_) => {}
+            Mutation::InsertErrorAfterEach(kind) => return insert_error_after_each(&ops, kind),
+            Mutation::HalveAllLimits => return halve_all_limits(&ops),
+            Mutation::ShuffleErrors(seed) => return shuffle_errors(&ops, seed),
+        }
+        ops
+    }
+}
+
+// Applies `mutations` to `trace` in order.
+fn apply_all(trace: &[PartialOp], mutations: &[Mutation]) -> Vec<PartialOp> {
+    mutations
+        .iter()
+        .fold(trace.to_vec(), |ops, mutation| mutation.apply(&ops))
+}
+
+/// A fuzz loop that mutates a recorded trace of `PartialOp`s.
+///
+/// A trace can come from `OpEngine::record_ops`, `strace`, `pcap` or `corpus`, or be written by
+/// hand. [`run`] first checks the property against the trace as-is, then against [`iterations`]
+/// mutated copies of it. Each copy has between 1 and [`max_mutations`] random [`Mutation`]s
+/// applied, picked with the seed, so a run can be reproduced.
+///
+/// Once the property fails, the mutations are shrunk: each one is removed in turn, and the
+/// removal is kept if the property still fails. The failure that's returned is reached from the
+/// original trace with as few mutations as this finds. Save its operations with `corpus::save`
+/// to replay it as a regression test.
+///
+/// # Examples
+///
+/// ```rust
+/// use partial_io::{mutate::Fuzzer, PartialOp, PartialWrite};
+/// use std::io::{self, Write};
+///
+/// // A trace recorded from a real connection.
+/// let trace = vec![PartialOp::Limited(1448), PartialOp::Limited(600), PartialOp::Unlimited];
+/// let res = Fuzzer::new(42).iterations(200).run(&trace, |ops| {
+///     let mut partial_write = PartialWrite::new(Vec::new(), ops);
+///     // write_all retries Interrupted errors, and returns other errors.
+///     match partial_write.write_all(&[7; 4096]) {
+///         Ok(()) => assert_eq!(partial_write.get_ref().len(), 4096),
+///         Err(err) => assert_ne!(err.kind(), io::ErrorKind::Interrupted),
+///     }
+///     Ok::<_, String>(())
+/// });
+/// res.unwrap();
+/// ```
+///
+/// [`run`]: struct.Fuzzer.html#method.run
+/// [`iterations`]: struct.Fuzzer.html#method.iterations
+/// [`max_mutations`]: struct.Fuzzer.html#method.max_mutations
+/// [`Mutation`]: enum.Mutation.html
+#[derive(Clone, Debug)]
+pub struct Fuzzer {
+    seed: u64,
+    iterations: usize,
+    max_mutations: usize,
+    error_kinds: Vec<io::ErrorKind>,
+}
+
+impl Fuzzer {
+    /// Creates a new `Fuzzer` that picks mutations with `seed`.
+    ///
+    /// By default, it runs 100 iterations of up to 4 mutations each, and inserts `Interrupted`
+    /// and `WouldBlock` errors.
+    pub fn new(seed: u64) -> Self {
+        Fuzzer {
+            seed,
+            iterations: 100,
+            max_mutations: 4,
+            error_kinds: vec![io::ErrorKind::Interrupted, io::ErrorKind::WouldBlock],
+        }
+    }
+
+    /// Sets the number of mutated traces to check.
+    pub fn iterations(&mut self, iterations: usize) -> &mut Self {
+        self.iterations = iterations;
+        self
+    }
+
+    /// Sets the maximum number of mutations applied to each trace.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_mutations` is 0.
+    pub fn max_mutations(&mut self, max_mutations: usize) -> &mut Self {
+        assert!(max_mutations > 0, "max_mutations must be greater than 0");
+        self.max_mutations = max_mutations;
+        self
+    }
+
+    /// Sets the kinds of errors that mutations insert.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `error_kinds` is empty.
+    pub fn error_kinds(&mut self, error_kinds: Vec<io::ErrorKind>) -> &mut Self {
+        assert!(!error_kinds.is_empty(), "error_kinds must not be empty");
+        self.error_kinds = error_kinds;
+        self
+    }
+
+    /// Checks `property` against `trace` and mutated copies of it, returning the first failure
+    /// after shrinking it.
+    pub fn run<E, F>(&self, trace: &[PartialOp], mut property: F) -> Result<(), FuzzFailure<E>>
+    where
+        F: FnMut(Vec<PartialOp>) -> Result<(), E>,
+    {
+        let mut state = self.seed;
+        for iteration in 0..=self.iterations {
+            let mutations = if iteration == 0 {
+                Vec::new()
+            } else {
+                let count = 1 + (splitmix64(&mut state) % self.max_mutations as u64) as usize;
+                (0..count).map(|_| self.pick(&mut state)).collect()
+            };
+            if let Err(error) = property(apply_all(trace, &mutations)) {
+                return Err(shrink(trace, iteration, mutations, error, property));
+            }
+        }
+        Ok(())
+    }
+
+    // Picks a random mutation.
+    fn pick(&self, state: &mut u64) -> Mutation {
+        let word = splitmix64(state);
+        // The low bits pick the kind of mutation, and the high bits its argument.
+        let arg = (word >> 32) as usize;
+        let kind = self.error_kinds[arg % self.error_kinds.len()];
+        match word % 10 {
+            0..=2 => Mutation::InsertError(arg, kind),
+            3 | 4 => Mutation::HalveLimit(arg),
+            5 => Mutation::Remove(arg),
+            6 => Mutation::InsertErrorAfterEach(kind),
+            7 => Mutation::HalveAllLimits,
+            _ => Mutation::ShuffleErrors(splitmix64(state)),
+        }
+    }
+}
+
+// Removes the mutations that aren't needed for `property` to fail.
+fn shrink<E, F>(
+    trace: &[PartialOp],
+    iteration: usize,
+    mut mutations: Vec<Mutation>,
+    mut error: E,
+    mut property: F,
+) -> FuzzFailure<E>
+where
+    F: FnMut(Vec<PartialOp>) -> Result<(), E>,
+{
+    let mut i = 0;
+    while i < mutations.len() {
+        let mut candidate = mutations.clone();
+        candidate.remove(i);
+        match property(apply_all(trace, &candidate)) {
+            Err(candidate_error) => {
+                mutations = candidate;
+                error = candidate_error;
+            }
+            Ok(()) => i += 1,
+        }
+    }
+    FuzzFailure {
+        iteration,
+        ops: apply_all(trace, &mutations),
+        mutations,
+        error,
+    }
+}
+
+/// A failure found by [`Fuzzer::run`], after shrinking.
+///
+/// [`Fuzzer::run`]: struct.Fuzzer.html#method.run
+#[derive(Debug)]
+pub struct FuzzFailure<E> {
+    /// The iteration that first failed, where 0 is the original trace.
+    pub iteration: usize,
+    /// The mutations that turn the original trace into `ops`, in the order they're applied.
+    pub mutations: Vec<Mutation>,
+    /// The operations the property failed with.
+    pub ops: Vec<PartialOp>,
+    /// The error returned by the property.
+    pub error: E,
+}
+
+impl<E: fmt::Display> fmt::Display for FuzzFailure<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "property failed at iteration {} with {} mutations {:?}, schedule {:016x}: {}",
+            self.iteration,
+            self.mutations.len(),
+            self.mutations,
+            corpus::fingerprint(&self.ops),
+            self.error
+        )?;
+        write!(f, "{}", corpus::encode(&self.ops))
+    }
+}
+
+// Shuffles `items` with the Fisher-Yates algorithm.
+fn shuffle<T>(items: &mut [T], state: &mut u64) {
+    for i in (1..items.len()).rev() {
+        let j = (splitmix64(state) % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_halve_all_limits() {
+        let ops = vec![
+            PartialOp::Limited(9),
+            PartialOp::Fragment(1),
+            PartialOp::Limited(0),
+            PartialOp::Limited(4).labeled("header"),
+            PartialOp::Unlimited,
+        ];
+        assert_eq!(
+            corpus_text(&halve_all_limits(&ops)),
+            corpus_text(&[
+                PartialOp::Limited(4),
+                PartialOp::Fragment(1),
+                PartialOp::Limited(0),
+                PartialOp::Limited(2).labeled("header"),
+                PartialOp::Unlimited,
+            ])
+        );
+    }
+
+    #[test]
+    fn test_shuffle_errors() {
+        let ops = vec![
+            PartialOp::Limited(1),
+            PartialOp::Err(io::ErrorKind::Interrupted),
+            PartialOp::Limited(2),
+            PartialOp::Err(io::ErrorKind::WouldBlock),
+            PartialOp::Limited(3),
+        ];
+        let mut seen = std::collections::HashSet::new();
+        for seed in 0..32 {
+            let shuffled = shuffle_errors(&ops, seed);
+            assert_eq!(
+                corpus_text(&shuffled),
+                corpus_text(&shuffle_errors(&ops, seed))
+            );
+            let others: Vec<_> = shuffled
+                .iter()
+                .filter(|op| !op.is_error())
+                .cloned()
+                .collect();
+            assert_eq!(corpus_text(&others), "limited 1\nlimited 2\nlimited 3\n");
+            assert_eq!(shuffled.iter().filter(|op| op.is_error()).count(), 2);
+            seen.insert(corpus_text(&shuffled));
+        }
+        assert!(seen.len() > 10, "only {} distinct sequences", seen.len());
+    }
+
+    #[test]
+    fn test_fuzzer() {
+        // Gives up on the first Interrupted error, instead of retrying it.
+        fn write_all<W: io::Write>(writer: &mut W, mut buf: &[u8]) -> io::Result<()> {
+            while !buf.is_empty() {
+                let n = writer.write(buf)?;
+                buf = &buf[n..];
+            }
+            Ok(())
+        }
+
+        let trace = vec![PartialOp::Limited(2), PartialOp::Limited(2)];
+        let mut fuzzer = Fuzzer::new(7);
+        fuzzer.error_kinds(vec![io::ErrorKind::Interrupted]);
+        let failure = fuzzer
+            .run(&trace, |ops| {
+                let mut partial_write = crate::PartialWrite::new(Vec::new(), ops);
+                write_all(&mut partial_write, b"abcdef").map_err(|err| err.kind())
+            })
+            .unwrap_err();
+        assert!(failure.iteration > 0, "the original trace passes");
+        assert_eq!(failure.mutations.len(), 1, "{}", failure);
+        assert_eq!(failure.error, io::ErrorKind::Interrupted);
+        assert!(failure.ops.iter().any(PartialOp::is_error));
+
+        let res = Fuzzer::new(7).run(&trace, |ops| {
+            let mut partial_write = crate::PartialWrite::new(Vec::new(), ops);
+            match io::Write::write_all(&mut partial_write, b"abcdef") {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => Err(err),
+                _ => Ok(()),
+            }
+        });
+        assert!(res.is_ok());
+    }
+
+    fn corpus_text(ops: &[PartialOp]) -> String {
+        crate::corpus::encode(ops)
+    }
+}
@@ -0,0 +1,377 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Importing sequences of `PartialOp`s from packet captures.
+//!
+//! Data sent over TCP arrives in segments whose sizes depend on the network, the sender's buffers
+//! and timing, and rarely look like the uniform chunks a test would pick. An [`Import`] reads a
+//! capture in the classic `pcap` format, as written by `tcpdump -w`, and turns the payload size of
+//! every TCP segment into `PartialOp::Limited`, so that a reader can be tested against the
+//! traffic shapes seen in practice.
+//!
+//! Segments can be filtered by source and destination port, to pick one direction of one
+//! connection. Segments without a payload, such as bare acknowledgements, are skipped. With
+//! `delays`, the gap between two segments is replayed as a `PartialOp::Delay` before the second
+//! one.
+//!
+//! Ethernet, Linux cooked (`tcpdump -i any`), loopback and raw IP captures are supported, over
+//! both IPv4 and IPv6. The newer `pcapng` format isn't: convert captures with
+//! `editcap -F pcap` first.
+//!
+//! Available with the `pcap` feature.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! # #[cfg(feature = "pcap")]
+//! use partial_io::{pcap::Import, PartialRead};
+//! # #[cfg(feature = "pcap")]
+//! use std::{fs, io::Read};
+//!
+//! # #[cfg(feature = "pcap")]
+//! fn main() -> std::io::Result<()> {
+//!     // Responses sent by a server listening on port 8080.
+//!     let capture = fs::read("server.pcap")?;
+//!     let ops = Import::new().src_port(8080).delays(true).ops(&capture)?;
+//!
+//!     let mut partial_read = PartialRead::new(fs::File::open("response.bin")?, ops);
+//!     let mut response = Vec::new();
+//!     partial_read.read_to_end(&mut response)?;
+//!     Ok(())
+//! }
+//!
+//! # #[cfg(not(feature = "pcap"))]
+//! # fn main() {
+//! #     assert!(true, "dummy test");
+//! # }
+//! ```
+//!
+//! [`Import`]: struct.Import.html
+
+use crate::PartialOp;
+use std::{io, time::Duration};
+
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const IPPROTO_TCP: u8 = 6;
+
+/// Converts the TCP segments in a packet capture into `PartialOp`s.
+///
+/// See [the module level documentation](index.html) for more.
+#[derive(Clone, Debug, Default)]
+pub struct Import {
+    src_port: Option<u16>,
+    dst_port: Option<u16>,
+    delays: bool,
+}
+
+impl Import {
+    /// Creates a new import that includes every TCP segment, without delays.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only includes segments sent from `port`.
+    pub fn src_port(&mut self, port: u16) -> &mut Self {
+        self.src_port = Some(port);
+        self
+    }
+
+    /// Only includes segments sent to `port`.
+    pub fn dst_port(&mut self, port: u16) -> &mut Self {
+        self.dst_port = Some(port);
+        self
+    }
+
+    /// Whether to add a `PartialOp::Delay` for the time between two included segments.
+    ///
+    /// Defaults to `false`.
+    pub fn delays(&mut self, delays: bool) -> &mut Self {
+        self.delays = delays;
+        self
+    }
+
+    /// Reads `capture`, the contents of a `pcap` file, and returns an operation for each included
+    /// segment.
+    ///
+    /// Returns an error of kind `InvalidData` if the capture is truncated, isn't in the `pcap`
+    /// format, or uses an unsupported link type. Packets that aren't TCP, or are too short to
+    /// hold the headers, are skipped.
+    pub fn ops(&self, capture: &[u8]) -> io::Result<Vec<PartialOp>> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        let header = capture
+            .get(..24)
+            .ok_or_else(|| invalid("truncated file header".to_string()))?;
+        let magic = [header[0], header[1], header[2], header[3]];
+        let (big_endian, nanos) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (MAGIC_MICROS, _) => (false, false),
+            (MAGIC_NANOS, _) => (false, true),
+            (_, MAGIC_MICROS) => (true, false),
+            (_, MAGIC_NANOS) => (true, true),
+            _ => return Err(invalid("not a pcap file".to_string())),
+        };
+        let read_u32 = |bytes: &[u8]| {
+            let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+            if big_endian {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            }
+        };
+        // The upper bits of the link type field hold other information.
+        let linktype = read_u32(&header[20..24]) & 0x0fff_ffff;
+        if !matches!(
+            linktype,
+            LINKTYPE_NULL
+                | LINKTYPE_ETHERNET
+                | LINKTYPE_RAW
+                | LINKTYPE_LINUX_SLL
+                | LINKTYPE_LINUX_SLL2
+        ) {
+            return Err(invalid(format!("unsupported link type {}", linktype)));
+        }
+
+        let mut ops = Vec::new();
+        let mut previous: Option<Duration> = None;
+        let mut rest = &capture[24..];
+        let mut index = 0;
+        while !rest.is_empty() {
+            index += 1;
+            let record = rest
+                .get(..16)
+                .ok_or_else(|| invalid(format!("packet {}: truncated header", index)))?;
+            let secs = read_u32(&record[0..4]);
+            let frac = read_u32(&record[4..8]);
+            let len = read_u32(&record[8..12]) as usize;
+            let packet = rest[16..]
+                .get(..len)
+                .ok_or_else(|| invalid(format!("packet {}: truncated data", index)))?;
+            rest = &rest[16 + len..];
+
+            let segment = match link_payload(linktype, packet)
+                .and_then(|(ethertype, ip)| ip_payload(ethertype, ip))
+            {
+                Some(segment) => segment,
+                None => continue,
+            };
+            if segment.len == 0
+                || self.src_port.is_some_and(|port| port != segment.src_port)
+                || self.dst_port.is_some_and(|port| port != segment.dst_port)
+            {
+                continue;
+            }
+
+            let timestamp = if nanos {
+                Duration::new(secs.into(), frac)
+            } else {
+                Duration::new(secs.into(), 0) + Duration::from_micros(frac.into())
+            };
+            if self.delays {
+                if let Some(gap) = previous.and_then(|previous| timestamp.checked_sub(previous)) {
+                    if gap > Duration::ZERO {
+                        ops.push(PartialOp::Delay(gap));
+                    }
+                }
+                previous = Some(timestamp);
+            }
+            ops.push(PartialOp::Limited(segment.len));
+        }
+        Ok(ops)
+    }
+}
+
+struct Segment {
+    src_port: u16,
+    dst_port: u16,
+    len: usize,
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+// Strips the link layer header, returning the ethertype of the network layer and its packet.
+fn link_payload(linktype: u32, packet: &[u8]) -> Option<(u16, &[u8])> {
+    match linktype {
+        LINKTYPE_NULL => {
+            // The address family is in the byte order of the host that wrote the capture.
+            let family = packet.get(..4)?;
+            let family = u32::from_le_bytes([family[0], family[1], family[2], family[3]]).min(
+                u32::from_be_bytes([family[0], family[1], family[2], family[3]]),
+            );
+            let ethertype = match family {
+                2 => ETHERTYPE_IPV4,
+                // AF_INET6 on Linux, FreeBSD, macOS and others.
+                10 | 24 | 28 | 30 => ETHERTYPE_IPV6,
+                _ => return None,
+            };
+            Some((ethertype, &packet[4..]))
+        }
+        LINKTYPE_ETHERNET => {
+            let mut ethertype = read_u16(packet.get(12..14)?);
+            let mut rest = &packet[14..];
+            while ethertype == ETHERTYPE_VLAN {
+                ethertype = read_u16(rest.get(2..4)?);
+                rest = &rest[4..];
+            }
+            Some((ethertype, rest))
+        }
+        LINKTYPE_RAW => {
+            let ethertype = match packet.first()? >> 4 {
+                4 => ETHERTYPE_IPV4,
+                6 => ETHERTYPE_IPV6,
+                _ => return None,
+            };
+            Some((ethertype, packet))
+        }
+        LINKTYPE_LINUX_SLL => Some((read_u16(packet.get(14..16)?), packet.get(16..)?)),
+        LINKTYPE_LINUX_SLL2 => Some((read_u16(packet.get(0..2)?), packet.get(20..)?)),
+        _ => None,
+    }
+}
+
+// Strips the IP header, returning the TCP segment it holds, if any.
+//
+// Lengths are taken from the IP header, so that a segment is counted in full even if the capture
+// only kept its first bytes.
+fn ip_payload(ethertype: u16, packet: &[u8]) -> Option<Segment> {
+    let (protocol, tcp, tcp_len) = match ethertype {
+        ETHERTYPE_IPV4 => {
+            let header_len = usize::from(packet.first()? & 0x0f) * 4;
+            // The total length is 0 for packets captured before segmentation offload.
+            let total_len = match usize::from(read_u16(packet.get(2..4)?)) {
+                0 => packet.len(),
+                total_len => total_len,
+            };
+            // Only the first fragment of a packet holds the TCP header.
+            if read_u16(packet.get(6..8)?) & 0x1fff != 0 {
+                return None;
+            }
+            (
+                *packet.get(9)?,
+                packet.get(header_len..)?,
+                total_len.checked_sub(header_len)?,
+            )
+        }
+        ETHERTYPE_IPV6 => {
+            let mut payload_len = usize::from(read_u16(packet.get(4..6)?));
+            let mut next = *packet.get(6)?;
+            let mut rest = packet.get(40..)?;
+            // Skip the hop-by-hop, routing and destination options extension headers.
+            while matches!(next, 0 | 43 | 60) {
+                let len = (usize::from(*rest.get(1)?) + 1) * 8;
+                next = rest[0];
+                rest = rest.get(len..)?;
+                payload_len = payload_len.checked_sub(len)?;
+            }
+            (next, rest, payload_len)
+        }
+        _ => return None,
+    };
+    if protocol != IPPROTO_TCP {
+        return None;
+    }
+    let header_len = usize::from(tcp.get(12)? >> 4) * 4;
+    Some(Segment {
+        src_port: read_u16(tcp.get(0..2)?),
+        dst_port: read_u16(tcp.get(2..4)?),
+        len: tcp_len.checked_sub(header_len)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a little-endian capture of Ethernet frames, each with a timestamp in microseconds,
+    // ports and a payload length.
+    fn capture(packets: &[(u32, u16, u16, usize)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&MAGIC_MICROS.to_le_bytes());
+        out.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0]);
+        out.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        for &(micros, src_port, dst_port, len) in packets {
+            let mut frame = vec![0; 12];
+            frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+            frame.extend_from_slice(&[0x45, 0]);
+            frame.extend_from_slice(&((20 + 20 + len) as u16).to_be_bytes());
+            frame.extend_from_slice(&[0, 0, 0x40, 0, 64, IPPROTO_TCP, 0, 0]);
+            frame.extend_from_slice(&[127, 0, 0, 1, 127, 0, 0, 1]);
+            frame.extend_from_slice(&src_port.to_be_bytes());
+            frame.extend_from_slice(&dst_port.to_be_bytes());
+            frame.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0x50, 0x18, 0, 0, 0, 0, 0, 0]);
+            frame.resize(frame.len() + len, b'x');
+            // Ethernet frames are padded to at least 60 bytes.
+            frame.resize(frame.len().max(60), 0);
+
+            out.extend_from_slice(&(micros / 1_000_000).to_le_bytes());
+            out.extend_from_slice(&(micros % 1_000_000).to_le_bytes());
+            out.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            out.extend_from_slice(&frame);
+        }
+        out
+    }
+
+    #[test]
+    fn test_import() {
+        let capture = capture(&[
+            (1_000_000, 40000, 80, 3),
+            (1_000_100, 80, 40000, 1448),
+            (1_000_200, 40000, 80, 0),
+            (1_500_000, 80, 40000, 5),
+        ]);
+
+        let ops = Import::new().ops(&capture).unwrap();
+        assert!(
+            matches!(
+                ops[..],
+                [
+                    PartialOp::Limited(3),
+                    PartialOp::Limited(1448),
+                    PartialOp::Limited(5),
+                ]
+            ),
+            "the padding and the bare acknowledgement are ignored: {:?}",
+            ops
+        );
+
+        let ops = Import::new()
+            .src_port(80)
+            .dst_port(40000)
+            .delays(true)
+            .ops(&capture)
+            .unwrap();
+        assert_eq!(ops.len(), 3, "{:?}", ops);
+        assert!(matches!(ops[0], PartialOp::Limited(1448)));
+        assert!(
+            matches!(ops[1], PartialOp::Delay(gap) if gap == Duration::from_micros(499_900)),
+            "{:?}",
+            ops
+        );
+        assert!(matches!(ops[2], PartialOp::Limited(5)));
+
+        let err = Import::new()
+            .ops(&capture[..capture.len() - 1])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "packet 4: truncated data");
+        assert!(Import::new().ops(b"\x0a\x0d\x0d\x0a").is_err(), "pcapng");
+    }
+}
@@ -0,0 +1,210 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! A fixture that feeds data into a real socket according to a schedule of `PartialOp`s.
+
+use crate::{engine::fraction_of, OpEngine, PartialOp};
+use std::{
+    cmp, fmt,
+    io::{self, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    thread::{self, JoinHandle},
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+/// Feeds data into a real OS socket, shaped by `PartialOp`s.
+///
+/// The wrappers in this crate work in-process, so they can't help with code that has to own a
+/// file descriptor, for example to register it with `poll` or to `splice` from it. A
+/// `PartialPipe` instead hands out one end of a socket, and writes data into the other end from a
+/// background thread, one operation at a time:
+///
+/// * `PartialOp::Limited(n)` writes the next `n` bytes. `PartialOp::Fragment(n)` does the same.
+///   `PartialOp::LimitedFraction(num, den)` writes that fraction of the data left.
+/// * `PartialOp::Unlimited`, and the end of the operations, write the rest of the data.
+/// * `PartialOp::Delay(duration)` pauses for `duration`.
+/// * `PartialOp::Err`, `PartialOp::ErrTimes`, `PartialOp::OsErr` and `PartialOp::ErrWith` close
+///   the socket early, so that the reader sees the end of the stream before the end of the data.
+///   The error itself can't be sent over the socket, so `join` still returns the number of bytes
+///   written.
+/// * `PartialOp::Panic` panics on the background thread, which closes the socket early too. The
+///   panic is resumed by `join`.
+/// * `PartialOp::Labeled` applies the operation it wraps.
+///
+/// The socket is closed once all of the data has been written. Each write goes out right away,
+/// with Nagle's algorithm disabled for TCP, but the reader only sees it as a separate chunk if it
+/// reads before the next write. Add a `PartialOp::Delay` between writes to make that likely.
+///
+/// # Examples
+///
+/// ```rust
+/// use partial_io::{PartialOp, PartialPipe};
+/// use std::{io::Read, time::Duration};
+///
+/// let ops = vec![
+///     PartialOp::Limited(2),
+///     PartialOp::Delay(Duration::from_millis(10)),
+///     PartialOp::Limited(3),
+///     PartialOp::Err(std::io::ErrorKind::ConnectionReset),
+/// ];
+/// let (mut stream, pipe) = PartialPipe::tcp(b"hello world".to_vec(), ops).unwrap();
+///
+/// let mut buf = Vec::new();
+/// stream.read_to_end(&mut buf).unwrap();
+/// assert_eq!(buf, b"hello", "the socket was closed early");
+/// assert_eq!(pipe.join().unwrap(), 5);
+/// ```
+pub struct PartialPipe {
+    handle: JoinHandle<io::Result<usize>>,
+}
+
+impl PartialPipe {
+    /// Creates a pair of connected Unix domain sockets, and feeds `data` into one of them.
+    ///
+    /// Returns the other socket, to be read by the code under test.
+    #[cfg(unix)]
+    pub fn unix<I>(data: Vec<u8>, iter: I) -> io::Result<(UnixStream, Self)>
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        let (reader, writer) = UnixStream::pair()?;
+        Ok((reader, Self::spawn(writer, data, OpEngine::new(iter))))
+    }
+
+    /// Creates a pair of TCP connections over the loopback interface, and feeds `data` into one of
+    /// them.
+    ///
+    /// Returns the other connection, to be read by the code under test.
+    pub fn tcp<I>(data: Vec<u8>, iter: I) -> io::Result<(TcpStream, Self)>
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let reader = TcpStream::connect(listener.local_addr()?)?;
+        let (writer, _) = listener.accept()?;
+        writer.set_nodelay(true)?;
+        Ok((reader, Self::spawn(writer, data, OpEngine::new(iter))))
+    }
+
+    fn spawn<W>(writer: W, data: Vec<u8>, engine: OpEngine) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        PartialPipe {
+            handle: thread::spawn(move || feed(writer, &data, engine)),
+        }
+    }
+
+    /// Waits for the background thread to finish, and returns the number of bytes it wrote.
+    ///
+    /// Returns an error if a write failed, for example because the reading end was closed.
+    pub fn join(self) -> io::Result<usize> {
+        match self.handle.join() {
+            Ok(res) => res,
+            Err(payload) => std::panic::resume_unwind(payload),
+        }
+    }
+}
+
+impl fmt::Debug for PartialPipe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PartialPipe")
+            .field("finished", &self.handle.is_finished())
+            .finish()
+    }
+}
+
+// Writes `data` into `writer` according to the operations in `engine`, then closes it.
+fn feed<W: Write>(mut writer: W, data: &[u8], mut engine: OpEngine) -> io::Result<usize> {
+    let mut offset = 0;
+    while offset < data.len() {
+        let n = match engine.next_op() {
+            Some(PartialOp::Limited(n)) | Some(PartialOp::Fragment(n)) => n,
+            Some(PartialOp::LimitedFraction(num, den)) => {
+                fraction_of(num, den, data.len() - offset)
+            }
+            Some(PartialOp::Unlimited) | None => data.len() - offset,
+            Some(PartialOp::Delay(duration)) => {
+                thread::sleep(duration);
+                continue;
+            }
+            Some(PartialOp::Err(_))
+            | Some(PartialOp::ErrTimes(..))
+            | Some(PartialOp::OsErr(_))
+            | Some(PartialOp::ErrWith(_)) => break,
+            Some(PartialOp::Panic(message)) => engine.injected_panic(message),
+            Some(PartialOp::Labeled(..)) => unreachable!("labels are removed by next_op"),
+        };
+        let end = cmp::min(offset + n, data.len());
+        writer.write_all(&data[offset..end])?;
+        writer.flush()?;
+        offset = end;
+    }
+    Ok(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix() {
+        use std::{io::Read, os::unix::io::AsRawFd};
+
+        let ops = vec![PartialOp::Limited(3), PartialOp::Limited(0)];
+        let (mut stream, pipe) = PartialPipe::unix(b"abcdef".to_vec(), ops).unwrap();
+        assert!(stream.as_raw_fd() >= 0);
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(
+            buf, b"abcdef",
+            "the rest is written once the operations run out"
+        );
+        assert_eq!(pipe.join().unwrap(), 6);
+    }
+
+    #[test]
+    fn test_tcp_closed_reader() {
+        let ops = vec![PartialOp::Delay(std::time::Duration::from_millis(20))];
+        let (stream, pipe) = PartialPipe::tcp(vec![0; 1 << 24], ops).unwrap();
+        drop(stream);
+        assert!(pipe.join().is_err(), "the data can't be written");
+    }
+
+    #[test]
+    fn test_tcp_errors_close_early() {
+        use std::io::Read;
+
+        let errors = vec![
+            PartialOp::Err(io::ErrorKind::ConnectionReset).labeled("reset"),
+            PartialOp::ErrTimes(io::ErrorKind::Interrupted, 2),
+            PartialOp::OsErr(32),
+            PartialOp::err_with(|| io::Error::other("custom")),
+        ];
+        for error in errors {
+            let ops = vec![PartialOp::Limited(2), error.clone()];
+            let (mut stream, pipe) = PartialPipe::tcp(b"abcdef".to_vec(), ops).unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).unwrap();
+            assert_eq!(buf, b"ab", "{:?} closes the socket", error);
+            assert_eq!(pipe.join().unwrap(), 2);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "injected, in 'feed'")]
+    fn test_tcp_panic() {
+        let ops = vec![PartialOp::Panic("injected").labeled("feed")];
+        let (_stream, pipe) = PartialPipe::tcp(b"abcdef".to_vec(), ops).unwrap();
+        let _ = pipe.join();
+    }
+}
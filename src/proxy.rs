@@ -0,0 +1,278 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Helpers for testing proxies and tunnels with partial IO.
+//!
+//! A proxy sits between two connections and copies data in both directions, so it has to cope
+//! with partial reads and writes on either side at any point. [`Scenario`] sets up a client and a
+//! server connection, hands the proxy's ends of both connections to the proxy as
+//! [`PartialDuplex`] endpoints with independent schedules, and records the data that arrives at
+//! each side.
+//!
+//! Available with the `tokio1` feature.
+//!
+//! # Examples
+//!
+//! ```rust
+//! # #[cfg(feature = "tokio1")]
+//! use partial_io::{proxy::Scenario, PartialOp};
+//! # #[cfg(feature = "tokio1")]
+//! use std::io;
+//!
+//! # #[cfg(feature = "tokio1")]
+//! #[tokio::main]
+//! async fn main() {
+//!     let transcript = Scenario::new(b"request".to_vec(), b"response".to_vec())
+//!         // Reads from the client are broken up into 2-byte pieces.
+//!         .client_ops(vec![PartialOp::Limited(2); 8], vec![])
+//!         // Writes to the server return Poll::Pending before every write.
+//!         .server_ops(vec![], vec![PartialOp::Err(io::ErrorKind::WouldBlock); 8])
+//!         .run_copy_bidirectional()
+//!         .await;
+//!
+//!     assert_eq!(transcript.to_server, b"request");
+//!     assert_eq!(transcript.to_client, b"response");
+//!     assert_eq!(transcript.output.unwrap(), (7, 8));
+//! }
+//!
+//! # #[cfg(not(feature = "tokio1"))]
+//! # fn main() {
+//! #     assert!(true, "dummy test");
+//! # }
+//! ```
+//!
+//! [`Scenario`]: struct.Scenario.html
+//! [`PartialDuplex`]: ../struct.PartialDuplex.html
+
+use crate::{make_ops, PartialDuplex, PartialOp};
+use std::{fmt, future::Future, io};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+/// The proxy's end of a connection set up by a [`Scenario`].
+///
+/// [`Scenario`]: struct.Scenario.html
+pub type Endpoint = PartialDuplex<DuplexStream>;
+
+/// The default size of the in-memory buffer for each connection, in bytes.
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A client and a server connected through a proxy under test.
+///
+/// The client writes its data, shuts down its write side, and reads whatever the proxy sends back
+/// until EOF. The server does the same. Schedules are set from the proxy's point of view: read
+/// ops apply to the proxy reading from an endpoint, and write ops to the proxy writing to it.
+///
+/// See [the module level documentation](index.html) for more.
+pub struct Scenario {
+    client_data: Vec<u8>,
+    server_data: Vec<u8>,
+    client_read_ops: Box<dyn Iterator<Item = PartialOp> + Send>,
+    client_write_ops: Box<dyn Iterator<Item = PartialOp> + Send>,
+    server_read_ops: Box<dyn Iterator<Item = PartialOp> + Send>,
+    server_write_ops: Box<dyn Iterator<Item = PartialOp> + Send>,
+    buffer_size: usize,
+}
+
+impl Scenario {
+    /// Creates a new scenario where the client sends `client_data` and the server sends
+    /// `server_data`.
+    ///
+    /// Both endpoints start out with empty schedules, so all operations are unlimited.
+    pub fn new(client_data: Vec<u8>, server_data: Vec<u8>) -> Self {
+        Scenario {
+            client_data,
+            server_data,
+            client_read_ops: make_ops(None),
+            client_write_ops: make_ops(None),
+            server_read_ops: make_ops(None),
+            server_write_ops: make_ops(None),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+        }
+    }
+
+    /// Sets the `PartialOp`s for the endpoint facing the client.
+    pub fn client_ops<IR, IW>(mut self, read_ops: IR, write_ops: IW) -> Self
+    where
+        IR: IntoIterator<Item = PartialOp> + 'static,
+        IR::IntoIter: Send,
+        IW: IntoIterator<Item = PartialOp> + 'static,
+        IW::IntoIter: Send,
+    {
+        self.client_read_ops = make_ops(read_ops);
+        self.client_write_ops = make_ops(write_ops);
+        self
+    }
+
+    /// Sets the `PartialOp`s for the endpoint facing the server.
+    pub fn server_ops<IR, IW>(mut self, read_ops: IR, write_ops: IW) -> Self
+    where
+        IR: IntoIterator<Item = PartialOp> + 'static,
+        IR::IntoIter: Send,
+        IW: IntoIterator<Item = PartialOp> + 'static,
+        IW::IntoIter: Send,
+    {
+        self.server_read_ops = make_ops(read_ops);
+        self.server_write_ops = make_ops(write_ops);
+        self
+    }
+
+    /// Sets the size of the in-memory buffer for each connection.
+    ///
+    /// A smaller buffer makes the client and server apply backpressure sooner. Defaults to
+    /// [`DEFAULT_BUFFER_SIZE`].
+    ///
+    /// [`DEFAULT_BUFFER_SIZE`]: constant.DEFAULT_BUFFER_SIZE.html
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Runs `proxy` between the client and the server to completion.
+    ///
+    /// `proxy` is called with the endpoints facing the client and the server, in that order. The
+    /// endpoints should be dropped or shut down once the proxy is done, so that the client and the
+    /// server see EOF.
+    ///
+    /// Errors seen by the client or the server (for example, because the proxy went away) end
+    /// that side's reads or writes early, and show up as missing data in the transcript.
+    pub async fn run<F, Fut>(self, proxy: F) -> Transcript<Fut::Output>
+    where
+        F: FnOnce(Endpoint, Endpoint) -> Fut,
+        Fut: Future,
+    {
+        let (client, client_endpoint) = tokio::io::duplex(self.buffer_size);
+        let (server, server_endpoint) = tokio::io::duplex(self.buffer_size);
+        let client_endpoint =
+            PartialDuplex::new(client_endpoint, self.client_read_ops, self.client_write_ops);
+        let server_endpoint =
+            PartialDuplex::new(server_endpoint, self.server_read_ops, self.server_write_ops);
+
+        let (to_client, to_server, output) = futures::join!(
+            exchange(client, self.client_data),
+            exchange(server, self.server_data),
+            proxy(client_endpoint, server_endpoint),
+        );
+        Transcript {
+            to_server,
+            to_client,
+            output,
+        }
+    }
+
+    /// Runs `tokio::io::copy_bidirectional` between the client and the server to completion.
+    pub async fn run_copy_bidirectional(self) -> Transcript<io::Result<(u64, u64)>> {
+        self.run(|mut client, mut server| async move {
+            tokio::io::copy_bidirectional(&mut client, &mut server).await
+        })
+        .await
+    }
+}
+
+impl fmt::Debug for Scenario {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scenario")
+            .field("client_data", &self.client_data)
+            .field("server_data", &self.server_data)
+            .field("buffer_size", &self.buffer_size)
+            .finish()
+    }
+}
+
+/// The data that arrived at each side of a [`Scenario`].
+///
+/// [`Scenario`]: struct.Scenario.html
+#[derive(Clone, Debug)]
+pub struct Transcript<T> {
+    /// The data received by the server.
+    pub to_server: Vec<u8>,
+    /// The data received by the client.
+    pub to_client: Vec<u8>,
+    /// The value returned by the proxy.
+    pub output: T,
+}
+
+// Writes `data` to `stream` while reading from it until EOF, returning the data read.
+async fn exchange(stream: DuplexStream, data: Vec<u8>) -> Vec<u8> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let write = async move {
+        if writer.write_all(&data).await.is_ok() {
+            let _ = writer.shutdown().await;
+        }
+    };
+    let read = async move {
+        let mut received = vec![];
+        // Data read before an error is still appended to `received`.
+        let _ = reader.read_to_end(&mut received).await;
+        received
+    };
+    let ((), received) = futures::join!(write, read);
+    received
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncRead, AsyncWrite};
+
+    #[tokio::test]
+    async fn test_copy_bidirectional() {
+        let client_data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let server_data: Vec<u8> = (0..=255).rev().cycle().take(700).collect();
+        let transcript = Scenario::new(client_data.clone(), server_data.clone())
+            .client_ops(
+                vec![
+                    PartialOp::Limited(3),
+                    PartialOp::Err(io::ErrorKind::WouldBlock),
+                    PartialOp::Err(io::ErrorKind::Interrupted),
+                    PartialOp::Limited(1),
+                ],
+                vec![PartialOp::Limited(7); 10],
+            )
+            .server_ops(
+                vec![PartialOp::Err(io::ErrorKind::WouldBlock); 10],
+                vec![PartialOp::Limited(1), PartialOp::Limited(50)],
+            )
+            .buffer_size(16)
+            .run_copy_bidirectional()
+            .await;
+
+        assert_eq!(transcript.to_server, client_data);
+        assert_eq!(transcript.to_client, server_data);
+        assert_eq!(transcript.output.unwrap(), (1000, 700));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_error() {
+        // A one-way proxy that gives up on the first error.
+        async fn forward<R, W>(mut reader: R, mut writer: W) -> io::Result<u64>
+        where
+            R: AsyncRead + Unpin,
+            W: AsyncWrite + Unpin,
+        {
+            tokio::io::copy(&mut reader, &mut writer).await
+        }
+
+        let transcript = Scenario::new(b"hello world".to_vec(), b"ignored".to_vec())
+            .client_ops(
+                vec![
+                    PartialOp::Limited(5),
+                    PartialOp::Err(io::ErrorKind::ConnectionReset),
+                ],
+                vec![],
+            )
+            .run(forward)
+            .await;
+
+        assert_eq!(transcript.to_server, b"hello");
+        assert_eq!(transcript.to_client, b"");
+        assert_eq!(
+            transcript.output.unwrap_err().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+    }
+}
@@ -11,6 +11,11 @@
 //! sequences can then be fed into a [`PartialRead`], [`PartialWrite`],
 //! [`PartialAsyncRead`] or [`PartialAsyncWrite`].
 //!
+//! The same sequences also drive the item-level wrappers: for a
+//! [`PartialStream`] or a [`PartialBody`], `Limited` operations become chunk split
+//! points, while for a [`PartialSink`], errors land between items. In all cases
+//! `WouldBlock` errors become `Poll::Pending` insertions.
+//!
 //! Once `quickcheck` has identified a failing test case, it will shrink the
 //! sequence of `PartialOp`s and find a minimal test case. This minimal case can
 //! then be used to reproduce the issue.
@@ -20,8 +25,36 @@
 //! the sequence in as the second argument to the partial wrapper.
 //!
 //! Several implementations of `GenError` are provided. These can be used to
-//! customize the sorts of errors generated. For even more customization, you
-//! can write your own `GenError` implementation.
+//! customize the sorts of errors generated. [`FrontLoaded`], [`BackLoaded`]
+//! and [`Clustered`] wrap another `GenError` to bias where errors land, for
+//! example `PartialWithErrors<BackLoaded<GenInterrupted>>`. The built-in
+//! generators produce errors 20% of the time; [`SizeScaled`] instead generates
+//! more errors for larger `Gen` sizes, for example
+//! `PartialWithErrors<SizeScaled<GenInterruptedWouldBlock>>`. To make sure
+//! every error kind shows up in every sequence, wrap the generator in
+//! [`Covering`]. For even more customization, you can write your own
+//! `GenError` implementation.
+//!
+//! The sizes of the other operations are generated separately, by a
+//! [`GenLimit`] passed in as the second type parameter. It defaults to
+//! [`GenUniformLimit`]; [`GenPowerOfTwoLimit`] and [`GenTinyLimit`] are also
+//! provided, for example `PartialWithErrors<GenInterrupted, GenTinyLimit>`.
+//!
+//! For async code, [`AsyncWithErrors`] ends every sequence with an operation
+//! that makes progress, and [`GenWouldBlockStreaks`] generates long streaks of
+//! `WouldBlock` errors.
+//! [`Bursty`] generates bursts of errors of any kind, for example to model
+//! congestion with `PartialWithErrors<Bursty<GenWouldBlock>>`.
+//!
+//! If the fault profile is only known at runtime, use a [`PartialWithConfig`]
+//! input instead, configured by a [`GenConfig`].
+//!
+//! To check that retry loops are bounded, generate an [`InterruptedStorm`]
+//! instead: a run of consecutive `Interrupted` errors of random length.
+//!
+//! To test a pipeline that copies a reader into a writer, generate a
+//! [`CorrelatedOps`] instead: a pair of sequences that split the same
+//! transfers up differently, with faults at the start of the same transfers.
 //!
 //! # Examples
 //!
@@ -41,6 +74,29 @@
 //! }
 //! ```
 //!
+//! The `#[partial_test]` attribute removes the `quickcheck!` boilerplate, and
+//! can also wrap a fixture in the generated sequence. If the test fails, it
+//! prints the operations of the minimal failing case as a Rust expression:
+//!
+//! ```rust
+//! use partial_io::{
+//!     partial_test,
+//!     quickcheck_types::{GenInterrupted, PartialWithErrors},
+//!     PartialWrite,
+//! };
+//! use std::io::Write;
+//!
+//! #[partial_test(fixture = Vec::new())]
+//! fn test_write_all(
+//!     seq: PartialWithErrors<GenInterrupted>,
+//!     mut partial_write: PartialWrite<Vec<u8>>,
+//! ) -> bool {
+//!     // write_all retries on Interrupted errors.
+//!     partial_write.write_all(b"Hello, world!").is_ok()
+//!         && partial_write.get_ref() == b"Hello, world!"
+//! }
+//! ```
+//!
 //! For a detailed example, see `examples/buggy_write.rs` in this repository.
 //!
 //! For a real-world example, see the [tests in `bzip2-rs`].
@@ -50,22 +106,54 @@
 //! [`PartialWrite`]: ../struct.PartialWrite.html
 //! [`PartialAsyncRead`]: ../struct.PartialAsyncRead.html
 //! [`PartialAsyncWrite`]: ../struct.PartialAsyncWrite.html
+//! [`PartialStream`]: ../struct.PartialStream.html
+//! [`PartialBody`]: ../struct.PartialBody.html
+//! [`PartialSink`]: ../struct.PartialSink.html
 //! [`GenError`]: trait.GenError.html
+//! [`GenLimit`]: trait.GenLimit.html
+//! [`GenUniformLimit`]: struct.GenUniformLimit.html
+//! [`GenPowerOfTwoLimit`]: struct.GenPowerOfTwoLimit.html
+//! [`GenTinyLimit`]: struct.GenTinyLimit.html
+//! [`FrontLoaded`]: struct.FrontLoaded.html
+//! [`BackLoaded`]: struct.BackLoaded.html
+//! [`Clustered`]: struct.Clustered.html
+//! [`SizeScaled`]: struct.SizeScaled.html
+//! [`Covering`]: struct.Covering.html
+//! [`Bursty`]: struct.Bursty.html
+//! [`InterruptedStorm`]: struct.InterruptedStorm.html
+//! [`CorrelatedOps`]: struct.CorrelatedOps.html
+//! [`PartialWithConfig`]: struct.PartialWithConfig.html
+//! [`AsyncWithErrors`]: struct.AsyncWithErrors.html
+//! [`GenWouldBlockStreaks`]: struct.GenWouldBlockStreaks.html
+//! [`GenConfig`]: struct.GenConfig.html
 //! [tests in `bzip2-rs`]: https://github.com/alexcrichton/bzip2-rs/blob/master/src/write.rs
 
 use crate::PartialOp;
-use quickcheck::{empty_shrinker, Arbitrary, Gen};
+use quickcheck::{empty_shrinker, Arbitrary, Gen, TestResult, Testable};
 use rand::{rngs::SmallRng, Rng, SeedableRng};
-use std::{io, marker::PhantomData, ops::Deref};
+use std::{
+    any::Any,
+    cell::RefCell,
+    cmp, fmt, io,
+    marker::PhantomData,
+    ops::Deref,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+};
 
 /// Given a custom error generator, randomly generate a list of `PartialOp`s.
+///
+/// The sizes of `PartialOp::Limited` operations are generated by `GL`, which
+/// defaults to [`GenUniformLimit`].
+///
+/// [`GenUniformLimit`]: struct.GenUniformLimit.html
 #[derive(Clone, Debug)]
-pub struct PartialWithErrors<GE> {
+pub struct PartialWithErrors<GE, GL = GenUniformLimit> {
     items: Vec<PartialOp>,
-    _marker: PhantomData<GE>,
+    _marker: PhantomData<(GE, GL)>,
 }
 
-impl<GE> IntoIterator for PartialWithErrors<GE> {
+impl<GE, GL> IntoIterator for PartialWithErrors<GE, GL> {
     type Item = PartialOp;
     type IntoIter = ::std::vec::IntoIter<PartialOp>;
 
@@ -74,7 +162,26 @@ impl<GE> IntoIterator for PartialWithErrors<GE> {
     }
 }
 
-impl<GE> Deref for PartialWithErrors<GE> {
+impl<GE, GL> From<Vec<PartialOp>> for PartialWithErrors<GE, GL> {
+    fn from(items: Vec<PartialOp>) -> Self {
+        PartialWithErrors {
+            items,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<GE, GL> PartialWithErrors<GE, GL> {
+    /// Returns the fingerprint of this sequence, to tell the same schedule failing again apart
+    /// from a new failure.
+    ///
+    /// See `corpus::fingerprint` for more.
+    pub fn fingerprint(&self) -> u64 {
+        crate::corpus::fingerprint(&self.items)
+    }
+}
+
+impl<GE, GL> Deref for PartialWithErrors<GE, GL> {
     type Target = [PartialOp];
     fn deref(&self) -> &Self::Target {
         self.items.deref()
@@ -87,6 +194,20 @@ impl<GE> Deref for PartialWithErrors<GE> {
 pub trait GenError: Clone + Default + Send {
     /// Optionally generate an `io::ErrorKind` instance.
     fn gen_error(&mut self, g: &mut Gen) -> Option<io::ErrorKind>;
+
+    /// Optionally generate an `io::ErrorKind` instance for the operation at
+    /// `index` in a sequence of `len` operations.
+    ///
+    /// This is how the sequence is generated. The default implementation
+    /// ignores the position and calls `gen_error`. Override it to control
+    /// where errors land, as [`FrontLoaded`] and [`BackLoaded`] do.
+    ///
+    /// [`FrontLoaded`]: struct.FrontLoaded.html
+    /// [`BackLoaded`]: struct.BackLoaded.html
+    fn gen_error_at(&mut self, g: &mut Gen, index: usize, len: usize) -> Option<io::ErrorKind> {
+        let _ = (index, len);
+        self.gen_error(g)
+    }
 }
 
 /// Generate an `ErrorKind::Interrupted` error 20% of the time.
@@ -107,8 +228,26 @@ pub struct GenWouldBlock;
 #[derive(Clone, Debug, Default)]
 pub struct GenInterruptedWouldBlock;
 
+/// The kinds of errors a generator picks from.
+///
+/// This is implemented by the fixed-probability generators in this module,
+/// and is used by [`SizeScaled`] to pick errors with its own probability.
+///
+/// [`SizeScaled`]: struct.SizeScaled.html
+pub trait ErrorKinds: Clone + Default + Send {
+    /// Returns the kinds of errors to pick from. If this is empty, no errors
+    /// are generated.
+    fn error_kinds() -> &'static [io::ErrorKind];
+}
+
 macro_rules! impl_gen_error {
     ($id: ident, [$($errors:expr),+]) => {
+        impl ErrorKinds for $id {
+            fn error_kinds() -> &'static [io::ErrorKind] {
+                &[$($errors,)*]
+            }
+        }
+
         impl GenError for $id {
             fn gen_error(&mut self, g: &mut Gen) -> Option<io::ErrorKind> {
                 // 20% chance to generate an error.
@@ -143,26 +282,357 @@ impl GenError for GenNoErrors {
     }
 }
 
-impl<GE> Arbitrary for PartialWithErrors<GE>
+impl ErrorKinds for GenNoErrors {
+    fn error_kinds() -> &'static [io::ErrorKind] {
+        &[]
+    }
+}
+
+/// Generate the errors of `K` with a probability that grows with
+/// `Gen::size()`, so that larger sizes generate denser faults.
+///
+/// An operation is an error with probability `size / (size + 400)`. This is
+/// the same 20% as the other generators at the default size of 100, 50% at a
+/// size of 400, and 80% at a size of 1600. The size can be set with
+/// `QuickCheck::gen` or the `QUICKCHECK_GENERATOR_SIZE` environment variable,
+/// for example to generate more faults in a nightly run.
+///
+/// See [the module level documentation](index.html) for more.
+#[derive(Clone, Debug, Default)]
+pub struct SizeScaled<K>(PhantomData<K>);
+
+impl<K: ErrorKinds> GenError for SizeScaled<K> {
+    fn gen_error(&mut self, g: &mut Gen) -> Option<io::ErrorKind> {
+        let size = g.size() as f64;
+        let mut rng = SmallRng::from_entropy();
+        if rng.gen_bool(size / (size + 400.0)) {
+            g.choose(K::error_kinds()).cloned()
+        } else {
+            None
+        }
+    }
+}
+
+/// Generate `ErrorKind::WouldBlock` errors in long streaks: 10% of the time,
+/// start a streak of 1 to 16 consecutive errors.
+///
+/// This models a source that stays unready for a while, which async code
+/// handles differently from a single `Poll::Pending`. It's part of the async
+/// presets; see [`AsyncWithErrors`].
+///
+/// [`AsyncWithErrors`]: struct.AsyncWithErrors.html
+#[derive(Clone, Debug, Default)]
+pub struct GenWouldBlockStreaks {
+    // The number of errors left in the current streak.
+    left: usize,
+}
+
+impl GenError for GenWouldBlockStreaks {
+    fn gen_error(&mut self, _g: &mut Gen) -> Option<io::ErrorKind> {
+        let mut rng = SmallRng::from_entropy();
+        if self.left == 0 && rng.gen_ratio(1, 10) {
+            self.left = rng.gen_range(1..=16);
+        }
+        if self.left > 0 {
+            self.left -= 1;
+            Some(io::ErrorKind::WouldBlock)
+        } else {
+            None
+        }
+    }
+}
+
+/// Generate the errors of `K` in bursts, where each operation depends on the
+/// one before it.
+///
+/// This is a two-state Markov chain. After an operation that isn't an error,
+/// the next one starts a burst with a 5% chance, with a kind picked from `K`.
+/// After an error, the next operation is an error of the same kind with an 80%
+/// chance, and ends the burst otherwise. Bursts are 5 operations long on
+/// average, with a long tail, and 20% of operations are errors overall, the
+/// same as the other generators.
+///
+/// Long streaks of `WouldBlock`, for example from
+/// `PartialWithErrors<Bursty<GenWouldBlock>>`, model congestion, which
+/// exercises different buffering behavior than isolated faults. Unlike
+/// [`GenWouldBlockStreaks`], this works with any kinds of errors, and burst
+/// lengths aren't capped.
+///
+/// See [the module level documentation](index.html) for more.
+///
+/// [`GenWouldBlockStreaks`]: struct.GenWouldBlockStreaks.html
+#[derive(Clone, Debug, Default)]
+pub struct Bursty<K> {
+    // The kind of the previous operation, if it was an error.
+    previous: Option<io::ErrorKind>,
+    _marker: PhantomData<K>,
+}
+
+impl<K: ErrorKinds> GenError for Bursty<K> {
+    fn gen_error(&mut self, g: &mut Gen) -> Option<io::ErrorKind> {
+        let mut rng = SmallRng::from_entropy();
+        self.previous = match self.previous {
+            Some(kind) if rng.gen_ratio(4, 5) => Some(kind),
+            Some(_) => None,
+            None if rng.gen_ratio(1, 20) => g.choose(K::error_kinds()).copied(),
+            None => None,
+        };
+        self.previous
+    }
+}
+
+impl<K: ErrorKinds> ErrorKinds for Bursty<K> {
+    fn error_kinds() -> &'static [io::ErrorKind] {
+        K::error_kinds()
+    }
+}
+
+/// Only generate errors from `GE` in the first quarter of the sequence.
+///
+/// This targets setup paths, such as reading a header, that uniform placement
+/// spreads few errors over.
+///
+/// See [the module level documentation](index.html) for more.
+#[derive(Clone, Debug, Default)]
+pub struct FrontLoaded<GE>(GE);
+
+/// Only generate errors from `GE` in the last quarter of the sequence.
+///
+/// This targets teardown paths, such as the final flush of a buffered writer.
+/// Errors only land there if the code under test consumes most of the
+/// sequence, so consider generating shorter sequences with `Gen::new`.
+///
+/// See [the module level documentation](index.html) for more.
+#[derive(Clone, Debug, Default)]
+pub struct BackLoaded<GE>(GE);
+
+/// Generate errors from `GE` in clusters: every error is followed by up to 3
+/// more errors of the same kind.
+///
+/// See [the module level documentation](index.html) for more.
+#[derive(Clone, Debug, Default)]
+pub struct Clustered<GE> {
+    inner: GE,
+    // The kind of the current cluster, and the number of errors left in it.
+    cluster: Option<(io::ErrorKind, usize)>,
+}
+
+/// The number of operations at either end of a sequence of `len` operations
+/// that errors are biased towards.
+fn bias_window(len: usize) -> usize {
+    cmp::max(len / 4, 1)
+}
+
+impl<GE: GenError> GenError for FrontLoaded<GE> {
+    fn gen_error(&mut self, g: &mut Gen) -> Option<io::ErrorKind> {
+        self.0.gen_error(g)
+    }
+
+    fn gen_error_at(&mut self, g: &mut Gen, index: usize, len: usize) -> Option<io::ErrorKind> {
+        if index < bias_window(len) {
+            self.0.gen_error_at(g, index, len)
+        } else {
+            None
+        }
+    }
+}
+
+impl<GE: GenError> GenError for BackLoaded<GE> {
+    fn gen_error(&mut self, g: &mut Gen) -> Option<io::ErrorKind> {
+        self.0.gen_error(g)
+    }
+
+    fn gen_error_at(&mut self, g: &mut Gen, index: usize, len: usize) -> Option<io::ErrorKind> {
+        if index + bias_window(len) >= len {
+            self.0.gen_error_at(g, index, len)
+        } else {
+            None
+        }
+    }
+}
+
+impl<GE: GenError> Clustered<GE> {
+    fn continue_cluster(
+        &mut self,
+        g: &mut Gen,
+        new_error: impl FnOnce(&mut GE, &mut Gen) -> Option<io::ErrorKind>,
+    ) -> Option<io::ErrorKind> {
+        match self.cluster.take() {
+            Some((kind, left)) => {
+                if left > 1 {
+                    self.cluster = Some((kind, left - 1));
+                }
+                Some(kind)
+            }
+            None => {
+                let kind = new_error(&mut self.inner, g)?;
+                let left = *g.choose(&[0, 1, 2, 3]).unwrap();
+                if left > 0 {
+                    self.cluster = Some((kind, left));
+                }
+                Some(kind)
+            }
+        }
+    }
+}
+
+impl<GE: GenError> GenError for Clustered<GE> {
+    fn gen_error(&mut self, g: &mut Gen) -> Option<io::ErrorKind> {
+        self.continue_cluster(g, |inner, g| inner.gen_error(g))
+    }
+
+    fn gen_error_at(&mut self, g: &mut Gen, index: usize, len: usize) -> Option<io::ErrorKind> {
+        self.continue_cluster(g, |inner, g| inner.gen_error_at(g, index, len))
+    }
+}
+
+/// Generate errors from `GE`, and also make sure that every kind in
+/// `GE::error_kinds()` appears at least once in the sequence.
+///
+/// Each kind is placed once at a random position, and `GE` generates the rest
+/// of the sequence as usual. If the sequence is shorter than the number of
+/// kinds, as many kinds as fit are placed. This is meant for runs with few
+/// `quickcheck` iterations, where leaving the kinds to chance often misses
+/// one entirely. Shrinking may remove kinds again.
+///
+/// For example `PartialWithErrors<Covering<GenInterruptedWouldBlock>>` always
+/// contains both an `Interrupted` and a `WouldBlock` error, given at least 2
+/// operations.
+///
+/// See [the module level documentation](index.html) for more.
+#[derive(Clone, Debug, Default)]
+pub struct Covering<GE> {
+    inner: GE,
+    // The position of each kind placed in the sequence, picked on the first call.
+    placed: Option<Vec<(usize, io::ErrorKind)>>,
+}
+
+impl<GE: GenError + ErrorKinds> GenError for Covering<GE> {
+    fn gen_error(&mut self, g: &mut Gen) -> Option<io::ErrorKind> {
+        self.inner.gen_error(g)
+    }
+
+    fn gen_error_at(&mut self, g: &mut Gen, index: usize, len: usize) -> Option<io::ErrorKind> {
+        let placed = self.placed.get_or_insert_with(|| {
+            let kinds = GE::error_kinds();
+            let mut rng = SmallRng::from_entropy();
+            let positions = rand::seq::index::sample(&mut rng, len, cmp::min(kinds.len(), len));
+            positions.into_iter().zip(kinds.iter().copied()).collect()
+        });
+        match placed.iter().find(|(position, _)| *position == index) {
+            Some(&(_, kind)) => Some(kind),
+            None => self.inner.gen_error_at(g, index, len),
+        }
+    }
+}
+
+impl<GE: ErrorKinds> ErrorKinds for Covering<GE> {
+    fn error_kinds() -> &'static [io::ErrorKind] {
+        GE::error_kinds()
+    }
+}
+
+impl<K: ErrorKinds> ErrorKinds for SizeScaled<K> {
+    fn error_kinds() -> &'static [io::ErrorKind] {
+        K::error_kinds()
+    }
+}
+
+/// Represents a way to generate the sizes of `PartialOp::Limited` operations.
+///
+/// This is the counterpart of [`GenError`] for the operations that aren't
+/// errors, and is passed in as the second type parameter of
+/// `PartialWithErrors`, for example
+/// `PartialWithErrors<GenInterrupted, GenPowerOfTwoLimit>`.
+///
+/// [`GenError`]: trait.GenError.html
+pub trait GenLimit: Clone + Default + Send {
+    /// Generate a limit for a sequence generated with a `Gen::size()` of
+    /// `size`.
+    ///
+    /// This should not return 0, because for writers it can mean that writes
+    /// are no longer accepted.
+    fn gen_limit(&mut self, g: &mut Gen, size: usize) -> usize;
+
+    /// Returns true if `limit` could have been generated.
+    ///
+    /// This is used while shrinking, so that smaller sequences keep the shape
+    /// of the limits. By default, every limit other than 0 is accepted.
+    fn accepts(limit: usize) -> bool {
+        limit != 0
+    }
+}
+
+/// Generate limits uniformly between 1 and `Gen::size()`. This is the
+/// default for `PartialWithErrors`.
+#[derive(Clone, Debug, Default)]
+pub struct GenUniformLimit;
+
+impl GenLimit for GenUniformLimit {
+    fn gen_limit(&mut self, _g: &mut Gen, size: usize) -> usize {
+        // A uniform distribution for this is fine because the goal is to shake
+        // bugs out relatively effectively.
+        let mut rng = SmallRng::from_entropy();
+        rng.gen_range(1..cmp::max(size, 2))
+    }
+}
+
+/// Generate limits that are powers of two, up to `Gen::size()`.
+///
+/// This matches the buffer sizes that many readers and writers use, and keeps
+/// limits powers of two while shrinking.
+#[derive(Clone, Debug, Default)]
+pub struct GenPowerOfTwoLimit;
+
+impl GenLimit for GenPowerOfTwoLimit {
+    fn gen_limit(&mut self, g: &mut Gen, size: usize) -> usize {
+        let max_exp = cmp::max(size, 1).ilog2() as usize;
+        1 << (usize::arbitrary(g) % (max_exp + 1))
+    }
+
+    fn accepts(limit: usize) -> bool {
+        limit.is_power_of_two()
+    }
+}
+
+/// Generate tiny limits, between 1 and 4 bytes.
+///
+/// This exercises code that handles a value, such as an integer, being split
+/// across several operations.
+#[derive(Clone, Debug, Default)]
+pub struct GenTinyLimit;
+
+impl GenLimit for GenTinyLimit {
+    fn gen_limit(&mut self, g: &mut Gen, _size: usize) -> usize {
+        usize::arbitrary(g) % 4 + 1
+    }
+
+    fn accepts(limit: usize) -> bool {
+        (1..=4).contains(&limit)
+    }
+}
+
+/// Returns true if every `PartialOp::Limited` in `items` is accepted by `GL`.
+fn limits_accepted<GL: GenLimit>(items: &[PartialOp]) -> bool {
+    items.iter().all(|op| match op {
+        PartialOp::Limited(n) => GL::accepts(*n),
+        _ => true,
+    })
+}
+
+impl<GE, GL> Arbitrary for PartialWithErrors<GE, GL>
 where
     GE: GenError + 'static,
+    GL: GenLimit + 'static,
 {
     fn arbitrary(g: &mut Gen) -> Self {
         let size = g.size();
-        // Generate a sequence of operations. A uniform distribution for this is
-        // fine because the goal is to shake bugs out relatively effectively.
         let mut gen_error = GE::default();
+        let mut gen_limit = GL::default();
         let items: Vec<_> = (0..size)
-            .map(|_| {
-                match gen_error.gen_error(g) {
-                    Some(err) => PartialOp::Err(err),
-                    // Don't generate 0 because for writers it can mean that
-                    // writes are no longer accepted.
-                    None => {
-                        let mut rng = SmallRng::from_entropy();
-                        PartialOp::Limited(rng.gen_range(1..size))
-                    }
-                }
+            .map(|index| match gen_error.gen_error_at(g, index, size) {
+                Some(err) => PartialOp::Err(err),
+                None => PartialOp::Limited(gen_limit.gen_limit(g, size)),
             })
             .collect();
         PartialWithErrors {
@@ -172,10 +642,519 @@ where
     }
 
     fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
-        Box::new(self.items.clone().shrink().map(|items| PartialWithErrors {
+        Box::new(
+            self.items
+                .clone()
+                .shrink()
+                .filter(|items| limits_accepted::<GL>(items))
+                .map(|items| PartialWithErrors {
+                    items,
+                    _marker: PhantomData,
+                }),
+        )
+    }
+}
+
+/// Given a custom error generator, randomly generate a list of `PartialOp`s
+/// for async code, that always ends with `PartialOp::Unlimited`.
+///
+/// The trailing `Unlimited` operation is kept while shrinking, so that the
+/// last operation of every sequence makes progress. It doesn't stop the
+/// sequence from stalling the future under test before then: each
+/// `PartialOp::Err(WouldBlock)` and `PartialOp::Delay` still returns
+/// `Poll::Pending`, and the future only completes if it's polled again after
+/// each of them, as a correct executor does once it's woken.
+///
+/// This is one of the async presets, together with
+/// [`GenWouldBlockStreaks`], for example
+/// `AsyncWithErrors<GenWouldBlockStreaks>`. To make the future suspend right
+/// before it flushes or shuts down, also call `set_pending_before_flush` on the
+/// `PartialAsyncWrite`.
+///
+/// The sizes of `PartialOp::Limited` operations are generated by `GL`, as with
+/// `PartialWithErrors`.
+///
+/// [`GenWouldBlockStreaks`]: struct.GenWouldBlockStreaks.html
+#[derive(Clone, Debug)]
+pub struct AsyncWithErrors<GE, GL = GenUniformLimit> {
+    items: Vec<PartialOp>,
+    _marker: PhantomData<(GE, GL)>,
+}
+
+impl<GE, GL> AsyncWithErrors<GE, GL> {
+    /// Returns the fingerprint of this sequence, including the trailing `PartialOp::Unlimited`
+    /// added when it was generated.
+    ///
+    /// See `corpus::fingerprint` for more.
+    pub fn fingerprint(&self) -> u64 {
+        crate::corpus::fingerprint(&self.items)
+    }
+
+    fn with_trailing_unlimited(mut items: Vec<PartialOp>) -> Self {
+        items.push(PartialOp::Unlimited);
+        AsyncWithErrors {
             items,
             _marker: PhantomData,
-        }))
+        }
+    }
+}
+
+impl<GE, GL> IntoIterator for AsyncWithErrors<GE, GL> {
+    type Item = PartialOp;
+    type IntoIter = ::std::vec::IntoIter<PartialOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.into_iter()
+    }
+}
+
+/// Creates a sequence from saved operations, as is.
+///
+/// The sequences it shrinks to end with `PartialOp::Unlimited`, like generated
+/// ones.
+impl<GE, GL> From<Vec<PartialOp>> for AsyncWithErrors<GE, GL> {
+    fn from(items: Vec<PartialOp>) -> Self {
+        AsyncWithErrors {
+            items,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<GE, GL> Deref for AsyncWithErrors<GE, GL> {
+    type Target = [PartialOp];
+    fn deref(&self) -> &Self::Target {
+        self.items.deref()
+    }
+}
+
+impl<GE, GL> Arbitrary for AsyncWithErrors<GE, GL>
+where
+    GE: GenError + 'static,
+    GL: GenLimit + 'static,
+{
+    fn arbitrary(g: &mut Gen) -> Self {
+        Self::with_trailing_unlimited(PartialWithErrors::<GE, GL>::arbitrary(g).items)
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        // Sequences created with `From` may be empty, or end with another operation.
+        let prefix = match self.items.split_last() {
+            Some((PartialOp::Unlimited, prefix)) => prefix,
+            _ => &self.items[..],
+        };
+        Box::new(
+            prefix
+                .to_vec()
+                .shrink()
+                .filter(|items| limits_accepted::<GL>(items))
+                .map(Self::with_trailing_unlimited),
+        )
+    }
+}
+
+/// A runtime configuration for generating sequences of `PartialOp`s, used by
+/// [`PartialWithConfig`].
+///
+/// This is an alternative to picking a `GenError` type, for when the fault
+/// profile is only known at runtime, for example because it's read from a
+/// config file.
+#[derive(Clone, Debug)]
+pub struct GenConfig {
+    /// The maximum number of operations in a sequence. The length of each
+    /// sequence is picked uniformly from `0..=max_len`.
+    pub max_len: usize,
+    /// The kinds of errors to pick from. If this is empty, no errors are
+    /// generated.
+    pub error_kinds: Vec<io::ErrorKind>,
+    /// The probability that an operation is an error, between 0 and 1.
+    pub error_prob: f64,
+    /// The distribution of the operations that aren't errors.
+    pub limit_dist: LimitDist,
+}
+
+/// The distribution of the operations that aren't errors, for [`GenConfig`].
+///
+/// [`GenConfig`]: struct.GenConfig.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LimitDist {
+    /// Generate `PartialOp::Limited(n)` with `n` picked uniformly from
+    /// `1..=max`.
+    Uniform {
+        /// The largest limit to generate. A `max` of 0 is treated as 1.
+        max: usize,
+    },
+    /// Always generate `PartialOp::Limited(n)`.
+    Fixed(usize),
+    /// Always generate `PartialOp::Unlimited`.
+    Unlimited,
+}
+
+impl Default for GenConfig {
+    /// Returns a configuration that is close to `PartialWithErrors<GenInterrupted>`
+    /// with the default `Gen` size.
+    fn default() -> Self {
+        GenConfig {
+            max_len: 100,
+            error_kinds: vec![io::ErrorKind::Interrupted],
+            error_prob: 0.2,
+            limit_dist: LimitDist::Uniform { max: 100 },
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT_CONFIG: RefCell<Option<GenConfig>> = const { RefCell::new(None) };
+}
+
+impl GenConfig {
+    /// Runs `f` with this configuration used to generate every
+    /// [`PartialWithConfig`] on the current thread.
+    ///
+    /// `quickcheck` generates inputs on the thread it's called from, so this
+    /// can wrap a call to `quickcheck::quickcheck` or `QuickCheck::quickcheck`.
+    /// Calls can be nested; the previous configuration is restored once `f`
+    /// returns or panics.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use partial_io::quickcheck_types::{GenConfig, LimitDist, PartialWithConfig};
+    /// use quickcheck::quickcheck;
+    /// use std::io;
+    ///
+    /// fn test_something(seq: PartialWithConfig) -> bool {
+    ///     seq.len() <= 20
+    /// }
+    ///
+    /// // This could be read from a config file instead.
+    /// let config = GenConfig {
+    ///     max_len: 20,
+    ///     error_kinds: vec![io::ErrorKind::Interrupted, io::ErrorKind::TimedOut],
+    ///     error_prob: 0.5,
+    ///     limit_dist: LimitDist::Fixed(1),
+    /// };
+    /// config.scope(|| quickcheck(test_something as fn(PartialWithConfig) -> bool));
+    /// ```
+    ///
+    /// [`PartialWithConfig`]: struct.PartialWithConfig.html
+    pub fn scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        struct Restore(Option<GenConfig>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                let previous = self.0.take();
+                CURRENT_CONFIG.with(|current| *current.borrow_mut() = previous);
+            }
+        }
+
+        let previous = CURRENT_CONFIG.with(|current| current.replace(Some(self.clone())));
+        let _restore = Restore(previous);
+        f()
+    }
+
+    /// Generates a sequence of operations with this configuration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `error_prob` is not between 0 and 1.
+    pub fn generate(&self, g: &mut Gen) -> PartialWithConfig {
+        let mut rng = SmallRng::from_entropy();
+        let len = rng.gen_range(0..=self.max_len);
+        let items = (0..len)
+            .map(|_| {
+                if !self.error_kinds.is_empty() && rng.gen_bool(self.error_prob) {
+                    return PartialOp::Err(*g.choose(&self.error_kinds).unwrap());
+                }
+                match self.limit_dist {
+                    LimitDist::Uniform { max } => {
+                        PartialOp::Limited(rng.gen_range(1..=cmp::max(max, 1)))
+                    }
+                    LimitDist::Fixed(n) => PartialOp::Limited(n),
+                    LimitDist::Unlimited => PartialOp::Unlimited,
+                }
+            })
+            .collect();
+        PartialWithConfig { items }
+    }
+}
+
+/// A randomly generated sequence of `PartialOp`s, configured at runtime by a
+/// [`GenConfig`].
+///
+/// When generated by `quickcheck`, this uses the configuration set with
+/// [`GenConfig::scope`], or `GenConfig::default()` outside of it.
+///
+/// [`GenConfig`]: struct.GenConfig.html
+/// [`GenConfig::scope`]: struct.GenConfig.html#method.scope
+#[derive(Clone, Debug)]
+pub struct PartialWithConfig {
+    items: Vec<PartialOp>,
+}
+
+impl IntoIterator for PartialWithConfig {
+    type Item = PartialOp;
+    type IntoIter = ::std::vec::IntoIter<PartialOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.into_iter()
+    }
+}
+
+impl From<Vec<PartialOp>> for PartialWithConfig {
+    fn from(items: Vec<PartialOp>) -> Self {
+        PartialWithConfig { items }
+    }
+}
+
+impl Deref for PartialWithConfig {
+    type Target = [PartialOp];
+    fn deref(&self) -> &Self::Target {
+        self.items.deref()
+    }
+}
+
+impl Arbitrary for PartialWithConfig {
+    fn arbitrary(g: &mut Gen) -> Self {
+        CURRENT_CONFIG.with(|current| match &*current.borrow() {
+            Some(config) => config.generate(g),
+            None => GenConfig::default().generate(g),
+        })
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        Box::new(
+            self.items
+                .clone()
+                .shrink()
+                .map(|items| PartialWithConfig { items }),
+        )
+    }
+}
+
+/// A storm of `k` consecutive `Interrupted` errors followed by an operation
+/// that allows progress, with `k` randomly generated.
+///
+/// This is the generated version of [`PartialOp::interrupted_storm`]. It can be
+/// passed in as the second argument to a partial wrapper, and shrinks towards
+/// smaller storms.
+///
+/// [`PartialOp::interrupted_storm`]: ../enum.PartialOp.html#method.interrupted_storm
+#[derive(Clone, Debug)]
+pub struct InterruptedStorm {
+    k: usize,
+    items: Vec<PartialOp>,
+}
+
+impl InterruptedStorm {
+    /// Creates a storm of `k` consecutive `Interrupted` errors.
+    pub fn new(k: usize) -> Self {
+        InterruptedStorm {
+            k,
+            items: PartialOp::interrupted_storm(k).collect(),
+        }
+    }
+
+    /// Returns the number of consecutive `Interrupted` errors in this storm.
+    pub fn k(&self) -> usize {
+        self.k
+    }
+}
+
+impl IntoIterator for InterruptedStorm {
+    type Item = PartialOp;
+    type IntoIter = ::std::vec::IntoIter<PartialOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.into_iter()
+    }
+}
+
+/// Creates a storm from saved operations, as is. `k` is the number of leading
+/// `Interrupted` errors.
+impl From<Vec<PartialOp>> for InterruptedStorm {
+    fn from(items: Vec<PartialOp>) -> Self {
+        let k = items
+            .iter()
+            .take_while(|op| matches!(op, PartialOp::Err(io::ErrorKind::Interrupted)))
+            .count();
+        InterruptedStorm { k, items }
+    }
+}
+
+impl Deref for InterruptedStorm {
+    type Target = [PartialOp];
+    fn deref(&self) -> &Self::Target {
+        self.items.deref()
+    }
+}
+
+impl Arbitrary for InterruptedStorm {
+    fn arbitrary(g: &mut Gen) -> Self {
+        let k = usize::arbitrary(g) % cmp::max(g.size(), 1) + 1;
+        InterruptedStorm::new(k)
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        Box::new(self.k.shrink().map(InterruptedStorm::new))
+    }
+}
+
+/// A pair of randomly generated sequences of `PartialOp`s, one for a reader
+/// and one for a writer, with faults at correlated positions.
+///
+/// This is meant for end-to-end tests of pipelines that copy or transcode a
+/// reader into a writer, such as `io::copy`. The sequences are generated as a
+/// list of transfers. Each transfer moves a random number of bytes, which the
+/// reader and the writer split up into `PartialOp::Limited` operations of
+/// their own sizes. An error from `GE` in a transfer is injected at its start,
+/// before the reader's operations, the writer's, or both. Independently
+/// generated sequences almost never put faults on both sides of the same
+/// transfer.
+///
+/// Shrinking removes whole transfers and their faults, keeping both sides
+/// aligned.
+///
+/// # Examples
+///
+/// ```rust
+/// use partial_io::{
+///     quickcheck_types::{CorrelatedOps, GenInterrupted},
+///     PartialRead, PartialWrite,
+/// };
+/// use quickcheck::quickcheck;
+/// use std::io;
+///
+/// quickcheck! {
+///     fn test_copy(ops: CorrelatedOps<GenInterrupted>) -> bool {
+///         let (read_ops, write_ops) = ops.into_ops();
+///         let mut reader = PartialRead::new(&b"Hello, world!"[..], read_ops);
+///         let mut writer = PartialWrite::new(Vec::new(), write_ops);
+///         io::copy(&mut reader, &mut writer).is_ok() && writer.get_ref() == b"Hello, world!"
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct CorrelatedOps<GE> {
+    transfers: Vec<Transfer>,
+    read: Vec<PartialOp>,
+    write: Vec<PartialOp>,
+    _marker: PhantomData<GE>,
+}
+
+/// A single transfer generated by `CorrelatedOps`.
+#[derive(Clone, Debug)]
+struct Transfer {
+    // The fault injected at the start of the transfer, and the sides it's injected into.
+    fault: Option<(PartialOp, FaultSides)>,
+    len: usize,
+    // The largest number of bytes each side processes in one operation.
+    read_chunk: usize,
+    write_chunk: usize,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum FaultSides {
+    Read,
+    Write,
+    Both,
+}
+
+impl Transfer {
+    fn ops(&self, read: bool) -> impl Iterator<Item = PartialOp> + '_ {
+        let fault = self.fault.as_ref().and_then(|(op, sides)| {
+            let applies = match sides {
+                FaultSides::Read => read,
+                FaultSides::Write => !read,
+                FaultSides::Both => true,
+            };
+            applies.then(|| op.clone())
+        });
+        let chunk = if read {
+            self.read_chunk
+        } else {
+            self.write_chunk
+        };
+        let len = self.len;
+        fault.into_iter().chain(
+            (0..len)
+                .step_by(chunk)
+                .map(move |start| PartialOp::Limited(cmp::min(chunk, len - start))),
+        )
+    }
+}
+
+impl<GE> CorrelatedOps<GE> {
+    fn from_transfers(transfers: Vec<Transfer>) -> Self {
+        let read = transfers.iter().flat_map(|t| t.ops(true)).collect();
+        let write = transfers.iter().flat_map(|t| t.ops(false)).collect();
+        CorrelatedOps {
+            transfers,
+            read,
+            write,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the operations for the reader.
+    pub fn read_ops(&self) -> &[PartialOp] {
+        &self.read
+    }
+
+    /// Returns the operations for the writer.
+    pub fn write_ops(&self) -> &[PartialOp] {
+        &self.write
+    }
+
+    /// Consumes this pair, returning the operations for the reader and the
+    /// writer, in that order.
+    pub fn into_ops(self) -> (Vec<PartialOp>, Vec<PartialOp>) {
+        (self.read, self.write)
+    }
+}
+
+impl<GE> Arbitrary for CorrelatedOps<GE>
+where
+    GE: GenError + 'static,
+{
+    fn arbitrary(g: &mut Gen) -> Self {
+        let size = g.size();
+        let mut gen_error = GE::default();
+        let mut rng = SmallRng::from_entropy();
+        let transfers = (0..size)
+            .map(|index| {
+                let fault = gen_error.gen_error_at(g, index, size).map(|kind| {
+                    let sides = *g
+                        .choose(&[FaultSides::Read, FaultSides::Write, FaultSides::Both])
+                        .unwrap();
+                    (PartialOp::Err(kind), sides)
+                });
+                // Don't generate 0 because it means EOF for readers.
+                let len = rng.gen_range(1..cmp::max(size, 2));
+                Transfer {
+                    fault,
+                    len,
+                    read_chunk: rng.gen_range(1..=len),
+                    write_chunk: rng.gen_range(1..=len),
+                }
+            })
+            .collect();
+        CorrelatedOps::from_transfers(transfers)
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        // Shrink the indexes of the transfers to keep, so that transfers are only ever removed.
+        let transfers = self.transfers.clone();
+        let indexes: Vec<usize> = (0..transfers.len()).collect();
+        Box::new(
+            indexes
+                .shrink()
+                .filter(|kept| kept.windows(2).all(|pair| pair[0] < pair[1]))
+                .map(move |kept| {
+                    let kept = kept.iter().map(|&index| transfers[index].clone()).collect();
+                    CorrelatedOps::from_transfers(kept)
+                }),
+        )
     }
 }
 
@@ -192,7 +1171,527 @@ impl Arbitrary for PartialOp {
             PartialOp::Limited(n) => {
                 Box::new(n.shrink().filter(|k| k != &0).map(PartialOp::Limited))
             }
+            PartialOp::Fragment(n) => {
+                Box::new(n.shrink().filter(|k| k != &0).map(PartialOp::Fragment))
+            }
+            // Shrink towards a single error.
+            PartialOp::ErrTimes(kind, n) => Box::new(
+                n.shrink()
+                    .filter(|k| k != &0)
+                    .map(move |k| PartialOp::ErrTimes(kind, k)),
+            ),
+            // Keep the label while shrinking.
+            PartialOp::Labeled(ref op, label) => {
+                Box::new(op.shrink().map(move |op| op.labeled(label)))
+            }
             _ => empty_shrinker(),
         }
     }
 }
+
+/// Runs `test_fn` against every sequence saved in `dir`, then against new random sequences,
+/// saving the minimal failing one to `dir`.
+///
+/// This is what `#[partial_test]` does when `PARTIAL_IO_CORPUS_DIR` is set, for
+/// `quickcheck` tests that pick their corpus directory themselves, such as one checked into
+/// version control. The saved cases run first and deterministically, so a regression shows up
+/// with its saved sequence rather than depending on what's generated. A directory that doesn't
+/// exist yet is treated as empty.
+///
+/// # Panics
+///
+/// Panics if the directory can't be read, or if any case fails. The operations of the minimal
+/// failing case are printed before panicking.
+///
+/// # Examples
+///
+/// ```rust
+/// use partial_io::{
+///     quickcheck_types::{run_with_corpus, GenInterrupted, PartialWithErrors},
+///     PartialRead,
+/// };
+/// use std::io::Read;
+///
+/// fn reads_everything(seq: PartialWithErrors<GenInterrupted>) -> bool {
+///     let mut reader = PartialRead::new(&b"Hello, world!"[..], seq);
+///     let mut buf = Vec::new();
+///     reader.read_to_end(&mut buf).is_ok() && buf == b"Hello, world!"
+/// }
+///
+/// run_with_corpus("tests/corpus/reads_everything", reads_everything);
+/// ```
+pub fn run_with_corpus<A, T>(dir: impl AsRef<Path>, test_fn: fn(A) -> T)
+where
+    A: Arbitrary + fmt::Debug + From<Vec<PartialOp>> + Deref<Target = [PartialOp]>,
+    T: Testable,
+{
+    thread_local! {
+        // The test function of the innermost `run_with_corpus` call, as `fn(A) -> T`.
+        static TEST_FN: RefCell<Vec<Box<dyn Any>>> = const { RefCell::new(Vec::new()) };
+    }
+
+    // `quickcheck` needs a function pointer, so the test function is passed through a thread
+    // local instead of being captured.
+    fn record<A, T>(input: A) -> TestResult
+    where
+        A: Deref<Target = [PartialOp]> + 'static,
+        T: Testable,
+    {
+        let test_fn = TEST_FN.with(|test_fn| {
+            *test_fn
+                .borrow()
+                .last()
+                .and_then(|test_fn| test_fn.downcast_ref::<fn(A) -> T>())
+                .expect("called from run_with_corpus")
+        });
+        __private::run_case(input.to_vec(), move || test_fn(input))
+    }
+
+    let dir = dir.as_ref();
+    TEST_FN.with(|stack| stack.borrow_mut().push(Box::new(test_fn)));
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        __private::check(&dir.display().to_string(), Some(dir), record::<A, T>)
+    }));
+    TEST_FN.with(|stack| stack.borrow_mut().pop());
+    res.unwrap_or_else(|payload| panic::resume_unwind(payload))
+}
+
+/// Support code for `#[partial_test]`. Not public API.
+#[doc(hidden)]
+pub mod __private {
+    use crate::{corpus, PartialOp};
+    use quickcheck::{Arbitrary, Gen, QuickCheck, Testable};
+    use std::{
+        cell::RefCell,
+        env, fmt,
+        panic::{self, AssertUnwindSafe},
+        path::{Path, PathBuf},
+    };
+
+    pub use quickcheck::TestResult;
+
+    thread_local! {
+        // The operations of the last case that failed.
+        static LAST_FAILURE: RefCell<Option<Vec<PartialOp>>> = const { RefCell::new(None) };
+    }
+
+    /// Runs a single case, recording its operations if it fails.
+    pub fn run_case<T: Testable>(ops: Vec<PartialOp>, case: impl FnOnce() -> T) -> TestResult {
+        let res = panic::catch_unwind(AssertUnwindSafe(|| case().result(&mut Gen::new(0))));
+        if res.as_ref().map_or(true, TestResult::is_failure) {
+            LAST_FAILURE.with(|last| *last.borrow_mut() = Some(ops));
+        }
+        res.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+
+    /// Runs the test, printing the operations of the minimal failing case on failure.
+    ///
+    /// If `PARTIAL_IO_CORPUS_DIR` is set, saved cases are replayed first, and the minimal
+    /// failing case is saved.
+    pub fn run<A>(name: &str, case: fn(A) -> TestResult)
+    where
+        A: Arbitrary + fmt::Debug + From<Vec<PartialOp>>,
+    {
+        let corpus_dir = env::var_os(corpus::CORPUS_DIR_VAR)
+            .map(|dir| PathBuf::from(dir).join(name.replace("::", ".")));
+        check(name, corpus_dir.as_deref(), case);
+    }
+
+    /// Replays the corpus in `corpus_dir` if there's one, then generates new cases, saving the
+    /// minimal failing one to the corpus.
+    pub(crate) fn check<A>(name: &str, corpus_dir: Option<&Path>, case: fn(A) -> TestResult)
+    where
+        A: Arbitrary + fmt::Debug + From<Vec<PartialOp>>,
+    {
+        if let Some(dir) = corpus_dir {
+            corpus::replay_corpus(dir, case);
+        }
+
+        LAST_FAILURE.with(|last| last.borrow_mut().take());
+        if let Err(payload) = panic::catch_unwind(|| QuickCheck::new().quickcheck(case)) {
+            // Shrinking stops at the last case that failed, so that's the minimal one.
+            if let Some(ops) = LAST_FAILURE.with(|last| last.borrow_mut().take()) {
+                eprintln!(
+                    "[partial_test] {} failed with schedule {:016x}. To reproduce, use these \
+                     operations:\n{}",
+                    name,
+                    corpus::fingerprint(&ops),
+                    repro(&ops)
+                );
+                if let Some(dir) = corpus_dir {
+                    match corpus::save(dir, &ops) {
+                        Ok(path) => eprintln!("[partial_test] saved to {}", path.display()),
+                        Err(err) => eprintln!("[partial_test] failed to save the case: {}", err),
+                    }
+                }
+            }
+            panic::resume_unwind(payload);
+        }
+    }
+
+    /// Formats operations as a Rust expression.
+    pub(crate) fn repro(ops: &[PartialOp]) -> String {
+        let ops: Vec<_> = ops.iter().map(repro_op).collect();
+        format!("vec![{}]", ops.join(", "))
+    }
+
+    fn repro_op(op: &PartialOp) -> String {
+        match op {
+            PartialOp::Limited(n) => format!("PartialOp::Limited({})", n),
+            PartialOp::LimitedFraction(num, den) => {
+                format!("PartialOp::LimitedFraction({}, {})", num, den)
+            }
+            PartialOp::Unlimited => "PartialOp::Unlimited".to_owned(),
+            PartialOp::Err(kind) => format!("PartialOp::Err(io::ErrorKind::{:?})", kind),
+            PartialOp::OsErr(code) => format!("PartialOp::OsErr({})", code),
+            PartialOp::ErrWith(_) => "PartialOp::err_with(/* ... */)".to_owned(),
+            PartialOp::ErrTimes(kind, n) => {
+                format!("PartialOp::ErrTimes(io::ErrorKind::{:?}, {})", kind, n)
+            }
+            PartialOp::Delay(duration) => format!(
+                "PartialOp::Delay(Duration::from_nanos({}))",
+                duration.as_nanos()
+            ),
+            PartialOp::Fragment(n) => format!("PartialOp::Fragment({})", n),
+            PartialOp::Panic(message) => format!("PartialOp::Panic({:?})", message),
+            PartialOp::Labeled(op, label) => format!("{}.labeled({:?})", repro_op(op), label),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Generates an error for every operation.
+    #[derive(Clone, Debug, Default)]
+    struct GenAlways;
+
+    impl GenError for GenAlways {
+        fn gen_error(&mut self, _g: &mut Gen) -> Option<io::ErrorKind> {
+            Some(io::ErrorKind::Interrupted)
+        }
+    }
+
+    fn error_positions<GE: GenError + 'static>() -> Vec<usize> {
+        let seq = PartialWithErrors::<GE>::arbitrary(&mut Gen::new(20));
+        seq.iter()
+            .enumerate()
+            .filter(|(_, op)| matches!(op, PartialOp::Err(_)))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    #[test]
+    fn test_bias() {
+        assert_eq!(error_positions::<FrontLoaded<GenAlways>>(), [0, 1, 2, 3, 4]);
+        assert_eq!(
+            error_positions::<BackLoaded<GenAlways>>(),
+            [15, 16, 17, 18, 19]
+        );
+        assert_eq!(error_positions::<Clustered<GenAlways>>().len(), 20);
+        assert!(error_positions::<Clustered<GenNoErrors>>().is_empty());
+        let positions = error_positions::<Clustered<FrontLoaded<GenAlways>>>();
+        assert!(positions.starts_with(&[0, 1, 2, 3, 4]) && positions.len() <= 8);
+    }
+
+    #[test]
+    fn test_covering() {
+        let kinds = |size| {
+            let seq = PartialWithErrors::<Covering<GenInterruptedWouldBlock>>::arbitrary(
+                &mut Gen::new(size),
+            );
+            let mut kinds: Vec<_> = seq
+                .iter()
+                .filter_map(|op| match op {
+                    PartialOp::Err(kind) => Some(*kind),
+                    _ => None,
+                })
+                .collect();
+            kinds.sort_by_key(|kind| format!("{:?}", kind));
+            kinds.dedup();
+            kinds
+        };
+        for _ in 0..16 {
+            assert_eq!(
+                kinds(2),
+                [io::ErrorKind::Interrupted, io::ErrorKind::WouldBlock]
+            );
+            assert_eq!(kinds(1).len(), 1, "only one kind fits");
+        }
+        assert!(kinds(0).is_empty());
+    }
+
+    #[test]
+    fn test_bursty() {
+        let seq = PartialWithErrors::<Bursty<GenWouldBlock>>::arbitrary(&mut Gen::new(1000));
+        let errors = seq.iter().filter(|op| op.is_error()).count();
+        assert!((50..400).contains(&errors), "errors: {}", errors);
+        let longest = seq
+            .split(|op| !op.is_error())
+            .map(|burst| burst.len())
+            .max()
+            .unwrap();
+        assert!(longest >= 5, "longest burst: {}", longest);
+
+        let seq = PartialWithErrors::<Bursty<GenNoErrors>>::arbitrary(&mut Gen::new(100));
+        assert!(!seq.iter().any(|op| op.is_error()));
+    }
+
+    #[test]
+    fn test_interrupted_storm() {
+        let mut g = Gen::new(16);
+        for _ in 0..32 {
+            let storm = InterruptedStorm::arbitrary(&mut g);
+            assert!((1..=16).contains(&storm.k()), "k: {}", storm.k());
+            assert_eq!(storm.len(), storm.k() + 1);
+            assert!(storm[..storm.k()]
+                .iter()
+                .all(|op| matches!(op, PartialOp::Err(io::ErrorKind::Interrupted))));
+            assert!(matches!(storm[storm.k()], PartialOp::Unlimited));
+            assert!(storm.shrink().all(|smaller| smaller.k() < storm.k()));
+        }
+    }
+
+    #[test]
+    fn test_size_scaled() {
+        fn error_count(size: usize, runs: usize) -> usize {
+            let mut g = Gen::new(size);
+            (0..runs)
+                .map(|_| {
+                    let seq = PartialWithErrors::<SizeScaled<GenInterrupted>>::arbitrary(&mut g);
+                    seq.iter().filter(|op| op.is_error()).count()
+                })
+                .sum()
+        }
+
+        // About 5% of 1000 operations at size 20, and 80% of 1600 at size 1600.
+        assert!(error_count(20, 50) < 150);
+        assert!(error_count(1600, 1) > 1000);
+        assert_eq!(
+            PartialWithErrors::<SizeScaled<GenNoErrors>>::arbitrary(&mut Gen::new(1600))
+                .iter()
+                .filter(|op| op.is_error())
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_gen_config() {
+        let config = GenConfig {
+            max_len: 8,
+            error_kinds: vec![io::ErrorKind::TimedOut],
+            error_prob: 0.5,
+            limit_dist: LimitDist::Fixed(3),
+        };
+        let mut g = Gen::new(100);
+        config.scope(|| {
+            for _ in 0..32 {
+                let seq = PartialWithConfig::arbitrary(&mut g);
+                assert!(seq.len() <= 8);
+                assert!(seq.iter().all(|op| matches!(
+                    op,
+                    PartialOp::Err(io::ErrorKind::TimedOut) | PartialOp::Limited(3)
+                )));
+            }
+        });
+
+        // Outside of the scope, the default configuration is used again.
+        let seq = PartialWithConfig::arbitrary(&mut Gen::new(100));
+        assert!(seq.iter().all(|op| matches!(
+            op,
+            PartialOp::Err(io::ErrorKind::Interrupted) | PartialOp::Limited(1..=100)
+        )));
+    }
+
+    #[test]
+    fn test_gen_config_uniform_zero() {
+        let config = GenConfig {
+            max_len: 8,
+            error_kinds: vec![],
+            error_prob: 0.0,
+            limit_dist: LimitDist::Uniform { max: 0 },
+        };
+        let mut g = Gen::new(100);
+        for _ in 0..32 {
+            let seq = config.generate(&mut g);
+            assert!(seq.iter().all(|op| matches!(op, PartialOp::Limited(1))));
+        }
+    }
+
+    #[test]
+    fn test_async_with_errors() {
+        let mut g = Gen::new(64);
+        for _ in 0..16 {
+            let seq = AsyncWithErrors::<GenWouldBlockStreaks>::arbitrary(&mut g);
+            assert!(matches!(seq.last(), Some(PartialOp::Unlimited)));
+            assert!(seq
+                .shrink()
+                .take(16)
+                .all(|smaller| matches!(smaller.last(), Some(PartialOp::Unlimited))));
+        }
+
+        let seq = AsyncWithErrors::<GenInterrupted>::from(vec![]);
+        assert_eq!(seq.shrink().count(), 0);
+        let err = PartialOp::Err(io::ErrorKind::Interrupted);
+        let seq = AsyncWithErrors::<GenInterrupted>::from(vec![PartialOp::Limited(1), err.clone()]);
+        let expected = [err, PartialOp::Unlimited];
+        assert!(
+            seq.shrink().any(|smaller| smaller[..] == expected),
+            "the last operation is kept"
+        );
+    }
+
+    #[test]
+    fn test_gen_limit() {
+        fn limits(seq: &[PartialOp]) -> Vec<usize> {
+            seq.iter()
+                .filter_map(|op| match op {
+                    PartialOp::Limited(n) => Some(*n),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        let mut g = Gen::new(64);
+        for _ in 0..16 {
+            let seq = PartialWithErrors::<GenInterrupted, GenPowerOfTwoLimit>::arbitrary(&mut g);
+            assert!(limits(&seq).iter().all(|n| n.is_power_of_two() && *n <= 64));
+            assert!(seq
+                .shrink()
+                .take(32)
+                .all(|smaller| limits_accepted::<GenPowerOfTwoLimit>(&smaller)));
+
+            let seq = AsyncWithErrors::<GenNoErrors, GenTinyLimit>::arbitrary(&mut g);
+            assert!(limits(&seq).iter().all(|n| (1..=4).contains(n)));
+        }
+    }
+
+    #[test]
+    fn test_correlated_ops() {
+        // Returns the total number of bytes, and the byte offset of each fault.
+        fn offsets(ops: &[PartialOp]) -> (usize, Vec<usize>) {
+            let mut offset = 0;
+            let mut faults = vec![];
+            for op in ops {
+                match op {
+                    PartialOp::Limited(n) => offset += n,
+                    _ => faults.push(offset),
+                }
+            }
+            (offset, faults)
+        }
+
+        fn check(ops: &CorrelatedOps<GenInterrupted>) {
+            let (read_len, read_faults) = offsets(ops.read_ops());
+            let (write_len, write_faults) = offsets(ops.write_ops());
+            assert_eq!(read_len, write_len, "both sides transfer the same bytes");
+            assert!(!ops.read_ops().contains(&PartialOp::Limited(0)));
+
+            // Faults are only injected where a faulty transfer starts, on at least one side.
+            let mut starts = vec![];
+            let mut offset = 0;
+            for transfer in &ops.transfers {
+                if transfer.fault.is_some() {
+                    starts.push(offset);
+                }
+                offset += transfer.len;
+            }
+            let mut faults = read_faults.clone();
+            faults.extend(&write_faults);
+            faults.sort_unstable();
+            faults.dedup();
+            assert_eq!(faults, starts);
+        }
+
+        let mut g = Gen::new(32);
+        let mut distinct = false;
+        for _ in 0..16 {
+            let ops = CorrelatedOps::<GenInterrupted>::arbitrary(&mut g);
+            check(&ops);
+            distinct |= ops.read_ops() != ops.write_ops();
+            for smaller in ops.shrink().take(16) {
+                check(&smaller);
+                assert!(smaller.transfers.len() < ops.transfers.len());
+            }
+        }
+        assert!(distinct, "the sides are split up differently");
+    }
+
+    quickcheck::quickcheck! {
+        fn test_correlated_copy(ops: CorrelatedOps<GenInterrupted>) -> bool {
+            use crate::{PartialRead, PartialWrite};
+
+            let (read_ops, write_ops) = ops.into_ops();
+            let mut reader = PartialRead::new(&b"Hello, world!"[..], read_ops);
+            let mut writer = PartialWrite::new(Vec::new(), write_ops);
+            io::copy(&mut reader, &mut writer).is_ok() && writer.get_ref() == b"Hello, world!"
+        }
+    }
+
+    #[test]
+    fn test_repro() {
+        let ops = [
+            PartialOp::Limited(3),
+            PartialOp::Err(io::ErrorKind::Interrupted),
+            PartialOp::ErrTimes(io::ErrorKind::WouldBlock, 2),
+        ];
+        assert_eq!(
+            __private::repro(&ops),
+            "vec![PartialOp::Limited(3), PartialOp::Err(io::ErrorKind::Interrupted), \
+             PartialOp::ErrTimes(io::ErrorKind::WouldBlock, 2)]"
+        );
+    }
+
+    #[test]
+    fn test_run_with_corpus() {
+        use crate::corpus;
+        use std::{fs, panic};
+
+        fn no_errors(seq: PartialWithErrors<GenInterrupted>) -> bool {
+            !seq.iter().any(PartialOp::is_error)
+        }
+
+        let dir =
+            std::env::temp_dir().join(format!("partial-io-run-corpus-{}", std::process::id()));
+        // The saved case fails, however unlikely generated errors are.
+        corpus::save(&dir, &[PartialOp::Err(io::ErrorKind::Interrupted)]).unwrap();
+        let payload = panic::catch_unwind(|| run_with_corpus(&dir, no_errors)).unwrap_err();
+        let message = payload.downcast_ref::<String>().unwrap();
+        assert!(message.starts_with("replaying"), "{}", message);
+        fs::remove_dir_all(&dir).unwrap();
+
+        // A new failure is saved, and replayed on the next run.
+        assert!(panic::catch_unwind(|| run_with_corpus(&dir, no_errors)).is_err());
+        let saved = corpus::load(&dir).unwrap();
+        assert_eq!(saved.len(), 1);
+        assert!(saved[0].1.iter().any(PartialOp::is_error));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[crate::partial_test]
+    fn test_partial_test(seq: PartialWithErrors<GenNoErrors>) -> bool {
+        seq.iter().all(|op| !op.is_error())
+    }
+
+    #[crate::partial_test(fixture = Vec::new())]
+    fn test_partial_test_fixture(
+        seq: PartialWithErrors<GenInterrupted>,
+        mut partial_write: crate::PartialWrite<Vec<u8>>,
+    ) {
+        use std::io::Write;
+
+        if seq.is_empty() {
+            return;
+        }
+        partial_write.write_all(b"abc").unwrap();
+        assert_eq!(partial_write.get_ref(), b"abc");
+    }
+
+    #[crate::partial_test]
+    #[should_panic]
+    fn test_partial_test_failure(seq: PartialWithErrors<GenInterrupted>) -> bool {
+        seq.iter().all(|op| !op.is_error())
+    }
+}
@@ -9,15 +9,21 @@
 //! a provided iterator.
 
 use std::{
-    cmp, fmt,
-    io::{self, Read, Write},
+    fmt,
+    io::{self, BufRead, Read, Seek, SeekFrom, Write},
 };
 
-use crate::{make_ops, PartialOp};
+use crate::{
+    seeded::SeededOps, seek::SeekOps, Alignment, AppliedOp, BufSize, OpEngine, PartialOp,
+    PartialWrap, Profile, SeekOp,
+};
 
 /// A reader wrapper that breaks inner `Read` instances up according to the
 /// provided iterator.
 ///
+/// The reader may be unsized, such as `PartialRead<dyn Read>` behind a `Box`,
+/// or borrowed, such as `PartialRead<&mut dyn Read>`.
+///
 /// # Examples
 ///
 /// ```rust
@@ -34,25 +40,142 @@ use crate::{make_ops, PartialOp};
 /// assert_eq!(size, 1);
 /// assert_eq!(&out[..1], &[1]);
 /// ```
-pub struct PartialRead<R> {
+pub struct PartialRead<R: ?Sized> {
+    ops: OpEngine,
+    checks: FillBufChecks,
+    eof: EofChecks,
+    seek_ops: SeekOps,
     inner: R,
-    ops: Box<dyn Iterator<Item = PartialOp> + Send>,
 }
 
-impl<R> PartialRead<R>
-where
-    R: Read,
-{
+/// Tracks the state needed for `set_invariant_checks` on `BufRead` and `AsyncBufRead` wrappers.
+#[derive(Debug, Default)]
+pub(crate) struct FillBufChecks {
+    pub(crate) enabled: bool,
+    // The number of bytes returned by the last successful fill that haven't been consumed yet.
+    available: usize,
+    // Whether the last fill returned `Poll::Pending` or an error.
+    failed: bool,
+}
+
+impl FillBufChecks {
+    // `res` is `None` if the fill returned `Poll::Pending`.
+    pub(crate) fn after_fill_buf(&mut self, res: Option<&io::Result<&[u8]>>) {
+        match res {
+            Some(Ok(buf)) => {
+                self.available = buf.len();
+                self.failed = false;
+            }
+            Some(Err(_)) | None => {
+                self.available = 0;
+                self.failed = true;
+            }
+        }
+    }
+
+    pub(crate) fn before_consume(&mut self, name: &str, fill_method: &str, amt: usize) {
+        if self.enabled && amt > self.available {
+            if self.failed {
+                panic!(
+                    "{}: consume called with {} bytes after {} failed or returned Pending, \
+                     so the buffer from an earlier call is stale",
+                    name, amt, fill_method
+                );
+            }
+            panic!(
+                "{}: consume called with {} bytes, but only {} bytes returned by {} are left",
+                name, amt, self.available, fill_method
+            );
+        }
+        self.available = self.available.saturating_sub(amt);
+    }
+}
+
+/// Tracks the state needed for `reads_after_eof` and `set_max_reads_after_eof` on `Read` and
+/// `AsyncRead` wrappers.
+#[derive(Debug, Default)]
+pub(crate) struct EofChecks {
+    pub(crate) max: Option<usize>,
+    // Whether the last read into a non-empty buffer returned 0 bytes.
+    at_eof: bool,
+    pub(crate) reads_after_eof: usize,
+}
+
+impl EofChecks {
+    // Returns an error if this read is over the limit, counting it.
+    pub(crate) fn before_read(&mut self, method: &str, len: usize) -> Option<io::Error> {
+        let max = self.max?;
+        if !self.at_eof || len == 0 || self.reads_after_eof < max {
+            return None;
+        }
+        self.reads_after_eof += 1;
+        Some(io::Error::other(format!(
+            "{} called {} times after EOF, over the limit of {}, generated by partial-io",
+            method, self.reads_after_eof, max
+        )))
+    }
+
+    // `n` is the number of bytes read, or `None` if the read failed.
+    pub(crate) fn after_read(&mut self, len: usize, n: Option<usize>) {
+        if len == 0 {
+            return;
+        }
+        if self.at_eof {
+            self.reads_after_eof += 1;
+        }
+        if let Some(n) = n {
+            self.at_eof = n == 0;
+        }
+    }
+}
+
+impl<R: ?Sized> PartialRead<R> {
     /// Creates a new `PartialRead` wrapper over the reader with the specified `PartialOp`s.
     pub fn new<I>(inner: R, iter: I) -> Self
     where
+        R: Sized,
         I: IntoIterator<Item = PartialOp> + 'static,
         I::IntoIter: Send,
     {
-        PartialRead {
+        Self::from_engine(inner, OpEngine::new(iter))
+    }
+
+    /// Creates a new `PartialRead` wrapper over the reader with a reproducible randomized schedule
+    /// of `PartialOp`s, generated from `seed` and `profile`.
+    ///
+    /// Unless the profile sets error kinds, operations fail with `Interrupted`. If the
+    /// thread panics while the wrapper is alive, the seed is printed when it's dropped. See
+    /// `Profile` for more.
+    pub fn with_seed(inner: R, seed: u64, profile: Profile) -> Self
+    where
+        R: Sized,
+    {
+        Self::new(
             inner,
-            ops: make_ops(iter),
-        }
+            SeededOps::for_wrapper("PartialRead", seed, profile, io::ErrorKind::Interrupted),
+        )
+    }
+
+    /// Creates a new `PartialRead` wrapper over the reader in passthrough mode, which forwards
+    /// every call as-is.
+    ///
+    /// Setting `PartialOp`s turns passthrough mode off. See `OpEngine::passthrough` for more.
+    pub fn passthrough(inner: R) -> Self
+    where
+        R: Sized,
+    {
+        Self::from_engine(inner, OpEngine::passthrough())
+    }
+
+    /// Creates a new `PartialRead` wrapper over the reader that limits every operation to 1 byte,
+    /// forever.
+    ///
+    /// This doesn't allocate. See `OpEngine::byte_by_byte` for more.
+    pub fn byte_by_byte(inner: R) -> Self
+    where
+        R: Sized,
+    {
+        Self::from_engine(inner, OpEngine::byte_by_byte())
     }
 
     /// Sets the `PartialOp`s for this reader.
@@ -61,7 +184,115 @@ where
         I: IntoIterator<Item = PartialOp> + 'static,
         I::IntoIter: Send,
     {
-        self.ops = make_ops(iter);
+        self.ops.replace(iter);
+        self
+    }
+
+    /// Caps every operation on this reader to at most `max_op_size` bytes, in addition to the
+    /// limits in `PartialOp::Limited`.
+    ///
+    /// See `OpEngine::set_max_op_size` for more.
+    pub fn set_max_op_size(&mut self, max_op_size: usize) -> &mut Self {
+        self.ops.set_max_op_size(max_op_size);
+        self
+    }
+
+    /// Caps the number of bytes read through this reader in total to `limit`. Once it's reached,
+    /// every further read returns EOF, whatever the operations are. `fill_buf` isn't capped.
+    ///
+    /// See `OpEngine::set_total_limit` for more.
+    pub fn set_total_limit(&mut self, limit: Option<u64>) -> &mut Self {
+        self.ops.set_total_limit(limit);
+        self
+    }
+
+    /// Starts recording the operations applied by this reader, and the methods they are applied to.
+    ///
+    /// Use `into_parts` and `OpEngine::into_report` to see which operations were never reached.
+    pub fn record_ops(&mut self) -> &mut Self {
+        self.ops.record_ops();
+        self
+    }
+
+    /// Returns the operations applied by this reader since `record_ops` was called.
+    pub fn applied_ops(&self) -> &[AppliedOp] {
+        self.ops.applied_ops()
+    }
+
+    /// Starts recording the size of every buffer passed to this reader, along with the size
+    /// forwarded to the inner reader after limiting.
+    ///
+    /// See `OpEngine::record_sizes` for more.
+    pub fn record_sizes(&mut self) -> &mut Self {
+        self.ops.record_sizes();
+        self
+    }
+
+    /// Returns the buffer sizes recorded by this reader since `record_sizes` was called, in order.
+    pub fn buf_sizes(&self) -> &[BufSize] {
+        self.ops.buf_sizes()
+    }
+
+    /// Starts recording the timeline of method calls on this reader.
+    ///
+    /// See the `timeline` module for more.
+    pub fn record_calls(&mut self) -> &mut Self {
+        self.ops.record_calls();
+        self
+    }
+
+    /// Returns the method calls on this reader since `record_calls` was called, in order.
+    pub fn calls(&self) -> &[&'static str] {
+        self.ops.calls()
+    }
+
+    /// Snaps the sizes in `PartialOp::Limited` on this reader to boundaries.
+    ///
+    /// See `OpEngine::set_alignment` for more.
+    pub fn set_alignment(&mut self, alignment: Option<Alignment>) -> &mut Self {
+        self.ops.set_alignment(alignment);
+        self
+    }
+
+    /// Sets the `SeekOp`s for seeks on this reader.
+    ///
+    /// See `SeekOp` for more.
+    pub fn set_seek_ops<I>(&mut self, iter: I) -> &mut Self
+    where
+        I: IntoIterator<Item = SeekOp> + 'static,
+        I::IntoIter: Send,
+    {
+        self.seek_ops.replace(iter);
+        self
+    }
+
+    /// Enables or disables checks for `BufRead` protocol invariants.
+    ///
+    /// When enabled, this reader panics if `consume` is called with more bytes than are left from
+    /// the last successful `fill_buf`, or with any bytes after `fill_buf` returned an error.
+    pub fn set_invariant_checks(&mut self, enabled: bool) -> &mut Self {
+        self.checks.enabled = enabled;
+        self
+    }
+
+    /// Returns the number of reads into a non-empty buffer made after this reader returned EOF.
+    ///
+    /// A read returns EOF if it reads 0 bytes into a non-empty buffer, including because of
+    /// `PartialOp::Limited(0)`. Reads after that are counted whatever they return, so a reader
+    /// that produces data again doesn't reset the count.
+    pub fn reads_after_eof(&self) -> usize {
+        self.eof.reads_after_eof
+    }
+
+    /// Makes reads fail once more than `max` reads were made after this reader returned EOF, or
+    /// removes the limit if `max` is `None`.
+    ///
+    /// Reads over the limit are counted, but aren't passed to the inner reader, and fail with an
+    /// error of kind `Other`. Since a read loop rarely forwards that kind of error quietly, a loop
+    /// that never stops at EOF fails right away, rather than spinning until the test times out.
+    /// Most correct loops stop at the first EOF, so `Some(0)` is a good strict setting.
+    pub fn set_max_reads_after_eof(&mut self, max: Option<usize>) -> &mut Self {
+        self.eof.max = max;
         self
     }
 
@@ -76,34 +307,96 @@ where
     }
 
     /// Consumes this wrapper, returning the underlying reader.
-    pub fn into_inner(self) -> R {
+    pub fn into_inner(self) -> R
+    where
+        R: Sized,
+    {
         self.inner
     }
+
+    /// Consumes this wrapper, returning the underlying reader and the engine with the `PartialOp`s
+    /// that haven't been consumed yet.
+    ///
+    /// Use `OpEngine::into_ops` to inspect the operations that are left.
+    pub fn into_parts(self) -> (R, OpEngine)
+    where
+        R: Sized,
+    {
+        (self.inner, self.ops)
+    }
 }
 
 impl<R> Read for PartialRead<R>
 where
-    R: Read,
+    R: Read + ?Sized,
 {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self.ops.next() {
-            Some(PartialOp::Limited(n)) => {
-                let len = cmp::min(n, buf.len());
-                self.inner.read(&mut buf[..len])
-            }
-            Some(PartialOp::Err(err)) => Err(io::Error::new(
-                err,
-                "error during read, generated by partial-io",
-            )),
-            Some(PartialOp::Unlimited) | None => self.inner.read(buf),
+        let inner = &mut self.inner;
+        let len = buf.len();
+        self.ops.begin_call("read");
+        if let Some(err) = self.eof.before_read("read", len) {
+            return Err(err);
+        }
+        let res = self.ops.run_fragmented(
+            |offset, limit| match limit {
+                Some(limit) => inner.read(&mut buf[offset..offset + limit]),
+                None => inner.read(&mut buf[offset..]),
+            },
+            len,
+            "error during read, generated by partial-io",
+        );
+        self.eof.after_read(len, res.as_ref().ok().copied());
+        res
+    }
+}
+
+impl<R> BufRead for PartialRead<R>
+where
+    R: BufRead + ?Sized,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let inner = &mut self.inner;
+        self.ops.begin_call("fill_buf");
+        let res = self.ops.run_no_limit(
+            move || inner.fill_buf(),
+            "error during fill_buf, generated by partial-io",
+        );
+        self.checks.after_fill_buf(Some(&res));
+        res
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.checks.before_consume("PartialRead", "fill_buf", amt);
+        self.inner.consume(amt)
+    }
+}
+
+impl<R> PartialWrap for PartialRead<R> {
+    type Inner = R;
+
+    fn from_engine(inner: R, engine: OpEngine) -> Self {
+        PartialRead {
+            inner,
+            checks: FillBufChecks::default(),
+            eof: EofChecks::default(),
+            seek_ops: SeekOps::new(),
+            ops: engine,
         }
     }
+
+    fn engine_mut(&mut self) -> &mut OpEngine {
+        &mut self.ops
+    }
+
+    fn into_parts(self) -> (R, OpEngine) {
+        PartialRead::into_parts(self)
+    }
 }
 
 // Forwarding impl to support duplex structs.
 impl<R> Write for PartialRead<R>
 where
-    R: Read + Write,
+    R: Read + Write + ?Sized,
 {
     #[inline]
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
@@ -116,13 +409,25 @@ where
     }
 }
 
+// Seeks are forwarded to the inner reader, after applying `SeekOp`s.
+impl<R> Seek for PartialRead<R>
+where
+    R: Seek + ?Sized,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = self.seek_ops.start(pos);
+        let res = self.inner.seek(pos);
+        self.seek_ops.complete(res)
+    }
+}
+
 impl<R> fmt::Debug for PartialRead<R>
 where
-    R: fmt::Debug,
+    R: fmt::Debug + ?Sized,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("PartialRead")
-            .field("inner", &self.inner)
+            .field("inner", &&self.inner)
             .finish()
     }
 }
@@ -131,12 +436,107 @@ where
 mod tests {
     use super::*;
 
-    use std::fs::File;
+    use std::{fs::File, io::Cursor};
 
-    use crate::tests::assert_send;
+    use crate::tests::{assert_send, assert_sync};
 
     #[test]
     fn test_sendable() {
         assert_send::<PartialRead<File>>();
+        assert_sync::<PartialRead<File>>();
+    }
+
+    #[test]
+    fn test_unsized() {
+        let ops = vec![PartialOp::Limited(1)];
+        let mut boxed: Box<PartialRead<dyn Read>> =
+            Box::new(PartialRead::new(Cursor::new(vec![1, 2]), ops.clone()));
+        let mut buf = [0; 2];
+        assert_eq!(boxed.read(&mut buf).unwrap(), 1);
+        boxed.set_ops(None);
+        assert_eq!(boxed.read(&mut buf).unwrap(), 1);
+
+        let mut cursor = Cursor::new(vec![1, 2]);
+        let reader: &mut dyn Read = &mut cursor;
+        let mut partial_read = PartialRead::new(reader, ops);
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 1);
+        assert_eq!(cursor.position(), 1);
+    }
+
+    #[test]
+    fn test_reads_after_eof() {
+        let ops = vec![PartialOp::Limited(2), PartialOp::Limited(0)];
+        let mut partial_read = PartialRead::new(Cursor::new(vec![1, 2, 3]), ops);
+        let mut buf = [0; 4];
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 2);
+        // An injected EOF counts too, and data after it doesn't reset the count.
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 0);
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 1);
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 0);
+        assert_eq!(partial_read.read(&mut []).unwrap(), 0);
+        assert_eq!(partial_read.reads_after_eof(), 1);
+
+        partial_read.set_max_reads_after_eof(Some(2));
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 0);
+        let err = partial_read.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(
+            err.to_string(),
+            "read called 3 times after EOF, over the limit of 2, generated by partial-io"
+        );
+        assert_eq!(partial_read.reads_after_eof(), 3);
+    }
+
+    #[test]
+    fn test_buf_read() {
+        let ops = vec![PartialOp::Err(io::ErrorKind::Interrupted)];
+        let mut partial_read = PartialRead::new(Cursor::new(vec![1, 2, 3]), ops);
+        partial_read.set_invariant_checks(true);
+        assert!(partial_read.fill_buf().is_err());
+        assert_eq!(partial_read.fill_buf().unwrap(), [1, 2, 3]);
+        partial_read.consume(2);
+        partial_read.consume(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "consume called with 3 bytes, but only 1 bytes")]
+    fn test_buf_read_overconsume() {
+        let mut partial_read = PartialRead::new(Cursor::new(vec![1, 2, 3]), vec![]);
+        partial_read.set_invariant_checks(true);
+        partial_read.fill_buf().unwrap();
+        partial_read.consume(2);
+        partial_read.consume(3);
+    }
+
+    #[test]
+    fn test_phases() {
+        let mut partial_read = PartialRead::new(Cursor::new(vec![1, 2, 3, 4]), vec![]);
+        let mut buf = [0; 4];
+
+        partial_read.set_ops(vec![PartialOp::Limited(1)]);
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 1);
+        assert_eq!(partial_read.get_ref().position(), 1);
+
+        partial_read.set_ops(vec![PartialOp::Err(io::ErrorKind::Interrupted)]);
+        assert_eq!(
+            partial_read.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::Interrupted
+        );
+        partial_read.get_mut().set_position(0);
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_by_ref() {
+        // The standard library's `Read for &mut R` and `Write for &mut W` impls apply to the
+        // wrappers, so they can be used by reference repeatedly.
+        let mut partial_read =
+            PartialRead::new(Cursor::new(vec![1; 8]), vec![PartialOp::Limited(3)]);
+        let mut partial_write = crate::PartialWrite::new(Vec::new(), vec![PartialOp::Limited(2)]);
+        for _ in 0..2 {
+            let mut limited = (&mut partial_read).take(4);
+            io::copy(&mut limited, &mut partial_write).unwrap();
+        }
+        assert_eq!(partial_write.get_ref(), &vec![1; 8]);
     }
 }
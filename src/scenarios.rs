@@ -0,0 +1,434 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Named sequences of `PartialOp`s for classic bug patterns.
+//!
+//! Each function returns the operations for a schedule that has broken real wrappers. Every
+//! operation is consumed by one call into the wrapper, so each scenario documents the calls it
+//! expects to line up with. Run them against a wrapper as a first set of regression tests, before
+//! reaching for `quickcheck_types`.
+//!
+//! [`interrupted_mid_loop`], [`eof_one_byte_short`] and [`would_block_on_last_byte`] target loops
+//! like `read_exact` and `write_all`. [`assert_read_exact_semantics`] and
+//! [`assert_write_all_semantics`] run a custom equivalent of either against them, and check that
+//! it behaves the way the standard library documents.
+//!
+//! [`tcp_segments`] is a preset of a different kind: an endless, seeded sequence of read sizes
+//! that resemble the ones seen on a TCP socket.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use partial_io::{scenarios, PartialWrite};
+//! use std::io::{self, Write};
+//!
+//! // A writer that writes once, then flushes.
+//! let mut partial_write = PartialWrite::new(Vec::new(), scenarios::interrupted_during_flush(1));
+//! partial_write.write_all(b"hello").unwrap();
+//! assert_eq!(partial_write.flush().unwrap_err().kind(), io::ErrorKind::Interrupted);
+//! // Retrying the flush succeeds.
+//! partial_write.flush().unwrap();
+//! ```
+//!
+//! [`interrupted_mid_loop`]: fn.interrupted_mid_loop.html
+//! [`eof_one_byte_short`]: fn.eof_one_byte_short.html
+//! [`would_block_on_last_byte`]: fn.would_block_on_last_byte.html
+//! [`assert_read_exact_semantics`]: fn.assert_read_exact_semantics.html
+//! [`assert_write_all_semantics`]: fn.assert_write_all_semantics.html
+//! [`tcp_segments`]: fn.tcp_segments.html
+
+use crate::{fixtures::splitmix64, PartialOp, PartialRead, PartialWrite};
+use std::{cmp, io};
+
+/// The payload of a full-sized segment: a 1500-byte MTU, minus the IPv4 and TCP headers with
+/// timestamps.
+const TCP_MSS: usize = 1448;
+
+/// The size of the first segment delivered by [`tcp_segments`].
+///
+/// [`tcp_segments`]: fn.tcp_segments.html
+const TCP_INITIAL_SEGMENT: usize = 64;
+
+/// A flush that fails with `Interrupted`, after `writes` calls to `write` go through unchanged.
+///
+/// Wrappers that clear their buffer before flushing the inner writer lose data when the flush
+/// is retried. Async wrappers retry `Interrupted` errors internally, so this targets sync
+/// writers.
+pub fn interrupted_during_flush(writes: usize) -> Vec<PartialOp> {
+    let mut ops = vec![PartialOp::Unlimited; writes];
+    ops.push(PartialOp::Err(io::ErrorKind::Interrupted));
+    ops
+}
+
+/// A read of exactly `prefix_len` bytes, followed by a `WouldBlock` before the rest arrives.
+///
+/// This splits a length-prefixed frame right after its prefix, and catches decoders that lose
+/// the prefix they've already parsed when they return `Poll::Pending` or `WouldBlock`.
+pub fn pending_between_prefix_and_payload(prefix_len: usize) -> Vec<PartialOp> {
+    vec![
+        PartialOp::Limited(prefix_len),
+        PartialOp::Err(io::ErrorKind::WouldBlock),
+        PartialOp::Unlimited,
+    ]
+}
+
+/// A read of `offset` bytes, followed by a read that returns 0 bytes.
+///
+/// To a reader, the empty read looks like the end of the stream in the middle of a frame, if
+/// `offset` falls inside one. Decoders should report an `UnexpectedEof` error instead of
+/// returning a partial frame or waiting for more data.
+pub fn eof_mid_frame(offset: usize) -> Vec<PartialOp> {
+    vec![PartialOp::Limited(offset), PartialOp::Limited(0)]
+}
+
+/// A write that only accepts `len` bytes, followed by a write that fails with `kind`.
+///
+/// Wrappers must account for the bytes that were written before the error, rather than
+/// assuming that a failed `write_all` wrote nothing.
+pub fn short_write_then_error(len: usize, kind: io::ErrorKind) -> Vec<PartialOp> {
+    vec![PartialOp::Limited(len), PartialOp::Err(kind)]
+}
+
+/// A read or write of `offset` bytes, followed by an `Interrupted` error, and then by one that
+/// goes through unchanged.
+///
+/// `read_exact` and `write_all` retry `Interrupted` errors, so they must succeed and keep the
+/// bytes from before the error.
+pub fn interrupted_mid_loop(offset: usize) -> Vec<PartialOp> {
+    vec![
+        PartialOp::Limited(offset),
+        PartialOp::Err(io::ErrorKind::Interrupted),
+        PartialOp::Unlimited,
+    ]
+}
+
+/// A read or write of `len - 1` bytes, followed by one of 0 bytes.
+///
+/// For a loop that reads or writes `len` bytes, this ends the stream one byte short.
+/// `read_exact` must then fail with `UnexpectedEof`, and `write_all` with `WriteZero`, rather
+/// than looping forever or reporting success.
+///
+/// # Panics
+///
+/// Panics if `len` is 0.
+pub fn eof_one_byte_short(len: usize) -> Vec<PartialOp> {
+    assert!(len > 0, "len must be greater than 0");
+    vec![PartialOp::Limited(len - 1), PartialOp::Limited(0)]
+}
+
+/// A read or write of `len - 1` bytes, followed by a `WouldBlock` error before the last byte.
+///
+/// `read_exact` and `write_all` don't retry `WouldBlock`, so they must return the error. For
+/// `write_all`, the first `len - 1` bytes have been written by then.
+///
+/// # Panics
+///
+/// Panics if `len` is 0.
+pub fn would_block_on_last_byte(len: usize) -> Vec<PartialOp> {
+    assert!(len > 0, "len must be greater than 0");
+    vec![
+        PartialOp::Limited(len - 1),
+        PartialOp::Err(io::ErrorKind::WouldBlock),
+        PartialOp::Unlimited,
+    ]
+}
+
+// The data that `assert_read_exact_semantics` and `assert_write_all_semantics` transfer.
+const LOOP_DATA: &[u8] = b"partial-io";
+
+/// Checks that `read_exact` behaves like `Read::read_exact` against the `read_exact` presets in
+/// this module.
+///
+/// `read_exact` is called with a reader of 10 bytes and a buffer of the same size, for each of
+/// [`interrupted_mid_loop`], [`eof_one_byte_short`] and [`would_block_on_last_byte`], and must
+/// return the result that `std` documents for it. The contents of the buffer are checked if it
+/// succeeds; `std` leaves them unspecified otherwise.
+///
+/// # Panics
+///
+/// Panics with the name of the scenario if `read_exact` doesn't behave like `Read::read_exact`.
+///
+/// # Examples
+///
+/// ```rust
+/// use partial_io::scenarios;
+/// use std::io::Read;
+///
+/// scenarios::assert_read_exact_semantics(|reader, buf| reader.read_exact(buf));
+/// ```
+///
+/// [`interrupted_mid_loop`]: fn.interrupted_mid_loop.html
+/// [`eof_one_byte_short`]: fn.eof_one_byte_short.html
+/// [`would_block_on_last_byte`]: fn.would_block_on_last_byte.html
+#[track_caller]
+pub fn assert_read_exact_semantics<F>(mut read_exact: F)
+where
+    F: FnMut(&mut PartialRead<&'static [u8]>, &mut [u8]) -> io::Result<()>,
+{
+    let len = LOOP_DATA.len();
+    let cases = [
+        ("interrupted_mid_loop", interrupted_mid_loop(len / 2), None),
+        (
+            "eof_one_byte_short",
+            eof_one_byte_short(len),
+            Some(io::ErrorKind::UnexpectedEof),
+        ),
+        (
+            "would_block_on_last_byte",
+            would_block_on_last_byte(len),
+            Some(io::ErrorKind::WouldBlock),
+        ),
+    ];
+    for (name, ops, expected) in cases {
+        let mut reader = PartialRead::new(LOOP_DATA, ops);
+        let mut buf = vec![0; len];
+        let res = read_exact(&mut reader, &mut buf);
+        assert_eq!(
+            res.as_ref().map_err(io::Error::kind).err(),
+            expected,
+            "{}: read_exact returned {:?}",
+            name,
+            res
+        );
+        if res.is_ok() {
+            assert_eq!(buf, LOOP_DATA, "{}: read_exact read the wrong bytes", name);
+        }
+    }
+}
+
+/// Checks that `write_all` behaves like `Write::write_all` against the `write_all` presets in
+/// this module.
+///
+/// `write_all` is called with 10 bytes, for each of [`interrupted_mid_loop`],
+/// [`eof_one_byte_short`] and [`would_block_on_last_byte`], and must return the result that
+/// `std` documents for it. The bytes that reached the inner writer are checked as well: all of
+/// them if it succeeds, and the ones before the failing write otherwise.
+///
+/// # Panics
+///
+/// Panics with the name of the scenario if `write_all` doesn't behave like `Write::write_all`.
+///
+/// # Examples
+///
+/// ```rust
+/// use partial_io::scenarios;
+/// use std::io::Write;
+///
+/// scenarios::assert_write_all_semantics(|writer, buf| writer.write_all(buf));
+/// ```
+///
+/// [`interrupted_mid_loop`]: fn.interrupted_mid_loop.html
+/// [`eof_one_byte_short`]: fn.eof_one_byte_short.html
+/// [`would_block_on_last_byte`]: fn.would_block_on_last_byte.html
+#[track_caller]
+pub fn assert_write_all_semantics<F>(mut write_all: F)
+where
+    F: FnMut(&mut PartialWrite<Vec<u8>>, &[u8]) -> io::Result<()>,
+{
+    let len = LOOP_DATA.len();
+    let cases = [
+        ("interrupted_mid_loop", interrupted_mid_loop(len / 2), None),
+        (
+            "eof_one_byte_short",
+            eof_one_byte_short(len),
+            Some(io::ErrorKind::WriteZero),
+        ),
+        (
+            "would_block_on_last_byte",
+            would_block_on_last_byte(len),
+            Some(io::ErrorKind::WouldBlock),
+        ),
+    ];
+    for (name, ops, expected) in cases {
+        let mut writer = PartialWrite::new(Vec::new(), ops);
+        let res = write_all(&mut writer, LOOP_DATA);
+        assert_eq!(
+            res.as_ref().map_err(io::Error::kind).err(),
+            expected,
+            "{}: write_all returned {:?}",
+            name,
+            res
+        );
+        let written = if res.is_ok() { len } else { len - 1 };
+        assert_eq!(
+            writer.get_ref()[..],
+            LOOP_DATA[..written],
+            "{}: write_all wrote the wrong bytes",
+            name
+        );
+    }
+}
+
+/// Reads the way a TCP socket delivers them, with sizes derived from `seed`.
+///
+/// The sequence never ends, and the same `seed` always produces the same sequence:
+///
+/// * The first reads are small, and double in size each time, as the sender's congestion window
+///   grows, until they reach a full 1448-byte segment.
+/// * From then on, most reads return one full segment.
+/// * About one read in 16 returns several segments that were coalesced in the receive buffer,
+///   and another one in 16 returns a short segment, such as the end of a burst.
+/// * About one read in 64 is a wakeup that makes no progress: `PartialOp::Err(WouldBlock)`.
+///
+/// This gives integration tests realistic read sizes without recording traffic. To replay a
+/// real capture instead, see `pcap::Import`.
+///
+/// The zero-progress wakeups make async wrappers return `Poll::Pending` and wake the task, as
+/// after a spurious readiness notification. Sync wrappers return the `WouldBlock` error, as a
+/// nonblocking socket would; filter them out with `PartialOp::is_error` for code that expects
+/// a blocking reader.
+///
+/// # Examples
+///
+/// ```rust
+/// use partial_io::{scenarios, PartialOp, PartialRead};
+/// use std::io::Read;
+///
+/// let data = vec![7; 10_000];
+/// let ops = scenarios::tcp_segments(42).filter(|op| !op.is_error());
+/// let mut partial_read = PartialRead::new(&data[..], ops);
+/// let mut buf = vec![0; 4096];
+/// // Slow start: the first segment is small.
+/// let first = partial_read.read(&mut buf).unwrap();
+/// assert!(first <= 64);
+///
+/// let mut rest = Vec::new();
+/// partial_read.read_to_end(&mut rest).unwrap();
+/// assert_eq!(first + rest.len(), data.len());
+/// ```
+pub fn tcp_segments(seed: u64) -> TcpSegments {
+    TcpSegments {
+        state: seed,
+        segment: TCP_INITIAL_SEGMENT,
+    }
+}
+
+/// The sequence of operations returned by [`tcp_segments`].
+///
+/// [`tcp_segments`]: fn.tcp_segments.html
+#[derive(Clone, Debug)]
+pub struct TcpSegments {
+    state: u64,
+    // The size of the next segment during slow start, or `TCP_MSS` once it's over.
+    segment: usize,
+}
+
+impl Iterator for TcpSegments {
+    type Item = PartialOp;
+
+    fn next(&mut self) -> Option<PartialOp> {
+        let word = splitmix64(&mut self.state);
+        // The low bits pick the kind of read, and the high bits its size.
+        let size = (word >> 32) as usize;
+        if self.segment < TCP_MSS {
+            // Each slow start read is between half of the current segment size and all of it.
+            let min = self.segment / 2 + 1;
+            let len = min + size % (self.segment - min + 1);
+            self.segment = cmp::min(self.segment * 2, TCP_MSS);
+            return Some(PartialOp::Limited(len));
+        }
+        let op = match word % 64 {
+            0 => PartialOp::Err(io::ErrorKind::WouldBlock),
+            1..=4 => PartialOp::Limited(TCP_MSS * (2 + size % 7)),
+            5..=8 => PartialOp::Limited(1 + size % (TCP_MSS - 1)),
+            _ => PartialOp::Limited(TCP_MSS),
+        };
+        Some(op)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (usize::MAX, None)
+    }
+}
+
+impl std::iter::FusedIterator for TcpSegments {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PartialRead, PartialWrite};
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_pending_between_prefix_and_payload() {
+        let mut partial_read =
+            PartialRead::new(&b"\x03abc"[..], pending_between_prefix_and_payload(1));
+        let mut buf = [0; 4];
+        assert_eq!(partial_read.read(&mut buf).unwrap(), 1);
+        let err = partial_read.read(&mut buf[1..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(partial_read.read(&mut buf[1..]).unwrap(), 3);
+        assert_eq!(&buf, b"\x03abc");
+    }
+
+    #[test]
+    fn test_eof_mid_frame() {
+        let mut partial_read = PartialRead::new(&b"\x03abc"[..], eof_mid_frame(2));
+        let mut buf = [0; 4];
+        let err = partial_read.read_exact(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_short_write_then_error() {
+        let ops = short_write_then_error(2, io::ErrorKind::BrokenPipe);
+        let mut partial_write = PartialWrite::new(Vec::new(), ops);
+        let err = partial_write.write_all(b"abcd").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(partial_write.get_ref(), b"ab");
+    }
+
+    #[test]
+    fn test_loop_semantics() {
+        assert_read_exact_semantics(|reader, buf| reader.read_exact(buf));
+        assert_write_all_semantics(|writer, buf| writer.write_all(buf));
+
+        // Gives up on Interrupted errors.
+        let res = std::panic::catch_unwind(|| {
+            assert_write_all_semantics(|writer, mut buf| {
+                while !buf.is_empty() {
+                    let n = writer.write(buf)?;
+                    buf = &buf[n..];
+                }
+                Ok(())
+            })
+        });
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_tcp_segments() {
+        let ops: Vec<_> = tcp_segments(7).take(10_000).collect();
+        assert_eq!(
+            format!("{:?}", ops),
+            format!("{:?}", tcp_segments(7).take(10_000).collect::<Vec<_>>()),
+            "the same seed produces the same sequence"
+        );
+        let lens: Vec<_> = ops
+            .iter()
+            .filter_map(|op| match op {
+                PartialOp::Limited(n) => Some(*n),
+                _ => None,
+            })
+            .collect();
+
+        // Slow start grows each segment up to double the previous one, up to the MSS.
+        let mut segment = TCP_INITIAL_SEGMENT;
+        for &len in &lens[..5] {
+            assert!(len > segment / 2 && len <= segment, "{:?}", &lens[..5]);
+            segment *= 2;
+        }
+        assert!(lens[5..].iter().all(|&len| len > 0 && len <= TCP_MSS * 8));
+
+        let full = lens.iter().filter(|&&len| len == TCP_MSS).count();
+        let coalesced = lens.iter().filter(|&&len| len > TCP_MSS).count();
+        let wakeups = ops.len() - lens.len();
+        assert!(full > 8000, "{} full segments", full);
+        assert!((400..900).contains(&coalesced), "{} coalesced", coalesced);
+        assert!((80..250).contains(&wakeups), "{} wakeups", wakeups);
+    }
+}
@@ -0,0 +1,221 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Reproducible randomized schedules, built from a seed and a `Profile`.
+
+use crate::{fixtures::splitmix64, PartialOp};
+use std::{io, iter::FusedIterator, thread};
+
+/// The shape of the randomized schedule built by `with_seed` constructors such as
+/// `PartialRead::with_seed`.
+///
+/// Every operation is either limited to between 1 and `max_limit` bytes, or fails with one of
+/// the error kinds. With the defaults, operations are limited to at most 64 bytes and one in 8
+/// of them fails with an error that correct callers retry: `Interrupted` for the synchronous
+/// wrappers, and `WouldBlock` for the asynchronous ones, which turns into `Poll::Pending`.
+///
+/// # Examples
+///
+/// ```rust
+/// use partial_io::{PartialRead, Profile};
+/// use std::io::{self, Read};
+///
+/// let profile = Profile::default()
+///     .max_limit(4)
+///     .error_kinds(vec![io::ErrorKind::Interrupted]);
+/// let mut reader = PartialRead::with_seed(&b"hello world"[..], 42, profile);
+/// let mut buf = Vec::new();
+/// reader.read_to_end(&mut buf).unwrap();
+/// assert_eq!(buf, b"hello world");
+/// ```
+#[derive(Clone, Debug)]
+pub struct Profile {
+    max_limit: usize,
+    error_one_in: u32,
+    error_kinds: Option<Vec<io::ErrorKind>>,
+}
+
+impl Profile {
+    /// Limits every operation that doesn't fail to at most `n` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is 0.
+    pub fn max_limit(mut self, n: usize) -> Self {
+        assert!(n > 0, "max limit must be greater than 0");
+        self.max_limit = n;
+        self
+    }
+
+    /// Makes one in `n` operations fail, on average, or none of them if `n` is 0.
+    pub fn error_one_in(mut self, n: u32) -> Self {
+        self.error_one_in = n;
+        self
+    }
+
+    /// Picks the error kind of each failing operation from `kinds`, instead of the default for
+    /// the wrapper.
+    ///
+    /// If `kinds` is empty, no operation fails.
+    pub fn error_kinds(mut self, kinds: Vec<io::ErrorKind>) -> Self {
+        self.error_kinds = Some(kinds);
+        self
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            max_limit: 64,
+            error_one_in: 8,
+            error_kinds: None,
+        }
+    }
+}
+
+/// An endless, reproducible sequence of `PartialOp`s generated from a seed and a [`Profile`].
+///
+/// If a thread panics while a `SeededOps` is alive, for example because an assertion failed in
+/// a test, dropping it prints the seed to standard error, so that the failure can be replayed
+/// by passing the same seed again.
+///
+/// [`Profile`]: struct.Profile.html
+#[derive(Clone, Debug)]
+pub struct SeededOps {
+    seed: u64,
+    state: u64,
+    max_limit: usize,
+    error_one_in: u32,
+    error_kinds: Vec<io::ErrorKind>,
+    wrapper: &'static str,
+}
+
+impl SeededOps {
+    /// Creates the sequence of `PartialOp`s for `seed` and `profile`.
+    ///
+    /// Failing operations default to `Interrupted`, unless the profile sets error kinds.
+    pub fn new(seed: u64, profile: Profile) -> Self {
+        Self::for_wrapper("SeededOps", seed, profile, io::ErrorKind::Interrupted)
+    }
+
+    pub(crate) fn for_wrapper(
+        wrapper: &'static str,
+        seed: u64,
+        profile: Profile,
+        default_kind: io::ErrorKind,
+    ) -> Self {
+        SeededOps {
+            seed,
+            state: seed,
+            max_limit: profile.max_limit,
+            error_one_in: profile.error_one_in,
+            error_kinds: profile.error_kinds.unwrap_or_else(|| vec![default_kind]),
+            wrapper,
+        }
+    }
+
+    /// Returns the seed this sequence was generated from.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+}
+
+impl Iterator for SeededOps {
+    type Item = PartialOp;
+
+    fn next(&mut self) -> Option<PartialOp> {
+        let word = splitmix64(&mut self.state);
+        // The low bits decide whether the operation fails, and the high bits pick the error kind
+        // or the limit.
+        let high = (word >> 32) as usize;
+        let fails = self.error_one_in > 0 && (word as u32).is_multiple_of(self.error_one_in);
+        let op = if fails && !self.error_kinds.is_empty() {
+            PartialOp::Err(self.error_kinds[high % self.error_kinds.len()])
+        } else {
+            PartialOp::Limited(1 + high % self.max_limit)
+        };
+        Some(op)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (usize::MAX, None)
+    }
+}
+
+impl FusedIterator for SeededOps {}
+
+impl Drop for SeededOps {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            eprintln!(
+                "partial-io: {} was generated with seed {}, pass it to `with_seed` to replay this \
+                 failure",
+                self.wrapper, self.seed
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{corpus, PartialRead, PartialWrite};
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_reproducible() {
+        let ops = |seed| {
+            let ops: Vec<_> = SeededOps::new(seed, Profile::default()).take(100).collect();
+            corpus::encode(&ops)
+        };
+        assert_eq!(ops(7), ops(7));
+        assert_ne!(ops(7), ops(8));
+
+        let profile = Profile::default().max_limit(3).error_one_in(2);
+        for op in SeededOps::new(7, profile).take(100) {
+            match op {
+                PartialOp::Limited(n) => assert!((1..=3).contains(&n)),
+                PartialOp::Err(kind) => assert_eq!(kind, io::ErrorKind::Interrupted),
+                other => panic!("unexpected op {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_with_seed() {
+        let data: Vec<u8> = (0..=255).collect();
+        for seed in 0..16 {
+            let mut reader = PartialRead::with_seed(&data[..], seed, Profile::default());
+            let mut buf = vec![0; data.len()];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, data);
+
+            let mut writer = PartialWrite::with_seed(Vec::new(), seed, Profile::default());
+            writer.write_all(&data).unwrap();
+            assert_eq!(writer.get_ref(), &data);
+        }
+    }
+
+    #[cfg(feature = "futures03")]
+    #[test]
+    fn test_async_with_seed() {
+        use crate::{PartialAsyncRead, PartialAsyncWrite};
+        use futures::{executor::block_on, AsyncReadExt, AsyncWriteExt};
+
+        let data: Vec<u8> = (0..=255).collect();
+        block_on(async {
+            let mut reader = PartialAsyncRead::with_seed(&data[..], 3, Profile::default());
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, data);
+
+            let mut writer = PartialAsyncWrite::with_seed(Vec::new(), 3, Profile::default());
+            writer.write_all(&data).await.unwrap();
+            assert_eq!(writer.get_ref(), &data);
+        });
+    }
+}
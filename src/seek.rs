@@ -0,0 +1,146 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Operations that make seeks on a wrapper inconsistent with the real position.
+
+use crate::exclusive::Exclusive;
+use std::io::{self, SeekFrom};
+
+/// An operation applied to each seek on a wrapper that forwards seeks to its inner type.
+///
+/// Readers that store offsets and double-check them after seeking should notice that the source
+/// is inconsistent. These operations simulate such sources: one that reports a wrong position,
+/// and one that seeks to a wrong offset without saying so.
+///
+/// Set with `set_seek_ops` on `PartialRead`, `PartialWrite`, `PartialAsyncRead` and
+/// `PartialAsyncWrite`. Once the iterator runs out, seeks are performed as-is.
+///
+/// # Examples
+///
+/// ```rust
+/// use partial_io::{PartialRead, SeekOp};
+/// use std::io::{Cursor, Read, Seek, SeekFrom};
+///
+/// let mut partial_read = PartialRead::new(Cursor::new(b"abcdef".to_vec()), None);
+/// partial_read.set_seek_ops(vec![SeekOp::ReportOffBy(1), SeekOp::SeekOffBy(2)]);
+///
+/// // The seek happened, but the position returned is wrong.
+/// assert_eq!(partial_read.seek(SeekFrom::Start(1)).unwrap(), 2);
+/// // The position returned is the one asked for, but the seek went elsewhere.
+/// assert_eq!(partial_read.seek(SeekFrom::Start(1)).unwrap(), 1);
+/// let mut buf = [0; 1];
+/// partial_read.read_exact(&mut buf).unwrap();
+/// assert_eq!(&buf, b"d");
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SeekOp {
+    /// Perform the seek as-is.
+    Accurate,
+    /// Perform the seek as-is, but report a position that is off by the given number of bytes.
+    ReportOffBy(i64),
+    /// Seek to an offset that is off by the given number of bytes, but report the position the
+    /// caller asked for.
+    SeekOffBy(i64),
+}
+
+// The `SeekOp`s for a wrapper, and the one applied to the seek in progress.
+pub(crate) struct SeekOps {
+    ops: Exclusive<Box<dyn Iterator<Item = SeekOp> + Send>>,
+    current: Option<SeekOp>,
+}
+
+impl SeekOps {
+    pub(crate) fn new() -> Self {
+        // Boxing an empty iterator doesn't allocate.
+        SeekOps {
+            ops: Exclusive::new(Box::new(std::iter::empty())),
+            current: None,
+        }
+    }
+
+    pub(crate) fn replace<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = SeekOp> + 'static,
+        I::IntoIter: Send,
+    {
+        self.ops = Exclusive::new(Box::new(iter.into_iter().fuse()));
+        self.current = None;
+    }
+
+    // Starts a new seek, returning the position to pass to the inner type.
+    pub(crate) fn start(&mut self, pos: SeekFrom) -> SeekFrom {
+        let op = self.ops.get_mut().next().unwrap_or(SeekOp::Accurate);
+        self.current = Some(op);
+        match op {
+            SeekOp::SeekOffBy(delta) => offset_by(pos, delta),
+            SeekOp::Accurate | SeekOp::ReportOffBy(_) => pos,
+        }
+    }
+
+    // Starts a new seek unless one is already in progress, for `poll_seek`, which is called again
+    // with the same position until it's ready.
+    #[cfg(feature = "futures03")]
+    pub(crate) fn resume(&mut self, pos: SeekFrom) -> SeekFrom {
+        match self.current {
+            Some(SeekOp::SeekOffBy(delta)) => offset_by(pos, delta),
+            Some(_) => pos,
+            None => self.start(pos),
+        }
+    }
+
+    // Ends the seek in progress, returning the result to report to the caller.
+    pub(crate) fn complete(&mut self, res: io::Result<u64>) -> io::Result<u64> {
+        let op = self.current.take().unwrap_or(SeekOp::Accurate);
+        res.map(|pos| match op {
+            SeekOp::ReportOffBy(delta) => pos.saturating_add_signed(delta),
+            SeekOp::SeekOffBy(delta) => pos.saturating_add_signed(delta.saturating_neg()),
+            SeekOp::Accurate => pos,
+        })
+    }
+
+    // Ends the seek in progress without a position, if starting it failed.
+    #[cfg(feature = "tokio1")]
+    pub(crate) fn cancel(&mut self) {
+        self.current = None;
+    }
+}
+
+fn offset_by(pos: SeekFrom, delta: i64) -> SeekFrom {
+    match pos {
+        SeekFrom::Start(n) => SeekFrom::Start(n.saturating_add_signed(delta)),
+        SeekFrom::End(n) => SeekFrom::End(n.saturating_add(delta)),
+        SeekFrom::Current(n) => SeekFrom::Current(n.saturating_add(delta)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Seek};
+
+    #[test]
+    fn test_seek_ops() {
+        let mut ops = SeekOps::new();
+        ops.replace(vec![SeekOp::SeekOffBy(-2), SeekOp::ReportOffBy(-10)]);
+        let mut cursor = Cursor::new(vec![0; 8]);
+
+        let pos = ops.start(SeekFrom::End(0));
+        assert_eq!(ops.complete(cursor.seek(pos)).unwrap(), 8);
+        assert_eq!(cursor.position(), 6);
+
+        let pos = ops.start(SeekFrom::Current(-1));
+        assert_eq!(
+            ops.complete(cursor.seek(pos)).unwrap(),
+            0,
+            "the reported position saturates"
+        );
+        assert_eq!(cursor.position(), 5);
+
+        let pos = ops.start(SeekFrom::Start(3));
+        assert_eq!(ops.complete(cursor.seek(pos)).unwrap(), 3);
+    }
+}
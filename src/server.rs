@@ -0,0 +1,593 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! A scripted server over TCP, Unix domain sockets or Windows named pipes, to test clients
+//! against a real peer.
+//!
+//! Clients of HTTP servers, databases and the like are hard to test with in-process wrappers:
+//! they open their own connections. [`Builder`] scripts a server instead, much like
+//! `mock::Builder` scripts a mock. The server listens on the loopback interface, a Unix domain
+//! socket or a named pipe, accepts a single connection, and alternates between expecting bytes
+//! from the client and sending bytes to it. `PartialOp`s break up its reads and writes:
+//!
+//! * Write operations shape what the client reads: `PartialOp::Limited(n)` sends small segments,
+//!   and `PartialOp::Delay` stalls before a segment.
+//! * Read operations shape how fast the server drains what the client writes.
+//! * Any error in the operations, other than `Interrupted`, ends the script and closes the
+//!   connection early. This is how a mid-stream reset is scripted: the client sees the end of the
+//!   stream, or a reset if it sent data that the server never read.
+//!
+//! [`ScriptedServer::join`] reports whether the client sent what the script expected.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use partial_io::{server::Builder, PartialOp};
+//! use std::{
+//!     io::{Read, Write},
+//!     net::TcpStream,
+//!     time::Duration,
+//! };
+//!
+//! let server = Builder::new()
+//!     .expect(b"GET / HTTP/1.0\r\n\r\n")
+//!     // The response arrives in two segments, 10 ms apart.
+//!     .send_with(
+//!         b"HTTP/1.0 200 OK\r\n\r\n",
+//!         vec![
+//!             PartialOp::Limited(9),
+//!             PartialOp::Delay(Duration::from_millis(10)),
+//!             PartialOp::Limited(10),
+//!         ],
+//!     )
+//!     .spawn()
+//!     .unwrap();
+//!
+//! let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+//! stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+//! let mut response = Vec::new();
+//! stream.read_to_end(&mut response).unwrap();
+//! assert_eq!(response, b"HTTP/1.0 200 OK\r\n\r\n");
+//! server.join().unwrap();
+//! ```
+//!
+//! [`Builder`]: struct.Builder.html
+//! [`ScriptedServer::join`]: struct.ScriptedServer.html#method.join
+
+use crate::{PartialOp, PartialRead, PartialWrite};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    mem,
+    net::{Ipv4Addr, SocketAddr, TcpListener},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+#[cfg(windows)]
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::{os::unix::net::UnixListener, path::Path};
+
+/// Builds a [`ScriptedServer`], scripting both data and `PartialOp`s.
+///
+/// Steps are performed in the order they were added. `PartialOp`s are consumed in the order they
+/// were added as well: read ops by the server's reads, and write ops by its writes. Once the ops
+/// run out, reads and writes are unlimited.
+///
+/// See [the module level documentation](index.html) for more.
+///
+/// [`ScriptedServer`]: struct.ScriptedServer.html
+#[derive(Debug, Default)]
+pub struct Builder {
+    steps: Vec<Step>,
+    read_ops: Vec<PartialOp>,
+    write_ops: Vec<PartialOp>,
+}
+
+#[derive(Debug)]
+enum Step {
+    Expect(Vec<u8>),
+    Send(Vec<u8>),
+    Wait(Duration),
+}
+
+impl Builder {
+    /// Creates a new, empty builder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sequences a read of `data` from the client.
+    ///
+    /// If the client sends anything else, the script ends and `ScriptedServer::join` returns an
+    /// error.
+    pub fn expect(&mut self, data: &[u8]) -> &mut Self {
+        self.steps.push(Step::Expect(data.to_vec()));
+        self
+    }
+
+    /// Sequences a read of `data` from the client, broken up according to `ops`.
+    pub fn expect_with<I>(&mut self, data: &[u8], ops: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp>,
+    {
+        self.expect(data);
+        self.read_ops.extend(ops);
+        self
+    }
+
+    /// Sequences `PartialOp`s for the next reads, without any data.
+    pub fn read_ops<I>(&mut self, ops: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp>,
+    {
+        self.read_ops.extend(ops);
+        self
+    }
+
+    /// Sequences a write of `data` to the client.
+    pub fn send(&mut self, data: &[u8]) -> &mut Self {
+        self.steps.push(Step::Send(data.to_vec()));
+        self
+    }
+
+    /// Sequences a write of `data` to the client, broken up according to `ops`.
+    pub fn send_with<I>(&mut self, data: &[u8], ops: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp>,
+    {
+        self.send(data);
+        self.write_ops.extend(ops);
+        self
+    }
+
+    /// Sequences `PartialOp`s for the next writes, without any data.
+    pub fn write_ops<I>(&mut self, ops: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp>,
+    {
+        self.write_ops.extend(ops);
+        self
+    }
+
+    /// Sequences a wait in the server, during which it neither reads nor writes.
+    pub fn wait(&mut self, duration: Duration) -> &mut Self {
+        self.steps.push(Step::Wait(duration));
+        self
+    }
+
+    /// Binds a listener on the loopback interface, and serves the script sequenced so far to the
+    /// first connection on a background thread.
+    ///
+    /// The builder is cleared, so it can be reused.
+    pub fn spawn(&mut self) -> io::Result<ScriptedServer> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        Ok(self.spawn_with(addr, move || {
+            let (stream, _) = listener.accept()?;
+            stream.set_nodelay(true)?;
+            Ok((stream.try_clone()?, stream))
+        }))
+    }
+
+    /// Binds a listener on a Unix domain socket at `path`, and serves the script sequenced so far
+    /// to the first connection on a background thread.
+    ///
+    /// Nothing may exist at `path` yet. The socket file isn't removed afterwards.
+    ///
+    /// The builder is cleared, so it can be reused.
+    #[cfg(unix)]
+    pub fn spawn_unix<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> io::Result<ScriptedServer<std::os::unix::net::SocketAddr>> {
+        self.spawn_unix_listener(UnixListener::bind(path)?)
+    }
+
+    /// Binds a listener on a Unix domain socket with `name` in the abstract namespace, and serves
+    /// the script sequenced so far to the first connection on a background thread.
+    ///
+    /// Abstract sockets don't exist in the file system, so nothing needs to be cleaned up
+    /// afterwards. Connect to them with `UnixStream::connect_addr` and
+    /// `ScriptedServer::local_addr`.
+    ///
+    /// The builder is cleared, so it can be reused. Only available on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn spawn_unix_abstract(
+        &mut self,
+        name: &[u8],
+    ) -> io::Result<ScriptedServer<std::os::unix::net::SocketAddr>> {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let addr = SocketAddr::from_abstract_name(name)?;
+        self.spawn_unix_listener(UnixListener::bind_addr(&addr)?)
+    }
+
+    /// Creates a Windows named pipe called `name`, such as `\\.\pipe\my-service`, and serves the
+    /// script sequenced so far to the first client that opens it, on a background thread.
+    ///
+    /// The pipe exists once this returns, so a client can open it right away, for example with
+    /// `OpenOptions::new().read(true).write(true).open(name)`. Creating the pipe fails if another
+    /// pipe with the same name exists. Remote clients are rejected.
+    ///
+    /// The builder is cleared, so it can be reused. Only available on Windows.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(windows)]
+    /// # fn main() {
+    /// use partial_io::{server::Builder, PartialOp};
+    /// use std::{fs::OpenOptions, io::{Read, Write}};
+    ///
+    /// let server = Builder::new()
+    ///     .expect(b"ping")
+    ///     .send_with(b"pong", vec![PartialOp::Limited(1)])
+    ///     .spawn_named_pipe(r"\\.\pipe\partial-io-example")
+    ///     .unwrap();
+    ///
+    /// let mut pipe = OpenOptions::new()
+    ///     .read(true)
+    ///     .write(true)
+    ///     .open(server.local_addr())
+    ///     .unwrap();
+    /// pipe.write_all(b"ping").unwrap();
+    /// let mut buf = [0; 4];
+    /// pipe.read_exact(&mut buf).unwrap();
+    /// assert_eq!(&buf, b"pong");
+    /// server.join().unwrap();
+    /// # }
+    /// #
+    /// # #[cfg(not(windows))]
+    /// # fn main() {
+    /// #     assert!(true, "dummy test");
+    /// # }
+    /// ```
+    #[cfg(windows)]
+    pub fn spawn_named_pipe<P: AsRef<Path>>(
+        &mut self,
+        name: P,
+    ) -> io::Result<ScriptedServer<PathBuf>> {
+        let name = name.as_ref();
+        let pipe = named_pipe::create(name)?;
+        Ok(self.spawn_with(name.to_path_buf(), move || {
+            named_pipe::connect(&pipe)?;
+            Ok((pipe.try_clone()?, named_pipe::Connection(pipe)))
+        }))
+    }
+
+    #[cfg(unix)]
+    fn spawn_unix_listener(
+        &mut self,
+        listener: UnixListener,
+    ) -> io::Result<ScriptedServer<std::os::unix::net::SocketAddr>> {
+        let addr = listener.local_addr()?;
+        Ok(self.spawn_with(addr, move || {
+            let (stream, _) = listener.accept()?;
+            Ok((stream.try_clone()?, stream))
+        }))
+    }
+
+    // Serves the script on a background thread, to the halves of the connection returned by
+    // `accept`.
+    fn spawn_with<A, R, W>(
+        &mut self,
+        addr: A,
+        accept: impl FnOnce() -> io::Result<(R, W)> + Send + 'static,
+    ) -> ScriptedServer<A>
+    where
+        R: Read,
+        W: Write,
+    {
+        let steps = mem::take(&mut self.steps);
+        let read_ops = mem::take(&mut self.read_ops);
+        let write_ops = mem::take(&mut self.write_ops);
+        let handle = thread::spawn(move || {
+            let (reader, writer) = accept()?;
+            serve(
+                PartialRead::new(reader, read_ops),
+                PartialWrite::new(writer, write_ops),
+                steps,
+            )
+        });
+        ScriptedServer { addr, handle }
+    }
+}
+
+/// A server that serves a script to a single connection, built by [`Builder`].
+///
+/// `A` is the type of the address the server is listening on: `std::net::SocketAddr` for TCP,
+/// `std::os::unix::net::SocketAddr` for Unix domain sockets, and the name of the pipe as a
+/// `PathBuf` for named pipes. The connection is closed once the script has been performed.
+///
+/// [`Builder`]: struct.Builder.html
+pub struct ScriptedServer<A = SocketAddr> {
+    addr: A,
+    handle: JoinHandle<io::Result<()>>,
+}
+
+impl<A> ScriptedServer<A> {
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> &A {
+        &self.addr
+    }
+
+    /// Waits for the script to end, which requires a client to have connected.
+    ///
+    /// Returns an error if the connection failed, if the operations ended the script early, or if
+    /// the client sent something other than what the script expected. Errors of the last kind are
+    /// of kind `InvalidData`.
+    pub fn join(self) -> io::Result<()> {
+        match self.handle.join() {
+            Ok(res) => res,
+            Err(payload) => std::panic::resume_unwind(payload),
+        }
+    }
+}
+
+impl<A: fmt::Debug> fmt::Debug for ScriptedServer<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScriptedServer")
+            .field("addr", &self.addr)
+            .field("finished", &self.handle.is_finished())
+            .finish()
+    }
+}
+
+fn serve<R: Read, W: Write>(
+    mut reader: PartialRead<R>,
+    mut writer: PartialWrite<W>,
+    steps: Vec<Step>,
+) -> io::Result<()> {
+    for (index, step) in steps.into_iter().enumerate() {
+        match step {
+            Step::Expect(expected) => {
+                let mut actual = vec![0; expected.len()];
+                reader.read_exact(&mut actual)?;
+                if actual != expected {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "step {}: expected \"{}\", got \"{}\"",
+                            index,
+                            expected.escape_ascii(),
+                            actual.escape_ascii()
+                        ),
+                    ));
+                }
+            }
+            Step::Send(data) => {
+                writer.write_all(&data)?;
+                writer.flush()?;
+            }
+            Step::Wait(duration) => thread::sleep(duration),
+        }
+    }
+    Ok(())
+}
+
+// The parts of the Win32 API needed to serve a named pipe, which `std` doesn't expose.
+#[cfg(windows)]
+mod named_pipe {
+    use std::{
+        ffi::c_void,
+        fs::File,
+        io::{self, Write},
+        iter,
+        os::windows::{
+            ffi::OsStrExt,
+            io::{AsRawHandle, FromRawHandle, RawHandle},
+        },
+        path::Path,
+        ptr,
+    };
+
+    const PIPE_ACCESS_DUPLEX: u32 = 0x0000_0003;
+    const FILE_FLAG_FIRST_PIPE_INSTANCE: u32 = 0x0008_0000;
+    // `PIPE_TYPE_BYTE`, `PIPE_READMODE_BYTE` and `PIPE_WAIT` are all 0.
+    const PIPE_REJECT_REMOTE_CLIENTS: u32 = 0x0000_0008;
+    const BUFFER_SIZE: u32 = 4096;
+    const ERROR_PIPE_CONNECTED: i32 = 535;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateNamedPipeW(
+            name: *const u16,
+            open_mode: u32,
+            pipe_mode: u32,
+            max_instances: u32,
+            out_buffer_size: u32,
+            in_buffer_size: u32,
+            default_timeout: u32,
+            security_attributes: *mut c_void,
+        ) -> RawHandle;
+        fn ConnectNamedPipe(pipe: RawHandle, overlapped: *mut c_void) -> i32;
+        fn FlushFileBuffers(file: RawHandle) -> i32;
+        fn DisconnectNamedPipe(pipe: RawHandle) -> i32;
+    }
+
+    // The server end of a connected pipe, which waits for the client to read everything written
+    // to it before disconnecting.
+    pub(super) struct Connection(pub(super) File);
+
+    impl Write for Connection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            // Closing the handle right away would discard the data the client hasn't read yet.
+            // Errors are ignored, since the client may have closed its end already.
+            // SAFETY: the handle is valid for as long as `self.0`.
+            unsafe {
+                FlushFileBuffers(self.0.as_raw_handle());
+                DisconnectNamedPipe(self.0.as_raw_handle());
+            }
+        }
+    }
+
+    // Creates the only instance of the pipe called `name`.
+    pub(super) fn create(name: &Path) -> io::Result<File> {
+        let name: Vec<u16> = name
+            .as_os_str()
+            .encode_wide()
+            .chain(iter::once(0))
+            .collect();
+        // SAFETY: `name` is a null-terminated wide string, and the other pointer may be null.
+        let handle = unsafe {
+            CreateNamedPipeW(
+                name.as_ptr(),
+                PIPE_ACCESS_DUPLEX | FILE_FLAG_FIRST_PIPE_INSTANCE,
+                PIPE_REJECT_REMOTE_CLIENTS,
+                1,
+                BUFFER_SIZE,
+                BUFFER_SIZE,
+                0,
+                ptr::null_mut(),
+            )
+        };
+        // `INVALID_HANDLE_VALUE` is -1.
+        if handle as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the handle was just created, and is owned by nothing else.
+        Ok(unsafe { File::from_raw_handle(handle) })
+    }
+
+    // Waits for a client to open the pipe.
+    pub(super) fn connect(pipe: &File) -> io::Result<()> {
+        // SAFETY: the handle is valid for as long as `pipe`, and wasn't opened for overlapped IO.
+        if unsafe { ConnectNamedPipe(pipe.as_raw_handle(), ptr::null_mut()) } != 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            // The client opened the pipe before this call.
+            Some(ERROR_PIPE_CONNECTED) => Ok(()),
+            _ => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+
+    #[test]
+    fn test_mismatch() {
+        let server = Builder::new()
+            .send(b"hello\n")
+            .expect_with(b"ping", vec![PartialOp::Limited(1); 4])
+            .spawn()
+            .unwrap();
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        let mut buf = [0; 6];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello\n");
+        stream.write_all(b"pong").unwrap();
+
+        let err = server.join().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "step 1: expected \"ping\", got \"pong\"");
+    }
+
+    #[test]
+    fn test_reset() {
+        let server = Builder::new()
+            .send_with(
+                b"hello world",
+                vec![
+                    PartialOp::Limited(5),
+                    PartialOp::Err(io::ErrorKind::ConnectionReset),
+                ],
+            )
+            .spawn()
+            .unwrap();
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello", "the connection was closed mid-stream");
+        let err = server.join().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix() {
+        use std::os::unix::net::UnixStream;
+
+        let path = std::env::temp_dir().join(format!("partial-io-server-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = Builder::new()
+            .expect(b"ping")
+            .send_with(b"pong", vec![PartialOp::Limited(1)])
+            .spawn_unix(&path)
+            .unwrap();
+        assert_eq!(server.local_addr().as_pathname(), Some(path.as_path()));
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(b"ping").unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"pong");
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_unix_abstract() {
+        use std::os::unix::net::UnixStream;
+
+        let name = format!("partial-io-server-{}", std::process::id());
+        let server = Builder::new()
+            .send(b"hello")
+            .spawn_unix_abstract(name.as_bytes())
+            .unwrap();
+        let mut stream = UnixStream::connect_addr(server.local_addr()).unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello");
+        server.join().unwrap();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_named_pipe() {
+        use std::fs::OpenOptions;
+
+        let name = format!(r"\\.\pipe\partial-io-server-{}", std::process::id());
+        let server = Builder::new()
+            .send_with(
+                b"hello world",
+                vec![
+                    PartialOp::Limited(5),
+                    PartialOp::Err(io::ErrorKind::ConnectionReset),
+                ],
+            )
+            .spawn_named_pipe(&name)
+            .unwrap();
+        let mut pipe = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(server.local_addr())
+            .unwrap();
+        let mut buf = [0; 5];
+        pipe.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        let err = server.join().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+}
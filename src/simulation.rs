@@ -0,0 +1,116 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Byte-level partiality for connections inside `turmoil` simulations.
+//!
+//! `turmoil` models latency and partitions between simulated hosts, but delivers every write in
+//! one piece. The helpers in this module wrap its TCP streams in a [`PartialDuplex`] that splits
+//! reads and writes into TCP-like segments, picked by [`scenarios::tcp_segments`] from a seed.
+//! The simulation stays deterministic as long as the seeds do: `PartialOp::Delay` runs on the
+//! simulation's clock through `tokio::time`, and `Poll::Pending`s let the simulation schedule
+//! other hosts in between.
+//!
+//! Available with the `turmoil06` feature.
+//!
+//! # Examples
+//!
+//! ```rust
+//! # #[cfg(feature = "turmoil06")]
+//! # fn main() -> turmoil::Result {
+//! use partial_io::simulation;
+//! use tokio::io::{AsyncReadExt, AsyncWriteExt};
+//!
+//! let mut sim = turmoil::Builder::new().build();
+//! sim.host("server", || async {
+//!     let listener = turmoil::net::TcpListener::bind(("0.0.0.0", 1234)).await?;
+//!     let (stream, _) = listener.accept().await?;
+//!     let mut stream = simulation::wrap(stream, 1);
+//!     let mut buf = [0; 4];
+//!     stream.read_exact(&mut buf).await?;
+//!     stream.write_all(&buf).await?;
+//!     Ok(())
+//! });
+//! sim.client("client", async {
+//!     let mut stream = simulation::connect(("server", 1234), 2).await?;
+//!     stream.write_all(b"ping").await?;
+//!     let mut buf = [0; 4];
+//!     stream.read_exact(&mut buf).await?;
+//!     assert_eq!(&buf, b"ping");
+//!     Ok(())
+//! });
+//! sim.run()
+//! # }
+//! #
+//! # #[cfg(not(feature = "turmoil06"))]
+//! # fn main() {
+//! #     assert!(true, "dummy test");
+//! # }
+//! ```
+//!
+//! [`PartialDuplex`]: ../struct.PartialDuplex.html
+//! [`scenarios::tcp_segments`]: ../scenarios/fn.tcp_segments.html
+
+use crate::{scenarios, PartialDuplex};
+use std::io;
+use turmoil::{net::TcpStream, ToSocketAddrs};
+
+/// Wraps a simulated TCP stream, splitting its reads and writes into segments picked from
+/// `seed`.
+///
+/// Reads and writes use different schedules, derived from the same seed.
+pub fn wrap(stream: TcpStream, seed: u64) -> PartialDuplex<TcpStream> {
+    PartialDuplex::new(
+        stream,
+        scenarios::tcp_segments(seed),
+        scenarios::tcp_segments(seed.wrapping_add(1)),
+    )
+}
+
+/// Connects to `addr` inside the simulation, and wraps the stream with [`wrap`].
+///
+/// [`wrap`]: fn.wrap.html
+pub async fn connect<A: ToSocketAddrs>(addr: A, seed: u64) -> io::Result<PartialDuplex<TcpStream>> {
+    Ok(wrap(TcpStream::connect(addr).await?, seed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_echo() {
+        const LEN: usize = 64 * 1024;
+
+        let mut sim = turmoil::Builder::new().build();
+        sim.host("server", || async {
+            let listener = turmoil::net::TcpListener::bind(("0.0.0.0", 1234)).await?;
+            let (stream, _) = listener.accept().await?;
+            let mut stream = wrap(stream, 7);
+            let mut buf = vec![0; LEN];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(&buf).await?;
+            Ok(())
+        });
+        sim.client("client", async {
+            let data: Vec<u8> = (0..LEN).map(|i| i as u8).collect();
+            let mut stream = connect(("server", 1234), 8).await?;
+            stream.record_trace();
+            stream.write_all(&data).await?;
+            let mut buf = vec![0; LEN];
+            stream.read_exact(&mut buf).await?;
+            assert_eq!(buf, data);
+            assert!(
+                stream.write_stats().calls > 1,
+                "writes are split into segments"
+            );
+            assert!(stream.read_stats().calls > 1, "and so are reads");
+            Ok(())
+        });
+        sim.run().unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! This module contains a `Sink` wrapper that injects pending states and errors according to a
+//! provided iterator.
+
+use crate::{futures_util::FuturesOps, PartialOp};
+use futures::prelude::*;
+use pin_project::pin_project;
+use std::{
+    fmt, io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A wrapper that injects pending states and errors into inner `Sink` instances according to the
+/// provided iterator.
+///
+/// Each call to `poll_ready`, `poll_flush` or `poll_close` consumes an operation.
+/// `PartialOp::Err(io::ErrorKind::WouldBlock)` returns `Poll::Pending` and reschedules the task,
+/// `PartialOp::Err(io::ErrorKind::Interrupted)` causes a retry, and any other error is converted
+/// into the sink's error type. Items are never split, so `PartialOp::Limited(n)` behaves the same
+/// as `Unlimited`.
+///
+/// `start_send` doesn't consume operations, since it can't return `Poll::Pending`.
+///
+/// Available with the `futures03` feature.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(feature = "futures03")]
+/// use futures::prelude::*;
+/// # #[cfg(feature = "futures03")]
+/// use partial_io::{PartialOp, PartialSink};
+/// # #[cfg(feature = "futures03")]
+/// use std::io;
+///
+/// # #[cfg(feature = "futures03")]
+/// # fn main() {
+/// futures::executor::block_on(async {
+///     let sink = Vec::<u8>::new().sink_map_err(|_| io::Error::from(io::ErrorKind::Other));
+///     let iter = vec![
+///         PartialOp::Err(io::ErrorKind::WouldBlock),   // A not-ready state for poll_ready.
+///         PartialOp::Unlimited,                        // poll_ready succeeds.
+///         PartialOp::Err(io::ErrorKind::BrokenPipe),   // poll_flush fails.
+///     ];
+///     let mut partial_sink = PartialSink::new(sink, iter);
+///
+///     assert_eq!(partial_sink.send(42).await.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+///     // The item was still sent.
+///     assert_eq!(partial_sink.get_ref().get_ref(), &[42]);
+/// });
+/// # }
+///
+/// # #[cfg(not(feature = "futures03"))]
+/// # fn main() {
+/// #     assert!(true, "dummy test");
+/// # }
+/// ```
+#[pin_project]
+pub struct PartialSink<S> {
+    #[pin]
+    inner: S,
+    ops: FuturesOps,
+}
+
+impl<S> PartialSink<S> {
+    /// Creates a new `PartialSink` wrapper over the sink with the specified `PartialOp`s.
+    pub fn new<I>(inner: S, iter: I) -> Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        PartialSink {
+            inner,
+            ops: FuturesOps::new(iter),
+        }
+    }
+
+    /// Sets the `PartialOp`s for this sink.
+    pub fn set_ops<I>(&mut self, iter: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        self.ops.replace(iter);
+        self
+    }
+
+    /// Sets the `PartialOp`s for this sink in a pinned context.
+    pub fn pin_set_ops<I>(self: Pin<&mut Self>, iter: I) -> Pin<&mut Self>
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        let mut this = self;
+        this.as_mut().project().ops.replace(iter);
+        this
+    }
+
+    /// Returns the operations of this sink, along with its wake and poll statistics.
+    pub fn ops(&self) -> &FuturesOps {
+        &self.ops
+    }
+
+    /// Returns the operations of this sink, to set its clock and how it delivers wakes, or to
+    /// enable checks on how it's polled.
+    ///
+    /// See `FuturesOps` for the settings.
+    pub fn ops_mut(&mut self) -> &mut FuturesOps {
+        &mut self.ops
+    }
+
+    /// Returns a shared reference to the underlying sink.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying sink.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Returns a pinned mutable reference to the underlying sink.
+    pub fn pin_get_mut(self: Pin<&mut Self>) -> Pin<&mut S> {
+        self.project().inner
+    }
+
+    /// Consumes this wrapper, returning the underlying sink.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S, Item> Sink<Item> for PartialSink<S>
+where
+    S: Sink<Item>,
+    S::Error: From<io::Error>,
+{
+    type Error = S::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.project();
+        let inner = this.inner;
+
+        flatten(this.ops.poll_impl_no_limit(
+            cx,
+            |cx| inner.poll_ready(cx).map(Ok),
+            "error during poll_ready, generated by partial-io",
+        ))
+    }
+
+    #[inline]
+    fn start_send(self: Pin<&mut Self>, item: Item) -> Result<(), Self::Error> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.project();
+        let inner = this.inner;
+
+        flatten(this.ops.poll_impl_no_limit(
+            cx,
+            |cx| inner.poll_flush(cx).map(Ok),
+            "error during poll_flush, generated by partial-io",
+        ))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.project();
+        let inner = this.inner;
+
+        flatten(this.ops.poll_impl_no_limit(
+            cx,
+            |cx| inner.poll_close(cx).map(Ok),
+            "error during poll_close, generated by partial-io",
+        ))
+    }
+}
+
+/// This is a forwarding impl to support sinks that are also streams.
+impl<S> Stream for PartialSink<S>
+where
+    S: Stream,
+{
+    type Item = S::Item;
+
+    #[inline]
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.project().inner.poll_next(cx)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+#[inline]
+fn flatten<E>(res: Poll<io::Result<Result<(), E>>>) -> Poll<Result<(), E>>
+where
+    E: From<io::Error>,
+{
+    res.map(|res| res.map_err(E::from).and_then(|res| res))
+}
+
+impl<S> fmt::Debug for PartialSink<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PartialSink")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::channel::mpsc;
+
+    use crate::tests::{assert_send, assert_sync};
+
+    #[test]
+    fn test_sendable() {
+        assert_send::<PartialSink<mpsc::Sender<u8>>>();
+        assert_sync::<PartialSink<mpsc::Sender<u8>>>();
+    }
+}
@@ -0,0 +1,379 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Stack several layers of `PartialOp`s around a single reader or writer.
+//!
+//! Nesting wrappers, such as a `PartialWrite` inside another `PartialWrite`, gives each layer its
+//! own schedule but no shared picture of what happened. A [`Stack`] holds every layer itself,
+//! each with its own [`OpEngine`], and applies them from the outermost layer inwards on every
+//! call:
+//!
+//! * Each layer consumes one operation, and limits the call further. The inner reader or writer
+//!   sees the smallest limit.
+//! * If a layer injects an error, the call fails there, and the layers inside it don't consume an
+//!   operation.
+//!
+//! Layers can be configured engines, for example an engine with `set_max_op_size` to throttle
+//! every call, under a schedule of faults. The operations applied by every layer are recorded in
+//! a single [`trace`], along with the index of the call they were applied to, and summed up by
+//! [`stats`].
+//!
+//! # Examples
+//!
+//! ```rust
+//! use partial_io::{stack::Stack, OpEngine, PartialOp};
+//! use std::io::{self, Write};
+//!
+//! let mut throttle = OpEngine::new(None);
+//! throttle.set_max_op_size(4);
+//! let faults = vec![PartialOp::Unlimited, PartialOp::Err(io::ErrorKind::Interrupted)];
+//!
+//! let mut stack = Stack::new(Vec::new())
+//!     .layer_engine("throttle", throttle)
+//!     .layer("faults", faults);
+//! stack.write_all(b"Hello, world!").unwrap();
+//! assert_eq!(stack.get_ref(), b"Hello, world!");
+//!
+//! let stats = stack.stats();
+//! assert_eq!(stats.calls, 5, "4 writes of at most 4 bytes, and a retry");
+//! assert_eq!(stats.layers[1].errors, 1);
+//! ```
+//!
+//! [`Stack`]: struct.Stack.html
+//! [`OpEngine`]: ../struct.OpEngine.html
+//! [`trace`]: struct.Stack.html#method.trace
+//! [`stats`]: struct.Stack.html#method.stats
+
+use crate::{OpEngine, PartialOp};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+/// A reader or writer wrapped in several layers of `PartialOp`s.
+///
+/// See [the module level documentation](index.html) for more.
+pub struct Stack<T: ?Sized> {
+    layers: Vec<Layer>,
+    trace: Vec<StackEvent>,
+    calls: usize,
+    inner: T,
+}
+
+struct Layer {
+    name: &'static str,
+    engine: OpEngine,
+}
+
+/// An operation applied by a layer of a `Stack`, recorded in `Stack::trace`.
+#[derive(Clone, Debug)]
+pub struct StackEvent {
+    /// The index of the call on the stack, starting from 0.
+    pub call: usize,
+    /// The name of the layer that applied the operation.
+    pub layer: &'static str,
+    /// The name of the method, such as `"read"` or `"flush"`.
+    pub method: &'static str,
+    /// The operation, without its label.
+    pub op: PartialOp,
+    /// The label of the operation, from `PartialOp::Labeled`.
+    pub label: Option<&'static str>,
+}
+
+/// A summary of the calls on a `Stack`, returned by `Stack::stats`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StackStats {
+    /// The number of calls on the stack.
+    pub calls: usize,
+    /// The statistics for each layer, from the outermost layer inwards.
+    pub layers: Vec<LayerStats>,
+}
+
+/// A summary of the operations applied by a single layer of a `Stack`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LayerStats {
+    /// The name of the layer.
+    pub name: &'static str,
+    /// The number of operations applied by this layer.
+    pub ops: usize,
+    /// The number of errors injected by this layer.
+    pub errors: usize,
+    /// The number of bytes that made it through this layer.
+    pub bytes: u64,
+}
+
+impl<T> Stack<T> {
+    /// Creates a new stack around `inner`, with no layers.
+    pub fn new(inner: T) -> Self {
+        Stack {
+            layers: Vec::new(),
+            trace: Vec::new(),
+            calls: 0,
+            inner,
+        }
+    }
+}
+
+impl<T: ?Sized> Stack<T> {
+    /// Adds a layer with the specified `PartialOp`s, inside the layers added so far.
+    ///
+    /// The name identifies the layer in the trace and statistics, so it should be unique.
+    pub fn layer<I>(self, name: &'static str, iter: I) -> Self
+    where
+        T: Sized,
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        self.layer_engine(name, OpEngine::new(iter))
+    }
+
+    /// Adds a layer with a configured engine, inside the layers added so far.
+    ///
+    /// Operations applied by the engine are recorded in the trace from now on, so the engine's
+    /// own `applied_ops` are cleared after every call.
+    pub fn layer_engine(mut self, name: &'static str, mut engine: OpEngine) -> Self
+    where
+        T: Sized,
+    {
+        engine.record_ops();
+        self.layers.push(Layer { name, engine });
+        self
+    }
+
+    /// Returns the operations applied by every layer, in the order they were applied.
+    ///
+    /// Within a call, operations are listed from the outermost layer inwards.
+    pub fn trace(&self) -> &[StackEvent] {
+        &self.trace
+    }
+
+    /// Returns a summary of the calls on this stack and the operations applied by each layer.
+    pub fn stats(&self) -> StackStats {
+        let layers = self
+            .layers
+            .iter()
+            .map(|layer| {
+                let events = self.trace.iter().filter(|event| event.layer == layer.name);
+                let (ops, errors) = events.fold((0, 0), |(ops, errors), event| {
+                    (ops + 1, errors + event.op.is_error() as usize)
+                });
+                LayerStats {
+                    name: layer.name,
+                    ops,
+                    errors,
+                    bytes: layer.engine.offset(),
+                }
+            })
+            .collect();
+        StackStats {
+            calls: self.calls,
+            layers,
+        }
+    }
+
+    /// Acquires a reference to the underlying reader or writer.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Acquires a mutable reference to the underlying reader or writer.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Consumes this stack, returning the underlying reader or writer.
+    pub fn into_inner(self) -> T
+    where
+        T: Sized,
+    {
+        self.inner
+    }
+
+    // Moves the operations applied by every layer during the last call into the trace.
+    fn end_call(&mut self) {
+        let call = self.calls;
+        self.calls += 1;
+        for layer in &mut self.layers {
+            self.trace
+                .extend(layer.engine.applied_ops().iter().map(|applied| StackEvent {
+                    call,
+                    layer: layer.name,
+                    method: applied.method,
+                    op: applied.op.clone(),
+                    label: applied.label,
+                }));
+            layer.engine.record_ops();
+        }
+    }
+}
+
+// Runs a call through `layers`, from the outermost layer inwards.
+fn run_layers(
+    layers: &mut [Layer],
+    method: &'static str,
+    remaining: usize,
+    err_str: &'static str,
+    cb: &mut dyn FnMut(Option<usize>) -> io::Result<usize>,
+) -> io::Result<usize> {
+    let (first, rest) = match layers.split_first_mut() {
+        Some(split) => split,
+        None => return cb(None),
+    };
+    first.engine.begin_call(method);
+    let res = first.engine.run(
+        |limit| {
+            let remaining = limit.unwrap_or(remaining);
+            run_layers(rest, method, remaining, err_str, &mut |inner| {
+                cb(inner.or(limit))
+            })
+        },
+        remaining,
+        err_str,
+    );
+    first.engine.record(res)
+}
+
+// Runs a call that doesn't have a limit through `layers`, from the outermost layer inwards.
+fn run_layers_no_limit(
+    layers: &mut [Layer],
+    method: &'static str,
+    err_str: &'static str,
+    cb: &mut dyn FnMut() -> io::Result<()>,
+) -> io::Result<()> {
+    let (first, rest) = match layers.split_first_mut() {
+        Some(split) => split,
+        None => return cb(),
+    };
+    first.engine.begin_call(method);
+    first
+        .engine
+        .run_no_limit(|| run_layers_no_limit(rest, method, err_str, cb), err_str)
+}
+
+impl<R> Read for Stack<R>
+where
+    R: Read + ?Sized,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        let len = buf.len();
+        let res = run_layers(
+            &mut self.layers,
+            "read",
+            len,
+            "error during read, generated by partial-io",
+            &mut |limit| match limit {
+                Some(limit) => inner.read(&mut buf[..limit]),
+                None => inner.read(buf),
+            },
+        );
+        self.end_call();
+        res
+    }
+}
+
+impl<W> Write for Stack<W>
+where
+    W: Write + ?Sized,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        let res = run_layers(
+            &mut self.layers,
+            "write",
+            buf.len(),
+            "error during write, generated by partial-io",
+            &mut |limit| match limit {
+                Some(limit) => inner.write(&buf[..limit]),
+                None => inner.write(buf),
+            },
+        );
+        self.end_call();
+        res
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let inner = &mut self.inner;
+        let res = run_layers_no_limit(
+            &mut self.layers,
+            "flush",
+            "error during flush, generated by partial-io",
+            &mut || inner.flush(),
+        );
+        self.end_call();
+        res
+    }
+}
+
+impl<T> fmt::Debug for Stack<T>
+where
+    T: fmt::Debug + ?Sized,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.layers.iter().map(|layer| layer.name).collect();
+        f.debug_struct("Stack")
+            .field("layers", &names)
+            .field("inner", &&self.inner)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_stack() {
+        let outer = vec![PartialOp::Limited(4), PartialOp::Unlimited];
+        let inner = vec![
+            PartialOp::Limited(2),
+            PartialOp::Err(io::ErrorKind::WouldBlock),
+        ];
+        let mut stack = Stack::new(Cursor::new(b"abcdef".to_vec()))
+            .layer("outer", outer)
+            .layer("inner", inner);
+        let mut buf = [0; 8];
+        assert_eq!(stack.read(&mut buf).unwrap(), 2, "the smaller limit wins");
+        let err = stack.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(stack.read(&mut buf).unwrap(), 4);
+
+        let trace: Vec<_> = stack
+            .trace()
+            .iter()
+            .map(|event| (event.call, event.layer))
+            .collect();
+        assert_eq!(
+            trace,
+            [(0, "outer"), (0, "inner"), (1, "outer"), (1, "inner")],
+            "the last call ran past the end of both schedules"
+        );
+
+        let stats = stack.stats();
+        assert_eq!(stats.calls, 3);
+        assert_eq!(stats.layers[0].bytes, 6);
+        assert_eq!(stats.layers[1].errors, 1);
+    }
+
+    #[test]
+    fn test_outer_error() {
+        let outer = vec![PartialOp::Err(io::ErrorKind::Other)];
+        let inner = vec![PartialOp::Limited(1)];
+        let mut stack = Stack::new(Vec::new())
+            .layer("outer", outer)
+            .layer("inner", inner);
+        assert!(stack.write(b"ab").is_err());
+        assert_eq!(
+            stack.write(b"ab").unwrap(),
+            1,
+            "the inner layer was not reached"
+        );
+        stack.flush().unwrap();
+        assert_eq!(stack.stats().layers[1].ops, 1);
+    }
+}
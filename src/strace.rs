@@ -0,0 +1,248 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Importing sequences of `PartialOp`s from `strace` logs.
+//!
+//! A log captured with, for example, `strace -f -tt -e trace=read,write -o trace.log`, records the
+//! result of every read and write system call. [`reads`] and [`writes`] turn those results into
+//! operations, so that the IO pattern observed in production can be replayed against a
+//! `PartialRead` or `PartialWrite` in a test:
+//!
+//! * A call that returned `n` bytes becomes `PartialOp::Limited(n)`. A read that returned 0 bytes
+//!   becomes `PartialOp::Limited(0)`, which looks like EOF to most readers.
+//! * A call that failed becomes `PartialOp::Err` with the matching error kind: `EAGAIN` becomes
+//!   `WouldBlock`, `EINTR` becomes `Interrupted`, and so on. Error names without a matching kind
+//!   become `Other`.
+//!
+//! Both the `read` and `write` families are recognized, including `readv`, `pread64`, `recvfrom`
+//! and `sendmsg`. Lines for other system calls, signals and calls without a result are skipped.
+//! Process IDs (from `-f`), timestamps (from `-t`, `-tt` or `-ttt`), durations (from `-T`), and
+//! calls split into `<unfinished ...>` and `resumed>` lines are handled. Calls are ordered by when
+//! they returned.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use partial_io::{strace, PartialRead};
+//! use std::io::{self, Read};
+//!
+//! let log = r#"
+//! 1234  12:00:01.000001 read(3, "GET / H"..., 4096) = 7
+//! 1234  12:00:01.000002 read(3, 0x7ffd5c6e1a30, 4096) = -1 EAGAIN (Resource temporarily unavailable)
+//! 1234  12:00:01.000003 write(1, "ok\n", 3) = 3
+//! 1234  12:00:01.000004 read(3, "TTP/1.1\r\n", 4096) = 10
+//! "#;
+//! let ops = strace::reads(log, Some(3)).unwrap();
+//!
+//! let mut partial_read = PartialRead::new(&b"GET / HTTP/1.1\r\n"[..], ops);
+//! let mut buf = [0; 64];
+//! assert_eq!(partial_read.read(&mut buf).unwrap(), 7);
+//! assert_eq!(
+//!     partial_read.read(&mut buf).unwrap_err().kind(),
+//!     io::ErrorKind::WouldBlock,
+//! );
+//! ```
+//!
+//! [`reads`]: fn.reads.html
+//! [`writes`]: fn.writes.html
+
+use crate::PartialOp;
+use std::{collections::HashMap, io};
+
+const READ_CALLS: &[&str] = &[
+    "read", "readv", "pread", "pread64", "preadv", "preadv2", "recv", "recvfrom", "recvmsg",
+];
+
+const WRITE_CALLS: &[&str] = &[
+    "write", "writev", "pwrite", "pwrite64", "pwritev", "pwritev2", "send", "sendto", "sendmsg",
+];
+
+/// Error names, as printed by `strace`, and the kinds they map to.
+const ERRNO_KINDS: &[(&str, io::ErrorKind)] = &[
+    ("EAGAIN", io::ErrorKind::WouldBlock),
+    ("EWOULDBLOCK", io::ErrorKind::WouldBlock),
+    ("EINTR", io::ErrorKind::Interrupted),
+    ("ERESTARTSYS", io::ErrorKind::Interrupted),
+    ("ERESTARTNOINTR", io::ErrorKind::Interrupted),
+    ("ERESTARTNOHAND", io::ErrorKind::Interrupted),
+    ("ERESTART_RESTARTBLOCK", io::ErrorKind::Interrupted),
+    ("ECONNRESET", io::ErrorKind::ConnectionReset),
+    ("ECONNREFUSED", io::ErrorKind::ConnectionRefused),
+    ("ECONNABORTED", io::ErrorKind::ConnectionAborted),
+    ("ENOTCONN", io::ErrorKind::NotConnected),
+    ("EPIPE", io::ErrorKind::BrokenPipe),
+    ("ETIMEDOUT", io::ErrorKind::TimedOut),
+    ("EINVAL", io::ErrorKind::InvalidInput),
+    ("ENOMEM", io::ErrorKind::OutOfMemory),
+    ("EACCES", io::ErrorKind::PermissionDenied),
+    ("EPERM", io::ErrorKind::PermissionDenied),
+    ("ENOENT", io::ErrorKind::NotFound),
+];
+
+/// Converts the results of the read calls in an `strace` log into operations.
+///
+/// If `fd` is `Some`, only calls on that file descriptor are included.
+///
+/// Returns an error of kind `InvalidData` if the result of a read call can't be parsed.
+pub fn reads(log: &str, fd: Option<i32>) -> io::Result<Vec<PartialOp>> {
+    parse(log, fd, READ_CALLS)
+}
+
+/// Converts the results of the write calls in an `strace` log into operations.
+///
+/// If `fd` is `Some`, only calls on that file descriptor are included.
+///
+/// Returns an error of kind `InvalidData` if the result of a write call can't be parsed.
+pub fn writes(log: &str, fd: Option<i32>) -> io::Result<Vec<PartialOp>> {
+    parse(log, fd, WRITE_CALLS)
+}
+
+fn parse(log: &str, fd: Option<i32>, calls: &[&str]) -> io::Result<Vec<PartialOp>> {
+    // The file descriptors of unfinished calls, by process ID and call name.
+    let mut unfinished: HashMap<(&str, &str), Option<i32>> = HashMap::new();
+    let mut ops = Vec::new();
+    for (index, line) in log.lines().enumerate() {
+        let (pid, rest) = strip_prefix(line.trim());
+        let (name, call_fd, rest) = match rest.strip_prefix("<... ") {
+            Some(resumed) => {
+                let (name, rest) = match resumed.split_once(" resumed>") {
+                    Some(split) => split,
+                    None => continue,
+                };
+                match unfinished.remove(&(pid, name)) {
+                    Some(call_fd) => (name, call_fd, rest),
+                    // The call started before the log did.
+                    None => continue,
+                }
+            }
+            None => {
+                let (name, args) = match rest.split_once('(') {
+                    Some(split) => split,
+                    None => continue,
+                };
+                if !calls.contains(&name) {
+                    continue;
+                }
+                let call_fd = args
+                    .split(',')
+                    .next()
+                    .and_then(|arg| arg.trim().parse().ok());
+                if args.ends_with("<unfinished ...>") {
+                    unfinished.insert((pid, name), call_fd);
+                    continue;
+                }
+                (name, call_fd, args)
+            }
+        };
+        if !calls.contains(&name) || (fd.is_some() && call_fd != fd) {
+            continue;
+        }
+        let result = match rest.rfind(") = ") {
+            Some(pos) => rest[pos + 4..].trim(),
+            None => continue,
+        };
+        if let Some(op) = parse_result(result).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: invalid result: {:?}", index + 1, result),
+            )
+        })? {
+            ops.push(op);
+        }
+    }
+    Ok(ops)
+}
+
+// Strips the process ID and timestamp from the start of a line, returning the process ID.
+fn strip_prefix(line: &str) -> (&str, &str) {
+    let mut pid = "";
+    let mut rest = line;
+    if let Some(bracketed) = rest.strip_prefix("[pid ") {
+        if let Some((id, after)) = bracketed.split_once(']') {
+            pid = id.trim();
+            rest = after.trim_start();
+        }
+    }
+    // A bare process ID comes first, then a timestamp made of digits, ':' and '.'.
+    while let Some((token, after)) = rest.split_once(char::is_whitespace) {
+        if token.is_empty()
+            || !token
+                .chars()
+                .all(|c| c.is_ascii_digit() || c == ':' || c == '.')
+        {
+            break;
+        }
+        if pid.is_empty() && token.chars().all(|c| c.is_ascii_digit()) {
+            pid = token;
+        }
+        rest = after.trim_start();
+    }
+    (pid, rest)
+}
+
+// Returns `Some(None)` for calls without a result, such as when the process exited.
+fn parse_result(result: &str) -> Option<Option<PartialOp>> {
+    let mut words = result.split_whitespace();
+    let op = match words.next()? {
+        "?" => return Some(None),
+        "-1" => {
+            let name = words.next()?;
+            let kind = ERRNO_KINDS
+                .iter()
+                .find(|(errno, _)| *errno == name)
+                .map_or(io::ErrorKind::Other, |(_, kind)| *kind);
+            PartialOp::Err(kind)
+        }
+        n => PartialOp::Limited(n.parse().ok()?),
+    };
+    Some(Some(op))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let log = r#"execve("./server", ["./server"], 0x7ffc /* 20 vars */) = 0
+[pid  4242] 1697000000.000001 read(5, "abc", 3) = 3 <0.000010>
+[pid  4243] 1697000000.000002 read(6, "x", 16) = 1
+[pid  4242] 1697000000.000003 recvfrom(5,  <unfinished ...>
+[pid  4243] 1697000000.000004 write(6, "a) = 1", 6) = 6
+[pid  4242] 1697000000.000005 <... recvfrom resumed>"de", 64, 0, NULL, NULL) = 2
+--- SIGPIPE {si_signo=SIGPIPE, si_code=SI_USER} ---
+[pid  4242] 1697000000.000006 read(5, 0x55d0, 64) = -1 EINTR (Interrupted system call)
+[pid  4242] 1697000000.000007 read(5, 0x55d0, 64) = -1 EBADF (Bad file descriptor)
+[pid  4242] 1697000000.000008 read(5, "", 64) = 0
+[pid  4242] 1697000000.000009 read(5,  <unfinished ...>
+[pid  4242] 1697000000.000010 +++ exited with 0 +++
+"#;
+        let ops = reads(log, Some(5)).unwrap();
+        assert!(
+            matches!(
+                ops[..],
+                [
+                    PartialOp::Limited(3),
+                    PartialOp::Limited(2),
+                    PartialOp::Err(io::ErrorKind::Interrupted),
+                    PartialOp::Err(io::ErrorKind::Other),
+                    PartialOp::Limited(0),
+                ]
+            ),
+            "{:?}",
+            ops
+        );
+        assert_eq!(reads(log, None).unwrap().len(), 6);
+
+        let ops = writes(log, None).unwrap();
+        assert!(matches!(ops[..], [PartialOp::Limited(6)]), "{:?}", ops);
+
+        let err = reads("read(3, \"\", 4) = x\n", None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("line 1:"), "{}", err);
+    }
+}
@@ -0,0 +1,435 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! This module contains a `Stream` wrapper that breaks chunks of bytes up according to a provided
+//! iterator.
+
+use crate::{exclusive::Exclusive, futures_util::FuturesOps, PartialOp};
+use bytes::{Buf, Bytes};
+use futures::{prelude::*, ready};
+use pin_project::pin_project;
+use std::{
+    collections::VecDeque,
+    fmt, io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A wrapper that breaks inner `Stream`s of byte chunks up according to the provided iterator.
+///
+/// The inner stream must return `Result<B, E>` items, where `B` is a `bytes::Buf` and `E` can be
+/// created from an `io::Error`. This matches streaming bodies and
+/// `tokio_util::io::ReaderStream`.
+///
+/// Each call to `poll_next` consumes an operation:
+///
+/// * `PartialOp::Limited(n)` splits chunks so that at most `n` bytes are returned in the next
+///   item. The rest of the chunk is held back and returned by subsequent calls.
+/// * `PartialOp::Err(io::ErrorKind::WouldBlock)` returns `Poll::Pending` and reschedules the task,
+///   `PartialOp::Err(io::ErrorKind::Interrupted)` causes a retry, and any other error is returned
+///   as an item.
+/// * `PartialOp::Unlimited` returns the next chunk (or the rest of a split chunk) as-is.
+///
+/// Chunks can also be duplicated and reordered, to test idempotency and ordering assumptions in
+/// message consumers. See [`set_chunk_ops`] for more.
+///
+/// Available with the `futures03` and `bytes1` features.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(all(feature = "futures03", feature = "bytes1"))]
+/// use bytes::Bytes;
+/// # #[cfg(all(feature = "futures03", feature = "bytes1"))]
+/// use futures::prelude::*;
+/// # #[cfg(all(feature = "futures03", feature = "bytes1"))]
+/// use partial_io::{PartialOp, PartialStream};
+/// # #[cfg(all(feature = "futures03", feature = "bytes1"))]
+/// use std::io;
+///
+/// # #[cfg(all(feature = "futures03", feature = "bytes1"))]
+/// # fn main() {
+/// futures::executor::block_on(async {
+///     let chunks = vec![Ok::<_, io::Error>(Bytes::from_static(b"Hello, world!"))];
+///     let iter = vec![
+///         PartialOp::Limited(5),                       // Only return 5 bytes in the first item.
+///         PartialOp::Err(io::ErrorKind::WouldBlock),   // A not-ready state.
+///         PartialOp::Err(io::ErrorKind::InvalidData),  // An error item.
+///     ];
+///     let mut partial_stream = PartialStream::new(stream::iter(chunks), iter);
+///
+///     assert_eq!(partial_stream.next().await.unwrap().unwrap(), "Hello");
+///     // This yields once (WouldBlock) before returning the error.
+///     assert_eq!(
+///         partial_stream.next().await.unwrap().unwrap_err().kind(),
+///         io::ErrorKind::InvalidData,
+///     );
+///     // The iterator has run out of operations, so it no longer splits chunks.
+///     assert_eq!(partial_stream.next().await.unwrap().unwrap(), ", world!");
+///     assert!(partial_stream.next().await.is_none());
+/// });
+/// # }
+///
+/// # #[cfg(not(all(feature = "futures03", feature = "bytes1")))]
+/// # fn main() {
+/// #     assert!(true, "dummy test");
+/// # }
+/// ```
+///
+/// [`set_chunk_ops`]: struct.PartialStream.html#method.set_chunk_ops
+#[pin_project]
+pub struct PartialStream<S> {
+    #[pin]
+    inner: S,
+    ops: FuturesOps,
+    // The unreturned part of a chunk that was split up.
+    remaining: Option<Bytes>,
+    chunk_ops: Exclusive<Box<dyn Iterator<Item = ChunkOp> + Send>>,
+    // Chunks to return before producing any new ones, from `Duplicate` and `SwapWithNext`.
+    queued: VecDeque<Bytes>,
+    // A chunk held back by `SwapWithNext` until the next chunk is produced.
+    held: Option<Bytes>,
+    // Whether the inner stream ended while a chunk was held back.
+    finished: bool,
+}
+
+/// An operation applied to each chunk returned by a [`PartialStream`].
+///
+/// Set with [`PartialStream::set_chunk_ops`].
+///
+/// [`PartialStream`]: struct.PartialStream.html
+/// [`PartialStream::set_chunk_ops`]: struct.PartialStream.html#method.set_chunk_ops
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChunkOp {
+    /// Return the chunk as-is.
+    Deliver,
+    /// Return the chunk twice in a row.
+    Duplicate,
+    /// Return the chunk after the one that follows it.
+    SwapWithNext,
+}
+
+impl<S> PartialStream<S> {
+    /// Creates a new `PartialStream` wrapper over the stream with the specified `PartialOp`s.
+    pub fn new<I>(inner: S, iter: I) -> Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        PartialStream {
+            inner,
+            ops: FuturesOps::new(iter),
+            remaining: None,
+            chunk_ops: Exclusive::new(Box::new(std::iter::empty())),
+            queued: VecDeque::new(),
+            held: None,
+            finished: false,
+        }
+    }
+
+    /// Sets the `PartialOp`s for this stream.
+    pub fn set_ops<I>(&mut self, iter: I) -> &mut Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        self.ops.replace(iter);
+        self
+    }
+
+    /// Sets the `PartialOp`s for this stream in a pinned context.
+    pub fn pin_set_ops<I>(self: Pin<&mut Self>, iter: I) -> Pin<&mut Self>
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        let mut this = self;
+        this.as_mut().project().ops.replace(iter);
+        this
+    }
+
+    /// Sets the `ChunkOp`s for this stream.
+    ///
+    /// A chunk op is consumed for every chunk produced after `PartialOp`s have been applied,
+    /// including the parts of split chunks. Duplicated and swapped chunks are returned without
+    /// consuming any more operations. If the inner stream ends while a chunk is held back by
+    /// `ChunkOp::SwapWithNext`, that chunk is returned last.
+    ///
+    /// Once the iterator runs out, chunks are returned as-is.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # #[cfg(all(feature = "futures03", feature = "bytes1"))]
+    /// use bytes::Bytes;
+    /// # #[cfg(all(feature = "futures03", feature = "bytes1"))]
+    /// use futures::prelude::*;
+    /// # #[cfg(all(feature = "futures03", feature = "bytes1"))]
+    /// use partial_io::{ChunkOp, PartialStream};
+    /// # #[cfg(all(feature = "futures03", feature = "bytes1"))]
+    /// use std::io;
+    ///
+    /// # #[cfg(all(feature = "futures03", feature = "bytes1"))]
+    /// # fn main() {
+    /// let chunks = ["a", "b", "c"]
+    ///     .iter()
+    ///     .map(|c| Ok::<_, io::Error>(Bytes::from_static(c.as_bytes())));
+    /// let mut partial_stream = PartialStream::new(stream::iter(chunks), None);
+    /// partial_stream.set_chunk_ops(vec![ChunkOp::Duplicate, ChunkOp::SwapWithNext]);
+    ///
+    /// let items: Vec<_> =
+    ///     futures::executor::block_on(partial_stream.map(Result::unwrap).collect());
+    /// assert_eq!(items, ["a", "a", "c", "b"]);
+    /// # }
+    ///
+    /// # #[cfg(not(all(feature = "futures03", feature = "bytes1")))]
+    /// # fn main() {
+    /// #     assert!(true, "dummy test");
+    /// # }
+    /// ```
+    pub fn set_chunk_ops<I>(&mut self, iter: I) -> &mut Self
+    where
+        I: IntoIterator<Item = ChunkOp> + 'static,
+        I::IntoIter: Send,
+    {
+        self.chunk_ops = Exclusive::new(Box::new(iter.into_iter().fuse()));
+        self
+    }
+
+    /// Returns the operations of this stream, along with its wake and poll statistics.
+    pub fn ops(&self) -> &FuturesOps {
+        &self.ops
+    }
+
+    /// Returns the operations of this stream, to set its clock and how it delivers wakes, or to
+    /// enable checks on how it's polled.
+    ///
+    /// See `FuturesOps` for the settings.
+    pub fn ops_mut(&mut self) -> &mut FuturesOps {
+        &mut self.ops
+    }
+
+    /// Returns a shared reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying stream.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Returns a pinned mutable reference to the underlying stream.
+    pub fn pin_get_mut(self: Pin<&mut Self>) -> Pin<&mut S> {
+        self.project().inner
+    }
+
+    /// Consumes this wrapper, returning the underlying stream.
+    ///
+    /// Any data held back from a split, duplicated or swapped chunk is lost.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S, B, E> PartialStream<S>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: Buf,
+    E: From<io::Error>,
+{
+    /// Produces the next chunk after applying `PartialOp`s, before `ChunkOp`s are applied.
+    fn poll_chunk(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Bytes, E>>> {
+        let this = self.project();
+        let inner = this.inner;
+        let remaining = this.remaining;
+        let len = remaining.as_ref().map_or(usize::MAX, |data| data.len());
+
+        let res = this.ops.poll_impl(
+            cx,
+            |cx, len| {
+                let mut data = match remaining.take() {
+                    Some(data) => data,
+                    None => match inner.poll_next(cx) {
+                        Poll::Ready(Some(Ok(mut data))) => data.copy_to_bytes(data.remaining()),
+                        Poll::Ready(Some(Err(err))) => return Poll::Ready(Ok(Some(Err(err)))),
+                        Poll::Ready(None) => return Poll::Ready(Ok(None)),
+                        Poll::Pending => return Poll::Pending,
+                    },
+                };
+
+                if let Some(len) = len {
+                    if len < data.len() {
+                        let head = data.split_to(len);
+                        *remaining = Some(data);
+                        return Poll::Ready(Ok(Some(Ok(head))));
+                    }
+                }
+                Poll::Ready(Ok(Some(Ok(data))))
+            },
+            len,
+            "error during poll_next, generated by partial-io",
+        );
+
+        match res {
+            Poll::Ready(Ok(item)) => Poll::Ready(item),
+            Poll::Ready(Err(err)) => Poll::Ready(Some(Err(err.into()))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S, B, E> Stream for PartialStream<S>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: Buf,
+    E: From<io::Error>,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if let Some(chunk) = self.as_mut().project().queued.pop_front() {
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        loop {
+            let item = ready!(self.as_mut().poll_chunk(cx));
+            let this = self.as_mut().project();
+            match item {
+                Some(Ok(chunk)) => {
+                    if let Some(held) = this.held.take() {
+                        this.queued.push_back(held);
+                        return Poll::Ready(Some(Ok(chunk)));
+                    }
+                    match this.chunk_ops.get_mut().next() {
+                        Some(ChunkOp::Duplicate) => {
+                            this.queued.push_back(chunk.clone());
+                            return Poll::Ready(Some(Ok(chunk)));
+                        }
+                        Some(ChunkOp::SwapWithNext) => {
+                            *this.held = Some(chunk);
+                            continue;
+                        }
+                        Some(ChunkOp::Deliver) | None => return Poll::Ready(Some(Ok(chunk))),
+                    }
+                }
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => {
+                    // Don't poll the inner stream again after it has ended.
+                    return match this.held.take() {
+                        Some(held) => {
+                            *this.finished = true;
+                            Poll::Ready(Some(Ok(held)))
+                        }
+                        None => Poll::Ready(None),
+                    };
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // Chunks may be split into any number of items and errors may be injected, so there's no
+        // upper bound.
+        let extra = usize::from(self.remaining.is_some())
+            + usize::from(self.held.is_some())
+            + self.queued.len();
+        (self.inner.size_hint().0.saturating_add(extra), None)
+    }
+}
+
+impl<S> fmt::Debug for PartialStream<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PartialStream")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::stream::{self, BoxStream};
+
+    use crate::tests::{assert_send, assert_sync};
+
+    #[test]
+    fn test_sendable() {
+        assert_send::<PartialStream<BoxStream<'static, io::Result<Bytes>>>>();
+        assert_sync::<PartialStream<stream::Iter<std::vec::IntoIter<io::Result<Bytes>>>>>();
+    }
+
+    #[test]
+    fn test_chunk_ops() {
+        let chunks = vec![
+            Ok::<_, io::Error>(Bytes::from_static(b"abcd")),
+            Ok(Bytes::from_static(b"ef")),
+            Ok(Bytes::from_static(b"gh")),
+        ];
+        let mut partial_stream =
+            PartialStream::new(stream::iter(chunks), vec![PartialOp::Limited(2)]);
+        partial_stream.set_chunk_ops(vec![
+            ChunkOp::SwapWithNext,
+            ChunkOp::Duplicate,
+            ChunkOp::SwapWithNext,
+        ]);
+
+        let items: Vec<_> = futures::executor::block_on(
+            partial_stream.map(|item| item.unwrap().to_vec()).collect(),
+        );
+        // "ab" is swapped with "cd", "ef" is duplicated, and "gh" is held back until the end.
+        let expected: Vec<&[u8]> = vec![b"cd", b"ab", b"ef", b"ef", b"gh"];
+        assert_eq!(items, expected);
+    }
+
+    #[test]
+    fn test_size_hint_unbounded() {
+        let inner = stream::repeat(Bytes::from_static(b"ab")).map(Ok::<_, io::Error>);
+        let mut partial_stream = PartialStream::new(inner, vec![PartialOp::Limited(1)]);
+        let item = futures::executor::block_on(partial_stream.next()).unwrap();
+        assert_eq!(item.unwrap(), &b"a"[..]);
+        // The rest of the chunk is held on top of an endless stream.
+        assert_eq!(partial_stream.size_hint(), (usize::MAX, None));
+    }
+
+    #[cfg(feature = "quickcheck1")]
+    #[test]
+    fn test_quickcheck_stream() {
+        use crate::quickcheck_types::{GenInterruptedWouldBlock, PartialWithErrors};
+        use quickcheck::{quickcheck, TestResult};
+
+        fn prop(
+            chunks: Vec<Vec<u8>>,
+            seq: PartialWithErrors<GenInterruptedWouldBlock>,
+        ) -> TestResult {
+            let expected: Vec<u8> = chunks.concat();
+            let inner = stream::iter(
+                chunks
+                    .into_iter()
+                    .map(|chunk| Ok::<_, io::Error>(Bytes::from(chunk))),
+            );
+            let mut partial_stream = PartialStream::new(inner, seq);
+
+            let actual = futures::executor::block_on(async {
+                let mut actual = vec![];
+                while let Some(item) = partial_stream.next().await {
+                    actual.extend_from_slice(&item.expect("only Interrupted and WouldBlock"));
+                }
+                actual
+            });
+            TestResult::from_bool(actual == expected)
+        }
+
+        quickcheck(prop as fn(_, _) -> TestResult);
+    }
+}
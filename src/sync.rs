@@ -0,0 +1,39 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! The synchronization primitives for state shared between wrappers and threads: `loom`'s with
+//! `--cfg loom`, and the standard library's otherwise.
+
+#[cfg(loom)]
+pub(crate) use loom::sync::{Arc, Mutex};
+#[cfg(not(loom))]
+pub(crate) use std::sync::{Arc, Mutex};
+
+// Only the async wrappers wake tasks from other threads.
+#[cfg(all(loom, feature = "futures03"))]
+pub(crate) use loom::{sync::atomic, thread};
+#[cfg(all(not(loom), feature = "futures03"))]
+pub(crate) use std::{sync::atomic, thread};
+
+/// Runs `f` under `loom::model` with `--cfg loom`, and once otherwise, for tests that create
+/// shared state.
+#[cfg(test)]
+pub(crate) fn model<F>(f: F)
+where
+    F: Fn() + Sync + Send + 'static,
+{
+    #[cfg(loom)]
+    loom::model(f);
+    #[cfg(not(loom))]
+    f();
+}
+
+// Drives futures in tests that are woken from threads spawned through this module.
+#[cfg(all(test, not(loom), feature = "futures03"))]
+pub(crate) use futures::executor::block_on;
+#[cfg(all(test, loom, feature = "futures03"))]
+pub(crate) use loom::future::block_on;
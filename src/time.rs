@@ -0,0 +1,700 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Clocks that drive `PartialOp::Delay` in async wrappers, and [`TimedOps`] to schedule
+//! operations by elapsed time.
+//!
+//! By default, async wrappers use [`DefaultClock`]. Within a `tokio` runtime (with the `tokio1`
+//! feature enabled), this uses `tokio::time`, so delays are controlled by `tokio::time::pause`
+//! and `tokio::time::advance`. These require `tokio`'s `test-util` feature. Outside of a `tokio`
+//! runtime, it uses [`StdClock`], which sleeps on a background thread.
+//!
+//! Other runtimes can implement [`Clock`] and pass it to `FuturesOps::set_clock`, through a wrapper's
+//! `ops_mut`. For deterministic tests that don't depend on a runtime, [`MockClock`] only moves
+//! when it's advanced by hand.
+//!
+//! Sync wrappers always block the current thread with `std::thread::sleep`.
+//!
+//! Available with the `futures03` feature.
+//!
+//! # WebAssembly
+//!
+//! The async wrappers work on `wasm32-unknown-unknown`, which has neither threads nor
+//! `Instant::now`. They don't need either unless they perform a `PartialOp::Delay`, and on that
+//! target, [`DefaultClock`] panics when asked to. Set a clock backed by a browser timer instead,
+//! with [`local_sleep`] to turn the timer, which isn't `Send`, into a [`Sleep`]:
+//!
+//! ```rust
+//! # #[cfg(feature = "futures03")]
+//! use partial_io::time::{local_sleep, Clock, Sleep};
+//! # #[cfg(feature = "futures03")]
+//! use std::time::{Duration, Instant};
+//!
+//! # #[cfg(feature = "futures03")]
+//! # mod gloo_timers { pub mod future {
+//! #     pub async fn sleep(_: std::time::Duration) { let _not_send = std::rc::Rc::new(()); }
+//! # } }
+//! # #[cfg(feature = "futures03")]
+//! #[derive(Debug)]
+//! struct BrowserClock;
+//!
+//! # #[cfg(feature = "futures03")]
+//! impl Clock for BrowserClock {
+//!     fn now(&self) -> Instant {
+//!         unimplemented!("only used by TimedOps")
+//!     }
+//!
+//!     fn sleep(&self, duration: Duration) -> Sleep {
+//!         local_sleep(gloo_timers::future::sleep(duration))
+//!     }
+//! }
+//! # fn main() {}
+//! ```
+//!
+//! [`TimedOps`] needs `Clock::now`, and so isn't available there.
+//!
+//! # Examples
+//!
+//! ```rust
+//! # #[cfg(feature = "tokio1")]
+//! use partial_io::{PartialAsyncWrite, PartialOp};
+//! # #[cfg(feature = "tokio1")]
+//! use std::time::Duration;
+//! # #[cfg(feature = "tokio1")]
+//! use tokio::{io::AsyncWriteExt, time::Instant};
+//!
+//! # #[cfg(feature = "tokio1")]
+//! #[tokio::main(flavor = "current_thread")]
+//! async fn main() {
+//!     tokio::time::pause();
+//!     let start = Instant::now();
+//!
+//!     let ops = vec![PartialOp::Delay(Duration::from_secs(60))];
+//!     let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+//!     // With time paused, this completes right away once the runtime is idle.
+//!     partial_write.write_all(b"hello").await.unwrap();
+//!
+//!     assert!(start.elapsed() >= Duration::from_secs(60));
+//! }
+//!
+//! # #[cfg(not(feature = "tokio1"))]
+//! # fn main() {
+//! #     assert!(true, "dummy test");
+//! # }
+//! ```
+//!
+//! [`DefaultClock`]: struct.DefaultClock.html
+//! [`StdClock`]: struct.StdClock.html
+//! [`MockClock`]: struct.MockClock.html
+//! [`local_sleep`]: fn.local_sleep.html
+//! [`Sleep`]: type.Sleep.html
+//! [`Clock`]: trait.Clock.html
+//! [`TimedOps`]: struct.TimedOps.html
+
+use crate::{local::Local, make_ops, sync, PartialOp};
+use std::{
+    collections::VecDeque,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// A future returned by [`Clock::sleep`].
+///
+/// [`Clock::sleep`]: trait.Clock.html#tymethod.sleep
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Turns a future that isn't `Send`, such as a browser timer on `wasm32-unknown-unknown`, into a
+/// `Sleep` for a [`Clock`].
+///
+/// The returned future can only be polled on the thread that created it, and panics otherwise.
+/// This is always the case with single-threaded executors. If it's dropped on another thread,
+/// `future` is leaked instead of being dropped.
+///
+/// [`Clock`]: trait.Clock.html
+pub fn local_sleep<F>(future: F) -> Sleep
+where
+    F: Future<Output = ()> + 'static,
+{
+    Box::pin(LocalSleep {
+        future: Local::new(Box::pin(future)),
+    })
+}
+
+struct LocalSleep {
+    future: Local<Pin<Box<dyn Future<Output = ()>>>>,
+}
+
+impl Future for LocalSleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        self.future.get_mut("local_sleep").as_mut().poll(cx)
+    }
+}
+
+/// A source of time for `PartialOp::Delay`.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the current time according to this clock.
+    fn now(&self) -> Instant;
+
+    /// Returns a future that completes once `duration` has elapsed according to this clock.
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+/// The clock used by async wrappers unless another one is set.
+///
+/// Uses [`TokioClock`] within a `tokio` runtime, and [`StdClock`] otherwise. The `tokio`
+/// runtime must have time enabled.
+///
+/// On `wasm32-unknown-unknown`, which has no threads or `Instant::now`, this panics instead.
+/// See [the module level documentation](index.html#webassembly) for how to set a clock there.
+///
+/// [`TokioClock`]: struct.TokioClock.html
+/// [`StdClock`]: struct.StdClock.html
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultClock;
+
+impl Clock for DefaultClock {
+    fn now(&self) -> Instant {
+        #[cfg(feature = "tokio1")]
+        {
+            if tokio::runtime::Handle::try_current().is_ok() {
+                return TokioClock.now();
+            }
+        }
+        if NO_STD_CLOCK {
+            panic!("{}", NO_STD_CLOCK_MESSAGE);
+        }
+        StdClock.now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        #[cfg(feature = "tokio1")]
+        {
+            if tokio::runtime::Handle::try_current().is_ok() {
+                return TokioClock.sleep(duration);
+            }
+        }
+        if NO_STD_CLOCK {
+            panic!("{}", NO_STD_CLOCK_MESSAGE);
+        }
+        StdClock.sleep(duration)
+    }
+}
+
+// Whether `StdClock` can't work on this target.
+const NO_STD_CLOCK: bool = cfg!(all(target_arch = "wasm32", target_os = "unknown"));
+
+const NO_STD_CLOCK_MESSAGE: &str =
+    "PartialOp::Delay needs a clock on wasm32-unknown-unknown: set one with ops_mut().set_clock";
+
+/// A clock that uses system time, and sleeps on a background thread.
+///
+/// This works with any executor, but not on `wasm32-unknown-unknown`, which has no threads.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdClock;
+
+impl Clock for StdClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        let state = sync::Arc::new(sync::Mutex::new(SleepState {
+            done: false,
+            waker: None,
+        }));
+        let thread_state = state.clone();
+        sync::thread::spawn(move || {
+            thread::sleep(duration);
+            let mut state = thread_state.lock().unwrap();
+            state.done = true;
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+        Box::pin(SharedSleep { state })
+    }
+}
+
+struct SleepState {
+    done: bool,
+    waker: Option<Waker>,
+}
+
+// A sleep that completes once whatever holds the other reference to `state` sets `done`.
+struct SharedSleep {
+    state: sync::Arc<sync::Mutex<SleepState>>,
+}
+
+impl Future for SharedSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.done {
+            Poll::Ready(())
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// A clock that uses `tokio::time`, so that it can be controlled with `tokio::time::pause` and
+/// `tokio::time::advance`.
+///
+/// Must be used within a `tokio` runtime that has time enabled.
+///
+/// Available with the `tokio1` feature.
+#[cfg(feature = "tokio1")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioClock;
+
+#[cfg(feature = "tokio1")]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// A clock that only moves when it's advanced, for deterministic tests with any executor.
+///
+/// Sleeps complete once [`advance`] moves the clock to or past the end of them, which wakes the
+/// tasks waiting for them. Clones share the same time, so a test can keep one clone and pass
+/// another to a wrapper's `ops_mut().set_clock`.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(feature = "futures03")]
+/// use futures::{executor::block_on, future, io::AsyncWriteExt, poll};
+/// # #[cfg(feature = "futures03")]
+/// use partial_io::{time::MockClock, PartialAsyncWrite, PartialOp};
+/// # #[cfg(feature = "futures03")]
+/// use std::time::Duration;
+///
+/// # #[cfg(feature = "futures03")]
+/// # fn main() {
+/// let clock = MockClock::new();
+/// let ops = vec![PartialOp::Delay(Duration::from_secs(60))];
+/// let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+/// partial_write.ops_mut().set_clock(clock.clone());
+///
+/// block_on(async {
+///     let mut write = partial_write.write_all(b"hello");
+///     assert!(poll!(&mut write).is_pending());
+///     clock.advance(Duration::from_secs(60));
+///     write.await.unwrap();
+/// });
+/// assert_eq!(clock.elapsed(), Duration::from_secs(60));
+/// # }
+/// #
+/// # #[cfg(not(feature = "futures03"))]
+/// # fn main() {
+/// #     assert!(true, "dummy test");
+/// # }
+/// ```
+///
+/// [`advance`]: struct.MockClock.html#method.advance
+#[derive(Clone)]
+pub struct MockClock {
+    state: sync::Arc<sync::Mutex<MockState>>,
+}
+
+struct MockState {
+    start: Instant,
+    now: Instant,
+    // The sleeps that haven't completed, and the time each one ends at.
+    sleeps: Vec<(Instant, sync::Arc<sync::Mutex<SleepState>>)>,
+}
+
+impl MockClock {
+    /// Creates a new `MockClock`, starting at the current system time.
+    pub fn new() -> Self {
+        Self::starting_at(Instant::now())
+    }
+
+    /// Creates a new `MockClock`, starting at `start`.
+    ///
+    /// On `wasm32-unknown-unknown`, which has no `Instant::now`, this is the only way to create
+    /// one.
+    pub fn starting_at(start: Instant) -> Self {
+        MockClock {
+            state: sync::Arc::new(sync::Mutex::new(MockState {
+                start,
+                now: start,
+                sleeps: Vec::new(),
+            })),
+        }
+    }
+
+    /// Moves the clock forward by `duration`, completing the sleeps that end by then.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.now += duration;
+        let now = state.now;
+        state.sleeps.retain(|(end, sleep)| {
+            if *end > now {
+                return true;
+            }
+            let mut sleep = sleep.lock().unwrap();
+            sleep.done = true;
+            if let Some(waker) = sleep.waker.take() {
+                waker.wake();
+            }
+            false
+        });
+    }
+
+    /// Returns the time elapsed since this clock started, which is the sum of the durations it
+    /// was advanced by.
+    pub fn elapsed(&self) -> Duration {
+        let state = self.state.lock().unwrap();
+        state.now - state.start
+    }
+
+    /// Returns the number of sleeps that haven't completed yet.
+    pub fn pending_sleeps(&self) -> usize {
+        self.state.lock().unwrap().sleeps.len()
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.state.lock().unwrap().now
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        let sleep = sync::Arc::new(sync::Mutex::new(SleepState {
+            done: duration.is_zero(),
+            waker: None,
+        }));
+        if !duration.is_zero() {
+            let mut state = self.state.lock().unwrap();
+            let end = state.now + duration;
+            state.sleeps.push((end, sleep.clone()));
+        }
+        Box::pin(SharedSleep { state: sleep })
+    }
+}
+
+impl fmt::Debug for MockClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockClock")
+            .field("elapsed", &self.elapsed())
+            .field("pending_sleeps", &self.pending_sleeps())
+            .finish()
+    }
+}
+
+/// An iterator of `PartialOp`s scheduled by elapsed time, rather than by call index.
+///
+/// Each phase of the schedule starts once its time has elapsed, according to a [`Clock`], since
+/// the `TimedOps` was created. Operations are taken from the latest phase that has started. If a
+/// phase runs out of operations before the next one starts, `PartialOp::Unlimited` is returned
+/// until then; once the last phase runs out, so does the iterator.
+///
+/// This aligns faults with timer-based behavior in the code under test, such as heartbeats and
+/// keepalives. With [`DefaultClock`] in a `tokio` runtime, the schedule follows `tokio::time`,
+/// so it can be driven with `tokio::time::pause` and `tokio::time::advance`.
+///
+/// `TimedOps` can be passed to any wrapper, including the sync ones. Since wrappers perform a
+/// `PartialOp::ErrTimes` by themselves once they've taken it, it isn't cut short when the next
+/// phase starts.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(feature = "futures03")]
+/// use partial_io::{time::TimedOps, PartialOp, PartialWrite};
+/// # #[cfg(feature = "futures03")]
+/// use std::{io::{self, Write}, iter, thread, time::Duration};
+///
+/// # #[cfg(feature = "futures03")]
+/// # fn main() {
+/// // After 50 ms, start failing with TimedOut.
+/// let ops = TimedOps::new(iter::empty()).after(
+///     Duration::from_millis(50),
+///     iter::repeat(PartialOp::Err(io::ErrorKind::TimedOut)),
+/// );
+/// let mut partial_write = PartialWrite::new(Vec::new(), ops);
+/// partial_write.write_all(b"ping").unwrap();
+/// thread::sleep(Duration::from_millis(50));
+/// assert_eq!(partial_write.write(b"ping").unwrap_err().kind(), io::ErrorKind::TimedOut);
+/// # }
+/// #
+/// # #[cfg(not(feature = "futures03"))]
+/// # fn main() {
+/// #     assert!(true, "dummy test");
+/// # }
+/// ```
+///
+/// [`Clock`]: trait.Clock.html
+/// [`DefaultClock`]: struct.DefaultClock.html
+pub struct TimedOps {
+    clock: Arc<dyn Clock>,
+    start: Instant,
+    // The phases that haven't been superseded, with the time each one starts at.
+    phases: VecDeque<(Duration, Box<dyn Iterator<Item = PartialOp> + Send>)>,
+}
+
+impl TimedOps {
+    /// Creates a new `TimedOps` that starts with `ops`, using [`DefaultClock`].
+    ///
+    /// [`DefaultClock`]: struct.DefaultClock.html
+    pub fn new<I>(ops: I) -> Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        Self::with_clock(DefaultClock, ops)
+    }
+
+    /// Creates a new `TimedOps` that starts with `ops`, using the specified clock.
+    pub fn with_clock<C, I>(clock: C, ops: I) -> Self
+    where
+        C: Clock + 'static,
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        let start = clock.now();
+        let mut phases = VecDeque::new();
+        phases.push_back((Duration::ZERO, make_ops(ops)));
+        TimedOps {
+            clock: Arc::new(clock),
+            start,
+            phases,
+        }
+    }
+
+    /// Switches to `ops` once `elapsed` has passed since this `TimedOps` was created.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `elapsed` is earlier than the start of the previous phase.
+    pub fn after<I>(mut self, elapsed: Duration, ops: I) -> Self
+    where
+        I: IntoIterator<Item = PartialOp> + 'static,
+        I::IntoIter: Send,
+    {
+        let last = self.phases.back().map_or(Duration::ZERO, |(at, _)| *at);
+        assert!(
+            elapsed >= last,
+            "phases must be added in order: {:?} is before {:?}",
+            elapsed,
+            last
+        );
+        self.phases.push_back((elapsed, make_ops(ops)));
+        self
+    }
+}
+
+impl Iterator for TimedOps {
+    type Item = PartialOp;
+
+    fn next(&mut self) -> Option<PartialOp> {
+        let elapsed = self.clock.now().saturating_duration_since(self.start);
+        while self.phases.len() > 1 && self.phases[1].0 <= elapsed {
+            self.phases.pop_front();
+        }
+        let has_next_phase = self.phases.len() > 1;
+        let (_, ops) = self.phases.front_mut()?;
+        match ops.next() {
+            Some(op) => Some(op),
+            None if has_next_phase => Some(PartialOp::Unlimited),
+            None => {
+                self.phases.clear();
+                None
+            }
+        }
+    }
+}
+
+impl fmt::Debug for TimedOps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TimedOps")
+            .field("clock", &self.clock)
+            .field("start", &self.start)
+            .field(
+                "phases",
+                &self.phases.iter().map(|(at, _)| at).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{executor::block_on, io::Cursor, prelude::*};
+    use std::io::{self, Read};
+
+    use crate::{PartialAsyncRead, PartialOp, PartialRead};
+
+    // `loom` explores thousands of schedules, each of which sleeps for real.
+    const DELAY: Duration = Duration::from_millis(if cfg!(loom) { 1 } else { 20 });
+
+    #[test]
+    fn test_std_delay() {
+        crate::sync::model(|| {
+            let ops = vec![
+                PartialOp::Limited(1),
+                PartialOp::Delay(DELAY),
+                PartialOp::Delay(DELAY),
+            ];
+            let mut partial_read = PartialAsyncRead::new(Cursor::new(b"abc".to_vec()), ops.clone());
+            partial_read.ops_mut().set_clock(StdClock);
+            let start = Instant::now();
+            let mut buf = vec![];
+            // The sleep is woken from a thread that `loom` schedules.
+            crate::sync::block_on(partial_read.read_to_end(&mut buf)).unwrap();
+            assert_eq!(buf, b"abc");
+            assert!(start.elapsed() >= DELAY * 2);
+
+            let mut partial_read = PartialRead::new(io::Cursor::new(b"abc".to_vec()), ops);
+            let start = Instant::now();
+            let mut buf = vec![];
+            partial_read.read_to_end(&mut buf).unwrap();
+            assert_eq!(buf, b"abc");
+            assert!(start.elapsed() >= DELAY * 2);
+        });
+    }
+
+    #[test]
+    fn test_local_sleep() {
+        use std::{cell::Cell, rc::Rc};
+
+        // A clock whose sleeps complete on the second poll, and share a counter through an `Rc`.
+        #[derive(Debug, Default)]
+        struct LocalClock;
+
+        impl Clock for LocalClock {
+            fn now(&self) -> Instant {
+                unimplemented!("not used by delays")
+            }
+
+            fn sleep(&self, _duration: Duration) -> Sleep {
+                let polls = Rc::new(Cell::new(0));
+                local_sleep(future::poll_fn(move |cx| {
+                    polls.set(polls.get() + 1);
+                    if polls.get() > 1 {
+                        Poll::Ready(())
+                    } else {
+                        cx.waker().wake_by_ref();
+                        Poll::Pending
+                    }
+                }))
+            }
+        }
+
+        let ops = vec![PartialOp::Delay(Duration::from_secs(3600))];
+        let mut partial_read = PartialAsyncRead::new(Cursor::new(b"abc".to_vec()), ops);
+        partial_read.ops_mut().set_clock(LocalClock);
+        let mut buf = vec![];
+        block_on(partial_read.read_to_end(&mut buf)).unwrap();
+        assert_eq!(buf, b"abc");
+
+        let mut sleep = LocalClock.sleep(DELAY);
+        let res = std::thread::spawn(move || {
+            let waker = futures::task::noop_waker();
+            let _ = sleep.as_mut().poll(&mut Context::from_waker(&waker));
+        })
+        .join();
+        assert!(res.is_err(), "polled on another thread");
+    }
+
+    #[test]
+    fn test_mock_clock() {
+        crate::sync::model(|| {
+            let clock = MockClock::new();
+            let ops = TimedOps::with_clock(clock.clone(), vec![PartialOp::Limited(1)]).after(
+                Duration::from_secs(10),
+                vec![PartialOp::Delay(Duration::from_secs(5))],
+            );
+            let mut partial_read = PartialAsyncRead::new(Cursor::new(b"abcd".to_vec()), ops);
+            partial_read.ops_mut().set_clock(clock.clone());
+            let mut buf = [0; 4];
+            assert_eq!(block_on(partial_read.read(&mut buf)).unwrap(), 1);
+
+            clock.advance(Duration::from_secs(10));
+            let waker = futures::task::noop_waker();
+            let mut cx = Context::from_waker(&waker);
+            let mut read = partial_read.read(&mut buf);
+            assert!(read.poll_unpin(&mut cx).is_pending());
+            assert_eq!(clock.pending_sleeps(), 1);
+            clock.advance(Duration::from_secs(4));
+            assert!(read.poll_unpin(&mut cx).is_pending());
+            clock.advance(Duration::from_secs(1));
+            assert_eq!(clock.pending_sleeps(), 0);
+            match read.poll_unpin(&mut cx) {
+                Poll::Ready(res) => assert_eq!(res.unwrap(), 3),
+                Poll::Pending => panic!("the delay has elapsed"),
+            }
+            assert_eq!(clock.elapsed(), Duration::from_secs(15));
+        });
+    }
+
+    #[cfg(feature = "tokio1")]
+    #[tokio::test(start_paused = true)]
+    async fn test_tokio_paused() {
+        use tokio::io::AsyncReadExt;
+
+        let ops = vec![PartialOp::Delay(Duration::from_secs(3600))];
+        let mut partial_read = PartialAsyncRead::new(io::Cursor::new(b"abc".to_vec()), ops);
+        let start = tokio::time::Instant::now();
+        let mut buf = vec![];
+        partial_read.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"abc");
+        assert!(start.elapsed() >= Duration::from_secs(3600));
+    }
+
+    #[cfg(feature = "tokio1")]
+    #[tokio::test(start_paused = true)]
+    async fn test_timed_ops() {
+        use crate::PartialAsyncWrite;
+
+        let ops = TimedOps::new(vec![PartialOp::Limited(1)])
+            .after(
+                Duration::from_millis(50),
+                vec![
+                    PartialOp::Err(io::ErrorKind::TimedOut),
+                    PartialOp::Err(io::ErrorKind::TimedOut),
+                ],
+            )
+            .after(Duration::from_millis(100), vec![PartialOp::Limited(2)]);
+        let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+        assert_eq!(partial_write.write(b"abc").await.unwrap(), 1);
+        // The first phase ran out, but the second one hasn't started.
+        assert_eq!(partial_write.write(b"abc").await.unwrap(), 3);
+
+        tokio::time::advance(Duration::from_millis(50)).await;
+        let err = partial_write.write(b"abc").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // The rest of the second phase is skipped once the third one starts.
+        tokio::time::advance(Duration::from_millis(50)).await;
+        assert_eq!(partial_write.write(b"abc").await.unwrap(), 2);
+        assert_eq!(partial_write.write(b"abc").await.unwrap(), 3);
+    }
+}
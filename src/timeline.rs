@@ -0,0 +1,199 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Assertions on the timeline of method calls made on a wrapper.
+//!
+//! Some properties are about the order of calls rather than data: for example, that a wrapper
+//! flushes before it shuts down. Call `record_calls` on a wrapper to record every method call,
+//! then check the timeline with [`assert_calls!`].
+//!
+//! Each entry in `assert_calls!` matches a run of consecutive calls to one method:
+//!
+//! * `name` matches exactly one call.
+//! * `name(n)` matches exactly `n` calls.
+//! * `name(>= n)` matches at least `n` calls.
+//!
+//! The whole timeline must be matched. Names match the method name as recorded, or without the
+//! `poll_` prefix, so `write` matches both `"write"` and `"poll_write"`. Every call is
+//! recorded, including retries after `Poll::Pending`.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use partial_io::{assert_calls, PartialOp, PartialWrite};
+//! use std::io::{self, Write};
+//!
+//! let ops = vec![PartialOp::Limited(2), PartialOp::Err(io::ErrorKind::Interrupted)];
+//! let mut partial_write = PartialWrite::new(Vec::new(), ops);
+//! partial_write.record_calls();
+//!
+//! partial_write.write_all(b"hello").unwrap();
+//! partial_write.flush().unwrap();
+//!
+//! assert_calls!(partial_write, [write(>= 1), flush]);
+//! assert_calls!(partial_write, [write(3), flush]);
+//! ```
+//!
+//! [`assert_calls!`]: ../macro.assert_calls.html
+
+use std::fmt;
+
+/// Matches a run of consecutive calls to one method.
+///
+/// Usually created through [`assert_calls!`].
+///
+/// [`assert_calls!`]: ../macro.assert_calls.html
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CallMatcher {
+    method: &'static str,
+    min: usize,
+    max: Option<usize>,
+}
+
+impl CallMatcher {
+    /// Matches exactly `n` consecutive calls to `method`.
+    pub fn exactly(method: &'static str, n: usize) -> Self {
+        CallMatcher {
+            method,
+            min: n,
+            max: Some(n),
+        }
+    }
+
+    /// Matches at least `n` consecutive calls to `method`.
+    pub fn at_least(method: &'static str, n: usize) -> Self {
+        CallMatcher {
+            method,
+            min: n,
+            max: None,
+        }
+    }
+
+    fn matches_method(&self, method: &str) -> bool {
+        method == self.method || method.strip_prefix("poll_") == Some(self.method)
+    }
+}
+
+impl fmt::Display for CallMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max {
+            Some(1) if self.min == 1 => write!(f, "{}", self.method),
+            Some(max) if max == self.min => write!(f, "{}({})", self.method, max),
+            Some(max) => write!(f, "{}({}..={})", self.method, self.min, max),
+            None => write!(f, "{}(>= {})", self.method, self.min),
+        }
+    }
+}
+
+/// Returns true if `matchers` match the whole timeline of `calls`.
+///
+/// Each matcher consumes as many consecutive calls as it can.
+pub fn matches(calls: &[&str], matchers: &[CallMatcher]) -> bool {
+    let mut pos = 0;
+    for matcher in matchers {
+        let run = calls[pos..]
+            .iter()
+            .take_while(|method| matcher.matches_method(method))
+            .count();
+        let taken = match matcher.max {
+            Some(max) => run.min(max),
+            None => run,
+        };
+        if taken < matcher.min {
+            return false;
+        }
+        pos += taken;
+    }
+    pos == calls.len()
+}
+
+/// Panics if `matchers` don't match the whole timeline of `calls`.
+///
+/// This is what [`assert_calls!`] calls.
+///
+/// [`assert_calls!`]: ../macro.assert_calls.html
+#[track_caller]
+pub fn assert_calls(calls: &[&str], matchers: &[CallMatcher]) {
+    if !matches(calls, matchers) {
+        let expected: Vec<_> = matchers.iter().map(|m| m.to_string()).collect();
+        panic!(
+            "call timeline didn't match\n  expected: [{}]\n    actual: [{}]",
+            expected.join(", "),
+            calls.join(", "),
+        );
+    }
+}
+
+/// Asserts that the timeline of method calls on a wrapper matches a pattern.
+///
+/// The wrapper must have had `record_calls` called on it. See the [`timeline`] module for the
+/// syntax.
+///
+/// [`timeline`]: timeline/index.html
+#[macro_export]
+macro_rules! assert_calls {
+    ($wrapper:expr, [$($method:ident $(($($count:tt)+))?),* $(,)?]) => {
+        $crate::timeline::assert_calls(
+            $wrapper.calls(),
+            &[$($crate::__call_matcher!($method $(($($count)+))?)),*],
+        )
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __call_matcher {
+    ($method:ident) => {
+        $crate::timeline::CallMatcher::exactly(stringify!($method), 1)
+    };
+    ($method:ident (>= $n:expr)) => {
+        $crate::timeline::CallMatcher::at_least(stringify!($method), $n)
+    };
+    ($method:ident ($n:expr)) => {
+        $crate::timeline::CallMatcher::exactly(stringify!($method), $n)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let calls = ["poll_write", "poll_write", "poll_flush", "poll_shutdown"];
+        assert!(matches(
+            &calls,
+            &[
+                CallMatcher::at_least("write", 1),
+                CallMatcher::exactly("flush", 1),
+                CallMatcher::exactly("shutdown", 1),
+            ]
+        ));
+        // Shutdown without a flush.
+        assert!(!matches(
+            &calls[..2],
+            &[
+                CallMatcher::at_least("write", 1),
+                CallMatcher::exactly("shutdown", 1)
+            ]
+        ));
+        assert!(!matches(&calls, &[CallMatcher::exactly("write", 2)]));
+        assert!(matches(&[], &[CallMatcher::at_least("write", 0)]));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected: [write(2), shutdown]")]
+    fn test_assert_calls_message() {
+        assert_calls(
+            &["write", "flush"],
+            &[
+                CallMatcher::exactly("write", 2),
+                CallMatcher::exactly("shutdown", 1),
+            ],
+        );
+    }
+}
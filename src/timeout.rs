@@ -0,0 +1,453 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Time out an operation exactly while one of its scheduled `Poll::Pending`s is outstanding.
+//!
+//! Code that wraps IO in a timeout has to cope with the timeout firing partway through an
+//! operation, for instance once `write_all` has written some of its data but not all of it.
+//! Aligning a real timer with a particular `Poll::Pending` is guesswork. Instead, a [`Deadline`]
+//! here is tied to the schedule itself: it fires when the wrapper reaches the chosen
+//! `PartialOp::Err(WouldBlock)` in its operations, so the operation under test returns
+//! `Poll::Pending` and the deadline is ready right after.
+//!
+//! [`TimeoutRace`] runs a test once for each `PartialOp::Err(WouldBlock)` in a schedule, timing
+//! out at that one, and [`timeout`] races a future against a deadline in the same way as
+//! `tokio::time::timeout`.
+//!
+//! Available with the `futures03` feature.
+//!
+//! # Examples
+//!
+//! ```rust
+//! # #[cfg(feature = "futures03")]
+//! use futures::io::AsyncWriteExt;
+//! # #[cfg(feature = "futures03")]
+//! use partial_io::{
+//!     timeout::{timeout, TimeoutRace},
+//!     PartialAsyncWrite, PartialOp,
+//! };
+//! # #[cfg(feature = "futures03")]
+//! use std::io;
+//!
+//! # #[cfg(feature = "futures03")]
+//! # fn main() {
+//! let race = TimeoutRace::new(vec![
+//!     PartialOp::Limited(2),
+//!     PartialOp::Err(io::ErrorKind::WouldBlock),
+//!     PartialOp::Limited(2),
+//!     PartialOp::Err(io::ErrorKind::WouldBlock),
+//! ]);
+//! let report = race.run(|ops, deadline| async move {
+//!     let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+//!     // Time out, then retry the whole write.
+//!     if timeout(deadline, partial_write.write_all(b"hello")).await.is_err() {
+//!         partial_write.write_all(b"hello").await.unwrap();
+//!     }
+//!     match partial_write.into_inner().as_slice() {
+//!         b"hehello" | b"hellhello" => Ok(()),
+//!         other => Err(other.to_vec()),
+//!     }
+//! });
+//! // The write timed out after 2 bytes in one run, and after 4 bytes in the other.
+//! assert_eq!(report.runs, 2);
+//! report.assert_ok();
+//! # }
+//!
+//! # #[cfg(not(feature = "futures03"))]
+//! # fn main() {
+//! #     assert!(true, "dummy test");
+//! # }
+//! ```
+//!
+//! [`Deadline`]: struct.Deadline.html
+//! [`TimeoutRace`]: struct.TimeoutRace.html
+//! [`timeout`]: fn.timeout.html
+
+use crate::PartialOp;
+use futures::executor::block_on;
+use pin_project::pin_project;
+use std::{
+    error, fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    vec,
+};
+
+/// Runs a test once for each `PartialOp::Err(WouldBlock)` in a schedule, timing out at that one.
+///
+/// See [the module level documentation](index.html) for more.
+#[derive(Clone, Debug)]
+pub struct TimeoutRace {
+    ops: Vec<PartialOp>,
+}
+
+impl TimeoutRace {
+    /// Creates a new `TimeoutRace` for the specified schedule.
+    pub fn new<I>(ops: I) -> Self
+    where
+        I: IntoIterator<Item = PartialOp>,
+    {
+        TimeoutRace {
+            ops: ops.into_iter().collect(),
+        }
+    }
+
+    /// Returns the number of `PartialOp::Err(WouldBlock)` in the schedule, which is the number of
+    /// runs made by [`run`].
+    ///
+    /// [`run`]: struct.TimeoutRace.html#method.run
+    pub fn pendings(&self) -> usize {
+        self.ops.iter().filter(|op| is_pending(op)).count()
+    }
+
+    /// Returns the schedule, along with a deadline that fires once the `pending`th
+    /// `PartialOp::Err(WouldBlock)` in it has been taken, counting from 0.
+    ///
+    /// This is what [`run`] passes to each test, for use with other executors.
+    ///
+    /// [`run`]: struct.TimeoutRace.html#method.run
+    pub fn gate(&self, pending: usize) -> (GatedOps, Deadline) {
+        let deadline = Deadline {
+            pending,
+            state: Arc::new(Mutex::new(DeadlineState {
+                fired: false,
+                waker: None,
+            })),
+        };
+        let ops = GatedOps {
+            ops: self.ops.clone().into_iter(),
+            pendings: 0,
+            deadline: deadline.clone(),
+        };
+        (ops, deadline)
+    }
+
+    /// Runs the future returned by `test` once for each `PartialOp::Err(WouldBlock)` in the
+    /// schedule, with the deadline for that one.
+    ///
+    /// `test` is passed the schedule, to give to the wrapper under test, and the deadline, to
+    /// race the operation under test against with [`timeout`]. Each future is driven to completion
+    /// on the current thread. A run fails if its future returns an error, or if the deadline
+    /// didn't fire because the wrapper never reached its `PartialOp::Err(WouldBlock)`.
+    ///
+    /// [`timeout`]: fn.timeout.html
+    pub fn run<F, Fut, E>(&self, mut test: F) -> Report<E>
+    where
+        F: FnMut(GatedOps, Deadline) -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+    {
+        let mut report = Report {
+            runs: 0,
+            failures: vec![],
+        };
+        for pending in 0..self.pendings() {
+            let (ops, deadline) = self.gate(pending);
+            let res = block_on(test(ops, deadline.clone()));
+            report.runs += 1;
+
+            let reason = match res {
+                Err(err) => FailureReason::Check(err),
+                Ok(()) if !deadline.has_fired() => FailureReason::NotReached,
+                Ok(()) => continue,
+            };
+            report.failures.push(Failure { pending, reason });
+        }
+        report
+    }
+}
+
+fn is_pending(op: &PartialOp) -> bool {
+    matches!(op, PartialOp::Err(io::ErrorKind::WouldBlock))
+}
+
+/// A schedule of `PartialOp`s that fires a [`Deadline`] when the wrapper takes a particular
+/// `PartialOp::Err(WouldBlock)`.
+///
+/// Returned by [`TimeoutRace::gate`].
+///
+/// [`Deadline`]: struct.Deadline.html
+/// [`TimeoutRace::gate`]: struct.TimeoutRace.html#method.gate
+#[derive(Debug)]
+pub struct GatedOps {
+    ops: vec::IntoIter<PartialOp>,
+    pendings: usize,
+    deadline: Deadline,
+}
+
+impl Iterator for GatedOps {
+    type Item = PartialOp;
+
+    fn next(&mut self) -> Option<PartialOp> {
+        let op = self.ops.next()?;
+        if is_pending(&op) {
+            if self.pendings == self.deadline.pending {
+                self.deadline.fire();
+            }
+            self.pendings += 1;
+        }
+        Some(op)
+    }
+}
+
+/// A future that completes once the wrapper under test has taken a particular
+/// `PartialOp::Err(WouldBlock)`.
+///
+/// Clones of a `Deadline` fire together.
+#[derive(Clone)]
+pub struct Deadline {
+    pending: usize,
+    state: Arc<Mutex<DeadlineState>>,
+}
+
+struct DeadlineState {
+    fired: bool,
+    waker: Option<Waker>,
+}
+
+impl Deadline {
+    /// Returns the index of the `PartialOp::Err(WouldBlock)` this deadline fires at, counting from
+    /// 0.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Returns true if this deadline has fired.
+    pub fn has_fired(&self) -> bool {
+        self.state.lock().unwrap().fired
+    }
+
+    fn fire(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.fired = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Future for Deadline {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.fired {
+            Poll::Ready(())
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl fmt::Debug for Deadline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Deadline")
+            .field("pending", &self.pending)
+            .field("fired", &self.has_fired())
+            .finish()
+    }
+}
+
+/// Races `future` against `deadline`, returning an error if the deadline fires first.
+///
+/// `future` is polled before `deadline`, so it times out as soon as it returns `Poll::Pending`
+/// for the `PartialOp::Err(WouldBlock)` the deadline is tied to.
+pub fn timeout<F: Future>(deadline: Deadline, future: F) -> Timeout<F> {
+    Timeout { future, deadline }
+}
+
+/// A future returned by [`timeout`].
+///
+/// [`timeout`]: fn.timeout.html
+#[pin_project]
+#[derive(Debug)]
+pub struct Timeout<F> {
+    #[pin]
+    future: F,
+    deadline: Deadline,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.project();
+        if let Poll::Ready(output) = this.future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut *this.deadline).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed {
+                pending: this.deadline.pending,
+            })),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// The error returned by [`Timeout`] when its deadline fires first.
+///
+/// Converts into an `io::Error` of kind `TimedOut`.
+///
+/// [`Timeout`]: struct.Timeout.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Elapsed {
+    pending: usize,
+}
+
+impl Elapsed {
+    /// Returns the index of the `PartialOp::Err(WouldBlock)` the deadline fired at, counting from
+    /// 0.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+}
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "deadline elapsed at pending #{}", self.pending)
+    }
+}
+
+impl error::Error for Elapsed {}
+
+impl From<Elapsed> for io::Error {
+    fn from(elapsed: Elapsed) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, elapsed)
+    }
+}
+
+/// The results of the runs made by a [`TimeoutRace`].
+///
+/// [`TimeoutRace`]: struct.TimeoutRace.html
+#[derive(Clone, Debug)]
+pub struct Report<E> {
+    /// The number of runs performed.
+    pub runs: usize,
+    /// The runs that failed.
+    pub failures: Vec<Failure<E>>,
+}
+
+impl<E> Report<E> {
+    /// Returns true if no runs failed.
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl<E: fmt::Debug> Report<E> {
+    /// Panics if any runs failed, reporting the first failure.
+    pub fn assert_ok(&self) {
+        if let Some(failure) = self.failures.first() {
+            panic!(
+                "{} of {} timeouts failed, first failure: {:?}",
+                self.failures.len(),
+                self.runs,
+                failure
+            );
+        }
+    }
+}
+
+/// A single failed run.
+#[derive(Clone, Debug)]
+pub struct Failure<E> {
+    /// The index of the `PartialOp::Err(WouldBlock)` the run timed out at, counting from 0.
+    pub pending: usize,
+    /// Why the run failed.
+    pub reason: FailureReason<E>,
+}
+
+/// Why a run failed.
+#[derive(Clone, Debug)]
+pub enum FailureReason<E> {
+    /// The test returned an error.
+    Check(E),
+    /// The wrapper never took the `PartialOp::Err(WouldBlock)`, so the deadline didn't fire.
+    NotReached,
+}
+
+impl<E> FailureReason<E> {
+    /// Returns the error returned by the test, if any.
+    pub fn check(&self) -> Option<&E> {
+        match self {
+            FailureReason::Check(err) => Some(err),
+            FailureReason::NotReached => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::io::AsyncWriteExt;
+
+    use crate::{
+        tests::{assert_send, assert_sync},
+        PartialAsyncWrite,
+    };
+
+    #[test]
+    fn test_sendable() {
+        assert_send::<GatedOps>();
+        assert_send::<Deadline>();
+        assert_sync::<Deadline>();
+    }
+
+    #[test]
+    fn test_partial_write_at_each_pending() {
+        let wouldblock = PartialOp::Err(io::ErrorKind::WouldBlock);
+        let race = TimeoutRace::new(vec![
+            wouldblock.clone(),
+            PartialOp::Limited(1),
+            wouldblock.clone(),
+            PartialOp::Limited(3),
+            wouldblock,
+        ]);
+        assert_eq!(race.pendings(), 3);
+        let report = race.run(|ops, deadline| async move {
+            let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+            let err = timeout(deadline, partial_write.write_all(b"abcdef"))
+                .await
+                .unwrap_err();
+            Err((err.pending(), partial_write.into_inner()))
+        });
+        assert_eq!(report.runs, 3);
+        let written: Vec<_> = report
+            .failures
+            .iter()
+            .map(|failure| failure.reason.check().unwrap().clone())
+            .collect();
+        assert_eq!(
+            written,
+            vec![(0, b"".to_vec()), (1, b"a".to_vec()), (2, b"abcd".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_not_reached() {
+        let race = TimeoutRace::new(vec![
+            PartialOp::Limited(1),
+            PartialOp::Err(io::ErrorKind::WouldBlock),
+        ]);
+        let report = race.run(|ops, deadline| async move {
+            let mut partial_write = PartialAsyncWrite::new(Vec::new(), ops);
+            // A single byte is written before the schedule reaches its WouldBlock.
+            timeout(deadline, partial_write.write_all(b"a"))
+                .await
+                .map_err(io::Error::from)?
+        });
+        assert_eq!(report.runs, 1);
+        assert!(matches!(
+            report.failures[0].reason,
+            FailureReason::NotReached
+        ));
+    }
+}
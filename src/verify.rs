@@ -0,0 +1,313 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Ready-made harnesses that check common kinds of wrappers for whole families of bugs.
+//!
+//! Rather than writing a property test from scratch, pass in a function that builds the wrapper
+//! under test around a partial inner type. The harness drives the wrapper through a fixed set of
+//! operation schedules, and reports the first invariant that broke along with the schedule that
+//! broke it.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use partial_io::verify;
+//! use std::io::BufWriter;
+//!
+//! verify::buffered_writer(|inner| BufWriter::with_capacity(8, inner)).unwrap();
+//! ```
+//!
+//! For a buffered writer that doesn't pass, see `examples/buggy_write.rs` in this repository.
+
+use crate::{corpus, PartialOp, PartialWrite};
+use std::{
+    error, fmt,
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+/// An inner writer that records everything written to it, shared with the harness.
+///
+/// This is the writer passed into the factory given to [`buffered_writer`], wrapped in a
+/// `PartialWrite`.
+///
+/// [`buffered_writer`]: fn.buffered_writer.html
+#[derive(Clone, Debug, Default)]
+pub struct Recorder {
+    data: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Recorder {
+    /// Returns a copy of everything written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.lock().expect("lock isn't poisoned").clone()
+    }
+}
+
+impl Write for Recorder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data
+            .lock()
+            .expect("lock isn't poisoned")
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The invariant that a wrapper broke, reported in a [`Violation`].
+///
+/// [`Violation`]: struct.Violation.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ViolationKind {
+    /// Data was written to the inner writer even though the call that was passed it failed.
+    ///
+    /// This typically happens when data is copied into the buffer before an error from the inner
+    /// writer is returned.
+    ConsumedOnError,
+    /// Data reached the inner writer more than once, for example because a buffer that was
+    /// partly written out was written out again from the start.
+    DuplicatedData,
+    /// Data that a call reported as written never reached the inner writer, for example because
+    /// the buffer was cleared after an `Interrupted` error.
+    LostData,
+    /// The inner writer received the right number of bytes, but in the wrong order or with the
+    /// wrong contents.
+    Corrupted,
+    /// A call to `write` returned a count larger than the buffer passed in.
+    InvalidCount,
+}
+
+/// A broken invariant, with the schedule of operations on the inner writer that broke it.
+#[derive(Clone, Debug)]
+pub struct Violation {
+    /// The invariant that broke.
+    pub kind: ViolationKind,
+    /// The operations on the inner writer that broke it.
+    pub ops: Vec<PartialOp>,
+    /// The data the inner writer should have received.
+    pub expected: Vec<u8>,
+    /// The data the inner writer actually received.
+    pub actual: Vec<u8>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}: expected {} bytes to be written, got {}, with schedule {:016x}: {:?}",
+            self.kind,
+            self.expected.len(),
+            self.actual.len(),
+            corpus::fingerprint(&self.ops),
+            self.ops
+        )
+    }
+}
+
+impl error::Error for Violation {}
+
+/// Checks a buffered writer for the classic family of buffering bugs.
+///
+/// `factory` builds the writer under test around a `PartialWrite`. For each schedule, the harness
+/// writes a few chunks of data with `write`, retrying on `Interrupted` errors and giving up on the
+/// rest of a chunk after any other error, like a caller of `write_all` would. It then flushes,
+/// retrying on errors. Once a flush succeeds, the inner writer must have received exactly the
+/// bytes that `write` reported as written, in order.
+///
+/// Schedules inject a single `Interrupted` or `BrokenPipe` error at each of the first few calls
+/// to the inner writer, around unlimited and short writes. This catches bugs such as copying data
+/// into the buffer before an inner write fails, writing a partly written buffer out twice, or
+/// clearing the buffer after an `Interrupted` error.
+///
+/// Returns the first violation found. Schedules in which the flush never succeeds are skipped.
+pub fn buffered_writer<W, F>(mut factory: F) -> Result<(), Violation>
+where
+    W: Write,
+    F: FnMut(PartialWrite<Recorder>) -> W,
+{
+    for ops in schedules() {
+        check_buffered_writer(&mut factory, ops)?;
+    }
+    Ok(())
+}
+
+/// The chunks written by `buffered_writer`, with different contents so that reordering shows up.
+const CHUNKS: &[&[u8]] = &[b"Hello, ", b"partial world", b"!\nBye."];
+
+/// The number of times a failed flush is retried.
+const FLUSH_ATTEMPTS: usize = 8;
+
+fn schedules() -> impl Iterator<Item = Vec<PartialOp>> {
+    let kinds = vec![io::ErrorKind::Interrupted, io::ErrorKind::BrokenPipe];
+    let limits = vec![
+        PartialOp::Unlimited,
+        PartialOp::Limited(1),
+        PartialOp::Limited(3),
+    ];
+    kinds.into_iter().flat_map(move |kind| {
+        limits.clone().into_iter().flat_map(move |limit| {
+            (0..8).map(move |pos| {
+                let mut ops = vec![limit.clone(); pos];
+                ops.push(PartialOp::Err(kind));
+                // Calls are unlimited once the operations run out.
+                if !matches!(limit, PartialOp::Unlimited) {
+                    ops.extend(vec![limit.clone(); 32]);
+                }
+                ops
+            })
+        })
+    })
+}
+
+fn check_buffered_writer<W, F>(factory: &mut F, ops: Vec<PartialOp>) -> Result<(), Violation>
+where
+    W: Write,
+    F: FnMut(PartialWrite<Recorder>) -> W,
+{
+    let recorder = Recorder::default();
+    let mut writer = factory(PartialWrite::new(recorder.clone(), ops.clone()));
+    // The bytes reported as written, and the bytes passed to calls that failed as well.
+    let mut expected = Vec::new();
+    let mut attempted = Vec::new();
+
+    let violation = |kind, expected: &[u8], actual| Violation {
+        kind,
+        ops: ops.clone(),
+        expected: expected.to_vec(),
+        actual,
+    };
+
+    for chunk in CHUNKS {
+        let mut rest = *chunk;
+        while !rest.is_empty() {
+            match writer.write(rest) {
+                Ok(0) => break,
+                Ok(n) if n > rest.len() => {
+                    return Err(violation(
+                        ViolationKind::InvalidCount,
+                        &expected,
+                        recorder.contents(),
+                    ));
+                }
+                Ok(n) => {
+                    expected.extend_from_slice(&rest[..n]);
+                    attempted.extend_from_slice(&rest[..n]);
+                    rest = &rest[n..];
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => {
+                    attempted.extend_from_slice(rest);
+                    break;
+                }
+            }
+        }
+    }
+
+    if !(0..FLUSH_ATTEMPTS).any(|_| writer.flush().is_ok()) {
+        return Ok(());
+    }
+    drop(writer);
+
+    let actual = recorder.contents();
+    let kind = if actual == expected {
+        return Ok(());
+    } else if actual == attempted {
+        ViolationKind::ConsumedOnError
+    } else if actual.len() > expected.len() {
+        ViolationKind::DuplicatedData
+    } else if actual.len() < expected.len() {
+        ViolationKind::LostData
+    } else {
+        ViolationKind::Corrupted
+    };
+    Err(violation(kind, &expected, actual))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufWriter;
+
+    /// A buffered writer that copies data into its buffer before writing it out, and reports
+    /// errors from the inner writer without accounting for the copy.
+    struct EagerWrite<W> {
+        inner: W,
+        buf: Vec<u8>,
+    }
+
+    impl<W: Write> Write for EagerWrite<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.buf.extend_from_slice(buf);
+            self.flush_buf()?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flush_buf()?;
+            self.inner.flush()
+        }
+    }
+
+    impl<W: Write> EagerWrite<W> {
+        fn flush_buf(&mut self) -> io::Result<()> {
+            while !self.buf.is_empty() {
+                let n = self.inner.write(&self.buf)?;
+                self.buf.drain(..n);
+            }
+            Ok(())
+        }
+    }
+
+    /// A buffered writer that clears its buffer if the inner writer fails.
+    struct ForgetfulWrite<W> {
+        inner: W,
+        buf: Vec<u8>,
+    }
+
+    impl<W: Write> Write for ForgetfulWrite<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            let buf = std::mem::take(&mut self.buf);
+            self.inner.write_all(&buf)?;
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn test_buffered_writer() {
+        buffered_writer(|inner| BufWriter::with_capacity(8, inner)).unwrap();
+        buffered_writer(|inner| BufWriter::with_capacity(64, inner)).unwrap();
+
+        let violation = buffered_writer(|inner| EagerWrite {
+            inner,
+            buf: Vec::new(),
+        })
+        .unwrap_err();
+        // The write is retried after an Interrupted error, so the data is copied in twice.
+        assert_eq!(violation.kind, ViolationKind::DuplicatedData);
+        assert!(
+            violation.to_string().contains("Err(Interrupted)"),
+            "{}",
+            violation
+        );
+
+        let violation = buffered_writer(|inner| ForgetfulWrite {
+            inner,
+            buf: Vec::new(),
+        })
+        .unwrap_err();
+        assert_eq!(violation.kind, ViolationKind::LostData);
+    }
+}
@@ -10,14 +10,20 @@
 
 use std::{
     cmp, fmt,
-    io::{self, Read, Write},
+    io::{self, Read, Seek, SeekFrom, Write},
 };
 
-use crate::{make_ops, PartialOp};
+use crate::{
+    seeded::SeededOps, seek::SeekOps, Alignment, AppliedOp, BufSize, OpEngine, PartialOp,
+    PartialWrap, Profile, SeekOp,
+};
 
 /// A writer wrapper that breaks inner `Write` instances up according to the
 /// provided iterator.
 ///
+/// The writer may be unsized, such as `PartialWrite<dyn Write>` behind a `Box`,
+/// or borrowed, such as `PartialWrite<&mut dyn Write>`.
+///
 /// # Examples
 ///
 /// ```rust
@@ -34,27 +40,132 @@ use crate::{make_ops, PartialOp};
 /// assert_eq!(size, 1);
 /// assert_eq!(&partial_writer.get_ref()[..], &[1]);
 /// ```
-pub struct PartialWrite<W> {
+pub struct PartialWrite<W: ?Sized> {
+    ops: OpEngine,
+    underreport: bool,
+    // The number of bytes passed to the inner writer that weren't reported as written.
+    unreported: u64,
+    partial_flushes: bool,
+    seek_ops: SeekOps,
+    lying_flushes: LyingFlushes,
     inner: W,
-    ops: Box<dyn Iterator<Item = PartialOp> + Send>,
 }
 
-impl<W> PartialWrite<W>
-where
-    W: Write,
-{
+/// A summary of the flushes on a writer, returned by `flush_report`.
+///
+/// This is meant to be checked at the end of a test that uses `set_lying_flushes`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FlushReport {
+    /// The number of flushes that returned `Ok` without reaching the inner writer.
+    pub skipped: usize,
+    /// The number of flushes that reached the inner writer, including those that failed.
+    pub forwarded: usize,
+    /// Whether everything written so far was followed by a successful flush of the inner writer.
+    ///
+    /// If this is false even though the caller finished with a flush, the caller trusted a flush
+    /// that was skipped, rather than retrying it or verifying that the data was stored.
+    pub durable: bool,
+}
+
+/// Tracks the state needed for `set_lying_flushes` on `Write` and `AsyncWrite` wrappers.
+#[derive(Debug)]
+pub(crate) struct LyingFlushes {
+    remaining: usize,
+    report: FlushReport,
+}
+
+impl Default for LyingFlushes {
+    fn default() -> Self {
+        LyingFlushes {
+            remaining: 0,
+            // Nothing has been written yet.
+            report: FlushReport {
+                durable: true,
+                ..FlushReport::default()
+            },
+        }
+    }
+}
+
+impl LyingFlushes {
+    pub(crate) fn set(&mut self, count: usize) {
+        self.remaining = count;
+    }
+
+    pub(crate) fn report(&self) -> FlushReport {
+        self.report
+    }
+
+    // Returns true if this flush should return `Ok` without reaching the inner writer.
+    pub(crate) fn lie(&mut self) -> bool {
+        if self.remaining == 0 {
+            return false;
+        }
+        self.remaining -= 1;
+        self.report.skipped += 1;
+        true
+    }
+
+    pub(crate) fn after_write(&mut self, res: &io::Result<usize>) {
+        if let Ok(n) = res {
+            self.report.durable &= *n == 0;
+        }
+    }
+
+    // Called with the result of a flush, or of a shutdown, that reached the inner writer.
+    pub(crate) fn after_flush<T>(&mut self, res: &io::Result<T>) {
+        self.report.forwarded += 1;
+        self.report.durable |= res.is_ok();
+    }
+}
+
+impl<W: ?Sized> PartialWrite<W> {
     /// Creates a new `PartialWrite` wrapper over the writer with the specified `PartialOp`s.
     pub fn new<I>(inner: W, iter: I) -> Self
     where
+        W: Sized,
         I: IntoIterator<Item = PartialOp> + 'static,
         I::IntoIter: Send,
     {
-        PartialWrite {
+        Self::from_engine(inner, OpEngine::new(iter))
+    }
+
+    /// Creates a new `PartialWrite` wrapper over the writer with a reproducible randomized schedule
+    /// of `PartialOp`s, generated from `seed` and `profile`.
+    ///
+    /// Unless the profile sets error kinds, operations fail with `Interrupted`. If the
+    /// thread panics while the wrapper is alive, the seed is printed when it's dropped. See
+    /// `Profile` for more.
+    pub fn with_seed(inner: W, seed: u64, profile: Profile) -> Self
+    where
+        W: Sized,
+    {
+        Self::new(
             inner,
-            // Use fuse here so that we don't keep calling the inner iterator
-            // once it's returned None.
-            ops: make_ops(iter),
-        }
+            SeededOps::for_wrapper("PartialWrite", seed, profile, io::ErrorKind::Interrupted),
+        )
+    }
+
+    /// Creates a new `PartialWrite` wrapper over the writer in passthrough mode, which forwards
+    /// every call as-is.
+    ///
+    /// Setting `PartialOp`s turns passthrough mode off. See `OpEngine::passthrough` for more.
+    pub fn passthrough(inner: W) -> Self
+    where
+        W: Sized,
+    {
+        Self::from_engine(inner, OpEngine::passthrough())
+    }
+
+    /// Creates a new `PartialWrite` wrapper over the writer that limits every operation to 1 byte,
+    /// forever.
+    ///
+    /// This doesn't allocate. See `OpEngine::byte_by_byte` for more.
+    pub fn byte_by_byte(inner: W) -> Self
+    where
+        W: Sized,
+    {
+        Self::from_engine(inner, OpEngine::byte_by_byte())
     }
 
     /// Sets the `PartialOp`s for this writer.
@@ -63,7 +174,150 @@ where
         I: IntoIterator<Item = PartialOp> + 'static,
         I::IntoIter: Send,
     {
-        self.ops = make_ops(iter);
+        self.ops.replace(iter);
+        self
+    }
+
+    /// Caps every operation on this writer to at most `max_op_size` bytes, in addition to the
+    /// limits in `PartialOp::Limited`.
+    ///
+    /// See `OpEngine::set_max_op_size` for more.
+    pub fn set_max_op_size(&mut self, max_op_size: usize) -> &mut Self {
+        self.ops.set_max_op_size(max_op_size);
+        self
+    }
+
+    /// Caps the number of bytes written through this writer in total to `limit`. Once it's
+    /// reached, every further write accepts 0 bytes, or fails with the error set with
+    /// `set_total_limit_error`.
+    ///
+    /// See `OpEngine::set_total_limit` for more.
+    pub fn set_total_limit(&mut self, limit: Option<u64>) -> &mut Self {
+        self.ops.set_total_limit(limit);
+        self
+    }
+
+    /// Makes writes on this writer fail with an error of kind `kind` once the limit set with
+    /// `set_total_limit` has been reached, like a full disk. Flushes still go through.
+    ///
+    /// See `OpEngine::set_total_limit_error` for more.
+    pub fn set_total_limit_error(&mut self, kind: Option<io::ErrorKind>) -> &mut Self {
+        self.ops.set_total_limit_error(kind);
+        self
+    }
+
+    /// Makes this writer misbehave by underreporting the bytes it accepted.
+    ///
+    /// With underreporting on, every call passes the whole buffer to the inner writer, but
+    /// `PartialOp::Limited(n)` reports at most `n` of the bytes it accepted as written. A caller
+    /// that trusts the count, such as `write_all`, writes the rest again, so the inner writer
+    /// receives duplicate data. This checks code that claims to detect or tolerate such writers,
+    /// such as a deduplicating layer.
+    ///
+    /// A `PartialOp::Fragment` still makes a single call into the inner writer, whose bytes are
+    /// then reported fragment by fragment, so it doesn't underreport.
+    ///
+    /// The limit set with `set_total_limit` caps the bytes passed to the inner writer, including
+    /// the ones that weren't reported.
+    ///
+    /// Buffer sizes recorded by `record_sizes` are the sizes reported, not the sizes passed in.
+    pub fn set_underreport(&mut self, underreport: bool) -> &mut Self {
+        self.underreport = underreport;
+        self
+    }
+
+    /// Makes flushes that take a `PartialOp::Limited` or `PartialOp::Fragment` return an
+    /// `Interrupted` error after flushing the inner writer.
+    ///
+    /// By default, flushes treat these operations as `PartialOp::Unlimited`, since a flush has no
+    /// length to limit. When enabled, such a flush is taken to have made partial progress, so the
+    /// caller has to call `flush` again, with the next operation. This exercises the retry loops
+    /// that wrappers need around `flush`, such as `BufWriter` flushing its buffer. The length of
+    /// the operation is ignored.
+    ///
+    /// This is ignored in passthrough mode.
+    pub fn set_partial_flushes(&mut self, enabled: bool) -> &mut Self {
+        self.partial_flushes = enabled;
+        self
+    }
+
+    /// Makes the next `count` flushes on this writer return `Ok` without flushing the inner writer.
+    ///
+    /// This models caches that acknowledge a flush before the data is stored, such as
+    /// battery-backed write caches or some network file systems. Skipped flushes don't consume a
+    /// `PartialOp`. Use `flush_report` at the end of a test to see whether the data written was
+    /// actually flushed.
+    ///
+    /// This is ignored in passthrough mode.
+    pub fn set_lying_flushes(&mut self, count: usize) -> &mut Self {
+        self.lying_flushes.set(count);
+        self
+    }
+
+    /// Returns a summary of the flushes on this writer so far.
+    ///
+    /// See `set_lying_flushes` for more.
+    pub fn flush_report(&self) -> FlushReport {
+        self.lying_flushes.report()
+    }
+
+    /// Sets the `SeekOp`s for seeks on this writer.
+    ///
+    /// See `SeekOp` for more.
+    pub fn set_seek_ops<I>(&mut self, iter: I) -> &mut Self
+    where
+        I: IntoIterator<Item = SeekOp> + 'static,
+        I::IntoIter: Send,
+    {
+        self.seek_ops.replace(iter);
+        self
+    }
+
+    /// Starts recording the operations applied by this writer, and the methods they are applied to.
+    ///
+    /// Use `into_parts` and `OpEngine::into_report` to see which operations were never reached.
+    pub fn record_ops(&mut self) -> &mut Self {
+        self.ops.record_ops();
+        self
+    }
+
+    /// Returns the operations applied by this writer since `record_ops` was called.
+    pub fn applied_ops(&self) -> &[AppliedOp] {
+        self.ops.applied_ops()
+    }
+
+    /// Starts recording the size of every buffer passed to this writer, along with the size
+    /// forwarded to the inner writer after limiting.
+    ///
+    /// See `OpEngine::record_sizes` for more.
+    pub fn record_sizes(&mut self) -> &mut Self {
+        self.ops.record_sizes();
+        self
+    }
+
+    /// Returns the buffer sizes recorded by this writer since `record_sizes` was called, in order.
+    pub fn buf_sizes(&self) -> &[BufSize] {
+        self.ops.buf_sizes()
+    }
+
+    /// Starts recording the timeline of method calls on this writer.
+    ///
+    /// See the `timeline` module for more.
+    pub fn record_calls(&mut self) -> &mut Self {
+        self.ops.record_calls();
+        self
+    }
+
+    /// Returns the method calls on this writer since `record_calls` was called, in order.
+    pub fn calls(&self) -> &[&'static str] {
+        self.ops.calls()
+    }
+
+    /// Snaps the sizes in `PartialOp::Limited` on this writer to boundaries.
+    ///
+    /// See `OpEngine::set_alignment` for more.
+    pub fn set_alignment(&mut self, alignment: Option<Alignment>) -> &mut Self {
+        self.ops.set_alignment(alignment);
         self
     }
 
@@ -78,44 +332,126 @@ where
     }
 
     /// Consumes this wrapper, returning the underlying writer.
-    pub fn into_inner(self) -> W {
+    pub fn into_inner(self) -> W
+    where
+        W: Sized,
+    {
         self.inner
     }
+
+    /// Consumes this wrapper, returning the underlying writer and the engine with the `PartialOp`s
+    /// that haven't been consumed yet.
+    ///
+    /// Use `OpEngine::into_ops` to inspect the operations that are left.
+    pub fn into_parts(self) -> (W, OpEngine)
+    where
+        W: Sized,
+    {
+        (self.inner, self.ops)
+    }
 }
 
 impl<W> Write for PartialWrite<W>
 where
-    W: Write,
+    W: Write + ?Sized,
 {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self.ops.next() {
-            Some(PartialOp::Limited(n)) => {
-                let len = cmp::min(n, buf.len());
-                self.inner.write(&buf[..len])
-            }
-            Some(PartialOp::Err(err)) => Err(io::Error::new(
-                err,
-                "error during write, generated by partial-io",
-            )),
-            Some(PartialOp::Unlimited) | None => self.inner.write(buf),
+        let inner = &mut self.inner;
+        let underreport = self.underreport;
+        // With underreporting, the end of the bytes the inner writer has accepted during this
+        // call. Later fragments of a `PartialOp::Fragment` report these bytes rather than passing
+        // them in again.
+        let mut accepted = 0;
+        // The total limit also counts the bytes that weren't reported.
+        let unreported = self.unreported;
+        let mut left = self
+            .ops
+            .total_left()
+            .map(|left| left.saturating_sub(unreported));
+        self.ops.begin_call("write");
+        let res = self.ops.run_fragmented(
+            |offset, limit| match limit {
+                _ if underreport => {
+                    if offset >= accepted {
+                        let mut len = buf.len() - offset;
+                        if let Some(left) = left {
+                            len = cmp::min(len as u64, left) as usize;
+                        }
+                        let written = inner.write(&buf[offset..offset + len])?;
+                        accepted = offset + written;
+                        left = left.map(|left| left - written as u64);
+                    }
+                    let reported = accepted - offset;
+                    Ok(limit.map_or(reported, |limit| cmp::min(reported, limit)))
+                }
+                Some(limit) => inner.write(&buf[offset..offset + limit]),
+                None => inner.write(&buf[offset..]),
+            },
+            buf.len(),
+            "error during write, generated by partial-io",
+        );
+        if let (true, Ok(n)) = (underreport, &res) {
+            self.unreported += (accepted - n) as u64;
         }
+        self.lying_flushes.after_write(&res);
+        res
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        match self.ops.next() {
-            Some(PartialOp::Err(err)) => Err(io::Error::new(
-                err,
-                "error during flush, generated by partial-io",
-            )),
-            _ => self.inner.flush(),
+        let inner = &mut self.inner;
+        let lying_flushes = &mut self.lying_flushes;
+        self.ops.begin_call("flush");
+        if !self.ops.is_passthrough() && lying_flushes.lie() {
+            return Ok(());
+        }
+        let res = self.ops.run_no_limit(
+            || {
+                let res = inner.flush();
+                lying_flushes.after_flush(&res);
+                res
+            },
+            "error during flush, generated by partial-io",
+        );
+        match res {
+            Ok(()) if self.partial_flushes && self.ops.took_limited() => {
+                Err(self.ops.injected_error(
+                    io::ErrorKind::Interrupted,
+                    "partial flush, generated by partial-io",
+                ))
+            }
+            res => res,
+        }
+    }
+}
+
+impl<W> PartialWrap for PartialWrite<W> {
+    type Inner = W;
+
+    fn from_engine(inner: W, engine: OpEngine) -> Self {
+        PartialWrite {
+            inner,
+            ops: engine,
+            underreport: false,
+            unreported: 0,
+            partial_flushes: false,
+            seek_ops: SeekOps::new(),
+            lying_flushes: LyingFlushes::default(),
         }
     }
+
+    fn engine_mut(&mut self) -> &mut OpEngine {
+        &mut self.ops
+    }
+
+    fn into_parts(self) -> (W, OpEngine) {
+        PartialWrite::into_parts(self)
+    }
 }
 
 // Forwarding impl to support duplex structs.
 impl<W> Read for PartialWrite<W>
 where
-    W: Read + Write,
+    W: Read + Write + ?Sized,
 {
     #[inline]
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
@@ -123,13 +459,25 @@ where
     }
 }
 
+// Seeks are forwarded to the inner writer, after applying `SeekOp`s.
+impl<W> Seek for PartialWrite<W>
+where
+    W: Seek + ?Sized,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = self.seek_ops.start(pos);
+        let res = self.inner.seek(pos);
+        self.seek_ops.complete(res)
+    }
+}
+
 impl<W> fmt::Debug for PartialWrite<W>
 where
-    W: fmt::Debug,
+    W: fmt::Debug + ?Sized,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("PartialWrite")
-            .field("inner", &self.inner)
+            .field("inner", &&self.inner)
             .finish()
     }
 }
@@ -140,10 +488,94 @@ mod tests {
 
     use std::fs::File;
 
-    use crate::tests::assert_send;
+    use crate::tests::{assert_send, assert_sync};
 
     #[test]
     fn test_sendable() {
         assert_send::<PartialWrite<File>>();
+        assert_sync::<PartialWrite<File>>();
+    }
+
+    #[test]
+    fn test_os_err() {
+        // ECONNRESET on Linux.
+        let ops = vec![PartialOp::OsErr(104), PartialOp::Unlimited];
+        let mut partial_write = PartialWrite::new(Vec::new(), ops);
+        let err = partial_write.write(b"abc").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(104));
+        assert_eq!(partial_write.write(b"abc").unwrap(), 3);
+    }
+
+    #[test]
+    fn test_lying_flushes() {
+        let mut partial_write = PartialWrite::new(Vec::new(), vec![PartialOp::Unlimited]);
+        partial_write.set_lying_flushes(2);
+        partial_write.write_all(b"abc").unwrap();
+        partial_write.flush().unwrap();
+        partial_write.flush().unwrap();
+        assert_eq!(
+            partial_write.flush_report(),
+            FlushReport {
+                skipped: 2,
+                forwarded: 0,
+                durable: false,
+            },
+            "the caller trusted a skipped flush"
+        );
+        partial_write.flush().unwrap();
+        assert!(partial_write.flush_report().durable);
+    }
+
+    #[test]
+    fn test_partial_flushes() {
+        let ops = vec![PartialOp::Limited(1), PartialOp::Fragment(1)];
+        let mut partial_write = PartialWrite::new(Vec::new(), ops.clone());
+        partial_write.flush().unwrap();
+        partial_write.flush().unwrap();
+
+        let mut partial_write = PartialWrite::new(Vec::new(), ops);
+        partial_write.set_partial_flushes(true);
+        for _ in 0..2 {
+            let err = partial_write.flush().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        }
+        partial_write.flush().unwrap();
+        assert_eq!(partial_write.flush_report().forwarded, 3);
+    }
+
+    #[test]
+    fn test_underreport() {
+        let ops = vec![PartialOp::Limited(2), PartialOp::Unlimited];
+        let mut partial_write = PartialWrite::new(Vec::new(), ops);
+        partial_write.set_underreport(true);
+        partial_write.write_all(b"abcd").unwrap();
+        assert_eq!(
+            partial_write.get_ref(),
+            b"abcdcd",
+            "the last 2 bytes were written again"
+        );
+    }
+
+    #[test]
+    fn test_underreport_fragment() {
+        let mut partial_write = PartialWrite::new(Vec::new(), vec![PartialOp::Fragment(2)]);
+        partial_write.set_underreport(true);
+        assert_eq!(partial_write.write(b"abcdef").unwrap(), 6);
+        assert_eq!(
+            partial_write.get_ref(),
+            b"abcdef",
+            "no bytes were passed in twice"
+        );
+    }
+
+    #[test]
+    fn test_underreport_total_limit() {
+        let mut partial_write = PartialWrite::new(Vec::new(), vec![PartialOp::Limited(2); 4]);
+        partial_write.set_underreport(true).set_total_limit(Some(4));
+        let written: Vec<_> = (0..4)
+            .map(|_| partial_write.write(&[0; 10]).unwrap())
+            .collect();
+        assert_eq!(written, [2, 0, 0, 0]);
+        assert_eq!(partial_write.get_ref().len(), 4);
     }
 }
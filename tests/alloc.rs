@@ -0,0 +1,84 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Checks that wrappers over `StaticOps` don't allocate.
+//!
+//! This is an integration test so that its global allocator only applies to this binary, rather
+//! than to every unit test in the crate.
+
+use partial_io::{OpEngine, PartialOp, PartialRead, StaticOps};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    io::Read,
+    iter,
+};
+
+// Counts the allocations made by each thread.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // The counter may be gone while the thread is shutting down.
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|count| count.get())
+}
+
+static OPS: [PartialOp; 3] = [
+    PartialOp::Limited(1),
+    PartialOp::Unlimited,
+    PartialOp::Limited(2),
+];
+
+#[test]
+fn test_static_ops() {
+    let before = allocations();
+    let mut partial_read = PartialRead::new(&b"abcdef"[..], StaticOps::new(&OPS));
+    let mut buf = [0; 4];
+    assert_eq!(partial_read.read(&mut buf).unwrap(), 1);
+    assert_eq!(partial_read.read(&mut buf).unwrap(), 4);
+    assert_eq!(partial_read.read(&mut buf).unwrap(), 1);
+    assert_eq!(partial_read.read(&mut buf).unwrap(), 0);
+    assert_eq!(allocations(), before, "no allocations");
+
+    let _boxed = OpEngine::new(iter::once(PartialOp::Unlimited));
+    assert!(allocations() > before, "other iterators are boxed");
+}
+
+#[cfg(feature = "futures03")]
+#[test]
+fn test_static_ops_async() {
+    use futures::{io::AsyncWrite, task::noop_waker_ref};
+    use partial_io::PartialAsyncWrite;
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    let before = allocations();
+    let mut partial_write = PartialAsyncWrite::new(futures::io::sink(), StaticOps::new(&OPS));
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let res = Pin::new(&mut partial_write).poll_write(&mut cx, b"abc");
+    assert!(matches!(res, Poll::Ready(Ok(1))));
+    assert_eq!(allocations(), before, "no allocations");
+}